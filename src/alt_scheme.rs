@@ -0,0 +1,71 @@
+use base64::{Engine as _, engine::general_purpose};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::utils;
+
+/// Checks the credentials presented with an alternative scheme, i.e. everything
+/// after the scheme name in the `Authorization` header.
+pub type CredentialFunc = Arc<dyn Fn(&str) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// Scheme challenged next to L402 in the same `WWW-Authenticate` header, e.g. `Basic`
+/// for staff accounts, so internal and paying users share endpoints. Requests that
+/// authenticate with it are served as paid without a token.
+#[derive(Clone)]
+pub struct AlternativeScheme {
+    /// Scheme name, matched case-insensitively, e.g. `Basic` or `Bearer`
+    pub name: String,
+    /// Auth-params of the challenge, e.g. `realm="staff"`
+    pub params: String,
+    pub verify: CredentialFunc,
+}
+
+impl AlternativeScheme {
+    /// `Basic` authentication against user to password pairs.
+    pub fn basic(realm: &str, users: HashMap<String, String>) -> Self {
+        let users = Arc::new(users);
+        AlternativeScheme {
+            name: "Basic".to_string(),
+            params: format!("realm=\"{}\"", realm),
+            verify: Arc::new(move |credentials: &str| {
+                let valid = general_purpose::STANDARD.decode(credentials.trim()).ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok())
+                    .is_some_and(|decoded| decoded.split_once(':')
+                        .and_then(|(user, password)| users.get(user).map(|expected| (expected, password)))
+                        .is_some_and(|(expected, password)| !expected.is_empty() && utils::constant_time_eq(expected.as_bytes(), password.as_bytes())));
+                Box::pin(async move { valid })
+            }),
+        }
+    }
+
+    /// `Bearer` authentication against a fixed set of tokens, e.g. internal API keys.
+    pub fn bearer(realm: &str, tokens: Vec<String>) -> Self {
+        let tokens = Arc::new(tokens);
+        AlternativeScheme {
+            name: "Bearer".to_string(),
+            params: format!("realm=\"{}\"", realm),
+            verify: Arc::new(move |credentials: &str| {
+                let credentials = credentials.trim();
+                let valid = tokens.iter().any(|token| !token.is_empty() && utils::constant_time_eq(token.as_bytes(), credentials.as_bytes()));
+                Box::pin(async move { valid })
+            }),
+        }
+    }
+
+    /// The challenge as it goes in `WWW-Authenticate`, e.g. `Basic realm="staff"`.
+    pub fn challenge(&self) -> String {
+        if self.params.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} {}", self.name, self.params)
+        }
+    }
+
+    /// Credentials of an `Authorization` header value, if it is in this scheme.
+    pub fn credentials<'a>(&self, auth_field: &'a str) -> Option<&'a str> {
+        let (name, credentials) = auth_field.trim().split_once(' ')?;
+        name.eq_ignore_ascii_case(&self.name).then(|| credentials.trim_start())
+    }
+}
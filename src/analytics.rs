@@ -0,0 +1,127 @@
+use rocket::Request;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+
+use crate::forwarded;
+use crate::paywall;
+use crate::store::{RevenueBucket, SpendRecord, TokenStore};
+
+const USER_AGENT_HEADER_NAME: &str = "User-Agent";
+
+/// Pseudonymous client id: truncated SHA256 of the client IP and user agent, so
+/// spend can be attributed across tokens without storing either. The IP is the one
+/// resolved through the middleware's trusted proxies.
+pub fn client_fingerprint(request: &Request<'_>) -> String {
+    let ip = forwarded::client_address(request).map(|ip| ip.to_string()).unwrap_or_default();
+    let user_agent = request.headers().get_one(USER_AGENT_HEADER_NAME).unwrap_or("");
+    let digest = Sha256::digest(format!("{}\n{}", ip, user_agent).as_bytes());
+    hex::encode(&digest[..16])
+}
+
+/// Usage a client can look up with its own token.
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub token_id: String,
+    /// Spend and requests made with this token
+    pub token: SpendRecord,
+    /// Spend and requests across all tokens used from the caller's fingerprint
+    pub client: SpendRecord,
+    /// Quota the token was minted with, if any
+    pub max_uses: Option<u64>,
+    pub remaining_uses: Option<u64>,
+}
+
+pub async fn usage_report(
+    store: &dyn TokenStore,
+    token_id: &str,
+    fingerprint: &str,
+    max_uses: Option<u64>,
+) -> Result<UsageReport, Box<dyn Error + Send + Sync>> {
+    let token = store.get_token_spend(token_id).await?.unwrap_or_default();
+    let client = store.get_fingerprint_spend(fingerprint).await?.unwrap_or_default();
+    let remaining_uses = match max_uses {
+        Some(max_uses) => {
+            let used = store.get_usage(token_id).await?.map(|u| u.uses).unwrap_or(0);
+            Some(max_uses.saturating_sub(used))
+        },
+        None => None,
+    };
+    Ok(UsageReport {
+        token_id: token_id.to_string(),
+        token,
+        client,
+        max_uses,
+        remaining_uses,
+    })
+}
+
+/// Settled revenue of one route, across hours and backends.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteRevenue {
+    pub path: String,
+    pub payments: u64,
+    pub revenue_msat: i64,
+}
+
+/// Which routes earned what since a point in time, for the `revenue` endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct RevenueReport {
+    pub since: u64,
+    pub payments: u64,
+    pub revenue_msat: i64,
+    /// Routes by revenue, highest first
+    pub routes: Vec<RouteRevenue>,
+    /// Revenue by route, hour and backend, oldest hour first
+    pub buckets: Vec<RevenueBucket>,
+}
+
+pub async fn revenue_report(store: &dyn TokenStore, since: u64) -> Result<RevenueReport, Box<dyn Error + Send + Sync>> {
+    let buckets = store.revenue_buckets(since).await?;
+    let mut routes: Vec<RouteRevenue> = Vec::new();
+    for bucket in &buckets {
+        match routes.iter_mut().find(|route| route.path == bucket.path) {
+            Some(route) => {
+                route.payments += bucket.payments;
+                route.revenue_msat += bucket.revenue_msat;
+            },
+            None => routes.push(RouteRevenue { path: bucket.path.clone(), payments: bucket.payments, revenue_msat: bucket.revenue_msat }),
+        }
+    }
+    routes.sort_by(|a, b| b.revenue_msat.cmp(&a.revenue_msat).then_with(|| a.path.cmp(&b.path)));
+    Ok(RevenueReport {
+        since,
+        payments: routes.iter().map(|route| route.payments).sum(),
+        revenue_msat: routes.iter().map(|route| route.revenue_msat).sum(),
+        routes,
+        buckets,
+    })
+}
+
+// `2024-05-01 13:00 UTC`
+fn utc_time(timestamp: u64) -> String {
+    match rocket::time::OffsetDateTime::from_unix_timestamp(timestamp as i64) {
+        Ok(time) => format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", time.year(), time.month() as u8, time.day(), time.hour(), time.minute()),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+/// Minimal HTML page of a revenue report: totals per route, then per hour and backend.
+pub fn render_revenue_dashboard(report: &RevenueReport) -> String {
+    let route_rows: String = report.routes.iter().map(|route| format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+        paywall::escape_html(&route.path), route.payments, route.revenue_msat / 1000,
+    )).collect();
+    let bucket_rows: String = report.buckets.iter().map(|bucket| format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        utc_time(bucket.hour), paywall::escape_html(&bucket.path), paywall::escape_html(&bucket.backend), bucket.payments, bucket.revenue_msat / 1000,
+    )).collect();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>L402 revenue</title></head><body>\
+         <h1>L402 revenue</h1><p>{} payments, {} sat since {}</p>\
+         <h2>Routes</h2><table><tr><th>Path</th><th>Payments</th><th>Sat</th></tr>{}</table>\
+         <h2>By hour</h2><table><tr><th>Hour</th><th>Path</th><th>Backend</th><th>Payments</th><th>Sat</th></tr>{}</table>\
+         </body></html>",
+        report.payments, report.revenue_msat / 1000, utc_time(report.since), route_rows, bucket_rows,
+    )
+}
@@ -0,0 +1,140 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::metrics::L402Metrics;
+use crate::observer::{self, L402Event, L402Observer};
+
+/// Thresholds for the anomaly detectors, evaluated over each `interval` on a
+/// background task after liftoff.
+#[derive(Debug, Clone)]
+pub struct AnomalyConfig {
+    /// How often the detectors compare the metrics against the previous sample
+    pub interval: Duration,
+    /// Tokens failing verification within an interval, e.g. from a brute-force attempt
+    pub max_verification_failures: u64,
+    /// Share of invoice creations failing within an interval, e.g. from a broken node
+    pub max_invoice_error_rate: f64,
+    /// Share of the challenges issued within an interval that weren't matched by
+    /// settlements, e.g. from someone draining invoices
+    pub max_unpaid_challenge_ratio: f64,
+    /// Challenges needed within an interval before the ratios are evaluated, so a
+    /// handful of requests can't trip them
+    pub min_challenges: u64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            interval: Duration::from_secs(60),
+            max_verification_failures: 100,
+            max_invoice_error_rate: 0.5,
+            max_unpaid_challenge_ratio: 0.99,
+            min_challenges: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    VerificationFailureSpike,
+    InvoiceErrorRate,
+    UnpaidChallengeRatio,
+}
+
+#[derive(Default)]
+struct DetectorState {
+    // Counter values at the previous sample
+    tokens_rejected: u64,
+    challenges_issued: u64,
+    challenges_settled: u64,
+    invoice_errors: u64,
+    // Anomalies already reported, so an ongoing one alerts once until it clears
+    active: HashSet<AnomalyKind>,
+}
+
+/// Samples the middleware's metrics and reports anomalies to the observers.
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    metrics: Arc<L402Metrics>,
+    observers: Vec<Arc<dyn L402Observer>>,
+    state: Mutex<DetectorState>,
+}
+
+impl AnomalyDetector {
+    /// Starts counting from the metrics' current values.
+    pub fn new(config: AnomalyConfig, metrics: Arc<L402Metrics>, observers: Vec<Arc<dyn L402Observer>>) -> Self {
+        let state = DetectorState {
+            tokens_rejected: load(&metrics.tokens_rejected),
+            challenges_issued: load(&metrics.challenges_issued),
+            challenges_settled: load(&metrics.challenges_settled),
+            invoice_errors: load(&metrics.invoice_errors),
+            active: HashSet::new(),
+        };
+        AnomalyDetector { config, metrics, observers, state: Mutex::new(state) }
+    }
+
+    /// Evaluates the detectors over the counts since the previous call.
+    /// Returns the anomalies newly reported.
+    pub fn run_once(&self) -> Vec<AnomalyKind> {
+        let Ok(mut state) = self.state.lock() else {
+            return Vec::new();
+        };
+        let rejected = delta(&self.metrics.tokens_rejected, &mut state.tokens_rejected);
+        let issued = delta(&self.metrics.challenges_issued, &mut state.challenges_issued);
+        let settled = delta(&self.metrics.challenges_settled, &mut state.challenges_settled);
+        let invoice_errors = delta(&self.metrics.invoice_errors, &mut state.invoice_errors);
+
+        let attempts = issued + invoice_errors;
+        let invoice_error_rate = (attempts >= self.config.min_challenges).then(|| invoice_errors as f64 / attempts as f64);
+        let unpaid_ratio = (issued >= self.config.min_challenges).then(|| 1.0 - settled.min(issued) as f64 / issued as f64);
+        let observations = [
+            (AnomalyKind::VerificationFailureSpike, Some(rejected as f64), self.config.max_verification_failures as f64),
+            (AnomalyKind::InvoiceErrorRate, invoice_error_rate, self.config.max_invoice_error_rate),
+            (AnomalyKind::UnpaidChallengeRatio, unpaid_ratio, self.config.max_unpaid_challenge_ratio),
+        ];
+
+        let mut reported = Vec::new();
+        for (kind, observed, threshold) in observations {
+            match observed {
+                Some(observed) if observed > threshold => {
+                    if state.active.insert(kind) {
+                        println!("L402 anomaly {:?}: {} over threshold {}", kind, observed, threshold);
+                        observer::notify(&self.observers, L402Event::Anomaly { kind, observed, threshold });
+                        reported.push(kind);
+                    }
+                },
+                _ => {
+                    state.active.remove(&kind);
+                },
+            }
+        }
+        reported
+    }
+
+    /// Runs the detectors every `interval` on the current runtime.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                self.run_once();
+            }
+        })
+    }
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+fn delta(counter: &AtomicU64, previous: &mut u64) -> u64 {
+    let current = load(counter);
+    let delta = current.saturating_sub(*previous);
+    *previous = current;
+    delta
+}
@@ -0,0 +1,50 @@
+use serde::Serialize;
+use std::error::Error;
+
+use crate::proof::PaymentProof;
+use crate::store::{PresentationRecord, TokenStore};
+
+/// What the ledger knows about one token, for investigating token sharing and
+/// payment disputes: who it was issued to, its receipt once paid, and each client
+/// fingerprint that presented it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenAudit {
+    pub payment_hash: String,
+    /// Client the challenge was issued to, see `analytics::client_fingerprint`
+    pub issued_to: Option<String>,
+    /// Unix timestamp the challenge was issued
+    pub issued_at: Option<u64>,
+    /// Payment proof, once a client proved the payment
+    pub receipt: Option<PaymentProof>,
+    /// Unix timestamp the token was first presented, from any fingerprint
+    pub first_presented_at: Option<u64>,
+    /// Seconds between the token's first and last presentation
+    pub replay_window_secs: u64,
+    /// Whether more than one fingerprint presented the token
+    pub shared: bool,
+    /// Fingerprints that presented the token, in order of first presentation
+    pub presentations: Vec<PresentationRecord>,
+}
+
+/// Audit of the token `payment_hash`, or None if the ledger has never seen it.
+pub async fn token_audit(store: &dyn TokenStore, payment_hash: &str) -> Result<Option<TokenAudit>, Box<dyn Error + Send + Sync>> {
+    let payment_hash = payment_hash.to_lowercase();
+    let challenge = store.get_challenge(&payment_hash).await?;
+    let presentations = store.token_presentations(&payment_hash).await?;
+    if challenge.is_none() && presentations.is_empty() {
+        return Ok(None);
+    }
+
+    let first_presented_at = presentations.iter().map(|p| p.first_seen).min();
+    let last_presented_at = presentations.iter().map(|p| p.last_seen).max();
+    Ok(Some(TokenAudit {
+        issued_to: challenge.as_ref().map(|c| c.fingerprint.clone()),
+        issued_at: challenge.as_ref().map(|c| c.created_at),
+        receipt: challenge.and_then(PaymentProof::from_challenge),
+        first_presented_at,
+        replay_window_secs: last_presented_at.zip(first_presented_at).map(|(last, first)| last - first).unwrap_or(0),
+        shared: presentations.len() > 1,
+        presentations,
+        payment_hash,
+    }))
+}
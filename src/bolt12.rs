@@ -1,14 +1,14 @@
-use std::{error::Error, sync::Arc, path::Path};
+use std::{error::Error, sync::Arc};
 use tokio::sync::Mutex;
 use std::future::Future;
 use std::pin::Pin;
 use cln_rpc::ClnRpc;
 use cln_rpc::model::requests::FetchinvoiceRequest;
-use cln_rpc::model::responses::FetchinvoiceResponse;
-use cln_rpc::primitives::Amount;
-use crate::lndrpc::lnrpc;
+use cln_rpc::model::responses::{FetchinvoiceResponse, ListinvoicesInvoicesStatus};
+use cln_rpc::primitives::{Amount, Sha256};
+use lightning::offers::offer::Offer;
 
-use crate::lnclient;
+use crate::lnclient::{self, BackendError};
 
 #[derive(Debug, Clone)]
 pub struct Bolt12Options {
@@ -25,6 +25,20 @@ pub trait Bolt12Backend: Send + Sync {
         amount_msat: u64,
         memo: Option<String>,
     ) -> Pin<Box<dyn Future<Output = Result<(String, Vec<u8>, Option<Vec<u8>>), Box<dyn Error + Send + Sync>>> + Send>>;
+
+    fn lookup_invoice(&self, _payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        Box::pin(async { Err("Invoice lookup is not supported by this BOLT12 backend".into()) })
+    }
+
+    /// Amount received by a settled invoice for `offer`, see `LNClient::lookup_offer_payment`.
+    fn lookup_offer_payment(&self, _offer: &str, _payment_hash: [u8; 32]) -> lnclient::LNClientFuture<Option<i64>> {
+        Box::pin(async { Err("Offer payments are not supported by this BOLT12 backend".into()) })
+    }
+
+    /// Closes the node connection, see `LNClient::close`.
+    fn close(&self) -> lnclient::LNClientFuture<()> {
+        Box::pin(async { Ok(()) })
+    }
 }
 
 /// CLN Implementation of Bolt12Backend
@@ -55,15 +69,7 @@ impl Bolt12Backend for ClnBolt12Backend {
 
         Box::pin(async move {
             let mut client_guard = client.lock().await;
-
-            if client_guard.is_none() {
-                let new_client = ClnRpc::new(Path::new(&lightning_dir)).await
-                    .map_err(|e| format!("CLN RPC error: {}", e))?;
-                *client_guard = Some(new_client);
-            }
-
-            let client = client_guard.as_mut().unwrap();
-
+            let client = crate::cln::connect(&mut client_guard, &lightning_dir).await?;
             let fetch_invoice_request = FetchinvoiceRequest {
                 offer: offer,
                 amount_msat: Some(Amount::from_msat(amount_msat)),
@@ -81,7 +87,7 @@ impl Bolt12Backend for ClnBolt12Backend {
                 Ok(res) => res,
                 Err(e) => {
                     *client_guard = None;
-                    return Err(format!("CLN RPC error: {}", e).into());
+                    return Err(crate::cln::rpc_error(e).into());
                 }
             };
 
@@ -96,7 +102,7 @@ impl Bolt12Backend for ClnBolt12Backend {
                  Ok(res) => res,
                  Err(e) => {
                      *client_guard = None;
-                     return Err(format!("CLN RPC error during decode: {}", e).into());
+                     return Err(crate::cln::rpc_error(e).into());
                  }
             };
 
@@ -104,9 +110,9 @@ impl Bolt12Backend for ClnBolt12Backend {
             let payment_hash_bytes = if let Some(ph) = decode_response.payment_hash {
                 <cln_rpc::primitives::Sha256 as AsRef<[u8]>>::as_ref(&ph).to_vec()
             } else if let Some(ph_hex) = decode_response.invoice_payment_hash {
-                hex::decode(ph_hex).map_err(|e| format!("Invalid hex in invoice_payment_hash: {}", e))?
+                hex::decode(ph_hex).map_err(|e| BackendError::malformed(format!("Invalid hex in invoice_payment_hash: {}", e)))?
             } else {
-                return Err("No payment hash in decode response".into());
+                return Err(BackendError::malformed("No payment hash in decode response").into());
             };
 
             let payment_secret = decode_response.payment_secret.map(|s| s.to_vec());
@@ -114,63 +120,124 @@ impl Bolt12Backend for ClnBolt12Backend {
             Ok((invoice_str, payment_hash_bytes, payment_secret))
         })
     }
+
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        let client = Arc::clone(&self.client);
+        let lightning_dir = self.lightning_dir.clone();
+
+        Box::pin(async move {
+            let mut client_guard = client.lock().await;
+            let client = crate::cln::connect(&mut client_guard, &lightning_dir).await?;
+            let result = crate::cln::lookup_invoice_state(client, payment_hash).await;
+            if result.is_err() {
+                *client_guard = None;
+            }
+            result
+        })
+    }
+
+    fn lookup_offer_payment(&self, offer: &str, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<Option<i64>> {
+        let client = Arc::clone(&self.client);
+        let lightning_dir = self.lightning_dir.clone();
+        let offer_id = offer.parse::<Offer>().map(|offer| offer.id().0).map_err(|e| format!("Invalid offer: {:?}", e));
+
+        Box::pin(async move {
+            let offer_id = offer_id?;
+            let mut client_guard = client.lock().await;
+            let client = crate::cln::connect(&mut client_guard, &lightning_dir).await?;
+            let invoice = match crate::cln::find_invoice(client, payment_hash).await {
+                Ok(Some(invoice)) => invoice,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    *client_guard = None;
+                    return Err(e);
+                }
+            };
+            let for_offer = invoice.local_offer_id.is_some_and(|id| <Sha256 as AsRef<[u8]>>::as_ref(&id) == offer_id.as_slice());
+            if !for_offer || invoice.status != ListinvoicesInvoicesStatus::PAID {
+                return Ok(None);
+            }
+            Ok(invoice.amount_received_msat.map(|amount| amount.msat() as i64))
+        })
+    }
+    fn close(&self) -> lnclient::LNClientFuture<()> {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move {
+            *client.lock().await = None;
+            Ok(())
+        })
+    }
 }
 
 pub struct Bolt12Wrapper {
     backend: Arc<dyn Bolt12Backend>,
     offer: String,
+    lightning_dir: String,
 }
 
 impl Bolt12Wrapper {
     pub async fn new_client(
         ln_client_config: &lnclient::LNClientConfig,
-    ) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn Error + Send + Sync>> {
-        let bolt12_options = ln_client_config.bolt12_config.clone().unwrap();
+    ) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn Error + Send + Sync>> {
+        let bolt12_options = ln_client_config.bolt12_config.clone()
+            .ok_or("bolt12_config is required for the BOLT12 client")?;
 
         println!("BOLT12 client {} with offer {}", bolt12_options.lightning_dir, bolt12_options.offer);
 
         // In the future, we can check config to decide which backend to instantiate
-        let backend = ClnBolt12Backend::new(bolt12_options.lightning_dir);
+        let backend = ClnBolt12Backend::new(bolt12_options.lightning_dir.clone());
 
         let wrapper = Bolt12Wrapper {
             backend: Arc::new(backend),
             offer: bolt12_options.offer,
+            lightning_dir: bolt12_options.lightning_dir,
         };
 
-        Ok(Arc::new(Mutex::new(wrapper)))
+        Ok(Arc::new(wrapper))
     }
 }
 
 impl lnclient::LNClient for Bolt12Wrapper {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>>> + Send>> {
+    fn backend_id(&self) -> String {
+        format!("bolt12:{}", self.lightning_dir)
+    }
+
+    fn static_offer(&self) -> Option<String> {
+        Some(self.offer.clone())
+    }
+
+    fn lookup_offer_payment(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<Option<i64>> {
+        self.backend.lookup_offer_payment(&self.offer, payment_hash)
+    }
+
+    fn close(&self) -> lnclient::LNClientFuture<()> {
+        self.backend.close()
+    }
+
+    fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
         let backend = Arc::clone(&self.backend);
         let offer = self.offer.clone();
 
         Box::pin(async move {
-            let memo = if invoice.memo.is_empty() { None } else { Some(invoice.memo.clone()) };
+            // Offers can't commit to a description hash, so a hashed memo stays private
+            let memo = invoice.description();
             
             let amount_msat = u64::try_from(invoice.value_msat)
                 .map_err(|_| format!("invalid value_msat: {}", invoice.value_msat))?;
-            let (payment_request, r_hash, payment_secret) = backend.fetch_invoice(
+            let (payment_request, r_hash, _payment_secret) = backend.fetch_invoice(
                 &offer,
                 amount_msat,
                 memo
             ).await?;
             
-            Ok(lnrpc::AddInvoiceResponse {
-                r_hash,
+            Ok(lnclient::InvoiceResponse {
+                payment_hash: r_hash.try_into().map_err(|_| "Invalid payment hash length from BOLT12 backend")?,
                 payment_request,
-                add_index: 0,
-                payment_addr: if let Some(secret) = payment_secret {
-                    secret
-                } else {
-                    vec![]
-                },
             })
         })
     }
-}
 
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        self.backend.lookup_invoice(payment_hash)
+    }
+}
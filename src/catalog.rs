@@ -0,0 +1,140 @@
+use rocket::Request;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::fiat::{self, FiatRateConfig};
+use crate::l402::BundleOption;
+use crate::middleware::{AmountFunc, BundleFunc, FiatPriceFunc};
+
+/// How a route is priced.
+#[derive(Debug, Clone)]
+pub enum RoutePrice {
+    Msat(i64),
+    /// Converted to sats at the current rate for every challenge
+    Fiat(FiatRateConfig),
+}
+
+/// A paid route, as charged by the middleware and advertised in the catalog.
+#[derive(Debug, Clone)]
+pub struct PricedRoute {
+    pub method: String,
+    /// Exact request path, e.g. `/protected`
+    pub path: String,
+    pub description: Option<String>,
+    pub price: RoutePrice,
+    /// Caveats a token for this route is restricted by, e.g. `RequestPath = /protected`
+    pub scope: Vec<String>,
+    pub bundles: Vec<BundleOption>,
+}
+
+impl PricedRoute {
+    pub fn new(method: &str, path: &str, price: RoutePrice) -> Self {
+        PricedRoute {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            description: None,
+            price,
+            scope: Vec::new(),
+            bundles: Vec::new(),
+        }
+    }
+
+    async fn price_msat(&self) -> Option<i64> {
+        match &self.price {
+            RoutePrice::Msat(msat) => Some(*msat),
+            RoutePrice::Fiat(fiat_rate_config) => fiat_rate_config.fetch_btc_amount().await.ok(),
+        }
+    }
+}
+
+/// Prices by route. Wire its hooks into the middleware so challenges are priced
+/// from the same table the `catalog` endpoint publishes.
+#[derive(Debug, Clone, Default)]
+pub struct PricingTable {
+    pub routes: Vec<PricedRoute>,
+    /// Price of requests that match no route
+    pub default_msat: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogBundle {
+    pub uses: u64,
+    pub price_msat: Option<i64>,
+}
+
+/// A route as listed by `GET <base>/catalog`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogEntry {
+    pub method: String,
+    pub path: String,
+    pub description: Option<String>,
+    /// Current price; None if the fiat rate couldn't be fetched
+    pub price_msat: Option<i64>,
+    /// Fiat amount behind the price, e.g. `0.01 USD`
+    pub price_fiat: Option<String>,
+    pub scope: Vec<String>,
+    /// Calls a single-call token pays for, when the middleware sets `max_uses`
+    pub max_uses: Option<u64>,
+    pub bundles: Vec<CatalogBundle>,
+}
+
+impl PricingTable {
+    pub fn route_for(&self, request: &Request<'_>) -> Option<&PricedRoute> {
+        let path = request.uri().path();
+        self.routes.iter().find(|route| route.method == request.method().as_str() && route.path == path.as_str())
+    }
+
+    pub fn amount_func(self: &Arc<Self>) -> AmountFunc {
+        let table = Arc::clone(self);
+        Arc::new(move |request: &Request<'_>| {
+            let route = table.route_for(request).cloned();
+            let default_msat = table.default_msat;
+            Box::pin(async move {
+                match route {
+                    Some(route) => route.price_msat().await.unwrap_or(fiat::MIN_SATS_TO_BE_PAID * fiat::MSAT_PER_SAT),
+                    None => default_msat,
+                }
+            })
+        })
+    }
+
+    pub fn fiat_price_func(self: &Arc<Self>) -> FiatPriceFunc {
+        let table = Arc::clone(self);
+        Arc::new(move |request: &Request<'_>| match &table.route_for(request)?.price {
+            RoutePrice::Fiat(fiat_rate_config) => fiat_rate_config.fiat_price(),
+            RoutePrice::Msat(_) => None,
+        })
+    }
+
+    pub fn bundle_func(self: &Arc<Self>) -> BundleFunc {
+        let table = Arc::clone(self);
+        Arc::new(move |request: &Request<'_>| table.route_for(request).map(|route| route.bundles.clone()).unwrap_or_default())
+    }
+
+    /// Lists every route at its current price.
+    pub async fn catalog(&self, max_uses: Option<u64>) -> Vec<CatalogEntry> {
+        let mut entries = Vec::new();
+        for route in &self.routes {
+            let price_msat = route.price_msat().await;
+            let price_fiat = match &route.price {
+                RoutePrice::Fiat(fiat_rate_config) => fiat_rate_config.fiat_price().map(|price| price.to_string()),
+                RoutePrice::Msat(_) => None,
+            };
+            let bundles = route.bundles.iter().map(|bundle| CatalogBundle {
+                uses: bundle.uses,
+                price_msat: bundle.amount_msat.or(price_msat.map(|msat| msat.saturating_mul(bundle.uses as i64))),
+            }).collect();
+            entries.push(CatalogEntry {
+                method: route.method.clone(),
+                path: route.path.clone(),
+                description: route.description.clone(),
+                price_msat,
+                price_fiat,
+                scope: route.scope.clone(),
+                max_uses,
+                bundles,
+            });
+        }
+        entries
+    }
+}
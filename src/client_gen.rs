@@ -0,0 +1,143 @@
+use serde::Serialize;
+
+use crate::catalog::CatalogEntry;
+use crate::l402;
+
+// Client program the generated Rust snippet wraps around the route table. It needs
+// `reqwest`, `tokio` and `nwc` (Nostr Wallet Connect) as dependencies.
+const RUST_CLIENT_TEMPLATE: &str = r#"// Cargo.toml: reqwest = "0.12", tokio = { version = "1", features = ["full"] }, nwc = "0.41"
+use nwc::prelude::*;
+
+const BASE_URL: &str = "__BASE_URL__";
+
+// Paid routes, as listed by the service catalog
+__ROUTES__
+
+// Parses `L402 macaroon="...", invoice="..."` into the macaroon and the invoice
+fn parse_challenge(header: &str) -> Option<(String, String)> {
+    let (_, params) = header.split_once(' ')?;
+    let mut macaroon = None;
+    let mut invoice = None;
+    for param in params.split(',') {
+        let (key, value) = param.trim().split_once('=')?;
+        let value = value.trim_matches('"').to_string();
+        match key {
+            "macaroon" => macaroon = Some(value),
+            "invoice" => invoice = Some(value),
+            _ => {}
+        }
+    }
+    Some((macaroon?, invoice?))
+}
+
+async fn call_paid_route(
+    http: &reqwest::Client,
+    wallet: &NWC,
+    method: reqwest::Method,
+    path: &str,
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let url = format!("{}{}", BASE_URL, path);
+    let response = http.request(method.clone(), &url)
+        .header("__ACCEPT_HEADER__", "__SCHEME__")
+        .send().await?;
+    if response.status() != reqwest::StatusCode::PAYMENT_REQUIRED {
+        return Ok(response);
+    }
+
+    // 402: pay the challenge invoice and retry with the token
+    let challenge = response.headers().get("__CHALLENGE_HEADER__")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_challenge)
+        .ok_or("402 without an L402 challenge")?;
+    let (macaroon, invoice) = challenge;
+    let paid = wallet.pay_invoice(PayInvoiceRequest::new(invoice)).await?;
+
+    // The token stays valid for later calls to the route until it is rejected
+    let token = format!("__SCHEME__ {}:{}", macaroon, paid.preimage);
+    Ok(http.request(method, &url).header("__AUTHORIZATION_HEADER__", token).send().await?)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let uri = NostrWalletConnectURI::parse(std::env::var("NWC_URI")?)?;
+    let wallet = NWC::new(uri);
+    let http = reqwest::Client::new();
+
+    for (method, path) in ROUTES {
+        let response = call_paid_route(&http, &wallet, reqwest::Method::from_bytes(method.as_bytes())?, path).await?;
+        println!("{} {} -> {}", method, path, response.status());
+    }
+    Ok(())
+}
+"#;
+
+/// Client code for consumers of a paid API: a Rust program that handles the 402,
+/// pays with NWC and retries, and the same flow with curl.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientSnippets {
+    pub rust: String,
+    pub curl: String,
+}
+
+fn price_comment(entry: &CatalogEntry) -> String {
+    let mut price = match entry.price_msat {
+        Some(msat) => format!("{} msat", msat),
+        None => "price unavailable".to_string(),
+    };
+    if let Some(price_fiat) = &entry.price_fiat {
+        price.push_str(&format!(" ({})", price_fiat));
+    }
+    if let Some(max_uses) = entry.max_uses {
+        price.push_str(&format!(", {} calls per token", max_uses));
+    }
+    match &entry.description {
+        Some(description) => format!("{}: {}", description, price),
+        None => price,
+    }
+}
+
+fn rust_snippet(base_url: &str, catalog: &[CatalogEntry]) -> String {
+    let mut routes = format!("const ROUTES: [(&str, &str); {}] = [\n", catalog.len());
+    for entry in catalog {
+        routes.push_str(&format!("    // {}\n    ({:?}, {:?}),\n", price_comment(entry), entry.method, entry.path));
+    }
+    routes.push_str("];");
+
+    RUST_CLIENT_TEMPLATE
+        .replace("__BASE_URL__", base_url)
+        .replace("__ROUTES__", &routes)
+        .replace("__ACCEPT_HEADER__", l402::L402_HEADER_NAME)
+        .replace("__CHALLENGE_HEADER__", l402::L402_AUTHENTICATE_HEADER_NAME)
+        .replace("__AUTHORIZATION_HEADER__", l402::L402_AUTHORIZATION_HEADER_NAME)
+        .replace("__SCHEME__", l402::L402_HEADER)
+}
+
+fn curl_snippet(base_url: &str, catalog: &[CatalogEntry]) -> String {
+    let mut snippet = String::new();
+    for entry in catalog {
+        let url = format!("{}{}", base_url, entry.path);
+        snippet.push_str(&format!("# {} {} - {}\n", entry.method, entry.path, price_comment(entry)));
+        snippet.push_str(&format!(
+            "# 1. Request the route; the 402 carries `{}: {} macaroon=\"...\", invoice=\"...\"`\n",
+            l402::L402_AUTHENTICATE_HEADER_NAME, l402::L402_HEADER,
+        ));
+        snippet.push_str(&format!("curl -i -X {} -H '{}: {}' '{}'\n", entry.method, l402::L402_HEADER_NAME, l402::L402_HEADER, url));
+        snippet.push_str("# 2. Pay the invoice with your NWC wallet and note the preimage\n");
+        snippet.push_str("# 3. Retry with the token\n");
+        snippet.push_str(&format!(
+            "curl -X {} -H '{}: {} <macaroon>:<preimage>' '{}'\n\n",
+            entry.method, l402::L402_AUTHORIZATION_HEADER_NAME, l402::L402_HEADER, url,
+        ));
+    }
+    snippet
+}
+
+/// Generates client snippets for the routes in `catalog`, e.g. from
+/// `PricingTable::catalog`, calling the service at `base_url`.
+pub fn generate_client(base_url: &str, catalog: &[CatalogEntry]) -> ClientSnippets {
+    let base_url = base_url.trim_end_matches('/');
+    ClientSnippets {
+        rust: rust_snippet(base_url, catalog),
+        curl: curl_snippet(base_url, catalog),
+    }
+}
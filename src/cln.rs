@@ -1,15 +1,12 @@
 use std::{error::Error, sync::Arc, path::Path};
 use tokio::sync::Mutex;
-use std::future::Future;
-use std::pin::Pin;
-use cln_rpc::ClnRpc;
-use cln_rpc::model::requests::InvoiceRequest;
-use cln_rpc::model::responses::InvoiceResponse;
+use cln_rpc::{ClnRpc, RpcError};
+use cln_rpc::model::requests::{DelinvoiceRequest, DelinvoiceStatus, InvoiceRequest, ListinvoicesRequest};
+use cln_rpc::model::responses::{DelinvoiceResponse, InvoiceResponse, ListinvoicesInvoices, ListinvoicesInvoicesStatus, ListinvoicesResponse};
 use cln_rpc::primitives::{Amount, AmountOrAny, Sha256};
-use crate::lndrpc::lnrpc;
 use uuid::Uuid;
 
-use crate::lnclient;
+use crate::lnclient::{self, BackendError};
 
 #[derive(Debug, Clone)]
 pub struct CLNOptions {
@@ -17,6 +14,7 @@ pub struct CLNOptions {
 }
 
 pub struct CLNWrapper {
+    // ClnRpc calls need `&mut`, so the socket is serialized here rather than by callers
     client: Arc<Mutex<Option<ClnRpc>>>,
     lightning_dir: String,
 }
@@ -24,8 +22,9 @@ pub struct CLNWrapper {
 impl CLNWrapper {
     pub async fn new_client(
         ln_client_config: &lnclient::LNClientConfig,
-    ) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn Error + Send + Sync>> {
-        let cln_options = ln_client_config.cln_config.clone().unwrap();
+    ) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn Error + Send + Sync>> {
+        let cln_options = ln_client_config.cln_config.clone()
+            .ok_or("cln_config is required for the CLN client")?;
 
         println!("CLN client {}", cln_options.lightning_dir);
 
@@ -34,54 +33,138 @@ impl CLNWrapper {
             lightning_dir: cln_options.lightning_dir,
         };
 
-        Ok(Arc::new(Mutex::new(wrapper)))
+        Ok(Arc::new(wrapper))
     }
 }
 
+/// The connection in `client`, opening one to `lightning_dir` if there is none. Shared with the BOLT12 backend.
+pub(crate) async fn connect<'a>(client: &'a mut Option<ClnRpc>, lightning_dir: &str) -> Result<&'a mut ClnRpc, BackendError> {
+    let rpc = match client.take() {
+        Some(rpc) => rpc,
+        None => ClnRpc::new(Path::new(lightning_dir)).await
+            .map_err(|e| BackendError::Unreachable(format!("CLN RPC error: {}", e)))?,
+    };
+    Ok(client.insert(rpc))
+}
+
+// CLN answers a failed command with an error code; errors without one are from the socket
+pub(crate) fn rpc_error(error: RpcError) -> BackendError {
+    match error.code {
+        Some(_) => BackendError::Rejected(format!("CLN RPC error: {}", error)),
+        None => BackendError::Unreachable(format!("CLN RPC error: {}", error)),
+    }
+}
+
+pub(crate) async fn find_invoice(
+    client: &mut ClnRpc,
+    payment_hash: [u8; 32],
+) -> Result<Option<ListinvoicesInvoices>, Box<dyn Error + Send + Sync>> {
+    let request = ListinvoicesRequest {
+        index: None,
+        invstring: None,
+        label: None,
+        limit: None,
+        offer_id: None,
+        payment_hash: Some(hex::encode(payment_hash)),
+        start: None,
+    };
+    let response: ListinvoicesResponse = client.call_typed(&request).await.map_err(rpc_error)?;
+    Ok(response.invoices.into_iter().next())
+}
+
+/// Maps the `listinvoices` status of `payment_hash` on a CLN node. Shared with the BOLT12 backend.
+pub(crate) async fn lookup_invoice_state(
+    client: &mut ClnRpc,
+    payment_hash: [u8; 32],
+) -> Result<lnclient::InvoiceState, Box<dyn Error + Send + Sync>> {
+    let invoice = find_invoice(client, payment_hash).await?.ok_or("Invoice not found")?;
+    Ok(match invoice.status {
+        ListinvoicesInvoicesStatus::PAID => lnclient::InvoiceState::Settled,
+        ListinvoicesInvoicesStatus::UNPAID => lnclient::InvoiceState::Open,
+        ListinvoicesInvoicesStatus::EXPIRED => lnclient::InvoiceState::Canceled,
+    })
+}
+
+lnclient::invoice_conversion!(TryFrom<lnclient::InvoiceRequest> for InvoiceRequest, |invoice| {
+    amount_msat: AmountOrAny::Amount(Amount::from_msat(
+        u64::try_from(invoice.value_msat)
+            .map_err(|_| format!("invalid value_msat: {}", invoice.value_msat))?,
+    )),
+    description: invoice.memo,
+    label: format!("l402-{}", Uuid::new_v4()),
+    expiry: invoice.expiry,
+    fallbacks: None,
+    preimage: None,
+    cltv: None,
+    // CLN hashes `description` into the invoice and keeps it out of the bolt11
+    deschashonly: invoice.description_hash.then_some(true),
+    exposeprivatechannels: None,
+});
+
+lnclient::invoice_conversion!(TryFrom<InvoiceResponse> for lnclient::InvoiceResponse, |response| {
+    payment_hash: <Sha256 as AsRef<[u8]>>::as_ref(&response.payment_hash).try_into().map_err(BackendError::malformed)?,
+    payment_request: response.bolt11,
+});
+
 impl lnclient::LNClient for CLNWrapper {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>>> + Send>> {
+    fn backend_id(&self) -> String {
+        format!("cln:{}", self.lightning_dir)
+    }
+
+    fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
         let client = Arc::clone(&self.client);
         let lightning_dir = self.lightning_dir.clone();
         
         Box::pin(async move {
             let mut client_guard = client.lock().await;
-            
-            if client_guard.is_none() {
-                // Create the CLN RPC client only when needed
-                let new_client = ClnRpc::new(Path::new(&lightning_dir)).await
-                    .map_err(|e| format!("CLN RPC error: {}", e))?;
-                *client_guard = Some(new_client);
+            let client = connect(&mut client_guard, &lightning_dir).await?;
+            let invoice_request = InvoiceRequest::try_from(invoice)?;
+
+            let response: InvoiceResponse = client.call_typed(&invoice_request).await.map_err(rpc_error)?;
+
+            response.try_into()
+        })
+    }
+
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        let client = Arc::clone(&self.client);
+        let lightning_dir = self.lightning_dir.clone();
+
+        Box::pin(async move {
+            let mut client_guard = client.lock().await;
+            let client = connect(&mut client_guard, &lightning_dir).await?;
+            lookup_invoice_state(client, payment_hash).await
+        })
+    }
+
+    fn cancel_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<()> {
+        let client = Arc::clone(&self.client);
+        let lightning_dir = self.lightning_dir.clone();
+
+        Box::pin(async move {
+            let mut client_guard = client.lock().await;
+            let client = connect(&mut client_guard, &lightning_dir).await?;
+            let invoice = find_invoice(client, payment_hash).await?.ok_or("Invoice not found")?;
+            if invoice.status != ListinvoicesInvoicesStatus::UNPAID {
+                return Err("Only unpaid invoices can be cancelled".into());
             }
-            
-            let client = client_guard.as_mut().unwrap();
-            
-            let invoice_request = InvoiceRequest {
-                amount_msat: AmountOrAny::Amount(Amount::from_msat(
-                    u64::try_from(invoice.value_msat)
-                        .map_err(|_| format!("invalid value_msat: {}", invoice.value_msat))?,
-                )),
-                description: invoice.memo,
-                label: format!("l402-{}", Uuid::new_v4()),
-                expiry: None,
-                fallbacks: None,
-                preimage: None,
-                cltv: None,
-                deschashonly: None,
-                exposeprivatechannels: None
-            };
 
-            let response: InvoiceResponse = client.call_typed(&invoice_request).await
-                .map_err(|e| format!("CLN RPC error: {}", e))?;
+            // CLN has no cancel; deleting the unpaid invoice makes it unpayable
+            let request = DelinvoiceRequest {
+                desconly: None,
+                status: DelinvoiceStatus::UNPAID,
+                label: invoice.label,
+            };
+            let _: DelinvoiceResponse = client.call_typed(&request).await.map_err(rpc_error)?;
+            Ok(())
+        })
+    }
 
-            Ok(lnrpc::AddInvoiceResponse {
-                r_hash: <Sha256 as AsRef<[u8]>>::as_ref(&response.payment_hash).to_vec(),
-                payment_request: response.bolt11,
-                add_index: 0, // CLN doesn't have this concept
-                payment_addr: vec![], // CLN doesn't have this concept
-            })
+    fn close(&self) -> lnclient::LNClientFuture<()> {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move {
+            *client.lock().await = None;
+            Ok(())
         })
     }
 }
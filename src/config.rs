@@ -0,0 +1,345 @@
+use lightning::offers::offer::Offer;
+use rocket::figment::providers::{Format, Serialized, Toml};
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::bolt12;
+use crate::cln;
+use crate::eclair;
+use crate::lnclient;
+#[cfg(feature = "lnd")]
+use crate::lnd;
+use crate::lnurl;
+use crate::nwc;
+use crate::provision;
+use crate::utils;
+
+/// File the example server reads when `L402_CONFIG` doesn't name another.
+pub const DEFAULT_CONFIG_FILE: &str = "l402.toml";
+
+/// The deployment's configuration, one field per key of the TOML file. Values are kept
+/// as written and checked by `validate`; empty values count as unset, as in `.env` files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct L402Config {
+    /// `LND`, `LNURL`, `NWC`, `CLN`, `BOLT12` or `ECLAIR`
+    pub ln_client_type: Option<String>,
+    pub lnurl_address: Option<String>,
+    pub nwc_uri: Option<String>,
+    pub lnd_address: Option<String>,
+    pub socks5_proxy: Option<String>,
+    pub macaroon_file_path: Option<String>,
+    pub cert_file_path: Option<String>,
+    pub lnc_pairing_phrase: Option<String>,
+    pub lnc_mailbox_server: Option<String>,
+    pub cln_lightning_rpc_file_path: Option<String>,
+    pub bolt12_ln_offer: Option<String>,
+    pub eclair_api_url: Option<String>,
+    pub eclair_password: Option<String>,
+    pub root_key: Option<String>,
+    pub l402_console_url: Option<String>,
+    pub l402_deployment_id: Option<String>,
+    pub l402_enrollment_token: Option<String>,
+    /// Hex X25519 public key of the operator console
+    pub l402_console_public_key: Option<String>,
+    /// Public URL clients reach the service at, e.g. `https://api.example.com`
+    pub base_url: Option<String>,
+    /// Calls each token pays for, minted as a `MaxUses` caveat
+    pub l402_max_uses: Option<String>,
+    /// Requests a token may have in flight at once, minted as a `MaxConcurrent` caveat
+    pub l402_max_concurrent: Option<String>,
+}
+
+/// Where the root key comes from.
+#[derive(Debug, Clone)]
+pub enum RootKeySource {
+    Static(Vec<u8>),
+    /// Fetched with `provision::fetch_root_key`
+    Console(provision::ProvisioningConfig),
+}
+
+/// Problems found in a configuration, and the features it turns on.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub problems: Vec<String>,
+    /// Feature name and how it is configured, e.g. `("quotas", "MaxUses = 10")`
+    pub features: Vec<(&'static str, String)>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "[FAIL] {}", problem)?;
+        }
+        for (feature, detail) in &self.features {
+            writeln!(f, "[INFO] {}: {}", feature, detail)?;
+        }
+        write!(f, "{}", if self.is_valid() { "Configuration is valid" } else { "Configuration is invalid" })
+    }
+}
+
+/// Every configuration key, as written in the TOML file: the fields of `L402Config`.
+/// The environment variable of a key is its uppercase form, e.g. `LN_CLIENT_TYPE`,
+/// and overrides the file.
+pub fn config_keys() -> Vec<String> {
+    // Unset fields serialize as null, so every field shows up
+    match serde_json::to_value(L402Config::default()) {
+        Ok(serde_json::Value::Object(fields)) => fields.into_iter().map(|(key, _)| key).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads the TOML file at `path`, if there is one, with the environment variables of
+/// `config_keys` layered on top. Unknown keys in the file are an error.
+pub fn load(path: impl AsRef<Path>) -> Result<L402Config, Box<dyn Error + Send + Sync>> {
+    // Taken verbatim: figment's Env provider would read an all-digit root key as a number
+    let overrides: BTreeMap<String, String> = config_keys().into_iter()
+        .filter_map(|key| env::var(key.to_uppercase()).ok().filter(|value| !value.is_empty()).map(|value| (key, value)))
+        .collect();
+    Figment::new()
+        .merge(Toml::file(path))
+        .merge(Serialized::defaults(overrides))
+        .extract()
+        .map_err(|error| format!("Invalid configuration: {}", error).into())
+}
+
+/// Loads and checks the configuration at `path` without connecting to anything.
+pub fn validate(path: impl AsRef<Path>) -> ValidationReport {
+    match load(path) {
+        Ok(config) => config.validate(),
+        Err(error) => ValidationReport { problems: vec![error.to_string()], features: Vec::new() },
+    }
+}
+
+// The value of a key, unless it is unset or empty
+fn value(field: &Option<String>) -> Option<&str> {
+    field.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
+
+fn require<'a>(field: &'a Option<String>, key: &str, problems: &mut Vec<String>) -> &'a str {
+    value(field).unwrap_or_else(|| {
+        problems.push(format!("{} is required", key));
+        ""
+    })
+}
+
+fn positive(field: &Option<String>, key: &str) -> Result<Option<u64>, String> {
+    match value(field) {
+        Some(raw) => match raw.parse::<u64>() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(format!("{} must be a positive integer, got {}", key, raw)),
+        },
+        None => Ok(None),
+    }
+}
+
+impl L402Config {
+    /// Checks every key, collecting all the problems rather than stopping at the first.
+    pub fn validate(&self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+        match self.backend_config(Vec::new()) {
+            Ok(ln_client_config) => {
+                report.features.push(("backend", self.backend_summary()));
+                let settlement = if ln_client_config.looks_up_invoices() {
+                    "preimage proof, invoices looked up through the backend".to_string()
+                } else {
+                    format!("preimage proof only, {} can't look invoices up", ln_client_config.ln_client_type)
+                };
+                report.features.push(("settlement", settlement));
+            },
+            Err(problems) => report.problems.extend(problems),
+        }
+        match self.root_key_source() {
+            Ok(RootKeySource::Static(_)) => report.features.push(("root key", "ROOT_KEY".to_string())),
+            Ok(RootKeySource::Console(console)) => report.features.push(("root key", format!("fetched from {}", console.console_url))),
+            Err(problems) => report.problems.extend(problems),
+        }
+        match (self.max_uses(), self.max_concurrent()) {
+            (Ok(max_uses), Ok(max_concurrent)) => {
+                let quotas: Vec<String> = [("MaxUses", max_uses), ("MaxConcurrent", max_concurrent)].iter()
+                    .filter_map(|(caveat, limit)| limit.map(|limit| format!("{} = {}", caveat, limit)))
+                    .collect();
+                report.features.push(("quotas", if quotas.is_empty() { "off".to_string() } else { quotas.join(", ") }));
+            },
+            (max_uses, max_concurrent) => report.problems.extend(max_uses.err().into_iter().chain(max_concurrent.err())),
+        }
+        match self.public_base_url() {
+            Ok(Some(base_url)) => report.features.push(("public URL", base_url)),
+            Ok(None) => {},
+            Err(problem) => report.problems.push(problem),
+        }
+        report
+    }
+
+    /// Backend options for `ln_client_type`, or everything wrong with them.
+    pub fn backend_config(&self, root_key: Vec<u8>) -> Result<lnclient::LNClientConfig, Vec<String>> {
+        let mut problems = Vec::new();
+        let mut ln_client_config = lnclient::LNClientConfig {
+            ln_client_type: require(&self.ln_client_type, "LN_CLIENT_TYPE", &mut problems).to_string(),
+            root_key,
+            ..Default::default()
+        };
+        match ln_client_config.ln_client_type.as_str() {
+            "LNURL" => {
+                let address = require(&self.lnurl_address, "LNURL_ADDRESS", &mut problems);
+                if !address.is_empty() {
+                    if let Err(error) = utils::parse_ln_address(address.to_string()) {
+                        problems.push(format!("LNURL_ADDRESS: {}", error));
+                    }
+                }
+                ln_client_config.lnurl_config = Some(lnurl::LNURLOptions { address: address.to_string() });
+            },
+            "NWC" => {
+                let uri = require(&self.nwc_uri, "NWC_URI", &mut problems);
+                if !uri.is_empty() && !uri.starts_with("nostr+walletconnect://") {
+                    problems.push("NWC_URI must start with nostr+walletconnect://".to_string());
+                }
+                ln_client_config.nwc_config = Some(nwc::NWCOptions { uri: uri.to_string() });
+            },
+            #[cfg(feature = "lnd")]
+            "LND" => {
+                let lnd_options = match value(&self.lnc_pairing_phrase) {
+                    Some(pairing_phrase) => lnd::LNDOptions {
+                        lnc_pairing_phrase: Some(pairing_phrase.to_string()),
+                        lnc_mailbox_server: value(&self.lnc_mailbox_server).map(str::to_string),
+                        ..Default::default()
+                    },
+                    None => {
+                        let address = require(&self.lnd_address, "LND_ADDRESS (or LNC_PAIRING_PHRASE)", &mut problems);
+                        if !address.is_empty() && address.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).is_none() {
+                            problems.push(format!("LND_ADDRESS must be host:port, got {}", address));
+                        }
+                        let mut files = Vec::new();
+                        for (field, key) in [(&self.macaroon_file_path, "MACAROON_FILE_PATH"), (&self.cert_file_path, "CERT_FILE_PATH")] {
+                            let file = require(field, key, &mut problems);
+                            if !file.is_empty() && !Path::new(file).is_file() {
+                                problems.push(format!("{}: no file at {}", key, file));
+                            }
+                            files.push(file.to_string());
+                        }
+                        lnd::LNDOptions {
+                            address: Some(address.to_string()),
+                            macaroon_file: Some(files[0].clone()),
+                            cert_file: Some(files[1].clone()),
+                            socks5_proxy: value(&self.socks5_proxy).map(str::to_string),
+                            ..Default::default()
+                        }
+                    },
+                };
+                ln_client_config.lnd_config = Some(lnd_options);
+            },
+            #[cfg(not(feature = "lnd"))]
+            "LND" => problems.push("LND support is not compiled in, enable the lnd feature".to_string()),
+            "CLN" => {
+                let lightning_dir = require(&self.cln_lightning_rpc_file_path, "CLN_LIGHTNING_RPC_FILE_PATH", &mut problems);
+                ln_client_config.cln_config = Some(cln::CLNOptions { lightning_dir: lightning_dir.to_string() });
+            },
+            "BOLT12" => {
+                let lightning_dir = require(&self.cln_lightning_rpc_file_path, "CLN_LIGHTNING_RPC_FILE_PATH", &mut problems);
+                let offer = require(&self.bolt12_ln_offer, "BOLT12_LN_OFFER", &mut problems);
+                if !offer.is_empty() {
+                    if let Err(error) = offer.parse::<Offer>() {
+                        problems.push(format!("BOLT12_LN_OFFER is not a valid offer: {:?}", error));
+                    }
+                }
+                ln_client_config.bolt12_config = Some(bolt12::Bolt12Options {
+                    lightning_dir: lightning_dir.to_string(),
+                    offer: offer.to_string(),
+                });
+            },
+            "ECLAIR" => {
+                let api_url = require(&self.eclair_api_url, "ECLAIR_API_URL", &mut problems);
+                if !api_url.is_empty() && !reqwest::Url::parse(api_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+                    problems.push(format!("ECLAIR_API_URL must be an http(s) URL, got {}", api_url));
+                }
+                let password = require(&self.eclair_password, "ECLAIR_PASSWORD", &mut problems);
+                ln_client_config.eclair_config = Some(eclair::EclairOptions {
+                    api_url: api_url.to_string(),
+                    password: password.to_string(),
+                });
+            },
+            "" => {},
+            other => problems.push(format!("Invalid LN_CLIENT_TYPE {}. Expected LNURL, LND, NWC, CLN, BOLT12 or ECLAIR", other)),
+        }
+        if problems.is_empty() { Ok(ln_client_config) } else { Err(problems) }
+    }
+
+    /// The root key, or the console to fetch it from when `L402_CONSOLE_URL` is set.
+    pub fn root_key_source(&self) -> Result<RootKeySource, Vec<String>> {
+        let mut problems = Vec::new();
+        let Some(console_url) = value(&self.l402_console_url) else {
+            let root_key = require(&self.root_key, "ROOT_KEY (or L402_CONSOLE_URL)", &mut problems);
+            return if problems.is_empty() { Ok(RootKeySource::Static(root_key.as_bytes().to_vec())) } else { Err(problems) };
+        };
+        let deployment_id = require(&self.l402_deployment_id, "L402_DEPLOYMENT_ID", &mut problems);
+        let enrollment_token = require(&self.l402_enrollment_token, "L402_ENROLLMENT_TOKEN", &mut problems);
+        let console_public_key = require(&self.l402_console_public_key, "L402_CONSOLE_PUBLIC_KEY", &mut problems);
+        let console_public_key: [u8; 32] = match hex::decode(console_public_key).ok().and_then(|k| k.try_into().ok()) {
+            Some(key) => key,
+            None => {
+                if !console_public_key.is_empty() {
+                    problems.push("L402_CONSOLE_PUBLIC_KEY must be a hex X25519 public key".to_string());
+                }
+                [0u8; 32]
+            },
+        };
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok(RootKeySource::Console(provision::ProvisioningConfig {
+            console_url: console_url.to_string(),
+            deployment_id: deployment_id.to_string(),
+            enrollment_token: enrollment_token.as_bytes().to_vec(),
+            console_public_key,
+        }))
+    }
+
+    pub fn max_uses(&self) -> Result<Option<u64>, String> {
+        positive(&self.l402_max_uses, "L402_MAX_USES")
+    }
+
+    pub fn max_concurrent(&self) -> Result<Option<u64>, String> {
+        positive(&self.l402_max_concurrent, "L402_MAX_CONCURRENT")
+    }
+
+    /// `BASE_URL`, for the middleware's `endpoints.public_base_url`.
+    pub fn public_base_url(&self) -> Result<Option<String>, String> {
+        match value(&self.base_url) {
+            Some(base_url) if reqwest::Url::parse(base_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) => {
+                Ok(Some(base_url.trim_end_matches('/').to_string()))
+            },
+            Some(base_url) => Err(format!("BASE_URL must be an http(s) URL, got {}", base_url)),
+            None => Ok(None),
+        }
+    }
+
+    fn backend_summary(&self) -> String {
+        let ln_client_type = value(&self.ln_client_type).unwrap_or_default();
+        let target = match ln_client_type {
+            "LND" => match value(&self.lnc_pairing_phrase) {
+                Some(_) => format!("over LNC via {}", value(&self.lnc_mailbox_server).unwrap_or("the default mailbox")),
+                None => match value(&self.socks5_proxy) {
+                    Some(proxy) => format!("at {} through SOCKS5 proxy {}", value(&self.lnd_address).unwrap_or_default(), proxy),
+                    None => format!("at {}", value(&self.lnd_address).unwrap_or_default()),
+                },
+            },
+            "LNURL" => format!("for {}", value(&self.lnurl_address).unwrap_or_default()),
+            "NWC" => "through Nostr Wallet Connect".to_string(),
+            "CLN" | "BOLT12" => format!("at {}", value(&self.cln_lightning_rpc_file_path).unwrap_or_default()),
+            "ECLAIR" => format!("at {}", value(&self.eclair_api_url).unwrap_or_default()),
+            _ => String::new(),
+        };
+        format!("{} {}", ln_client_type, target)
+    }
+}
@@ -0,0 +1,518 @@
+use rocket::fairing::AdHoc;
+use rocket_db_pools::sqlx::{self, Row};
+use rocket_db_pools::Database;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, OnceLock};
+
+use crate::store::{
+    ChallengeRecord, PresentationRecord, ResourceCharge, SessionRecord, SpendRecord, StoreFuture, TokenStore, UsageRecord,
+    MAX_PRESENTATIONS_PER_TOKEN, PRESENTATION_RETENTION_SECS, RANGE_CONTINUATION_WINDOW_SECS, UNSETTLED_CHALLENGE_RETENTION_SECS,
+};
+use crate::utils;
+
+// Same SQL for every driver: `$N` parameters, upserts and BIGINT columns work in both SQLite and Postgres
+const SCHEMA: [&str; 9] = [
+    "CREATE TABLE IF NOT EXISTS l402_sessions (session_id TEXT PRIMARY KEY, token TEXT NOT NULL, expires_at BIGINT NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS l402_usage (token_id TEXT PRIMARY KEY, uses BIGINT NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS l402_resource_charges (token_id TEXT NOT NULL, resource TEXT NOT NULL, charged_at BIGINT NOT NULL, \
+     served_end BIGINT, PRIMARY KEY (token_id, resource))",
+    "CREATE TABLE IF NOT EXISTS l402_in_flight (token_id TEXT PRIMARY KEY, slots BIGINT NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS l402_challenges (payment_hash TEXT PRIMARY KEY, invoice TEXT NOT NULL, macaroon TEXT NOT NULL, \
+     amount_msat BIGINT NOT NULL, memo TEXT NOT NULL, backend TEXT NOT NULL, fingerprint TEXT NOT NULL, path TEXT NOT NULL, \
+     created_at BIGINT NOT NULL, preimage TEXT, settled_at BIGINT, node_settled_at BIGINT)",
+    "CREATE TABLE IF NOT EXISTS l402_revoked (token_id TEXT PRIMARY KEY)",
+    "CREATE TABLE IF NOT EXISTS l402_spend (kind TEXT NOT NULL, spender TEXT NOT NULL, requests BIGINT NOT NULL, \
+     spent_msat BIGINT NOT NULL, last_seen BIGINT NOT NULL, PRIMARY KEY (kind, spender))",
+    "CREATE TABLE IF NOT EXISTS l402_presentations (token_id TEXT NOT NULL, fingerprint TEXT NOT NULL, first_seen BIGINT NOT NULL, \
+     last_seen BIGINT NOT NULL, requests BIGINT NOT NULL, PRIMARY KEY (token_id, fingerprint))",
+    "CREATE TABLE IF NOT EXISTS l402_offer_payments (payment_hash TEXT PRIMARY KEY, token_id TEXT NOT NULL)",
+];
+
+const CHALLENGE_COLUMNS: &str = "payment_hash, invoice, macaroon, amount_msat, memo, backend, fingerprint, path, created_at, \
+    preimage, settled_at, node_settled_at";
+
+/// TokenStore on a `rocket_db_pools` sqlx pool, so the L402 ledger, quotas and
+/// sessions live in the application's own database and survive restarts. Its tables
+/// are prefixed `l402_` and created by `migrate`. Quota, slot, settlement and offer
+/// binding updates are conditional statements or single transactions, so several
+/// instances can share the database.
+pub struct DbTokenStore<DB: sqlx::Database> {
+    pool: OnceLock<sqlx::Pool<DB>>,
+}
+
+impl<DB: sqlx::Database> Default for DbTokenStore<DB> {
+    fn default() -> Self {
+        DbTokenStore { pool: OnceLock::new() }
+    }
+}
+
+impl<DB: sqlx::Database> DbTokenStore<DB> {
+    /// A store that gets its pool when Rocket ignites, see `bind`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store on a pool that is already open. Call `migrate` before use.
+    pub fn with_pool(pool: sqlx::Pool<DB>) -> Self {
+        let store = Self::default();
+        let _ = store.pool.set(pool);
+        store
+    }
+
+    fn pool(&self) -> Result<&sqlx::Pool<DB>, Box<dyn Error + Send + Sync>> {
+        self.pool.get().ok_or_else(|| "L402 token store has no database pool yet; attach its bind fairing".into())
+    }
+}
+
+macro_rules! impl_db_token_store {
+    ($db:ty) => {
+        impl DbTokenStore<$db> {
+            /// Creates the store's tables if they don't exist yet.
+            pub async fn migrate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+                let pool = self.pool()?;
+                for statement in SCHEMA {
+                    sqlx::query(statement).execute(pool).await?;
+                }
+                Ok(())
+            }
+
+            /// Fairing that takes the pool of the `rocket_db_pools` database `D` and
+            /// migrates it. Attach it after `D::init()` and before the middleware.
+            pub fn bind<D: Database<Pool = sqlx::Pool<$db>>>(self: &Arc<Self>) -> AdHoc {
+                let store = Arc::clone(self);
+                AdHoc::try_on_ignite("L402 token store", |rocket| async move {
+                    let Some(db) = D::fetch(&rocket) else {
+                        println!("Error binding L402 token store: database {} is not attached", D::NAME);
+                        return Err(rocket);
+                    };
+                    let _ = store.pool.set((**db).clone());
+                    match store.migrate().await {
+                        Ok(()) => Ok(rocket),
+                        Err(error) => {
+                            println!("Error migrating L402 token store: {}", error);
+                            Err(rocket)
+                        },
+                    }
+                })
+            }
+
+            fn challenge_from_row(row: &<$db as sqlx::Database>::Row) -> Result<ChallengeRecord, sqlx::Error> {
+                Ok(ChallengeRecord {
+                    payment_hash: row.try_get("payment_hash")?,
+                    invoice: row.try_get("invoice")?,
+                    macaroon: row.try_get("macaroon")?,
+                    amount_msat: row.try_get("amount_msat")?,
+                    memo: row.try_get("memo")?,
+                    backend: row.try_get("backend")?,
+                    fingerprint: row.try_get("fingerprint")?,
+                    path: row.try_get("path")?,
+                    created_at: row.try_get::<i64, _>("created_at")? as u64,
+                    preimage: row.try_get("preimage")?,
+                    settled_at: row.try_get::<Option<i64>, _>("settled_at")?.map(|t| t as u64),
+                    node_settled_at: row.try_get::<Option<i64>, _>("node_settled_at")?.map(|t| t as u64),
+                })
+            }
+
+            async fn get_spend(&self, kind: &str, spender: &str) -> Result<Option<SpendRecord>, Box<dyn Error + Send + Sync>> {
+                let row = sqlx::query("SELECT requests, spent_msat, last_seen FROM l402_spend WHERE kind = $1 AND spender = $2")
+                    .bind(kind)
+                    .bind(spender)
+                    .fetch_optional(self.pool()?)
+                    .await?;
+                Ok(match row {
+                    Some(row) => Some(SpendRecord {
+                        requests: row.try_get::<i64, _>("requests")? as u64,
+                        spent_msat: row.try_get("spent_msat")?,
+                        last_seen: row.try_get::<i64, _>("last_seen")? as u64,
+                    }),
+                    None => None,
+                })
+            }
+        }
+
+        impl TokenStore for DbTokenStore<$db> {
+            fn put_session(&self, session_id: String, record: SessionRecord) -> StoreFuture<'_, ()> {
+                Box::pin(async move {
+                    let pool = self.pool()?;
+                    sqlx::query("DELETE FROM l402_sessions WHERE expires_at <= $1")
+                        .bind(utils::now_unix() as i64)
+                        .execute(pool)
+                        .await?;
+                    sqlx::query("INSERT INTO l402_sessions (session_id, token, expires_at) VALUES ($1, $2, $3) \
+                                 ON CONFLICT (session_id) DO UPDATE SET token = excluded.token, expires_at = excluded.expires_at")
+                        .bind(session_id)
+                        .bind(record.token)
+                        .bind(record.expires_at as i64)
+                        .execute(pool)
+                        .await?;
+                    Ok(())
+                })
+            }
+
+            fn get_session(&self, session_id: &str) -> StoreFuture<'_, Option<SessionRecord>> {
+                let session_id = session_id.to_string();
+                Box::pin(async move {
+                    let row = sqlx::query("SELECT token, expires_at FROM l402_sessions WHERE session_id = $1 AND expires_at > $2")
+                        .bind(session_id)
+                        .bind(utils::now_unix() as i64)
+                        .fetch_optional(self.pool()?)
+                        .await?;
+                    Ok(match row {
+                        Some(row) => Some(SessionRecord {
+                            token: row.try_get("token")?,
+                            expires_at: row.try_get::<i64, _>("expires_at")? as u64,
+                        }),
+                        None => None,
+                    })
+                })
+            }
+
+            fn consume_use(&self, token_id: &str, resource: &str, range_start: Option<u64>, max_uses: u64) -> StoreFuture<'_, bool> {
+                let token_id = token_id.to_string();
+                let resource = resource.to_string();
+                Box::pin(async move {
+                    let pool = self.pool()?;
+                    let now = utils::now_unix();
+                    if let Some(start) = range_start {
+                        // Free only if it starts where the last partial response ended, within the window
+                        let continued = sqlx::query("UPDATE l402_resource_charges SET served_end = NULL \
+                                                     WHERE token_id = $1 AND resource = $2 AND served_end <= $3 AND charged_at > $4")
+                            .bind(&token_id)
+                            .bind(&resource)
+                            .bind(start as i64)
+                            .bind(now.saturating_sub(RANGE_CONTINUATION_WINDOW_SECS) as i64)
+                            .execute(pool)
+                            .await?;
+                        if continued.rows_affected() > 0 {
+                            return Ok(true);
+                        }
+                    }
+                    if max_uses == 0 {
+                        return Ok(false);
+                    }
+
+                    let mut tx = pool.begin().await?;
+                    let charged = sqlx::query("INSERT INTO l402_usage (token_id, uses) VALUES ($1, 1) \
+                                               ON CONFLICT (token_id) DO UPDATE SET uses = l402_usage.uses + 1 WHERE l402_usage.uses < $2")
+                        .bind(&token_id)
+                        .bind(max_uses as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    if charged.rows_affected() == 0 {
+                        return Ok(false);
+                    }
+                    sqlx::query("INSERT INTO l402_resource_charges (token_id, resource, charged_at, served_end) VALUES ($1, $2, $3, NULL) \
+                                 ON CONFLICT (token_id, resource) DO UPDATE SET charged_at = excluded.charged_at, served_end = NULL")
+                        .bind(&token_id)
+                        .bind(&resource)
+                        .bind(now as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    tx.commit().await?;
+                    Ok(true)
+                })
+            }
+
+            fn record_served(&self, token_id: &str, resource: &str, served_end: u64) -> StoreFuture<'_, ()> {
+                let token_id = token_id.to_string();
+                let resource = resource.to_string();
+                Box::pin(async move {
+                    sqlx::query("UPDATE l402_resource_charges SET served_end = $3 WHERE token_id = $1 AND resource = $2")
+                        .bind(&token_id)
+                        .bind(&resource)
+                        .bind(served_end as i64)
+                        .execute(self.pool()?)
+                        .await?;
+                    Ok(())
+                })
+            }
+
+            fn get_usage(&self, token_id: &str) -> StoreFuture<'_, Option<UsageRecord>> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    let pool = self.pool()?;
+                    let Some(row) = sqlx::query("SELECT uses FROM l402_usage WHERE token_id = $1")
+                        .bind(&token_id)
+                        .fetch_optional(pool)
+                        .await?
+                    else {
+                        return Ok(None);
+                    };
+                    let mut resources = HashMap::new();
+                    for row in sqlx::query("SELECT resource, charged_at, served_end FROM l402_resource_charges WHERE token_id = $1")
+                        .bind(&token_id)
+                        .fetch_all(pool)
+                        .await?
+                    {
+                        resources.insert(row.try_get("resource")?, ResourceCharge {
+                            charged_at: row.try_get::<i64, _>("charged_at")? as u64,
+                            served_end: row.try_get::<Option<i64>, _>("served_end")?.map(|served_end| served_end as u64),
+                        });
+                    }
+                    Ok(Some(UsageRecord { uses: row.try_get::<i64, _>("uses")? as u64, resources }))
+                })
+            }
+
+            fn acquire_slot(&self, token_id: &str, max_concurrent: u64) -> StoreFuture<'_, bool> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    if max_concurrent == 0 {
+                        return Ok(false);
+                    }
+                    let acquired = sqlx::query("INSERT INTO l402_in_flight (token_id, slots) VALUES ($1, 1) \
+                                                ON CONFLICT (token_id) DO UPDATE SET slots = l402_in_flight.slots + 1 WHERE l402_in_flight.slots < $2")
+                        .bind(token_id)
+                        .bind(max_concurrent as i64)
+                        .execute(self.pool()?)
+                        .await?;
+                    Ok(acquired.rows_affected() > 0)
+                })
+            }
+
+            fn release_slot(&self, token_id: &str) -> StoreFuture<'_, ()> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    let pool = self.pool()?;
+                    sqlx::query("UPDATE l402_in_flight SET slots = slots - 1 WHERE token_id = $1 AND slots > 0")
+                        .bind(&token_id)
+                        .execute(pool)
+                        .await?;
+                    sqlx::query("DELETE FROM l402_in_flight WHERE token_id = $1 AND slots <= 0")
+                        .bind(&token_id)
+                        .execute(pool)
+                        .await?;
+                    Ok(())
+                })
+            }
+
+            fn record_challenge(&self, record: ChallengeRecord) -> StoreFuture<'_, ()> {
+                Box::pin(async move {
+                    let pool = self.pool()?;
+                    let cutoff = utils::now_unix().saturating_sub(UNSETTLED_CHALLENGE_RETENTION_SECS);
+                    sqlx::query("DELETE FROM l402_challenges WHERE settled_at IS NULL AND node_settled_at IS NULL AND created_at <= $1")
+                        .bind(cutoff as i64)
+                        .execute(pool)
+                        .await?;
+                    sqlx::query(&format!(
+                        "INSERT INTO l402_challenges ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+                         ON CONFLICT (payment_hash) DO UPDATE SET invoice = excluded.invoice, macaroon = excluded.macaroon, \
+                         amount_msat = excluded.amount_msat, memo = excluded.memo, backend = excluded.backend, \
+                         fingerprint = excluded.fingerprint, path = excluded.path, created_at = excluded.created_at, \
+                         preimage = excluded.preimage, settled_at = excluded.settled_at, node_settled_at = excluded.node_settled_at",
+                        CHALLENGE_COLUMNS,
+                    ))
+                        .bind(record.payment_hash)
+                        .bind(record.invoice)
+                        .bind(record.macaroon)
+                        .bind(record.amount_msat)
+                        .bind(record.memo)
+                        .bind(record.backend)
+                        .bind(record.fingerprint)
+                        .bind(record.path)
+                        .bind(record.created_at as i64)
+                        .bind(record.preimage)
+                        .bind(record.settled_at.map(|t| t as i64))
+                        .bind(record.node_settled_at.map(|t| t as i64))
+                        .execute(pool)
+                        .await?;
+                    Ok(())
+                })
+            }
+
+            fn mark_settled(&self, payment_hash: &str, preimage: &str, settled_at: u64) -> StoreFuture<'_, bool> {
+                let payment_hash = payment_hash.to_string();
+                let preimage = preimage.to_string();
+                Box::pin(async move {
+                    let settled = sqlx::query("UPDATE l402_challenges SET preimage = $2, settled_at = $3 WHERE payment_hash = $1 AND settled_at IS NULL")
+                        .bind(payment_hash)
+                        .bind(preimage)
+                        .bind(settled_at as i64)
+                        .execute(self.pool()?)
+                        .await?;
+                    Ok(settled.rows_affected() > 0)
+                })
+            }
+
+            fn record_node_settlement(&self, payment_hash: &str, settled_at: u64) -> StoreFuture<'_, bool> {
+                let payment_hash = payment_hash.to_string();
+                Box::pin(async move {
+                    let recorded = sqlx::query("UPDATE l402_challenges SET node_settled_at = COALESCE(node_settled_at, $2) WHERE payment_hash = $1")
+                        .bind(payment_hash)
+                        .bind(settled_at as i64)
+                        .execute(self.pool()?)
+                        .await?;
+                    Ok(recorded.rows_affected() > 0)
+                })
+            }
+
+            fn get_challenge(&self, payment_hash: &str) -> StoreFuture<'_, Option<ChallengeRecord>> {
+                let payment_hash = payment_hash.to_string();
+                Box::pin(async move {
+                    let row = sqlx::query(&format!("SELECT {} FROM l402_challenges WHERE payment_hash = $1", CHALLENGE_COLUMNS))
+                        .bind(payment_hash)
+                        .fetch_optional(self.pool()?)
+                        .await?;
+                    Ok(row.as_ref().map(Self::challenge_from_row).transpose()?)
+                })
+            }
+
+            fn settled_challenges(&self, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>> {
+                Box::pin(async move {
+                    let rows = sqlx::query(&format!("SELECT {} FROM l402_challenges WHERE settled_at >= $1", CHALLENGE_COLUMNS))
+                        .bind(since as i64)
+                        .fetch_all(self.pool()?)
+                        .await?;
+                    Ok(rows.iter().map(Self::challenge_from_row).collect::<Result<_, _>>()?)
+                })
+            }
+
+            fn fingerprint_challenges(&self, fingerprint: &str, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>> {
+                let fingerprint = fingerprint.to_string();
+                Box::pin(async move {
+                    let rows = sqlx::query(&format!("SELECT {} FROM l402_challenges WHERE fingerprint = $1 AND created_at >= $2", CHALLENGE_COLUMNS))
+                        .bind(fingerprint)
+                        .bind(since as i64)
+                        .fetch_all(self.pool()?)
+                        .await?;
+                    Ok(rows.iter().map(Self::challenge_from_row).collect::<Result<_, _>>()?)
+                })
+            }
+
+            fn revoke_token(&self, token_id: &str) -> StoreFuture<'_, ()> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    sqlx::query("INSERT INTO l402_revoked (token_id) VALUES ($1) ON CONFLICT (token_id) DO NOTHING")
+                        .bind(token_id)
+                        .execute(self.pool()?)
+                        .await?;
+                    Ok(())
+                })
+            }
+
+            fn is_revoked(&self, token_id: &str) -> StoreFuture<'_, bool> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    let row = sqlx::query("SELECT token_id FROM l402_revoked WHERE token_id = $1")
+                        .bind(token_id)
+                        .fetch_optional(self.pool()?)
+                        .await?;
+                    Ok(row.is_some())
+                })
+            }
+
+            fn record_spend(&self, token_id: &str, fingerprint: &str, spent_msat: i64) -> StoreFuture<'_, ()> {
+                let token_id = token_id.to_string();
+                let fingerprint = fingerprint.to_string();
+                Box::pin(async move {
+                    let mut tx = self.pool()?.begin().await?;
+                    for (kind, spender) in [("token", token_id), ("fingerprint", fingerprint)] {
+                        sqlx::query("INSERT INTO l402_spend (kind, spender, requests, spent_msat, last_seen) VALUES ($1, $2, 1, $3, $4) \
+                                     ON CONFLICT (kind, spender) DO UPDATE SET requests = l402_spend.requests + 1, \
+                                     spent_msat = l402_spend.spent_msat + excluded.spent_msat, last_seen = excluded.last_seen")
+                            .bind(kind)
+                            .bind(spender)
+                            .bind(spent_msat)
+                            .bind(utils::now_unix() as i64)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    tx.commit().await?;
+                    Ok(())
+                })
+            }
+
+            fn get_token_spend(&self, token_id: &str) -> StoreFuture<'_, Option<SpendRecord>> {
+                let token_id = token_id.to_string();
+                Box::pin(async move { self.get_spend("token", &token_id).await })
+            }
+
+            fn get_fingerprint_spend(&self, fingerprint: &str) -> StoreFuture<'_, Option<SpendRecord>> {
+                let fingerprint = fingerprint.to_string();
+                Box::pin(async move { self.get_spend("fingerprint", &fingerprint).await })
+            }
+
+            fn record_presentation(&self, token_id: &str, fingerprint: &str, seen_at: u64) -> StoreFuture<'_, ()> {
+                let token_id = token_id.to_string();
+                let fingerprint = fingerprint.to_string();
+                Box::pin(async move {
+                    let mut tx = self.pool()?.begin().await?;
+                    let cutoff = utils::now_unix().saturating_sub(PRESENTATION_RETENTION_SECS);
+                    sqlx::query("DELETE FROM l402_presentations WHERE token_id IN \
+                                 (SELECT token_id FROM l402_presentations GROUP BY token_id HAVING MAX(last_seen) <= $1)")
+                        .bind(cutoff as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    let seen = sqlx::query("UPDATE l402_presentations SET requests = requests + 1, \
+                                            last_seen = CASE WHEN last_seen < $3 THEN $3 ELSE last_seen END \
+                                            WHERE token_id = $1 AND fingerprint = $2")
+                        .bind(&token_id)
+                        .bind(&fingerprint)
+                        .bind(seen_at as i64)
+                        .execute(&mut *tx)
+                        .await?;
+                    if seen.rows_affected() == 0 {
+                        sqlx::query("INSERT INTO l402_presentations (token_id, fingerprint, first_seen, last_seen, requests) \
+                                     SELECT $1, $2, $3, $3, 1 WHERE (SELECT COUNT(*) FROM l402_presentations WHERE token_id = $1) < $4 \
+                                     ON CONFLICT (token_id, fingerprint) DO NOTHING")
+                            .bind(&token_id)
+                            .bind(&fingerprint)
+                            .bind(seen_at as i64)
+                            .bind(MAX_PRESENTATIONS_PER_TOKEN as i64)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    tx.commit().await?;
+                    Ok(())
+                })
+            }
+
+            fn token_presentations(&self, token_id: &str) -> StoreFuture<'_, Vec<PresentationRecord>> {
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    let rows = sqlx::query("SELECT fingerprint, first_seen, last_seen, requests FROM l402_presentations \
+                                            WHERE token_id = $1 ORDER BY first_seen")
+                        .bind(token_id)
+                        .fetch_all(self.pool()?)
+                        .await?;
+                    let mut presentations = Vec::with_capacity(rows.len());
+                    for row in rows {
+                        presentations.push(PresentationRecord {
+                            fingerprint: row.try_get("fingerprint")?,
+                            first_seen: row.try_get::<i64, _>("first_seen")? as u64,
+                            last_seen: row.try_get::<i64, _>("last_seen")? as u64,
+                            requests: row.try_get::<i64, _>("requests")? as u64,
+                        });
+                    }
+                    Ok(presentations)
+                })
+            }
+
+            fn bind_offer_payment(&self, payment_hash: &str, token_id: &str) -> StoreFuture<'_, bool> {
+                let payment_hash = payment_hash.to_string();
+                let token_id = token_id.to_string();
+                Box::pin(async move {
+                    let mut tx = self.pool()?.begin().await?;
+                    sqlx::query("INSERT INTO l402_offer_payments (payment_hash, token_id) VALUES ($1, $2) ON CONFLICT (payment_hash) DO NOTHING")
+                        .bind(&payment_hash)
+                        .bind(&token_id)
+                        .execute(&mut *tx)
+                        .await?;
+                    let bound: String = sqlx::query("SELECT token_id FROM l402_offer_payments WHERE payment_hash = $1")
+                        .bind(&payment_hash)
+                        .fetch_one(&mut *tx)
+                        .await?
+                        .try_get("token_id")?;
+                    tx.commit().await?;
+                    Ok(bound == token_id)
+                })
+            }
+        }
+    };
+}
+
+#[cfg(feature = "db-sqlite")]
+impl_db_token_store!(sqlx::Sqlite);
+
+#[cfg(feature = "db-postgres")]
+impl_db_token_store!(sqlx::Postgres);
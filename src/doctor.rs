@@ -0,0 +1,147 @@
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+use lightning_invoice::Bolt11Invoice;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::fiat;
+use crate::l402;
+use crate::lnclient;
+use crate::macaroon_util::get_macaroon_as_string;
+use crate::utils;
+
+const DOCTOR_INVOICE_MSAT: i64 = 1000;
+const DOCTOR_CAVEAT: &str = "RequestPath = /doctor";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckOutcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: CheckOutcome,
+}
+
+/// Pass/fail report of a deployment self-test.
+#[derive(Debug, Clone, Default)]
+pub struct DoctorReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl DoctorReport {
+    /// True unless a check failed; skipped checks don't count as failures.
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|c| matches!(c.outcome, CheckOutcome::Fail(_)))
+    }
+
+    fn push(&mut self, name: &'static str, outcome: CheckOutcome) {
+        self.checks.push(CheckResult { name, outcome });
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            let (label, detail) = match &check.outcome {
+                CheckOutcome::Pass(detail) => ("PASS", detail),
+                CheckOutcome::Fail(detail) => ("FAIL", detail),
+                CheckOutcome::Skip(detail) => ("SKIP", detail),
+            };
+            writeln!(f, "[{}] {}: {}", label, check.name, detail)?;
+        }
+        write!(f, "{}", if self.passed() { "All checks passed" } else { "Some checks failed" })
+    }
+}
+
+/// Exercises the configured backend end-to-end for deployment debugging: creates a
+/// 1-sat invoice, decodes it, optionally cancels it, round-trips a macaroon through
+/// mint and verify, and queries the fiat rate provider when one is configured.
+pub async fn run_doctor(
+    ln_client: Arc<dyn lnclient::LNClient>,
+    root_key: Vec<u8>,
+    fiat_rate_config: Option<&fiat::FiatRateConfig>,
+    cancel_invoice: bool,
+) -> DoctorReport {
+    let mut report = DoctorReport::default();
+
+    let ln_invoice = lnclient::InvoiceRequest {
+        value_msat: DOCTOR_INVOICE_MSAT,
+        memo: format!("{} doctor", l402::L402_HEADER),
+        ..Default::default()
+    };
+    let ln_client_conn = lnclient::LNClientConn { ln_client: Arc::clone(&ln_client) };
+    let created = ln_client_conn.generate_invoice(ln_invoice).await.map_err(|e| e.to_string());
+    match created {
+        Ok((invoice, payment_hash)) => {
+            report.push("create invoice", CheckOutcome::Pass(format!("payment hash {}", hex::encode(payment_hash.0))));
+            report.push("decode invoice", decode_check(&invoice, payment_hash));
+
+            if cancel_invoice {
+                let cancel = ln_client.cancel_invoice(payment_hash.0);
+                let outcome = match cancel.await.map_err(|e| e.to_string()) {
+                    Ok(()) => CheckOutcome::Pass("invoice cancelled".to_string()),
+                    Err(error) if error.contains("not supported") => CheckOutcome::Skip(error),
+                    Err(error) => CheckOutcome::Fail(error),
+                };
+                report.push("cancel invoice", outcome);
+            }
+        },
+        Err(error) => {
+            report.push("create invoice", CheckOutcome::Fail(error));
+            report.push("decode invoice", CheckOutcome::Skip("no invoice to decode".to_string()));
+        },
+    }
+
+    report.push("macaroon roundtrip", macaroon_check(root_key));
+
+    let fiat_outcome = match fiat_rate_config {
+        Some(config) => match config.fetch_btc_amount().await.map_err(|e| e.to_string()) {
+            Ok(msat) => CheckOutcome::Pass(format!("{} {} = {} msat", config.amount, config.currency, msat)),
+            Err(error) => CheckOutcome::Fail(error),
+        },
+        None => CheckOutcome::Skip("no fiat rate provider configured".to_string()),
+    };
+    report.push("fiat rate provider", fiat_outcome);
+
+    report
+}
+
+fn decode_check(invoice: &str, payment_hash: PaymentHash) -> CheckOutcome {
+    // BOLT12 invoices aren't BOLT11-decodable
+    if invoice.starts_with("lni") {
+        return CheckOutcome::Skip("BOLT12 invoice".to_string());
+    }
+    let decoded = match invoice.parse::<Bolt11Invoice>() {
+        Ok(decoded) => decoded,
+        Err(error) => return CheckOutcome::Fail(format!("invalid BOLT11 invoice: {}", error)),
+    };
+    if decoded.payment_hash().as_ref() as &[u8] != payment_hash.0.as_slice() {
+        return CheckOutcome::Fail("invoice payment hash doesn't match the backend's".to_string());
+    }
+    match decoded.amount_milli_satoshis() {
+        Some(amount) if amount == DOCTOR_INVOICE_MSAT as u64 => CheckOutcome::Pass(format!("{} msat on {}", amount, decoded.network())),
+        amount => CheckOutcome::Fail(format!("expected {} msat, invoice is for {:?}", DOCTOR_INVOICE_MSAT, amount)),
+    }
+}
+
+fn macaroon_check(root_key: Vec<u8>) -> CheckOutcome {
+    let preimage = PaymentPreimage(rand::random());
+    let mac = match get_macaroon_as_string(PaymentHash::from(preimage), vec![DOCTOR_CAVEAT.to_string()], root_key.clone()) {
+        Ok(mac) => mac,
+        Err(error) => return CheckOutcome::Fail(format!("mint failed: {}", error)),
+    };
+    let (mac, preimage) = match utils::parse_l402_header(&format!("L402 {}:{}", mac, hex::encode(preimage.0))) {
+        Ok(parsed) => parsed,
+        Err(error) => return CheckOutcome::Fail(format!("parse failed: {}", error)),
+    };
+    if let Err(error) = l402::verify_l402(&mac, vec![DOCTOR_CAVEAT.to_string()], root_key.clone(), preimage) {
+        return CheckOutcome::Fail(format!("verify failed: {}", error));
+    }
+    if l402::verify_l402(&mac, vec!["RequestPath = /other".to_string()], root_key, preimage).is_ok() {
+        return CheckOutcome::Fail("token verified against caveats it wasn't minted with".to_string());
+    }
+    CheckOutcome::Pass("minted token verifies and rejects foreign caveats".to_string())
+}
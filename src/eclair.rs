@@ -1,13 +1,10 @@
-use std::{error::Error, sync::Arc};
-use tokio::sync::Mutex;
-use std::future::Future;
-use std::pin::Pin;
-use reqwest::Client;
+use std::{error::Error, future::Future, sync::Arc};
 use serde::{Deserialize, Serialize};
-use crate::lndrpc::lnrpc;
 use base64::{Engine as _, engine::general_purpose};
 
-use crate::lnclient;
+use crate::lnclient::{self, BackendError};
+use crate::trace;
+use crate::transport::{self, HttpTransport};
 
 #[derive(Debug, Clone)]
 pub struct EclairOptions {
@@ -21,9 +18,12 @@ pub struct EclairOptions {
 struct CreateInvoiceRequest {
     #[serde(rename = "amountMsat")]
     amount_msat: i64,
-    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(rename = "descriptionHash", skip_serializing_if = "Option::is_none")]
+    description_hash: Option<String>,
     #[serde(rename = "expireIn", skip_serializing_if = "Option::is_none")]
-    expire_in: Option<i64>,
+    expire_in: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -34,8 +34,25 @@ struct CreateInvoiceResponse {
     payment_hash: String,
 }
 
+#[derive(Serialize)]
+struct GetReceivedInfoRequest {
+    #[serde(rename = "paymentHash")]
+    payment_hash: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReceivedInfoStatus {
+    #[serde(rename = "type")]
+    status_type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetReceivedInfoResponse {
+    status: ReceivedInfoStatus,
+}
+
 pub struct EclairWrapper {
-    client: Client,
+    transport: Arc<dyn HttpTransport>,
     api_url: String,
     password: String,
 }
@@ -43,8 +60,9 @@ pub struct EclairWrapper {
 impl EclairWrapper {
     pub async fn new_client(
         ln_client_config: &lnclient::LNClientConfig,
-    ) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn Error + Send + Sync>> {
-        let mut eclair_options = ln_client_config.eclair_config.clone().unwrap();
+    ) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn Error + Send + Sync>> {
+        let mut eclair_options = ln_client_config.eclair_config.clone()
+            .ok_or("eclair_config is required for the Eclair client")?;
 
         // Ensure API URL has a scheme
         if !eclair_options.api_url.starts_with("http://") && !eclair_options.api_url.starts_with("https://") {
@@ -53,100 +71,92 @@ impl EclairWrapper {
 
         println!("Eclair client connecting to {}", eclair_options.api_url);
 
-        // Test connection by making a simple API call
-        let client = Client::new();
-        let test_url = format!("{}/getinfo", eclair_options.api_url);
-        
-        let auth_header = format!(":{}", eclair_options.password);
-        let encoded = general_purpose::STANDARD.encode(auth_header.as_bytes());
-        
-        match client
-            .post(&test_url)
-            .header("Authorization", format!("Basic {}", encoded))
-            .send()
-            .await
-        {
-            Ok(response) => {
-                if response.status().is_success() {
-                    println!("✓ Successfully connected to Eclair node");
-                } else {
-                    eprintln!("⚠ Eclair connection test returned status: {}", response.status());
-                }
-            }
-            Err(e) => {
-                eprintln!("⚠ Failed to connect to Eclair node: {}", e);
-            }
-        }
-
         let wrapper = EclairWrapper {
-            client,
+            transport: ln_client_config.http_transport.clone().unwrap_or_else(transport::default_transport),
             api_url: eclair_options.api_url,
             password: eclair_options.password,
         };
 
-        Ok(Arc::new(Mutex::new(wrapper)))
+        // Test connection by making a simple API call
+        match wrapper.call("getinfo", &()).await {
+            Ok(_) => println!("✓ Successfully connected to Eclair node"),
+            Err(e) => eprintln!("⚠ Eclair connection test failed: {}", e),
+        }
+
+        Ok(Arc::new(wrapper))
+    }
+
+    // Form POST to an API method with basic auth (username is empty for Eclair, password only)
+    fn api_request<T: Serialize>(&self, method: &str, form: &T) -> Result<transport::HttpRequest, Box<dyn Error + Send + Sync>> {
+        let mut request = transport::post_form(&format!("{}/{}", self.api_url, method), form)?;
+        let encoded = general_purpose::STANDARD.encode(format!(":{}", self.password).as_bytes());
+        request.headers_mut().insert(http::header::AUTHORIZATION, format!("Basic {}", encoded).parse()?);
+        request.headers_mut().extend(trace::http_headers());
+        Ok(request)
+    }
+
+    // Calls an API method and returns the body of a successful response
+    fn call<T: Serialize>(&self, method: &str, form: &T) -> impl Future<Output = Result<Vec<u8>, Box<dyn Error + Send + Sync>>> + Send + 'static {
+        let transport = self.transport.clone();
+        let request = self.api_request(method, form);
+
+        async move {
+            let response = transport.send(request?).await
+                .map_err(|e| BackendError::Unreachable(format!("Failed to send request to Eclair: {}", e)))?;
+
+            if !response.status().is_success() {
+                return Err(BackendError::Status {
+                    status: response.status().as_u16(),
+                    body: String::from_utf8_lossy(response.body()).into_owned(),
+                }.into());
+            }
+            Ok(response.into_body())
+        }
     }
 }
 
+lnclient::invoice_conversion!(From<lnclient::InvoiceRequest> for CreateInvoiceRequest, |invoice| {
+    amount_msat: invoice.value_msat,
+    description: invoice.description(),
+    description_hash: invoice.memo_hash().map(hex::encode),
+    expire_in: invoice.expiry,
+});
+
+lnclient::invoice_conversion!(TryFrom<CreateInvoiceResponse> for lnclient::InvoiceResponse, |response| {
+    payment_hash: hex::decode(&response.payment_hash)
+        .map_err(|e| BackendError::malformed(format!("Failed to decode payment hash: {}", e)))?
+        .try_into()
+        .map_err(|_| BackendError::malformed("Invalid payment hash length from Eclair"))?,
+    payment_request: response.invoice,
+});
+
 impl lnclient::LNClient for EclairWrapper {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>>> + Send>> {
-        let client = self.client.clone();
-        let api_url = self.api_url.clone();
-        let password = self.password.clone();
-        
+    fn backend_id(&self) -> String {
+        format!("eclair:{}", self.api_url)
+    }
+
+    fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+        let response = self.call("createinvoice", &CreateInvoiceRequest::from(invoice));
+
         Box::pin(async move {
-            let url = format!("{}/createinvoice", api_url);
-            
-            // Prepare the request
-            let request_data = CreateInvoiceRequest {
-                amount_msat: invoice.value_msat,
-                description: invoice.memo,
-                expire_in: if invoice.expiry > 0 {
-                    Some(invoice.expiry)
-                } else {
-                    None
-                },
-            };
-            
-            // Create basic auth header (username is empty for Eclair, password only)
-            let auth_header = format!(":{}", password);
-            let encoded = general_purpose::STANDARD.encode(auth_header.as_bytes());
-            
-            // Make the API call
-            let response = client
-                .post(&url)
-                .header("Authorization", format!("Basic {}", encoded))
-                .form(&request_data)
-                .send()
-                .await
-                .map_err(|e| format!("Failed to send request to Eclair: {}", e))?;
+            let eclair_response: CreateInvoiceResponse = serde_json::from_slice(&response.await?)
+                .map_err(|e| BackendError::malformed(format!("Failed to parse Eclair response: {}", e)))?;
 
-            if !response.status().is_success() {
-                let status = response.status();
-                let error_body = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-                return Err(format!(
-                    "Eclair API returned error status {}: {}",
-                    status, error_body
-                ).into());
-            }
+            eclair_response.try_into()
+        })
+    }
 
-            let eclair_response: CreateInvoiceResponse = response
-                .json()
-                .await
-                .map_err(|e| format!("Failed to parse Eclair response: {}", e))?;
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        let response = self.call("getreceivedinfo", &GetReceivedInfoRequest { payment_hash: hex::encode(payment_hash) });
 
-            // Convert payment hash from hex string to bytes
-            let payment_hash_bytes = hex::decode(&eclair_response.payment_hash)
-                .map_err(|e| format!("Failed to decode payment hash: {}", e))?;
+        Box::pin(async move {
+            let received: GetReceivedInfoResponse = serde_json::from_slice(&response.await?)
+                .map_err(|e| BackendError::malformed(format!("Failed to parse Eclair response: {}", e)))?;
 
-            Ok(lnrpc::AddInvoiceResponse {
-                r_hash: payment_hash_bytes,
-                payment_request: eclair_response.invoice,
-                add_index: 0, // Eclair doesn't provide this
-                payment_addr: vec![], // Eclair doesn't provide this in the invoice response
+            Ok(match received.status.status_type.as_str() {
+                "received" => lnclient::InvoiceState::Settled,
+                "expired" => lnclient::InvoiceState::Canceled,
+                _ => lnclient::InvoiceState::Open,
             })
         })
     }
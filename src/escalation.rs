@@ -0,0 +1,69 @@
+use std::error::Error;
+use std::time::Duration;
+
+use crate::store::TokenStore;
+use crate::utils;
+
+/// Raises challenge prices for a client fingerprint that keeps requesting
+/// challenges without paying them, making invoice spam progressively costlier to
+/// sustain. Counts come from the challenge ledger, so the policy is shared by
+/// every instance using the same `TokenStore`.
+#[derive(Debug, Clone)]
+pub struct EscalationConfig {
+    /// Unpaid challenge invoices, bundle offers included, a fingerprint may collect
+    /// at the base price
+    pub free_unpaid: u64,
+    /// Price factor applied for each unpaid challenge beyond `free_unpaid`
+    pub factor: f64,
+    /// Upper bound on the resulting price multiplier
+    pub max_multiplier: f64,
+    /// How far back unpaid challenges are counted
+    pub window: Duration,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        EscalationConfig {
+            free_unpaid: 5,
+            factor: 2.0,
+            max_multiplier: 64.0,
+            window: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl EscalationConfig {
+    /// Multiplier for `unpaid` outstanding challenges.
+    pub fn multiplier(&self, unpaid: u64) -> f64 {
+        let excess = unpaid.saturating_sub(self.free_unpaid);
+        if excess == 0 {
+            return 1.0;
+        }
+        self.factor.max(1.0).powi(excess.min(i32::MAX as u64) as i32).min(self.max_multiplier.max(1.0))
+    }
+
+    /// Unpaid challenge invoices issued to `fingerprint` within the window. Paying
+    /// any challenge clears the slate: only challenges issued after it count.
+    pub async fn unpaid_challenges(&self, store: &dyn TokenStore, fingerprint: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let since = utils::now_unix().saturating_sub(self.window.as_secs());
+        let challenges = store.fingerprint_challenges(fingerprint, since).await?;
+        let last_paid = challenges.iter()
+            .filter(|challenge| challenge.settled_at.is_some())
+            .map(|challenge| challenge.created_at)
+            .max();
+        Ok(challenges.iter()
+            .filter(|challenge| challenge.settled_at.is_none())
+            .filter(|challenge| last_paid.is_none_or(|paid| challenge.created_at > paid))
+            .count() as u64)
+    }
+
+    pub async fn price_multiplier(&self, store: &dyn TokenStore, fingerprint: &str) -> f64 {
+        match self.unpaid_challenges(store, fingerprint).await {
+            Ok(unpaid) => self.multiplier(unpaid),
+            Err(error) => {
+                println!("Error counting unpaid challenges: {}", error);
+                1.0
+            },
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+
+use crate::metrics::L402Metrics;
+use crate::observer::L402Observer;
+use crate::store::ChallengeRecord;
+
+// Events buffered for `EventBus::receiver` consumers before the slowest one lags
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Who proved a challenge's invoice paid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementSource {
+    /// The client presented a token with the invoice's preimage
+    Token,
+    /// The node reported the settlement to a `settlements` endpoint
+    Node,
+}
+
+/// What happens to a token over its lifetime, published on the middleware's `EventBus`
+/// once the step is done.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainEvent {
+    /// A challenge was minted, as it goes in the ledger
+    ChallengeIssued { challenge: ChallengeRecord },
+    /// First proof that a challenge's invoice was paid, from either source
+    InvoiceSettled { payment_hash: String, source: SettlementSource },
+    /// A token was accepted for a request
+    TokenVerified {
+        payment_hash: String,
+        /// Client that presented it, see `analytics::client_fingerprint`
+        fingerprint: String,
+        /// Backend that minted the invoice, on the token's first use
+        backend: Option<String>,
+        /// Invoice amount, charged on the token's first use and 0 after
+        spent_msat: i64,
+    },
+    /// A token was revoked, e.g. by reconciliation
+    TokenRevoked { payment_hash: String },
+}
+
+pub type EventFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Handles events as they are published. Subscribers run in subscription order and
+/// the publisher waits for them, so a later request sees their effects.
+pub trait EventSubscriber: Send + Sync + 'static {
+    fn on_event<'a>(&'a self, event: &'a DomainEvent) -> EventFuture<'a>;
+}
+
+/// Fans domain events out to subscribers, and to `receiver` channels for consumers
+/// that would rather process them on their own task.
+pub struct EventBus {
+    subscribers: RwLock<Vec<Arc<dyn EventSubscriber>>>,
+    sender: broadcast::Sender<DomainEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            subscribers: RwLock::new(Vec::new()),
+            sender: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    pub fn subscribe(&self, subscriber: Arc<dyn EventSubscriber>) {
+        if let Ok(mut subscribers) = self.subscribers.write() {
+            subscribers.push(subscriber);
+        }
+    }
+
+    /// Channel of events published from now on. A receiver that falls more than
+    /// 1024 events behind skips the oldest, see `broadcast::error::RecvError::Lagged`.
+    pub fn receiver(&self) -> broadcast::Receiver<DomainEvent> {
+        self.sender.subscribe()
+    }
+
+    pub async fn publish(&self, event: DomainEvent) {
+        let subscribers = self.subscribers.read().map(|s| s.clone()).unwrap_or_default();
+        for subscriber in subscribers {
+            subscriber.on_event(&event).await;
+        }
+        // No receivers is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+impl EventSubscriber for L402Metrics {
+    fn on_event<'a>(&'a self, event: &'a DomainEvent) -> EventFuture<'a> {
+        match event {
+            DomainEvent::ChallengeIssued { challenge } => {
+                L402Metrics::incr(&self.challenges_issued);
+                self.incr_backend(&challenge.backend, |m| m.challenges_issued += 1);
+            },
+            // Node reports aren't counted, the client proves those payments too
+            DomainEvent::InvoiceSettled { source: SettlementSource::Token, .. } => L402Metrics::incr(&self.challenges_settled),
+            DomainEvent::InvoiceSettled { source: SettlementSource::Node, .. } => {},
+            DomainEvent::TokenVerified { backend, spent_msat, .. } => {
+                L402Metrics::incr(&self.tokens_accepted);
+                if let Some(backend) = backend {
+                    let revenue_msat = (*spent_msat).max(0) as u64;
+                    self.incr_backend(backend, |m| m.revenue_msat += revenue_msat);
+                }
+            },
+            DomainEvent::TokenRevoked { .. } => L402Metrics::incr(&self.tokens_revoked),
+        }
+        Box::pin(async {})
+    }
+}
+
+/// Hands the events to `L402Observer::on_domain_event`.
+pub struct ObserverSubscriber(pub Vec<Arc<dyn L402Observer>>);
+
+impl EventSubscriber for ObserverSubscriber {
+    fn on_event<'a>(&'a self, event: &'a DomainEvent) -> EventFuture<'a> {
+        for observer in &self.0 {
+            observer.on_domain_event(event);
+        }
+        Box::pin(async {})
+    }
+}
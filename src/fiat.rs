@@ -0,0 +1,149 @@
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::transport::{self, HttpTransport};
+use crate::utils;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SATS_PER_BTC: i64 = 100_000_000;
+pub const MIN_SATS_TO_BE_PAID: i64 = 1;
+pub const MSAT_PER_SAT: i64 = 1000;
+pub const ORACLE_SIGNATURE_HEADER_NAME: &str = "X-Oracle-Signature";
+
+/// Fiat amount that produced a sat price, advertised in the challenge as `price_fiat="0.01 USD"`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatPrice {
+    pub amount: f64,
+    pub currency: String,
+}
+
+impl fmt::Display for FiatPrice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.amount, self.currency)
+    }
+}
+
+/// Operator-run price oracle, queried as `GET <url>?currency=USD` instead of
+/// blockchain.info. It answers with the price of one BTC and when it was quoted:
+/// `{"currency":"USD","btc_price":65000.0,"timestamp":1700000000}`.
+#[derive(Debug, Clone)]
+pub struct RateOracle {
+    pub url: String,
+    /// When set, responses must carry the hex HMAC-SHA256 of the body in `X-Oracle-Signature`
+    pub secret: Option<Vec<u8>>,
+    /// Quotes older than this are rejected
+    pub max_age: Duration,
+}
+
+impl RateOracle {
+    pub fn new(url: &str) -> Self {
+        RateOracle {
+            url: url.to_string(),
+            secret: None,
+            max_age: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OracleQuote {
+    currency: String,
+    btc_price: f64,
+    timestamp: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FiatRateConfig {
+    pub currency: String,
+    pub amount: f64,
+    /// Sends the rate provider request; a plain reqwest client when None
+    pub transport: Option<Arc<dyn HttpTransport>>,
+    /// Rate source; blockchain.info when None
+    pub oracle: Option<RateOracle>,
+}
+
+impl FiatRateConfig {
+    // Converts fiat amount to BTC equivalent in millisats. Customization possible for different API endpoints.
+    pub async fn fiat_to_btc_amount_func(&self) -> i64 {
+        // Return the minimum sats if the amount is invalid.
+        if self.amount <= 0.0 {
+            return MIN_SATS_TO_BE_PAID * MSAT_PER_SAT;
+        }
+
+        self.fetch_btc_amount().await.unwrap_or(MIN_SATS_TO_BE_PAID * MSAT_PER_SAT)
+    }
+
+    // Queries the rate provider without falling back to the minimum, so failures can be reported.
+    pub async fn fetch_btc_amount(&self) -> Result<i64, Box<dyn Error + Send + Sync>> {
+        if let Some(oracle) = &self.oracle {
+            return self.fetch_oracle_amount(oracle).await;
+        }
+
+        // API request to get BTC equivalent of the fiat amount.
+        let url = format!(
+            "https://blockchain.info/tobtc?currency={}&value={}",
+            self.currency, self.amount
+        );
+
+        let transport = self.transport.clone().unwrap_or_else(transport::default_transport);
+        let response = transport.send(transport::get(&url)?).await?;
+        if !response.status().is_success() {
+            return Err(format!("Rate provider returned status {}", response.status()).into());
+        }
+        let body = String::from_utf8_lossy(response.body());
+        let amount_in_btc = body.trim().parse::<f64>()
+            .map_err(|_| format!("Unexpected rate provider response: {}", body))?;
+        Ok(((SATS_PER_BTC as f64 * amount_in_btc) * MSAT_PER_SAT as f64) as i64)
+    }
+
+    async fn fetch_oracle_amount(&self, oracle: &RateOracle) -> Result<i64, Box<dyn Error + Send + Sync>> {
+        let separator = if oracle.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}currency={}", oracle.url, separator, self.currency);
+
+        let transport = self.transport.clone().unwrap_or_else(transport::default_transport);
+        let response = transport.send(transport::get(&url)?).await?;
+        if !response.status().is_success() {
+            return Err(format!("Rate oracle returned status {}", response.status()).into());
+        }
+        if let Some(secret) = &oracle.secret {
+            let signature = response.headers().get(ORACLE_SIGNATURE_HEADER_NAME)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| hex::decode(value.trim()).ok())
+                .ok_or("Rate oracle response is not signed")?;
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+            mac.update(response.body());
+            mac.verify_slice(&signature).map_err(|_| "Rate oracle signature does not match")?;
+        }
+
+        let quote: OracleQuote = serde_json::from_slice(response.body())
+            .map_err(|error| format!("Unexpected rate oracle response: {}", error))?;
+        if !quote.currency.eq_ignore_ascii_case(&self.currency) {
+            return Err(format!("Rate oracle quoted {} instead of {}", quote.currency, self.currency).into());
+        }
+        if utils::now_unix().saturating_sub(quote.timestamp) > oracle.max_age.as_secs() {
+            return Err("Rate oracle quote is stale".into());
+        }
+        if !quote.btc_price.is_finite() || quote.btc_price <= 0.0 {
+            return Err(format!("Invalid rate oracle price: {}", quote.btc_price).into());
+        }
+        let amount_in_btc = self.amount / quote.btc_price;
+        Ok(((SATS_PER_BTC as f64 * amount_in_btc) * MSAT_PER_SAT as f64) as i64)
+    }
+
+    // Fiat hint for the challenge; None when the minimum sats are charged instead.
+    pub fn fiat_price(&self) -> Option<FiatPrice> {
+        if self.amount <= 0.0 {
+            return None;
+        }
+        Some(FiatPrice {
+            amount: self.amount,
+            currency: self.currency.clone(),
+        })
+    }
+}
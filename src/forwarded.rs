@@ -0,0 +1,149 @@
+use rocket::http::HeaderMap;
+use rocket::Request;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+pub const FORWARDED_HEADER_NAME: &str = "Forwarded";
+pub const X_FORWARDED_FOR_HEADER_NAME: &str = "X-Forwarded-For";
+pub const X_FORWARDED_PROTO_HEADER_NAME: &str = "X-Forwarded-Proto";
+
+/// An address range such as `10.0.0.0/8` or `::1/128`. A bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.trim().split_once('/').map_or((s.trim(), None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.parse().map_err(|_| format!("Invalid network address: {}", s))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("Invalid network prefix: {}", s))?,
+            None => max_prefix,
+        };
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+/// Which forwarding header the trusted proxies maintain. Only one is read, so a
+/// client can't slip a spoofed address in through the other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: client, proxy1, proxy2`, as set by nginx and Cloudflare
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=client, for=proxy1`
+    Forwarded,
+}
+
+/// Reverse proxies whose forwarding headers are believed when resolving the client
+/// address. With no networks configured the socket peer is always the client.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    pub networks: Vec<IpNetwork>,
+    pub header: ForwardedHeader,
+}
+
+// Client address resolved by the middleware for this request
+struct ClientAddress(Option<IpAddr>);
+
+impl TrustedProxies {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+
+    /// Walks the forwarding chain back from the socket peer while hops are trusted.
+    /// The first untrusted hop is the client. A hop that can't be parsed (e.g.
+    /// `for=unknown`) ends the walk at the last trusted proxy.
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap<'_>) -> Option<IpAddr> {
+        let mut client = peer?;
+        if !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        let hops: Vec<&str> = match self.header {
+            ForwardedHeader::XForwardedFor => headers.get(X_FORWARDED_FOR_HEADER_NAME)
+                .flat_map(|value| value.split(','))
+                .collect(),
+            ForwardedHeader::Forwarded => headers.get(FORWARDED_HEADER_NAME)
+                .flat_map(|value| value.split(','))
+                .map(|element| element.split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map_or("", |(_, value)| value))
+                .collect(),
+        };
+        for hop in hops.iter().rev() {
+            let Some(ip) = parse_hop(hop) else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    /// Scheme the client used to reach the outermost proxy, e.g. `https` where the
+    /// proxies terminate TLS. Read from `X-Forwarded-Proto`, or the `proto` of the
+    /// first `Forwarded` element, and only believed if the socket peer is trusted.
+    pub fn forwarded_proto(&self, peer: Option<IpAddr>, headers: &HeaderMap<'_>) -> Option<String> {
+        if !peer.is_some_and(|peer| self.is_trusted(peer)) {
+            return None;
+        }
+        let proto = match self.header {
+            ForwardedHeader::XForwardedFor => headers.get_one(X_FORWARDED_PROTO_HEADER_NAME)?.split(',').next()?,
+            ForwardedHeader::Forwarded => headers.get_one(FORWARDED_HEADER_NAME)?
+                .split(',').next()?
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("proto"))?.1,
+        };
+        let proto = proto.trim().trim_matches('"').to_ascii_lowercase();
+        matches!(proto.as_str(), "http" | "https").then_some(proto)
+    }
+}
+
+// `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:4711"` or `2001:db8::1`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.rsplit_once(':')?.0.parse().ok()
+}
+
+/// Resolves the request's client address once, for every IP-based feature to share.
+pub fn cache_client_address(request: &Request<'_>, trusted_proxies: &TrustedProxies) {
+    request.local_cache(|| ClientAddress(trusted_proxies.resolve(request.remote().map(|r| r.ip()), request.headers())));
+}
+
+/// Client address as resolved by the middleware, or the socket peer if it didn't run.
+pub fn client_address(request: &Request<'_>) -> Option<IpAddr> {
+    request.local_cache(|| ClientAddress(request.remote().map(|r| r.ip()))).0
+}
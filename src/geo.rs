@@ -0,0 +1,90 @@
+use rocket::Request;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::forwarded;
+
+pub type GeoFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<ClientLocation>, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// What a GeoIP/ASN database knows about a client address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientLocation {
+    /// ISO 3166-1 alpha-2 country code, e.g. `BR`
+    pub country: Option<String>,
+    /// Autonomous system the address is announced from
+    pub asn: Option<u32>,
+    /// Whether the ASN belongs to a hosting or cloud provider rather than an ISP
+    pub datacenter: bool,
+}
+
+/// Looks up client addresses, e.g. in a local MaxMind database or a hosted IP
+/// intelligence API. Returning `None` leaves the client unclassified.
+pub trait GeoProvider: Send + Sync + 'static {
+    fn lookup(&self, ip: IpAddr) -> GeoFuture<'_>;
+}
+
+/// Pricing by client region and network. The resolved location is also available
+/// to `amount_func` and handlers through `client_location`.
+#[derive(Clone)]
+pub struct GeoPricing {
+    pub provider: Arc<dyn GeoProvider>,
+    /// Price multipliers by country code, e.g. `0.3` for purchasing-power-adjusted
+    /// regions. Clients from unlisted or unknown countries pay the base price.
+    pub country_multipliers: HashMap<String, f64>,
+    /// Challenges clients on datacenter networks instead of serving them the free tier
+    pub deny_free_tier_to_datacenters: bool,
+    /// ASNs denied the free tier regardless of what the provider reports
+    pub denied_asns: HashSet<u32>,
+}
+
+impl GeoPricing {
+    pub fn new(provider: Arc<dyn GeoProvider>) -> Self {
+        GeoPricing {
+            provider,
+            country_multipliers: HashMap::new(),
+            deny_free_tier_to_datacenters: false,
+            denied_asns: HashSet::new(),
+        }
+    }
+
+    pub fn multiplier(&self, location: Option<&ClientLocation>) -> f64 {
+        location
+            .and_then(|location| location.country.as_ref())
+            .and_then(|country| self.country_multipliers.get(&country.to_ascii_uppercase()))
+            .copied()
+            .filter(|multiplier| multiplier.is_finite() && *multiplier >= 0.0)
+            .unwrap_or(1.0)
+    }
+
+    pub fn denies_free_tier(&self, location: Option<&ClientLocation>) -> bool {
+        location.is_some_and(|location| {
+            (self.deny_free_tier_to_datacenters && location.datacenter)
+                || location.asn.is_some_and(|asn| self.denied_asns.contains(&asn))
+        })
+    }
+}
+
+// Location resolved by the middleware for this request
+struct CachedLocation(Option<ClientLocation>);
+
+/// Looks the request's client address up once, for pricing and handlers to share.
+/// Lookup failures leave the client unclassified rather than failing the request.
+pub async fn cache_client_location(request: &Request<'_>, provider: &dyn GeoProvider) {
+    let location = match forwarded::client_address(request) {
+        Some(ip) => provider.lookup(ip).await.unwrap_or_else(|error| {
+            println!("Error looking up client location: {}", error);
+            None
+        }),
+        None => None,
+    };
+    request.local_cache(|| CachedLocation(location));
+}
+
+/// Client location as resolved by the middleware, if geo pricing is configured.
+pub fn client_location(request: &Request<'_>) -> Option<ClientLocation> {
+    request.local_cache(|| CachedLocation(None)).0.clone()
+}
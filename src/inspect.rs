@@ -0,0 +1,104 @@
+use lightning::types::payment::PaymentHash;
+use lightning_invoice::Bolt11Invoice;
+use macaroon::Caveat;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::l402;
+use crate::lnclient;
+use crate::store::TokenStore;
+use crate::utils;
+
+/// Structured diagnosis of a token for integrators. It reports what the middleware
+/// would see without granting access to anything.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InspectReport {
+    /// The macaroon could be deserialized
+    pub macaroon_valid: bool,
+    /// The macaroon was minted with this deployment's root key
+    pub signature_valid: bool,
+    /// Hex payment hash the macaroon is bound to
+    pub payment_hash: Option<String>,
+    pub caveats: Vec<String>,
+    /// Whether the presented preimage hashes to the payment hash; None if no preimage was sent
+    pub preimage_matches: Option<bool>,
+    /// Invoice issued with the challenge, if it is in the ledger
+    pub invoice: Option<String>,
+    /// Unix timestamp the challenge invoice expires
+    pub invoice_expires_at: Option<u64>,
+    /// Backend that minted the challenge invoice, if it is in the ledger
+    pub backend: Option<String>,
+    /// Invoice state at the backend, looked up only for macaroons with a valid signature
+    pub invoice_state: Option<lnclient::InvoiceState>,
+    /// Why the invoice state couldn't be looked up
+    pub invoice_state_error: Option<String>,
+    pub revoked: bool,
+    /// Parse errors, if any
+    pub error: Option<String>,
+}
+
+/// Inspects `token`, given as `<macaroon>` or `<macaroon>:<preimage>`, optionally
+/// prefixed with `L402 ` or `LSAT `.
+pub async fn inspect_token(
+    token: &str,
+    root_key: &[u8],
+    store: &dyn TokenStore,
+    ln_client: &Arc<dyn lnclient::LNClient>,
+) -> InspectReport {
+    let mut report = InspectReport::default();
+    let (_, token) = l402::split_auth_scheme(token);
+    let (macaroon_string, preimage_string) = match token.split_once(':') {
+        Some((mac, preimage)) => (mac.trim(), Some(preimage.trim())),
+        None => (token, None),
+    };
+
+    let mac = match utils::get_macaroon_from_string(macaroon_string.to_string()) {
+        Ok(mac) => mac,
+        Err(error) => {
+            report.error = Some(error);
+            return report;
+        }
+    };
+    report.macaroon_valid = true;
+    report.signature_valid = l402::verify_macaroon_signature(&mac, root_key);
+    report.caveats = mac.first_party_caveats().iter().filter_map(|caveat| match caveat {
+        Caveat::FirstParty(fp) => Some(String::from_utf8_lossy(fp.predicate().as_ref()).to_string()),
+        _ => None,
+    }).collect();
+
+    let Some(payment_hash) = l402::macaroon_payment_hash(&mac) else {
+        report.error = Some("Macaroon identifier doesn't carry a payment hash".to_string());
+        return report;
+    };
+    let token_id = hex::encode(payment_hash);
+    report.payment_hash = Some(token_id.clone());
+
+    if let Some(preimage_string) = preimage_string {
+        match utils::get_preimage_from_string(preimage_string.to_string()) {
+            Ok(preimage) => report.preimage_matches = Some(PaymentHash::from(preimage).0 == payment_hash),
+            Err(error) => report.error = Some(error),
+        }
+    }
+
+    if let Ok(Some(challenge)) = store.get_challenge(&token_id).await {
+        report.invoice_expires_at = challenge.invoice.parse::<Bolt11Invoice>().ok()
+            .and_then(|invoice| invoice.expires_at())
+            .map(|expires_at| expires_at.as_secs());
+        report.invoice = Some(challenge.invoice);
+        report.backend = Some(challenge.backend);
+    }
+    report.revoked = store.is_revoked(&token_id).await.unwrap_or(false);
+
+    // Anyone can forge an unsigned macaroon, so only our own tokens get to probe the node's invoices
+    if !report.signature_valid {
+        report.invoice_state_error = Some("Not looked up, the macaroon signature is invalid".to_string());
+        return report;
+    }
+    let lookup = ln_client.lookup_invoice(payment_hash);
+    match lookup.await {
+        Ok(state) => report.invoice_state = Some(state),
+        Err(error) => report.invoice_state_error = Some(error.to_string()),
+    }
+
+    report
+}
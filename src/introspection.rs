@@ -0,0 +1,91 @@
+use lightning::types::payment::PaymentHash;
+use macaroon::Caveat;
+use serde::Serialize;
+
+use crate::l402;
+use crate::store::TokenStore;
+use crate::utils;
+
+/// RFC 7662 introspection response. Inactive tokens only carry `active: false`,
+/// so callers learn nothing about tokens they couldn't use, except the holder of a
+/// token outside its validity window learns which side of it they are.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    /// Caveats the token is restricted by, space separated, e.g. `RequestPath=/protected`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Hex payment hash, which is also the token id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// Unix timestamp the challenge was issued, if it is in the ledger
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_uses: Option<u64>,
+    /// `NotBefore` caveat of the token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+    /// `NotAfter` caveat of the token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    /// Why a settled token is inactive, as in the `L402-Error` header, e.g. `token_expired`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inactive_reason: Option<String>,
+}
+
+/// Introspects `token`, given as `[L402 ]<macaroon>:<preimage>`. It is active if it
+/// was minted with `root_key`, the preimage settles it, it is within its validity
+/// window, it wasn't revoked and its quota isn't spent. Other caveats are reported,
+/// not enforced: they are the caller's scope.
+pub async fn introspect_token(token: &str, root_key: &[u8], store: &dyn TokenStore) -> IntrospectionResponse {
+    let inactive = IntrospectionResponse::default();
+    let Ok((mac, preimage)) = utils::parse_l402_header(token) else {
+        return inactive;
+    };
+    if l402::verify_l402_holder(&mac, root_key.to_vec(), preimage).is_err() {
+        return inactive;
+    }
+    if let Err(validity_error) = l402::check_validity_window(&mac, utils::now_unix()) {
+        return IntrospectionResponse { inactive_reason: Some(validity_error.code().to_string()), ..inactive };
+    }
+    let token_id = hex::encode(PaymentHash::from(preimage).0);
+    if !matches!(store.is_revoked(&token_id).await, Ok(false)) {
+        return inactive;
+    }
+
+    let max_uses = l402::get_caveat_value(&mac, l402::MAX_USES_CAVEAT).and_then(|value| value.parse::<u64>().ok());
+    let remaining_uses = match max_uses {
+        Some(max_uses) => match store.get_usage(&token_id).await {
+            Ok(usage) => Some(max_uses.saturating_sub(usage.map_or(0, |usage| usage.uses))),
+            Err(_) => return inactive,
+        },
+        None => None,
+    };
+    if remaining_uses == Some(0) {
+        return inactive;
+    }
+
+    let scope: Vec<String> = mac.first_party_caveats().iter().filter_map(|caveat| match caveat {
+        Caveat::FirstParty(fp) => Some(String::from_utf8_lossy(fp.predicate().as_ref()).replace(' ', "")),
+        _ => None,
+    }).collect();
+    let iat = store.get_challenge(&token_id).await.ok().flatten().map(|challenge| challenge.created_at);
+
+    IntrospectionResponse {
+        active: true,
+        token_type: Some(l402::L402_HEADER.to_string()),
+        scope: Some(scope.join(" ")),
+        sub: Some(token_id),
+        iat,
+        max_uses,
+        remaining_uses,
+        nbf: l402::get_caveat_value(&mac, l402::NOT_BEFORE_CAVEAT).and_then(|value| value.parse::<u64>().ok()),
+        exp: l402::get_caveat_value(&mac, l402::NOT_AFTER_CAVEAT).and_then(|value| value.parse::<u64>().ok()),
+        inactive_reason: None,
+    }
+}
@@ -1,18 +1,123 @@
+use bitcoin::secp256k1::PublicKey;
 use lightning::types::payment::{PaymentHash, PaymentPreimage};
-use macaroon::{Macaroon, Verifier, MacaroonKey};
-use rocket::{request, Request};
+use lightning::util::message_signing;
+use macaroon::{ByteString, Caveat, Macaroon, Verifier, MacaroonKey};
 use hex;
 
-use crate::l402;
 
 pub const L402_TYPE_FREE: &str = "FREE";
 pub const L402_TYPE_PAYMENT_REQUIRED: &str = "PAYMENT REQUIRED";
 pub const L402_TYPE_PAID: &str = "PAID";
 pub const L402_TYPE_ERROR: &str = "ERROR";
 pub const L402_HEADER: &str = "L402";
+// Scheme name L402 went by before the rename, still sent by older clients
+pub const LSAT_HEADER: &str = "LSAT";
 pub const L402_HEADER_NAME: &str = "Accept-Authenticate";
 pub const L402_AUTHENTICATE_HEADER_NAME: &str = "WWW-Authenticate";
 pub const L402_AUTHORIZATION_HEADER_NAME: &str = "Authorization";
+pub const L402_RANGE_HEADER_NAME: &str = "Range";
+pub const L402_CONTENT_RANGE_HEADER_NAME: &str = "Content-Range";
+pub const L402_CONTENT_HASH_HEADER_NAME: &str = "L402-Content-Hash";
+pub const L402_ERROR_HEADER_NAME: &str = "L402-Error";
+// L402-Error code of requests beyond a token's MaxConcurrent limit
+pub const TOO_MANY_CONCURRENT_ERROR: &str = "too_many_concurrent_requests";
+// L402-Error code of requests failed closed after overrunning the middleware's request budget
+pub const BUDGET_EXCEEDED_ERROR: &str = "budget_exceeded";
+// L402-Error code of requests that needed a challenge while the instance was shutting down
+pub const SHUTTING_DOWN_ERROR: &str = "shutting_down";
+
+// Caveats minted and enforced by the middleware itself rather than by caveat_func
+pub const MAX_USES_CAVEAT: &str = "MaxUses";
+pub const MAX_CONCURRENT_CAVEAT: &str = "MaxConcurrent";
+
+// Caveat restricting a token to a request path, normalized by the middleware's `path_normalization`
+pub const REQUEST_PATH_CAVEAT: &str = "RequestPath";
+
+// Caveat committing a token to the SHA256 of the exact content version it paid for
+pub const CONTENT_HASH_CAVEAT: &str = "ContentHash";
+
+// Caveats bounding when a token may be used, as unix timestamps
+pub const NOT_BEFORE_CAVEAT: &str = "NotBefore";
+pub const NOT_AFTER_CAVEAT: &str = "NotAfter";
+
+/// Period a token may be used in, e.g. the hour a webinar runs. Tokens can be
+/// bought ahead of it and are refused with `token_not_yet_valid` until it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidityWindow {
+    pub not_before: u64,
+    pub not_after: Option<u64>,
+}
+
+impl ValidityWindow {
+    pub fn caveats(&self) -> Vec<String> {
+        let mut caveats = vec![format!("{} = {}", NOT_BEFORE_CAVEAT, self.not_before)];
+        if let Some(not_after) = self.not_after {
+            caveats.push(format!("{} = {}", NOT_AFTER_CAVEAT, not_after));
+        }
+        caveats
+    }
+}
+
+/// Why a token's `NotBefore`/`NotAfter` caveats don't admit it at the time it was presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidityError {
+    NotYetValid { not_before: u64 },
+    Expired { not_after: u64 },
+}
+
+impl ValidityError {
+    /// Machine-readable code sent in the `L402-Error` header
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidityError::NotYetValid { .. } => "token_not_yet_valid",
+            ValidityError::Expired { .. } => "token_expired",
+        }
+    }
+}
+
+impl std::fmt::Display for ValidityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidityError::NotYetValid { not_before } => write!(f, "Token is not valid before {}", not_before),
+            ValidityError::Expired { not_after } => write!(f, "Token expired at {}", not_after),
+        }
+    }
+}
+
+/// Checks the token's `NotBefore`/`NotAfter` caveats against `now`. Tokens without them are always valid.
+pub fn check_validity_window(mac: &Macaroon, now: u64) -> Result<(), ValidityError> {
+    let timestamp = |key| get_caveat_value(mac, key).and_then(|value| value.parse::<u64>().ok());
+    if let Some(not_before) = timestamp(NOT_BEFORE_CAVEAT).filter(|not_before| now < *not_before) {
+        return Err(ValidityError::NotYetValid { not_before });
+    }
+    if let Some(not_after) = timestamp(NOT_AFTER_CAVEAT).filter(|not_after| now > *not_after) {
+        return Err(ValidityError::Expired { not_after });
+    }
+    Ok(())
+}
+
+/// A prepaid bundle of calls offered in the challenge alongside the single-call price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleOption {
+    /// Number of calls the bundle pays for, minted as a `MaxUses` caveat
+    pub uses: u64,
+    /// Price of the whole bundle; defaults to the single-call price times `uses`
+    pub amount_msat: Option<i64>,
+}
+
+/// An authentication scheme and protocol version, as negotiated via `Accept-Authenticate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthScheme {
+    pub name: &'static str,
+    pub version: u32,
+}
+
+/// Schemes the middleware can challenge with, most preferred first, each at the
+/// highest protocol version it speaks. Lower versions are spoken too.
+pub const SUPPORTED_SCHEMES: [AuthScheme; 2] = [
+    AuthScheme { name: L402_HEADER, version: 1 },
+    AuthScheme { name: LSAT_HEADER, version: 0 },
+];
 
 #[derive(Clone)]
 pub struct L402Info {
@@ -21,26 +126,65 @@ pub struct L402Info {
 	pub payment_hash: Option<PaymentHash>,
 	pub error: Option<String>,
     pub auth_header: Option<String>,
+    /// Scheme the challenge was issued with, or the token was presented with
+    pub scheme: Option<AuthScheme>,
 }
 
-#[rocket::async_trait]
-impl<'r> request::FromRequest<'r> for L402Info {
-    type Error = &'static str;
+/// Picks the best scheme from an `Accept-Authenticate` header such as
+/// `L402;v=1, LSAT;q=0.5`: highest quality value first, then our preference.
+/// Entries with `q=0`, unknown schemes and versions we don't speak are skipped.
+/// Returns None if nothing is mutually supported.
+pub fn negotiate_scheme(accept_authenticate: &str) -> Option<AuthScheme> {
+    let mut best: Option<(f32, usize, AuthScheme)> = None;
+    for entry in accept_authenticate.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let Some((preference, supported)) = SUPPORTED_SCHEMES.iter().enumerate()
+            .find(|(_, scheme)| scheme.name.eq_ignore_ascii_case(name)) else {
+            continue;
+        };
 
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        // Retrieve L402Info from the local cache
-        let l402_info = request.local_cache::<L402Info, _>(|| {
-            L402Info {
-                l402_type: l402::L402_TYPE_ERROR.to_string(),
-                error: Some("No L402 header present".to_string()),
-                preimage: None,
-                payment_hash: None,
-                auth_header: None,
+        let mut quality = 1.0;
+        let mut version = supported.version;
+        let mut valid = true;
+        for param in parts {
+            match param.split_once('=').map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().trim_matches('"'))) {
+                Some((key, value)) if key == "q" => match value.parse::<f32>() {
+                    Ok(q) if (0.0..=1.0).contains(&q) => quality = q,
+                    _ => valid = false,
+                },
+                Some((key, value)) if key == "v" || key == "version" => match value.parse::<u32>() {
+                    Ok(v) if v <= supported.version => version = v,
+                    _ => valid = false,
+                },
+                _ => {},
             }
-        });
+        }
+        if !valid || quality <= 0.0 {
+            continue;
+        }
 
-        request::Outcome::Success(l402_info.clone())
+        let better = match best {
+            Some((best_quality, best_preference, _)) => quality > best_quality || (quality == best_quality && preference < best_preference),
+            None => true,
+        };
+        if better {
+            best = Some((quality, preference, AuthScheme { name: supported.name, version }));
+        }
     }
+    best.map(|(_, _, scheme)| scheme)
+}
+
+/// Splits an `Authorization` header value into its `L402`/`LSAT` scheme, if it has
+/// one, and the `<macaroon>:<preimage>` token.
+pub fn split_auth_scheme(auth_field: &str) -> (Option<AuthScheme>, &str) {
+    let auth_field = auth_field.trim();
+    if let Some((name, token)) = auth_field.split_once(' ') {
+        if let Some(scheme) = SUPPORTED_SCHEMES.iter().find(|scheme| scheme.name.eq_ignore_ascii_case(name)) {
+            return (Some(*scheme), token.trim_start());
+        }
+    }
+    (None, auth_field)
 }
 
 fn macaroon_id_matches_payment_hash(id_bytes: &[u8], payment_hash: &PaymentHash) -> bool {
@@ -55,13 +199,86 @@ fn macaroon_id_matches_payment_hash(id_bytes: &[u8], payment_hash: &PaymentHash)
     }
 }
 
+/// Payment hash a macaroon was minted for, from its identifier.
+pub fn macaroon_payment_hash(mac: &Macaroon) -> Option<[u8; 32]> {
+    let id_bytes = &mac.identifier().0;
+    match id_bytes.len() {
+        33 if id_bytes[0] == 0xff => id_bytes[1..].try_into().ok(),
+        32 => id_bytes.as_slice().try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Checks only that the macaroon was minted with `root_key`, whatever its caveats.
+pub fn verify_macaroon_signature(mac: &Macaroon, root_key: &[u8]) -> bool {
+    let mut verifier = Verifier::default();
+    verifier.satisfy_general(|_| true);
+    verifier.verify(mac, &MacaroonKey::generate(root_key), Default::default()).is_ok()
+}
+
+/// Message the node signs for a challenge when the middleware's `sign_challenges` is
+/// set. Clients rebuild it from the challenge's `macaroon`, `invoice` and `timestamp`.
+pub fn challenge_signing_message(macaroon: &str, invoice: &str, timestamp: u64) -> String {
+    format!("{}:{}:{}", macaroon, invoice, timestamp)
+}
+
+/// Checks that a challenge's `signature` was made by the node with the hex `node_pubkey`,
+/// so a proxy in between can't have swapped its invoice.
+pub fn verify_challenge_signature(macaroon: &str, invoice: &str, timestamp: u64, signature: &str, node_pubkey: &str) -> bool {
+    let Ok(node_pubkey) = node_pubkey.parse::<PublicKey>() else {
+        return false;
+    };
+    message_signing::verify(challenge_signing_message(macaroon, invoice, timestamp).as_bytes(), signature, &node_pubkey)
+}
+
+/// Returns the value of the first `<key> = <value>` first-party caveat on the macaroon.
+pub fn get_caveat_value(mac: &Macaroon, key: &str) -> Option<String> {
+    let prefix = format!("{} = ", key);
+    mac.first_party_caveats().iter().find_map(|caveat| match caveat {
+        Caveat::FirstParty(fp) => {
+            let predicate = String::from_utf8_lossy(fp.predicate().as_ref()).to_string();
+            predicate.strip_prefix(&prefix).map(|value| value.trim().to_string())
+        },
+        _ => None,
+    })
+}
+
+// Middleware-managed caveats are satisfied here and enforced statefully by the middleware.
+fn satisfies_managed_caveat(caveat: &ByteString) -> bool {
+    let predicate = String::from_utf8_lossy(caveat.as_ref());
+    match predicate.split_once(" = ") {
+        Some((MAX_USES_CAVEAT | MAX_CONCURRENT_CAVEAT, value)) => value.trim().parse::<u64>().is_ok(),
+        _ => false,
+    }
+}
+
 pub fn verify_l402(
     mac: &Macaroon,
     caveats: Vec<String>,
     root_key: Vec<u8>,
     preimage: PaymentPreimage,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // caveat verification
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    verify_l402_caveats(mac, caveats, root_key)?;
+
+    let payment_hash: PaymentHash = PaymentHash::from(preimage);
+    let id_bytes = &mac.identifier().clone().0;
+    if macaroon_id_matches_payment_hash(id_bytes, &payment_hash) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid PaymentHash {} for macaroon {}",
+            hex::encode(payment_hash.0), hex::encode(id_bytes)
+        ).into())
+    }
+}
+
+/// Verifies the macaroon's signature and caveats without checking a preimage, for
+/// tokens paid through another invoice than the one they were minted for.
+pub fn verify_l402_caveats(
+    mac: &Macaroon,
+    caveats: Vec<String>,
+    root_key: Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mac_caveats = mac.first_party_caveats();
     if caveats.len() > mac_caveats.len() {
         return Err("Error validating macaroon: Caveats don't match".into());
@@ -73,24 +290,22 @@ pub fn verify_l402(
     for caveat in caveats {
         verifier.satisfy_exact(caveat.into());
     }
+    verifier.satisfy_general(satisfies_managed_caveat);
 
-    match verifier.verify(&mac, &mac_key, Default::default()) {
-        Ok(_) => {
-            let payment_hash: PaymentHash = PaymentHash::from(preimage);
-            let id_bytes = &mac.identifier().clone().0;
-            if macaroon_id_matches_payment_hash(id_bytes, &payment_hash) {
-                Ok(())
-            } else {
-                Err(format!(
-                    "Invalid PaymentHash {} for macaroon {}",
-                    hex::encode(payment_hash.0), hex::encode(id_bytes)
-                ).into())
-            }
-        },
-        Err(error) => {
-            Err(format!("Error validating macaroon: {:?}", error).into())
-        }
-    }
+    verifier.verify(mac, &mac_key, Default::default())
+        .map_err(|error| format!("Error validating macaroon: {:?}", error).into())
+}
+
+/// Verifies that the bearer holds a token minted with `root_key` without checking
+/// its caveats. Used by the built-in endpoints, which aren't tied to a resource.
+pub fn verify_l402_holder(
+    mac: &Macaroon,
+    root_key: Vec<u8>,
+    preimage: PaymentPreimage,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut verifier = Verifier::default();
+    verifier.satisfy_general(|_| true);
+    verify_l402_with_verifier(mac, &mut verifier, root_key, preimage)
 }
 
 /// Verify L402 using a provided Verifier instance
@@ -99,7 +314,7 @@ pub fn verify_l402_with_verifier(
     verifier: &mut Verifier,
     root_key: Vec<u8>,
     preimage: PaymentPreimage,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mac_key = MacaroonKey::generate(&root_key);
     
     match verifier.verify(&mac, &mac_key, Default::default()) {
@@ -1,13 +1,96 @@
+#[cfg(feature = "lnd")]
+#[doc(hidden)]
 pub mod lndrpc;
 pub mod l402;
+#[cfg(feature = "lnd")]
+#[doc(hidden)]
 pub mod lnc;
+#[cfg(feature = "rocket")]
 pub mod lnclient;
+pub mod lsat;
+#[cfg(feature = "lnd")]
 pub mod lnd;
+#[cfg(feature = "rocket")]
 pub mod lnurl;
+#[cfg(feature = "rocket")]
 pub mod nwc;
+#[cfg(feature = "rocket")]
 pub mod cln;
+#[cfg(feature = "rocket")]
 pub mod bolt12;
+#[cfg(feature = "rocket")]
+pub mod catalog;
+#[cfg(feature = "rocket")]
+pub mod config;
+#[cfg(feature = "rocket")]
+pub mod client_gen;
+#[cfg(feature = "rocket")]
 pub mod eclair;
+#[cfg(feature = "rocket")]
+pub mod escalation;
+#[cfg(feature = "rocket")]
+pub mod events;
+#[cfg(feature = "rocket")]
+pub mod fiat;
+#[cfg(feature = "rocket")]
+pub mod forwarded;
+#[cfg(feature = "rocket")]
+pub mod geo;
 pub mod macaroon_util;
+#[cfg(feature = "rocket")]
 pub mod middleware;
+#[doc(hidden)]
 pub mod utils;
+#[cfg(feature = "rocket")]
+pub mod sandbox;
+#[cfg(feature = "rocket")]
+pub mod session;
+#[cfg(feature = "rocket")]
+pub mod shutdown;
+#[cfg(feature = "rocket")]
+pub mod signed_url;
+#[cfg(feature = "rocket")]
+pub mod store;
+#[cfg(any(feature = "db-sqlite", feature = "db-postgres"))]
+pub mod db_store;
+#[cfg(feature = "rocket")]
+pub mod proof;
+#[cfg(feature = "rocket")]
+pub mod routes;
+#[cfg(feature = "rocket")]
+pub mod metrics;
+#[cfg(feature = "rocket")]
+pub mod observer;
+#[cfg(feature = "rocket")]
+pub mod offline;
+#[cfg(feature = "okapi")]
+pub mod openapi;
+#[cfg(feature = "rocket")]
+pub mod path;
+#[cfg(feature = "rocket")]
+pub mod paywall;
+pub mod prelude;
+#[cfg(feature = "rocket")]
+pub mod webhook;
+#[cfg(feature = "rocket")]
+pub mod reconcile;
+#[cfg(feature = "rocket")]
+pub mod doctor;
+#[cfg(feature = "rocket")]
+pub mod alt_scheme;
+#[cfg(feature = "rocket")]
+pub mod analytics;
+#[cfg(feature = "rocket")]
+pub mod anomaly;
+#[cfg(feature = "rocket")]
+pub mod audit;
+#[cfg(feature = "rocket")]
+pub mod provision;
+#[cfg(feature = "rocket")]
+pub mod inspect;
+#[cfg(feature = "rocket")]
+pub mod introspection;
+#[cfg(feature = "rocket")]
+pub mod trace;
+#[cfg(feature = "rocket")]
+pub mod transport;
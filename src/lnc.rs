@@ -1,5 +1,6 @@
+use std::collections::HashSet;
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 use tokio::sync::{Mutex};
 use tokio_tungstenite::{connect_async, tungstenite::{protocol::Message}};
@@ -42,6 +43,9 @@ const SPAKE2_N_HEX: &str = "0254a58cd0f31c008fd0bc9b2dd5ba586144933829f6da33ac41
 /// Noise protocol prologue
 const LIGHTNING_NODE_CONNECT_PROLOGUE: &[u8] = b"lightning-node-connect";
 
+/// Mailbox server used when `LNDOptions::lnc_mailbox_server` isn't set.
+pub const DEFAULT_MAILBOX_SERVER: &str = "ws://127.0.0.1:8085";
+
 /// The aezeed wordlist (BIP39 compatible)
 /// This is the standard English BIP39 wordlist used by lnd/aezeed
 static AEZEED_WORDLIST: &[&str] = &[
@@ -371,8 +375,7 @@ pub fn parse_pairing_phrase(phrase: &str) -> Result<LNCPairingData, Box<dyn Erro
         passphrase_entropy: passphrase_entropy.to_vec(),
         stream_id,
         local_keypair: keypair,
-        mailbox_server: std::env::var("LNC_MAILBOX_SERVER")
-            .unwrap_or_else(|_| "ws://127.0.0.1:8085".to_string()),
+        mailbox_server: DEFAULT_MAILBOX_SERVER.to_string(),
     })
 }
 
@@ -398,11 +401,24 @@ pub fn parse_pairing_phrase_from_entropy(entropy_hex: &str) -> Result<LNCPairing
         passphrase_entropy,
         stream_id,
         local_keypair: keypair,
-        mailbox_server: std::env::var("LNC_MAILBOX_SERVER")
-            .unwrap_or_else(|_| "ws://127.0.0.1:8085".to_string()),
+        mailbox_server: DEFAULT_MAILBOX_SERVER.to_string(),
     })
 }
 
+/// Transport keys and implicit nonces of an established Noise session
+struct SessionCiphers {
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_nonce: u64,
+    recv_nonce: u64,
+}
+
+fn session_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..12].copy_from_slice(&counter.to_le_bytes());
+    nonce_bytes
+}
+
 /// Represents an LNC mailbox connection
 pub struct LNCMailbox {
     passphrase_entropy: Vec<u8>,
@@ -413,21 +429,16 @@ pub struct LNCMailbox {
     shared_secret: Option<[u8; 32]>,
     mailbox_server: String,
     
-    // Separate ciphers for sending and receiving (Noise protocol requirement)
-    send_cipher: Option<ChaCha20Poly1305>,
-    recv_cipher: Option<ChaCha20Poly1305>,
-    
-    // Store keys separately so we can recreate ciphers on clone
-    send_key: Option<[u8; 32]>,
-    recv_key: Option<[u8; 32]>,
+    // Separate send and receive ciphers with their implicit nonces (counters, not sent
+    // on wire). Shared by every clone of the mailbox, e.g. the one the connection holds,
+    // so two handles can never encrypt with the same nonce under the same key.
+    session: Arc<StdMutex<Option<SessionCiphers>>>,
+    // Send keys of every session so far; a session must never start over with one
+    used_send_keys: HashSet<[u8; 32]>,
     
     /// Authentication data received from server in Act 2 (to be sent as gRPC metadata)
     pub auth_data: Option<String>,
     
-    // Implicit nonces (counters), not sent on wire
-    send_nonce: u64,
-    recv_nonce: u64,
-    
     connection: Option<Arc<Mutex<MailboxConnection>>>,
 }
 
@@ -452,12 +463,8 @@ impl LNCMailbox {
             remote_public: None,
             shared_secret: None,
             mailbox_server: server,
-            send_cipher: None,
-            recv_cipher: None,
-            send_key: None,
-            recv_key: None,
-            send_nonce: 0,
-            recv_nonce: 0,
+            session: Arc::new(StdMutex::new(None)),
+            used_send_keys: HashSet::new(),
             auth_data: None,
             connection: None,
         })
@@ -470,7 +477,8 @@ impl LNCMailbox {
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
         eprintln!("🔒 Encrypting {} bytes to send: {:02x?}", plaintext.len(), &plaintext[..plaintext.len().min(50)]);
         
-        let cipher = self.send_cipher.as_ref()
+        let mut session = self.session.lock().map_err(|_| "Noise session poisoned")?;
+        let session = session.as_mut()
             .ok_or("Send cipher not initialized. Complete the Noise handshake before encrypting.")?;
         
         if plaintext.len() > 65535 {
@@ -481,23 +489,21 @@ impl LNCMailbox {
         let length = plaintext.len() as u16;
         let length_bytes = length.to_be_bytes();
         
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.send_nonce.to_le_bytes());
+        let nonce_bytes = session_nonce(session.send_nonce);
         let nonce = Nonce::from_slice(&nonce_bytes);
-        self.send_nonce = self.send_nonce.checked_add(1).ok_or("Send nonce overflow")?;
+        session.send_nonce = session.send_nonce.checked_add(1).ok_or("Send nonce overflow")?;
         
-        let encrypted_header = cipher.encrypt(nonce, &length_bytes[..])
+        let encrypted_header = session.send_cipher.encrypt(nonce, &length_bytes[..])
             .map_err(|e| format!("Failed to encrypt length header: {}", e))?;
         
         eprintln!("   📏 Encrypted length header: {} bytes -> {} bytes", length_bytes.len(), encrypted_header.len());
         
         // Step 2: Encrypt the message body
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.send_nonce.to_le_bytes());
+        let nonce_bytes = session_nonce(session.send_nonce);
         let nonce = Nonce::from_slice(&nonce_bytes);
-        self.send_nonce = self.send_nonce.checked_add(1).ok_or("Send nonce overflow")?;
+        session.send_nonce = session.send_nonce.checked_add(1).ok_or("Send nonce overflow")?;
         
-        let encrypted_body = cipher.encrypt(nonce, plaintext)
+        let encrypted_body = session.send_cipher.encrypt(nonce, plaintext)
             .map_err(|e| format!("Failed to encrypt body: {}", e))?;
         
         eprintln!("   📦 Encrypted body: {} bytes -> {} bytes", plaintext.len(), encrypted_body.len());
@@ -514,9 +520,14 @@ impl LNCMailbox {
     /// Implements the Noise Machine's length-prefixed framing:
     /// 1. Decrypt 18-byte length header -> 2 bytes length
     /// 2. Decrypt (length + 16) bytes body -> length bytes plaintext
+    ///
+    /// Nonces are only consumed by a frame that decrypts, so an incomplete frame can be
+    /// retried once more bytes arrive.
     pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-        let cipher = self.recv_cipher.as_ref()
+        let mut session = self.session.lock().map_err(|_| "Noise session poisoned")?;
+        let session = session.as_mut()
             .ok_or("Recv cipher not initialized")?;
+        let mut recv_nonce = session.recv_nonce;
         
         // Minimum size: 18 bytes (encrypted header with MAC)
         if ciphertext.len() < 18 {
@@ -526,12 +537,11 @@ impl LNCMailbox {
         // Step 1: Decrypt the length header (first 18 bytes: 2 bytes + 16-byte MAC)
         let encrypted_header = &ciphertext[0..18];
         
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.recv_nonce.to_le_bytes());
+        let nonce_bytes = session_nonce(recv_nonce);
         let nonce = Nonce::from_slice(&nonce_bytes);
-        self.recv_nonce = self.recv_nonce.checked_add(1).ok_or("Recv nonce overflow")?;
+        recv_nonce = recv_nonce.checked_add(1).ok_or("Recv nonce overflow")?;
         
-        let length_bytes = cipher.decrypt(nonce, encrypted_header)
+        let length_bytes = session.recv_cipher.decrypt(nonce, encrypted_header)
             .map_err(|e| format!("Failed to decrypt length header: {}", e))?;
         
         if length_bytes.len() != 2 {
@@ -552,12 +562,11 @@ impl LNCMailbox {
         
         let encrypted_body = &ciphertext[18..18 + expected_body_len];
         
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.recv_nonce.to_le_bytes());
+        let nonce_bytes = session_nonce(recv_nonce);
         let nonce = Nonce::from_slice(&nonce_bytes);
-        self.recv_nonce = self.recv_nonce.checked_add(1).ok_or("Recv nonce overflow")?;
+        recv_nonce = recv_nonce.checked_add(1).ok_or("Recv nonce overflow")?;
         
-        let plaintext = cipher.decrypt(nonce, encrypted_body)
+        let plaintext = session.recv_cipher.decrypt(nonce, encrypted_body)
             .map_err(|e| format!("Failed to decrypt body: {}", e))?;
         
         eprintln!("🔓 Decrypted {} bytes from server: {:02x?}", plaintext.len(), &plaintext[..plaintext.len().min(50)]);
@@ -569,9 +578,37 @@ impl LNCMailbox {
             ).into());
         }
         
+        session.recv_nonce = recv_nonce;
         Ok(plaintext)
     }
     
+    /// Starts the Noise transport session with the keys a handshake derived, nonces at
+    /// zero. Clones made before this keep the session they had, so a stale handle can't
+    /// share the new keys' nonces. Keys of an earlier session are refused: restarting
+    /// their nonces would encrypt twice under the same key and nonce.
+    pub fn install_session_keys(&mut self, send_key: [u8; 32], recv_key: [u8; 32]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.used_send_keys.insert(send_key) {
+            return Err("Noise session keys were already used; renegotiate with a new handshake".into());
+        }
+        self.session = Arc::new(StdMutex::new(Some(SessionCiphers {
+            send_cipher: ChaCha20Poly1305::new(&send_key.into()),
+            recv_cipher: ChaCha20Poly1305::new(&recv_key.into()),
+            send_nonce: 0,
+            recv_nonce: 0,
+        })));
+        Ok(())
+    }
+    
+    /// Ends the Noise session and drops the mailbox connection, e.g. after a failed RPC.
+    /// Every handle on the session stops encrypting, and the next `get_connection`
+    /// renegotiates fresh keys instead of resuming the old nonces.
+    pub fn reset_session(&mut self) {
+        if let Ok(mut session) = self.session.lock() {
+            *session = None;
+        }
+        self.connection = None;
+    }
+    
     /// Get the receive SID for client (server-to-client stream)
     /// In LNC client: receiveSID := GetSID(sid, true) which returns sid
     fn get_receive_sid(&self) -> [u8; 64] {
@@ -952,6 +989,7 @@ impl LNCMailbox {
 
                                 // Create connection with initialized cipher
                                 let connection = MailboxConnection {
+                                    writer: gobn.writer(),
                                     gobn: Arc::new(Mutex::new(gobn)),
                                     mailbox: Arc::new(Mutex::new(self.clone())),
                                     read_buffer: Arc::new(Mutex::new(Vec::new())),
@@ -1098,6 +1136,7 @@ impl LNCMailbox {
 
                     // Create connection with initialized cipher
                     let connection = MailboxConnection {
+                        writer: gobn.writer(),
                         gobn: Arc::new(Mutex::new(gobn)),
                         mailbox: Arc::new(Mutex::new(self.clone())),
                         read_buffer: Arc::new(Mutex::new(Vec::new())),
@@ -1168,11 +1207,21 @@ fn create_gbn_ack(seq: u8) -> Vec<u8> {
 }
 
 // Permanent GoBN Connection struct to handle the protocol state
+type WsSink = futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, Message>;
+
+/// Send half of a GoBN connection. The connection shares it with the writer of
+/// `MailboxConnection`, so HTTP/2 frames of concurrent RPCs can go out while
+/// another task is blocked reading, instead of queueing behind the read lock.
+pub struct GoBNWriter {
+    send_write: WsSink,
+    send_sid_base64: String,
+    send_seq: u8,  // Sequence number for GoBN DATA packets
+}
+
 pub struct GoBNConnection {
-    pub send_write: futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, Message>,
+    writer: Arc<Mutex<GoBNWriter>>,
     pub recv_read: futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
     send_sid_base64: String,
-    send_seq: u8,  // Sequence number for GoBN DATA packets
     recv_seq: u8,  // Expected sequence number for received packets
     recv_buffer: Vec<u8>,  // Buffer for reassembling multi-chunk messages
     // Cache the last Act 1 packet so we can resend it if the server restarts the
@@ -1182,53 +1231,7 @@ pub struct GoBNConnection {
     created_at: tokio::time::Instant,
 }
 
-impl GoBNConnection {
-    pub fn new(
-        send_write: futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, Message>,
-        recv_read: futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
-        send_sid_base64: String,
-    ) -> Self {
-        Self {
-            send_write,
-            recv_read,
-            send_sid_base64,
-            send_seq: 0,
-            recv_seq: 0,
-            recv_buffer: Vec::new(),
-            last_act1_msg_json: None,
-            created_at: tokio::time::Instant::now(),
-        }
-    }
-
-    /// Unwrap MsgData format from a byte buffer
-    /// MsgData format: [version (1 byte)] [payload_length (4 bytes BE)] [payload (N bytes)]
-    /// Returns the unwrapped Noise message payload
-    fn unwrap_msgdata(&self, msgdata_bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-        if msgdata_bytes.len() < 5 {
-            return Err(format!("MsgData too short: {} bytes (need at least 5)", msgdata_bytes.len()).into());
-        }
-        
-        let _version = msgdata_bytes[0];  // Should be 0
-        let payload_len = u32::from_be_bytes([
-            msgdata_bytes[1],
-            msgdata_bytes[2],
-            msgdata_bytes[3],
-            msgdata_bytes[4],
-        ]) as usize;
-        
-        if msgdata_bytes.len() < 5 + payload_len {
-            return Err(format!("Incomplete MsgData: have {} bytes, need {} bytes", 
-                msgdata_bytes.len(), 5 + payload_len).into());
-        }
-        
-        // Extract the actual Noise message payload (skip MsgData header)
-        let noise_payload = msgdata_bytes[5..5 + payload_len].to_vec();
-        eprintln!("📦 Unwrapped MsgData: version={}, payload_len={}, Noise message len={}", 
-            _version, payload_len, noise_payload.len());
-        
-        Ok(noise_payload)
-    }
-    
+impl GoBNWriter {
     pub async fn write_msg(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
         // CRITICAL: ALL messages sent through GoBN (including handshake messages) must be wrapped in MsgData format!
         // MsgData format: [version (1 byte)] [payload_length (4 bytes BE)] [payload (N bytes)]
@@ -1294,6 +1297,70 @@ impl GoBNConnection {
         Ok(())
     }
     
+}
+
+impl GoBNConnection {
+    pub fn new(
+        send_write: WsSink,
+        recv_read: futures_util::stream::SplitStream<tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
+        send_sid_base64: String,
+    ) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(GoBNWriter {
+                send_write,
+                send_sid_base64: send_sid_base64.clone(),
+                send_seq: 0,
+            })),
+            recv_read,
+            send_sid_base64,
+            recv_seq: 0,
+            recv_buffer: Vec::new(),
+            last_act1_msg_json: None,
+            created_at: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Send half, for writing without holding the connection's read lock
+    pub fn writer(&self) -> Arc<Mutex<GoBNWriter>> {
+        Arc::clone(&self.writer)
+    }
+    /// Unwrap MsgData format from a byte buffer
+    /// MsgData format: [version (1 byte)] [payload_length (4 bytes BE)] [payload (N bytes)]
+    /// Returns the unwrapped Noise message payload
+    fn unwrap_msgdata(&self, msgdata_bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        if msgdata_bytes.len() < 5 {
+            return Err(format!("MsgData too short: {} bytes (need at least 5)", msgdata_bytes.len()).into());
+        }
+        
+        let _version = msgdata_bytes[0];  // Should be 0
+        let payload_len = u32::from_be_bytes([
+            msgdata_bytes[1],
+            msgdata_bytes[2],
+            msgdata_bytes[3],
+            msgdata_bytes[4],
+        ]) as usize;
+        
+        if msgdata_bytes.len() < 5 + payload_len {
+            return Err(format!("Incomplete MsgData: have {} bytes, need {} bytes", 
+                msgdata_bytes.len(), 5 + payload_len).into());
+        }
+        
+        // Extract the actual Noise message payload (skip MsgData header)
+        let noise_payload = msgdata_bytes[5..5 + payload_len].to_vec();
+        eprintln!("📦 Unwrapped MsgData: version={}, payload_len={}, Noise message len={}", 
+            _version, payload_len, noise_payload.len());
+        
+        Ok(noise_payload)
+    }
+    
+    pub async fn write_msg(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.lock().await.write_msg(data).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.lock().await.flush().await
+    }
+    
     pub async fn read_msg(&mut self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
         use futures_util::StreamExt;
         
@@ -1335,10 +1402,11 @@ impl GoBNConnection {
                     // Timeout occurred - proactively resend Act 1 IF we are in handshake (last_act1_msg_json is set)
                     if let Some(act1_json) = &self.last_act1_msg_json {
                         eprintln!("⏳ Read timeout waiting for Act 2; proactively resending Act 1...");
-                        if let Err(e) = self.send_write.send(Message::Text(act1_json.clone())).await {
+                        let sent = self.writer.lock().await.send_write.send(Message::Text(act1_json.clone())).await;
+                        if let Err(e) = sent {
                             eprintln!("⚠️  Failed to resend Act 1 on timeout: {}", e);
                         } else {
-                            let _ = self.send_write.flush().await;
+                            let _ = self.writer.lock().await.send_write.flush().await;
                             eprintln!("✅ Act 1 resent on timeout");
                         }
                     }
@@ -1391,10 +1459,11 @@ impl GoBNConnection {
                                                 r#"{{"desc":{{"stream_id":"{}"}},"msg":"{}"}}"#,
                                                 self.send_sid_base64, ack_base64
                                             );
-                                            if let Err(e) = self.send_write.send(Message::Text(ack_msg)).await {
+                                            let sent = self.writer.lock().await.send_write.send(Message::Text(ack_msg)).await;
+                                            if let Err(e) = sent {
                                                 eprintln!("⚠️  Failed to send ping ACK: {}", e);
                                             }
-                                            let _ = self.send_write.flush().await;
+                                            let _ = self.writer.lock().await.send_write.flush().await;
                                             
                                             if seq == self.recv_seq {
                                                 self.recv_seq = (self.recv_seq + 1) % 21; // s = n + 1 (n=20, s=21)
@@ -1405,10 +1474,11 @@ impl GoBNConnection {
                                             // Resend Act 1 here to ensure it gets through if we are in handshake
                                             if let Some(act1_json) = &self.last_act1_msg_json {
                                                 eprintln!("📤 Received PING waiting for Act 2; proactively resending Act 1...");
-                                                if let Err(e) = self.send_write.send(Message::Text(act1_json.clone())).await {
+                                                let sent = self.writer.lock().await.send_write.send(Message::Text(act1_json.clone())).await;
+                                                if let Err(e) = sent {
                                                     eprintln!("⚠️  Failed to resend Act 1 on PING: {}", e);
                                                 } else {
-                                                    let _ = self.send_write.flush().await;
+                                                    let _ = self.writer.lock().await.send_write.flush().await;
                                                 }
                                             }
 
@@ -1432,8 +1502,8 @@ impl GoBNConnection {
                                                 r#"{{"desc":{{"stream_id":"{}"}},"msg":"{}"}}"#,
                                                 self.send_sid_base64, nack_base64
                                             );
-                                            let _ = self.send_write.send(Message::Text(nack_msg)).await;
-                                            let _ = self.send_write.flush().await;
+                                            let _ = self.writer.lock().await.send_write.send(Message::Text(nack_msg)).await;
+                                            let _ = self.writer.lock().await.send_write.flush().await;
                                             continue;
                                         }
                                         
@@ -1450,9 +1520,9 @@ impl GoBNConnection {
                                             self.send_sid_base64, ack_base64
                                         );
                                         // CRITICAL: ACK must be sent and flushed immediately
-                                        self.send_write.send(Message::Text(ack_msg)).await
+                                        self.writer.lock().await.send_write.send(Message::Text(ack_msg)).await
                                             .map_err(|e| format!("Failed to send ACK: {}", e))?;
-                                        self.send_write.flush().await
+                                        self.writer.lock().await.send_write.flush().await
                                             .map_err(|e| format!("Failed to flush ACK: {}", e))?;
                                         eprintln!("✅ ACK sent and flushed for seq {}", seq);
                                         
@@ -1492,8 +1562,8 @@ impl GoBNConnection {
                                         eprintln!("📥 Received NACK packet (expected seq {}), resending last message...", seq);
                                         // This is mostly useful during handshake if we cached the Act 1 message
                                         if let Some(act1_json) = &self.last_act1_msg_json {
-                                             let _ = self.send_write.send(Message::Text(act1_json.clone())).await;
-                                             let _ = self.send_write.flush().await;
+                                             let _ = self.writer.lock().await.send_write.send(Message::Text(act1_json.clone())).await;
+                                             let _ = self.writer.lock().await.send_write.flush().await;
                                              eprintln!("✅ Last message (Act 1) resent due to NACK");
                                         }
                                         continue;
@@ -1542,16 +1612,16 @@ impl GoBNConnection {
                                 let ack_packet = create_gbn_ack(seq);
                                 let ack_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &ack_packet);
                                 let ack_msg = format!(r#"{{"desc":{{"stream_id":"{}"}},"msg":"{}"}}"#, self.send_sid_base64, ack_base64);
-                                let _ = self.send_write.send(Message::Text(ack_msg)).await;
-                                let _ = self.send_write.flush().await;
+                                let _ = self.writer.lock().await.send_write.send(Message::Text(ack_msg)).await;
+                                let _ = self.writer.lock().await.send_write.flush().await;
                                 
                                 if seq == self.recv_seq {
                                     self.recv_seq = (self.recv_seq + 1) % 21; // s = n + 1 (n=20, s=21)
                                 }
                                 
                                 if let Some(act1_json) = &self.last_act1_msg_json {
-                                    let _ = self.send_write.send(Message::Text(act1_json.clone())).await;
-                                    let _ = self.send_write.flush().await;
+                                    let _ = self.writer.lock().await.send_write.send(Message::Text(act1_json.clone())).await;
+                                    let _ = self.writer.lock().await.send_write.flush().await;
                                 }
                                 continue;
                             }
@@ -1565,8 +1635,8 @@ impl GoBNConnection {
                                 let nack_packet = vec![GBN_MSG_NACK, self.recv_seq];
                                 let nack_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &nack_packet);
                                 let nack_msg = format!(r#"{{"desc":{{"stream_id":"{}"}},"msg":"{}"}}"#, self.send_sid_base64, nack_base64);
-                                let _ = self.send_write.send(Message::Text(nack_msg)).await;
-                                let _ = self.send_write.flush().await;
+                                let _ = self.writer.lock().await.send_write.send(Message::Text(nack_msg)).await;
+                                let _ = self.writer.lock().await.send_write.flush().await;
                                 continue;
                             }
                             
@@ -1576,10 +1646,12 @@ impl GoBNConnection {
                             let ack_packet = create_gbn_ack(seq);
                             let ack_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &ack_packet);
                             let ack_msg = format!(r#"{{"desc":{{"stream_id":"{}"}},"msg":"{}"}}"#, self.send_sid_base64, ack_base64);
-                            if let Err(e) = self.send_write.send(Message::Text(ack_msg)).await {
+                            let sent = self.writer.lock().await.send_write.send(Message::Text(ack_msg)).await;
+                            if let Err(e) = sent {
                                 return Err(format!("Failed to send ACK: {}", e).into());
                             }
-                            if let Err(e) = self.send_write.flush().await {
+                            let sent = self.writer.lock().await.send_write.flush().await;
+                            if let Err(e) = sent {
                                 return Err(format!("Failed to flush ACK: {}", e).into());
                             }
                             
@@ -1607,8 +1679,8 @@ impl GoBNConnection {
                         }
                         GBN_MSG_NACK => {
                              if let Some(act1_json) = &self.last_act1_msg_json {
-                                  let _ = self.send_write.send(Message::Text(act1_json.clone())).await;
-                                  let _ = self.send_write.flush().await;
+                                  let _ = self.writer.lock().await.send_write.send(Message::Text(act1_json.clone())).await;
+                                  let _ = self.writer.lock().await.send_write.flush().await;
                              }
                              continue;
                         }
@@ -2108,22 +2180,10 @@ impl LNCMailbox {
         // Split handshake and initialize cipher
         let (send_key, recv_key) = state.split()?;
         
-        // Initialize the ciphers with the respective keys
-        let send_cipher = ChaCha20Poly1305::new(&send_key.into());
-        let recv_cipher = ChaCha20Poly1305::new(&recv_key.into());
-        
-        self.send_cipher = Some(send_cipher);
-        self.recv_cipher = Some(recv_cipher);
-        
-        // Store keys so we can recreate ciphers on clone
-        self.send_key = Some(send_key);
-        self.recv_key = Some(recv_key);
+        // Start the transport session with fresh nonces
+        self.install_session_keys(send_key, recv_key)?;
         self.shared_secret = Some(send_key); // Keep for compatibility
         
-        // Reset nonces
-        self.send_nonce = 0;
-        self.recv_nonce = 0;
-        
         // Store remote public key
         if let Some(remote_pub) = remote_pub {
             self.remote_public = Some(remote_pub);
@@ -2182,13 +2242,9 @@ impl Clone for LNCMailbox {
             remote_public: self.remote_public,
             shared_secret: self.shared_secret,
             mailbox_server: self.mailbox_server.clone(),
-            // Recreate ciphers from stored keys
-            send_cipher: self.send_key.map(|key| ChaCha20Poly1305::new(&key.into())),
-            recv_cipher: self.recv_key.map(|key| ChaCha20Poly1305::new(&key.into())),
-            send_key: self.send_key,
-            recv_key: self.recv_key,
-            send_nonce: self.send_nonce,
-            recv_nonce: self.recv_nonce,
+            // Same session, so nonces keep counting across clones
+            session: Arc::clone(&self.session),
+            used_send_keys: self.used_send_keys.clone(),
             auth_data: self.auth_data.clone(),
             connection: None,
         }
@@ -2199,6 +2255,8 @@ impl Clone for LNCMailbox {
 pub struct MailboxConnection {
     // GoBN connection handles all the transport logic (ACKs, PINGs, MsgData wrapping)
     gobn: Arc<Mutex<GoBNConnection>>,
+    // Its send half; writes lock only this, so they don't wait for a pending read
+    writer: Arc<Mutex<GoBNWriter>>,
     mailbox: Arc<Mutex<LNCMailbox>>,
     
     // Buffering for AsyncRead/AsyncWrite implementation
@@ -2273,9 +2331,9 @@ impl MailboxConnection {
         drop(mailbox);
         
         // Send via GoBN (wraps in MsgData, handles ACKs internally)
-        let mut gobn = self.gobn.lock().await;
-        gobn.write_msg(&encrypted).await?;
-        gobn.flush().await?;
+        let mut writer = self.writer.lock().await;
+        writer.write_msg(&encrypted).await?;
+        writer.flush().await?;
         
         Ok(())
     }
@@ -2423,9 +2481,8 @@ impl tokio::io::AsyncRead for MailboxConnection {
                     let encrypted_data = enc_buf.clone();
                     let enc_buf_len_before = enc_buf.len();
                     
-                    // Save the current nonces before attempting decryption
+                    // A frame that doesn't decrypt leaves the nonces as they were
                     let mut mailbox_guard = mailbox.lock().await;
-                    let recv_nonce_before = mailbox_guard.recv_nonce;
                     
                     match mailbox_guard.decrypt(&encrypted_data) {
                         Ok(decrypted) => {
@@ -2459,17 +2516,12 @@ impl tokio::io::AsyncRead for MailboxConnection {
                             // Incomplete frame - need more data
                             if e.to_string().contains("Incomplete message") {
                                 eprintln!("   ⏳ Incomplete frame, waiting for more data");
-                                
-                                // CRITICAL: Restore the nonce since we didn't successfully complete the operation
-                                mailbox_guard.recv_nonce = recv_nonce_before;
-                                eprintln!("   🔄 Restored recv_nonce to {} (was incremented during failed attempt)", recv_nonce_before);
-                                
                                 break;
                             } else {
                                 // Real decryption error - could be connection closing or corrupted data
                                 eprintln!("   ❌ Decryption error: {}", e);
                                 eprintln!("   📊 Encrypted buffer contents ({} bytes): {:02x?}", enc_buf.len(), &enc_buf[..enc_buf.len().min(50)]);
-                                eprintln!("   🔢 Buffer length: {}, Nonce before: {}, Nonce after: {}", enc_buf_len_before, recv_nonce_before, mailbox_guard.recv_nonce);
+                                eprintln!("   🔢 Buffer length: {}", enc_buf_len_before);
                                 
                                 // Check if this might be a connection close or error message
                                 // If buffer is very small (< 18 bytes), it's not a valid Noise frame
@@ -2701,7 +2753,7 @@ impl tokio::io::AsyncWrite for MailboxConnection {
         let is_settings_ack = data.len() >= 9 && 
                               data[data.len()-9..].starts_with(&[0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00]);
         
-        let gobn = Arc::clone(&this.gobn);
+        let writer = Arc::clone(&this.writer);
         let mailbox = Arc::clone(&this.mailbox);
         let writing = Arc::clone(&this.writing);
         let http2_ready_arc = Arc::clone(&this.http2_ready);
@@ -2715,13 +2767,13 @@ impl tokio::io::AsyncWrite for MailboxConnection {
                 eprintln!("✅ Mailbox lock acquired, encrypting...");
                 let encrypted = mailbox_guard.encrypt(&data)?;
                 drop(mailbox_guard);
-                eprintln!("✅ Encryption complete, acquiring GoBN lock...");
+                eprintln!("✅ Encryption complete, acquiring GoBN writer lock...");
                 
-                let mut gobn_guard = gobn.lock().await;
-                eprintln!("✅ GoBN lock acquired, writing message...");
-                gobn_guard.write_msg(&encrypted).await?;
+                let mut writer_guard = writer.lock().await;
+                eprintln!("✅ GoBN writer lock acquired, writing message...");
+                writer_guard.write_msg(&encrypted).await?;
                 eprintln!("✅ Message written, flushing...");
-                gobn_guard.flush().await?;
+                writer_guard.flush().await?;
                 eprintln!("✅ Flush complete!");
                 
                 if is_settings_ack {
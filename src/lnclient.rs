@@ -1,17 +1,19 @@
-use crate::lndrpc::lnrpc;
 use lightning::types::payment::{PaymentHash};
+use lightning_invoice::Bolt11Invoice;
+use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use std::future::Future;
 use std::pin::Pin;
 
 use crate::lnurl;
+#[cfg(feature = "lnd")]
 use crate::lnd;
 use crate::nwc;
 use crate::cln;
 use crate::bolt12;
 use crate::eclair;
+use crate::transport;
 
 const LND_CLIENT_TYPE: &str = "LND";
 const LNURL_CLIENT_TYPE: &str = "LNURL";
@@ -20,33 +22,281 @@ const CLN_CLIENT_TYPE: &str = "CLN";
 const BOLT12_CLIENT_TYPE: &str = "BOLT12";
 const ECLAIR_CLIENT_TYPE: &str = "ECLAIR";
 
-#[derive(Debug, Clone)]
+/// Everything needed to connect a backend. Can be built entirely in code, e.g.
+/// `LNClientConfig { ln_client_type: "CLN".to_string(), cln_config: Some(..), root_key, ..Default::default() }`.
+#[derive(Debug, Clone, Default)]
 pub struct LNClientConfig {
     pub ln_client_type: String,
+    #[cfg(feature = "lnd")]
     pub lnd_config: Option<lnd::LNDOptions>,
     pub lnurl_config: Option<lnurl::LNURLOptions>,
     pub nwc_config: Option<nwc::NWCOptions>,
     pub cln_config: Option<cln::CLNOptions>,
     pub bolt12_config: Option<bolt12::Bolt12Options>,
     pub eclair_config: Option<eclair::EclairOptions>,
+    /// Sends the LNURL and Eclair backends' HTTP calls; a plain reqwest client when None
+    pub http_transport: Option<Arc<dyn transport::HttpTransport>>,
+    /// What challenge invoices minted through this backend say about themselves
+    pub memo: MemoConfig,
     pub root_key: Vec<u8>,
 }
 
+impl LNClientConfig {
+    /// Whether the backend can look invoices up, for reconciliation, `inspect` and
+    /// `await`. The others leave `LNClient::lookup_invoice` unsupported.
+    pub fn looks_up_invoices(&self) -> bool {
+        matches!(
+            self.ln_client_type.as_str(),
+            LND_CLIENT_TYPE | NWC_CLIENT_TYPE | CLN_CLIENT_TYPE | BOLT12_CLIENT_TYPE | ECLAIR_CLIENT_TYPE
+        )
+    }
+}
+
+/// Description the middleware gives challenge invoices when no `memo_func` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MemoPolicy {
+    /// `L402`: easy to recognise in wallet history, and for custodians to flag
+    #[default]
+    Scheme,
+    Blank,
+    Static(String),
+    /// Rendered per challenge, substituting `{path}`, `{amount_sat}` and `{backend}`
+    Template(String),
+    /// A fresh random identifier per invoice, so invoices can't be grouped by memo
+    Random,
+}
+
+impl MemoPolicy {
+    pub fn render(&self, path: &str, value_msat: i64, backend: &str) -> String {
+        match self {
+            MemoPolicy::Scheme => crate::l402::L402_HEADER.to_string(),
+            MemoPolicy::Blank => String::new(),
+            MemoPolicy::Static(memo) => memo.clone(),
+            MemoPolicy::Template(template) => template
+                .replace("{path}", path)
+                .replace("{amount_sat}", &(value_msat / 1000).to_string())
+                .replace("{backend}", backend),
+            MemoPolicy::Random => uuid::Uuid::new_v4().simple().to_string(),
+        }
+    }
+}
+
+/// Memo settings for a backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoConfig {
+    pub policy: MemoPolicy,
+    /// Commits to the memo's SHA256 in the invoice instead of carrying it in
+    /// plaintext, so only the payment proof reveals it
+    pub description_hash: bool,
+}
+
+/// Invoice the middleware asks a backend to create.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvoiceRequest {
+    pub value_msat: i64,
+    pub memo: String,
+    /// Seconds until the invoice expires; None leaves it to the backend
+    pub expiry: Option<u64>,
+    /// Commit to SHA256(memo) instead of the memo. Backends that can't set a
+    /// description hash leave the memo out altogether.
+    pub description_hash: bool,
+}
+
+impl InvoiceRequest {
+    /// Memo to carry in plaintext, if any.
+    pub fn description(&self) -> Option<String> {
+        Some(self.memo.clone()).filter(|memo| !memo.is_empty() && !self.description_hash)
+    }
+
+    /// Hash the invoice should commit to instead of a plaintext memo.
+    pub fn memo_hash(&self) -> Option<[u8; 32]> {
+        self.description_hash.then(|| Sha256::digest(self.memo.as_bytes()).into())
+    }
+}
+
+/// Invoice created by a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceResponse {
+    pub payment_request: String,
+    pub payment_hash: [u8; 32],
+}
+
+/// Why an invoice returned by a backend can't be used for a challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceValidationError {
+    EmptyPaymentRequest,
+    Undecodable(String),
+    /// The invoice pays a different hash than the backend reported
+    PaymentHashMismatch,
+    /// The invoice is for another amount than requested; None for zero-amount invoices
+    AmountMismatch { requested_msat: i64, invoice_msat: Option<u64> },
+}
+
+impl std::fmt::Display for InvoiceValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceValidationError::EmptyPaymentRequest => write!(f, "Backend returned an empty payment request"),
+            InvoiceValidationError::Undecodable(error) => write!(f, "Backend returned an undecodable invoice: {}", error),
+            InvoiceValidationError::PaymentHashMismatch => write!(f, "Backend invoice doesn't pay the payment hash it reported"),
+            InvoiceValidationError::AmountMismatch { requested_msat, invoice_msat: Some(invoice_msat) } => {
+                write!(f, "Backend invoice is for {} msat instead of {} msat", invoice_msat, requested_msat)
+            }
+            InvoiceValidationError::AmountMismatch { requested_msat, invoice_msat: None } => {
+                write!(f, "Backend invoice has no amount instead of {} msat", requested_msat)
+            }
+        }
+    }
+}
+
+impl Error for InvoiceValidationError {}
+
+/// Why a backend call failed. Backends return it boxed in their `LNClientFuture`s,
+/// so callers can `downcast_ref` it to tell an unreachable node from a bad reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendError {
+    /// The node or wallet service couldn't be reached
+    Unreachable(String),
+    /// The service answered with a non-success HTTP status
+    Status { status: u16, body: String },
+    /// The service refused the request, e.g. a CLN RPC error or an LNURL `ERROR` reply
+    Rejected(String),
+    /// The response couldn't be parsed, or lacks what the call needs
+    MalformedResponse(String),
+}
+
+impl BackendError {
+    pub(crate) fn malformed(error: impl std::fmt::Display) -> Self {
+        BackendError::MalformedResponse(error.to_string())
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Unreachable(error) => write!(f, "Backend unreachable: {}", error),
+            BackendError::Status { status, body } => write!(f, "Backend returned HTTP status {}: {}", status, body),
+            BackendError::Rejected(reason) => write!(f, "Backend rejected the request: {}", reason),
+            BackendError::MalformedResponse(error) => write!(f, "Backend returned a malformed response: {}", error),
+        }
+    }
+}
+
+impl Error for BackendError {}
+
+impl InvoiceResponse {
+    /// Checks the invoice is one a challenge can carry: a decodable BOLT11 invoice
+    /// for exactly the requested amount, paying the reported hash. BOLT12 invoices
+    /// can't be decoded here, so only their presence is checked.
+    pub fn validate(&self, request: &InvoiceRequest) -> Result<(), InvoiceValidationError> {
+        let payment_request = self.payment_request.trim();
+        if payment_request.is_empty() {
+            return Err(InvoiceValidationError::EmptyPaymentRequest);
+        }
+        if payment_request.starts_with("lni") {
+            return Ok(());
+        }
+        let decoded = payment_request.parse::<Bolt11Invoice>()
+            .map_err(|error| InvoiceValidationError::Undecodable(error.to_string()))?;
+        if decoded.payment_hash().as_ref() as &[u8] != self.payment_hash.as_slice() {
+            return Err(InvoiceValidationError::PaymentHashMismatch);
+        }
+        let invoice_msat = decoded.amount_milli_satoshis();
+        if invoice_msat.is_none_or(|msat| i64::try_from(msat) != Ok(request.value_msat)) {
+            return Err(InvoiceValidationError::AmountMismatch { requested_msat: request.value_msat, invoice_msat });
+        }
+        Ok(())
+    }
+}
+
+/// Generates a conversion between the native invoice types and a backend's own.
+/// Each field is computed from the source value bound to `$v`; `TryFrom` fields may use `?`.
+macro_rules! invoice_conversion {
+    (From<$from:ty> for $to:ty, |$v:ident| { $($field:ident: $value:expr),* $(,)? } $(..$rest:expr)?) => {
+        impl From<$from> for $to {
+            fn from($v: $from) -> Self {
+                Self { $($field: $value,)* $(..$rest)? }
+            }
+        }
+    };
+    (TryFrom<$from:ty> for $to:ty, |$v:ident| { $($field:ident: $value:expr),* $(,)? } $(..$rest:expr)?) => {
+        impl TryFrom<$from> for $to {
+            type Error = Box<dyn std::error::Error + Send + Sync>;
+
+            fn try_from($v: $from) -> Result<Self, Self::Error> {
+                Ok(Self { $($field: $value,)* $(..$rest)? })
+            }
+        }
+    };
+}
+pub(crate) use invoice_conversion;
+
+pub type LNClientFuture<T> = Pin<Box<dyn Future<Output = Result<T, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Settlement state of an invoice as reported by the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum InvoiceState {
+    Open,
+    Settled,
+    Canceled,
+}
+
+/// Backends are shared as `Arc<dyn LNClient>` and called concurrently; any that
+/// need exclusive access to a connection synchronize internally.
 pub trait LNClient: Send + Sync + 'static {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>>> + Send>>;
+    fn add_invoice(&self, invoice: InvoiceRequest) -> LNClientFuture<InvoiceResponse>;
+
+    /// Identifies the node or provider behind this client, e.g. `lnd:127.0.0.1:10009`.
+    /// Recorded with every challenge so revenue and failures can be attributed to it.
+    fn backend_id(&self) -> String {
+        "custom".to_string()
+    }
+
+    /// Looks up the settlement state of an invoice by payment hash.
+    /// Backends that can't look invoices up keep this default.
+    fn lookup_invoice(&self, _payment_hash: [u8; 32]) -> LNClientFuture<InvoiceState> {
+        Box::pin(async { Err("Invoice lookup is not supported by this backend".into()) })
+    }
+
+    /// Cancels an unpaid invoice so it can no longer be paid.
+    fn cancel_invoice(&self, _payment_hash: [u8; 32]) -> LNClientFuture<()> {
+        Box::pin(async { Err("Invoice cancellation is not supported by this backend".into()) })
+    }
+
+    /// Reusable BOLT12 offer that capable wallets can pay directly instead of the
+    /// challenge invoice. None for backends without one.
+    fn static_offer(&self) -> Option<String> {
+        None
+    }
+
+    /// Amount received by a settled payment of `static_offer`, identified by the
+    /// payment hash of the invoice the payer fetched from it. None if there is none.
+    fn lookup_offer_payment(&self, _payment_hash: [u8; 32]) -> LNClientFuture<Option<i64>> {
+        Box::pin(async { Err("Offer payments are not supported by this backend".into()) })
+    }
+
+    /// Signs `message` with the node key. The signature is zbase32 encoded and pubkey
+    /// recoverable, like LND's `signmessage`, so anyone can check which node signed it.
+    fn sign_message(&self, _message: &[u8]) -> LNClientFuture<String> {
+        Box::pin(async { Err("Message signing is not supported by this backend".into()) })
+    }
+
+    /// Closes the backend connection on shutdown. Later calls may reconnect.
+    fn close(&self) -> LNClientFuture<()> {
+        Box::pin(async { Ok(()) })
+    }
 }
 
 pub struct LNClientConn {
-    pub ln_client: Arc<Mutex<dyn LNClient>>,
+    pub ln_client: Arc<dyn LNClient>,
 }
 
 impl LNClientConn {
-    pub async fn init(ln_client_config: &LNClientConfig) -> Result<Arc<Mutex<dyn LNClient>>, Box<dyn Error + Send + Sync>> {
-        let ln_client: Arc<Mutex<dyn LNClient>> = match ln_client_config.ln_client_type.as_str() {
+    pub async fn init(ln_client_config: &LNClientConfig) -> Result<Arc<dyn LNClient>, Box<dyn Error + Send + Sync>> {
+        let ln_client: Arc<dyn LNClient> = match ln_client_config.ln_client_type.as_str() {
+            #[cfg(feature = "lnd")]
             LND_CLIENT_TYPE => lnd::LNDWrapper::new_client(ln_client_config).await?,
+            #[cfg(not(feature = "lnd"))]
+            LND_CLIENT_TYPE => return Err("LND support requires the `lnd` feature".into()),
             LNURL_CLIENT_TYPE => lnurl::LnAddressUrlResJson::new_client(ln_client_config).await?,
             NWC_CLIENT_TYPE => nwc::NWCWrapper::new_client(ln_client_config).await?,
             CLN_CLIENT_TYPE => cln::CLNWrapper::new_client(ln_client_config).await?,
@@ -64,17 +314,15 @@ impl LNClientConn {
         Ok(ln_client)
     }
 
+    /// Creates an invoice at the backend. Malformed responses fail with an
+    /// `InvoiceValidationError` rather than reaching a challenge.
     pub async fn generate_invoice(
         &self,
-        ln_invoice: lnrpc::Invoice,
+        ln_invoice: InvoiceRequest,
     ) -> Result<(String, PaymentHash), Box<dyn Error + Send + Sync>> {
-        let client = &mut self.ln_client.lock().await;
-        let ln_client_invoice = &mut client.add_invoice(ln_invoice).await?;
-
-        let invoice = &ln_client_invoice.payment_request;
-        let hash: [u8; 32] = ln_client_invoice.r_hash.clone().try_into().map_err(|_| "Invalid length for r_hash, must be 32 bytes")?;
-        let payment_hash = PaymentHash(hash);
+        let ln_client_invoice = self.ln_client.add_invoice(ln_invoice.clone()).await?;
+        ln_client_invoice.validate(&ln_invoice)?;
 
-        Ok((invoice.to_string(), payment_hash))
+        Ok((ln_client_invoice.payment_request, PaymentHash(ln_client_invoice.payment_hash)))
     }
 }
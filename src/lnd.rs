@@ -1,14 +1,19 @@
-use std::{error::Error, sync::Arc, future::Future, pin::Pin, str::FromStr};
+use std::{error::Error, sync::Arc, pin::Pin, str::FromStr};
 use tokio::sync::Mutex;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::time::{timeout, Duration};
 use tokio_socks::tcp::Socks5Stream;
+#[cfg(feature = "lnd-openssl")]
 use tokio_openssl::SslStream;
+#[cfg(not(feature = "lnd-openssl"))]
+use tokio_rustls::rustls::{self, pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime}};
 use tonic::transport::{Endpoint, Channel};
 use tonic::metadata::MetadataValue;
 use tonic::{Request, service::interceptor::InterceptedService};
 use hyper_util::rt::TokioIo;
+#[cfg(feature = "lnd-openssl")]
 use openssl::ssl::{Ssl, SslContext, SslMethod, SslVerifyMode};
+#[cfg(feature = "lnd-openssl")]
 use openssl::x509::X509;
 use hex;
 
@@ -16,14 +21,19 @@ use hex;
 use crate::lndrpc::lnrpc;
 use crate::lnclient;
 use crate::lnc;
+use crate::trace;
+
+pub use crate::lnc::DEFAULT_MAILBOX_SERVER;
 
 // ---- TLS stream wrappers for custom connectors -----------------------------------------
 
 trait AsyncReadWrite: AsyncRead + AsyncWrite {}
 impl<T: AsyncRead + AsyncWrite> AsyncReadWrite for T {}
 
+#[cfg(feature = "lnd-openssl")]
 struct TlsStreamWrapper(SslStream<tokio::net::TcpStream>);
 
+#[cfg(feature = "lnd-openssl")]
 impl AsyncRead for TlsStreamWrapper {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -34,6 +44,7 @@ impl AsyncRead for TlsStreamWrapper {
     }
 }
 
+#[cfg(feature = "lnd-openssl")]
 impl AsyncWrite for TlsStreamWrapper {
     fn poll_write(
         self: Pin<&mut Self>,
@@ -76,14 +87,19 @@ type LndLightningClient =
 
 // ---- LND connection types --------------------------------------------------------------
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct LNDOptions {
     /// LND address (required for traditional, not used for LNC)
     pub address: Option<String>,
-    /// Macaroon file path (required for traditional, not needed for LNC)
+    /// Macaroon file path (traditional only; `macaroon_hex` can be given instead)
     pub macaroon_file: Option<String>,
-    /// Cert file path (required for traditional, not needed for LNC)
+    /// Cert file path (traditional only; `cert_pem` can be given instead)
     pub cert_file: Option<String>,
+    /// Hex-encoded macaroon, taking precedence over `macaroon_file` so credentials
+    /// can be handed over without touching the filesystem
+    pub macaroon_hex: Option<String>,
+    /// PEM-encoded TLS cert, taking precedence over `cert_file`
+    pub cert_pem: Option<Vec<u8>>,
     /// SOCKS5 proxy (optional, for traditional connection only)
     /// Format: "host:port" (e.g., "127.0.0.1:9050" for Tor)
     /// REQUIRED for Tor .onion addresses (DNS resolution needs Tor)
@@ -109,6 +125,7 @@ enum LNDConnectionType {
 
 pub struct LNDWrapper {
     connection: LNDConnectionType,
+    backend_id: String,
 }
 
 // ---- Clone for LNDConnectionType -------------------------------------------------------
@@ -130,12 +147,96 @@ impl Clone for LNDConnectionType {
     }
 }
 
-// ---- Helper: build an OpenSSL TLS context from a PEM cert file -------------------------
+// ---- Helper: TLS to the node, pinned to its PEM cert -----------------------------------
+
+#[cfg(not(feature = "lnd-openssl"))]
+type TlsConfig = Arc<rustls::ClientConfig>;
+
+// LND's self-signed cert is a CA, which webpki refuses as a server cert, so the
+// node is trusted by comparing its cert with the configured one instead.
+#[cfg(not(feature = "lnd-openssl"))]
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+#[cfg(not(feature = "lnd-openssl"))]
+impl rustls::client::danger::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.cert.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer));
+        }
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(not(feature = "lnd-openssl"))]
+fn build_tls_config(cert_data: &[u8]) -> Result<TlsConfig, Box<dyn Error + Send + Sync>> {
+    let cert = CertificateDer::from_pem_slice(cert_data)
+        .map_err(|e| format!("Failed to parse cert: {}", e))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedCertVerifier { cert, provider: Arc::clone(&provider) };
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to create TLS config: {}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    // gRPC runs over HTTP/2, which LND expects negotiated via ALPN
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Arc::new(config))
+}
 
-fn build_ssl_context(cert_file: &str) -> Result<SslContext, Box<dyn Error + Send + Sync>> {
-    let cert_data = std::fs::read(cert_file)
-        .map_err(|e| format!("Failed to read cert file: {}", e))?;
-    let cert = X509::from_pem(&cert_data)
+#[cfg(not(feature = "lnd-openssl"))]
+async fn tls_handshake(
+    config: &TlsConfig,
+    host: &str,
+    tcp: tokio::net::TcpStream,
+) -> std::io::Result<Pin<Box<dyn AsyncReadWrite + Send>>> {
+    let server_name = ServerName::try_from(host.to_string())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let tls = tokio_rustls::TlsConnector::from(Arc::clone(config))
+        .connect(server_name, tcp)
+        .await?;
+    Ok(Box::pin(tls))
+}
+
+#[cfg(feature = "lnd-openssl")]
+type TlsConfig = Arc<SslContext>;
+
+#[cfg(feature = "lnd-openssl")]
+fn build_tls_config(cert_data: &[u8]) -> Result<TlsConfig, Box<dyn Error + Send + Sync>> {
+    let cert = X509::from_pem(cert_data)
         .map_err(|e| format!("Failed to parse cert: {}", e))?;
     let mut ctx = SslContext::builder(SslMethod::tls_client())
         .map_err(|e| format!("Failed to create SSL context: {}", e))?;
@@ -146,7 +247,24 @@ fn build_ssl_context(cert_file: &str) -> Result<SslContext, Box<dyn Error + Send
         .map_err(|e| format!("Failed to add cert: {}", e))?;
     ctx.set_verify_cert_store(store.build())
         .map_err(|e| format!("Failed to set cert store: {}", e))?;
-    Ok(ctx.build())
+    Ok(Arc::new(ctx.build()))
+}
+
+#[cfg(feature = "lnd-openssl")]
+async fn tls_handshake(
+    config: &TlsConfig,
+    host: &str,
+    tcp: tokio::net::TcpStream,
+) -> std::io::Result<Pin<Box<dyn AsyncReadWrite + Send>>> {
+    let mut ssl = Ssl::new(config.as_ref())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    ssl.set_hostname(host)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let mut tls = SslStream::new(ssl, tcp)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    Pin::new(&mut tls).connect().await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    Ok(Box::pin(TlsStreamWrapper(tls)))
 }
 
 // ---- Helper: build LightningClient from a Channel + macaroon ---------------------------
@@ -163,13 +281,28 @@ fn make_lightning_client(
     ))
 }
 
+// ---- Conversions to and from the native invoice types ----------------------------------
+
+lnclient::invoice_conversion!(From<lnclient::InvoiceRequest> for lnrpc::Invoice, |invoice| {
+    value_msat: invoice.value_msat,
+    memo: invoice.description().unwrap_or_default(),
+    description_hash: invoice.memo_hash().map_or_else(Vec::new, |hash| hash.to_vec()),
+    expiry: invoice.expiry.map_or(0, |expiry| expiry as i64),
+} ..Default::default());
+
+lnclient::invoice_conversion!(TryFrom<lnrpc::AddInvoiceResponse> for lnclient::InvoiceResponse, |response| {
+    payment_hash: response.r_hash.try_into().map_err(|_| "Invalid length for r_hash, must be 32 bytes")?,
+    payment_request: response.payment_request,
+});
+
 // ---- LNDWrapper implementation ---------------------------------------------------------
 
 impl LNDWrapper {
     pub async fn new_client(
         ln_client_config: &lnclient::LNClientConfig,
-    ) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn Error + Send + Sync>> {
-        let lnd_options = ln_client_config.lnd_config.clone().unwrap();
+    ) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn Error + Send + Sync>> {
+        let lnd_options = ln_client_config.lnd_config.clone()
+            .ok_or("lnd_config is required for the LND client")?;
         
         // Check if LNC pairing phrase is provided
         let connection = if let Some(pairing_phrase) = &lnd_options.lnc_pairing_phrase {
@@ -179,7 +312,11 @@ impl LNDWrapper {
             // Use traditional connection
             Self::connect_traditional(&lnd_options).await?
         };
-        Ok(Arc::new(Mutex::new(LNDWrapper { connection })))
+        let backend_id = match &connection {
+            LNDConnectionType::Traditional(_) => format!("lnd:{}", lnd_options.address.clone().unwrap_or_default()),
+            LNDConnectionType::LNC { mailbox_server, .. } => format!("lnc:{}", mailbox_server),
+        };
+        Ok(Arc::new(LNDWrapper { connection, backend_id }))
     }
 
     // ------ Traditional (direct TLS or SOCKS5) ------------------------------------------
@@ -190,10 +327,18 @@ impl LNDWrapper {
         // Validate required fields for traditional connection
         let address = lnd_options.address.as_ref()
             .ok_or("LND_ADDRESS is required for traditional connection")?;
-        let cert = lnd_options.cert_file.as_ref()
-            .ok_or("CERT_FILE_PATH is required for traditional connection")?;
-        let macaroon = lnd_options.macaroon_file.as_ref()
-            .ok_or("MACAROON_FILE_PATH is required for traditional connection")?;
+        let cert = match (&lnd_options.cert_pem, &lnd_options.cert_file) {
+            (Some(pem), _) => pem.clone(),
+            (None, Some(path)) => std::fs::read(path)
+                .map_err(|e| format!("Failed to read cert file: {}", e))?,
+            (None, None) => return Err("CERT_FILE_PATH or cert_pem is required for traditional connection".into()),
+        };
+        let macaroon_hex = match (&lnd_options.macaroon_hex, &lnd_options.macaroon_file) {
+            (Some(macaroon_hex), _) => macaroon_hex.clone(),
+            (None, Some(path)) => hex::encode(std::fs::read(path)
+                .map_err(|e| format!("Failed to read macaroon file: {}", e))?),
+            (None, None) => return Err("MACAROON_FILE_PATH or macaroon_hex is required for traditional connection".into()),
+        };
         
         // Parse the port from the LNDOptions address, assuming the format is "host:port"
         let parts: Vec<&str> = address.split(':').collect();
@@ -210,45 +355,35 @@ impl LNDWrapper {
 
         let channel = if let Some(proxy_addr) = &lnd_options.socks5_proxy {
             println!("Connecting to LND via SOCKS5 proxy {} -> {}:{}", proxy_addr, host, port);
-            Self::connect_channel_socks5(host.clone(), port, cert.clone(), proxy_addr.clone()).await?
+            Self::connect_channel_socks5(host.clone(), port, cert, proxy_addr.clone()).await?
         } else {
             println!("Connecting to LND directly at {}:{}", host, port);
-            Self::connect_channel_direct(host.clone(), port, cert.clone()).await?
+            Self::connect_channel_direct(host.clone(), port, cert).await?
         };
 
-        let macaroon_data = std::fs::read(macaroon)
-            .map_err(|e| format!("Failed to read macaroon file: {}", e))?;
-        let macaroon_hex = hex::encode(&macaroon_data);
         let client = make_lightning_client(channel, macaroon_hex)?;
         println!("\u{2713} LND gRPC channel ready");
         Ok(LNDConnectionType::Traditional(Arc::new(Mutex::new(client))))
     }
 
-    /// Direct TLS connection using OpenSSL (no proxy).
+    /// Direct TLS connection (no proxy).
     async fn connect_channel_direct(
         host: String,
         port: u32,
-        cert_file: String,
+        cert_pem: Vec<u8>,
     ) -> Result<Channel, Box<dyn Error + Send + Sync>> {
-        let ssl_context = Arc::new(build_ssl_context(&cert_file)?);
+        let tls_config = build_tls_config(&cert_pem)?;
         let target_host = host.clone();
         let connector = tower::service_fn(move |_uri: http::Uri| {
             let host = target_host.clone();
             let port = port;
-            let ctx = Arc::clone(&ssl_context);
+            let tls_config = tls_config.clone();
             async move {
                 let tcp = tokio::net::TcpStream::connect(format!("{}:{}", host, port))
                     .await
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                let mut ssl = Ssl::new(ctx.as_ref())
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                ssl.set_hostname(&host)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                let mut tls = SslStream::new(ssl, tcp)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                Pin::new(&mut tls).connect().await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                Ok::<_, std::io::Error>(TokioIo::new(Box::pin(TlsStreamWrapper(tls)) as Pin<Box<dyn AsyncReadWrite + Send>>))
+                let tls = tls_handshake(&tls_config, &host, tcp).await?;
+                Ok::<_, std::io::Error>(TokioIo::new(tls))
             }
         });
         let channel = Endpoint::from_str(&format!("https://{}:{}", host, port))
@@ -263,7 +398,7 @@ impl LNDWrapper {
     async fn connect_channel_socks5(
         host: String,
         port: u32,
-        cert_file: String,
+        cert_pem: Vec<u8>,
         proxy_addr: String,
     ) -> Result<Channel, Box<dyn Error + Send + Sync>> {
         let proxy_parts: Vec<&str> = proxy_addr.split(':').collect();
@@ -284,12 +419,12 @@ impl LNDWrapper {
             Err(_) => return Err(format!("SOCKS5 proxy at {}:{} not responding", proxy_host, proxy_port).into()),
         }
 
-        let ssl_context = Arc::new(build_ssl_context(&cert_file)?);
+        let tls_config = build_tls_config(&cert_pem)?;
         let target_host = host.clone();
         let connector = tower::service_fn(move |_uri: http::Uri| {
             let host = target_host.clone();
             let port = port;
-            let ctx = Arc::clone(&ssl_context);
+            let tls_config = tls_config.clone();
             let proxy_host = proxy_host.clone();
             let proxy_port = proxy_port;
             async move {
@@ -302,15 +437,8 @@ impl LNDWrapper {
                     .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "SOCKS5 timed out"))?
                     .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
                 let tcp = socks_stream.into_inner();
-                let mut ssl = Ssl::new(ctx.as_ref())
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                ssl.set_hostname(&host)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                let mut tls = SslStream::new(ssl, tcp)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                Pin::new(&mut tls).connect().await
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                Ok::<_, std::io::Error>(TokioIo::new(Box::pin(TlsStreamWrapper(tls)) as Pin<Box<dyn AsyncReadWrite + Send>>))
+                let tls = tls_handshake(&tls_config, &host, tcp).await?;
+                Ok::<_, std::io::Error>(TokioIo::new(tls))
             }
         });
         let channel = Endpoint::from_str(&format!("https://{}:{}", host, port))
@@ -363,6 +491,25 @@ impl LNDWrapper {
         })
     }
 
+    /// Shared gRPC client for the LNC session. The cache stays locked while a missing
+    /// client is set up, so concurrent callers share a single handshake, and each caller
+    /// gets a clone: tonic runs every RPC as its own HTTP/2 stream over the one session,
+    /// so invoice creation and settlement lookups don't wait for each other.
+    async fn lnc_client(
+        mailbox: &Arc<Mutex<lnc::LNCMailbox>>,
+        client_cache: &Arc<Mutex<Option<LndLightningClient>>>,
+    ) -> Result<LndLightningClient, Box<dyn Error + Send + Sync>> {
+        let mut cached = client_cache.lock().await;
+        if let Some(client) = cached.as_ref() {
+            eprintln!("✅ Reusing cached gRPC client");
+            return Ok(client.clone());
+        }
+        eprintln!("🔄 No cached gRPC client, creating new connection...");
+        let client = Self::setup_lnc_client(mailbox).await?;
+        *cached = Some(client.clone());
+        Ok(client)
+    }
+
     /// Add invoice through LNC mailbox connection using proper gRPC client.
     /// Connection-reusable version that keeps the gRPC client alive.
     async fn add_invoice_via_lnc(
@@ -370,35 +517,76 @@ impl LNDWrapper {
         client_cache: &Arc<Mutex<Option<LndLightningClient>>>,
         invoice: lnrpc::Invoice,
     ) -> Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>> {
-        // Atomically take the cached client. If it is absent (first call or prior error),
-        // create a fresh one. This single lock-and-take eliminates the TOCTOU window that
-        // existed when check and take were two separate lock acquisitions.
-        let cached = client_cache.lock().await.take();
-        let mut lightning_client = if let Some(client) = cached {
-            eprintln!("✅ Reusing cached gRPC client");
-            client
-        } else {
-            eprintln!("🔄 No cached gRPC client, creating new connection...");
-            Self::setup_lnc_client(mailbox).await?
-        };
+        let mut lightning_client = Self::lnc_client(mailbox, client_cache).await?;
 
         eprintln!("📤 Sending AddInvoice request...");
         // MacaroonInterceptor (baked into the client at setup time) handles auth — no manual insert needed.
-        match lightning_client.add_invoice(Request::new(invoice)).await {
+        let mut request = Request::new(invoice);
+        trace::inject_metadata(request.metadata_mut());
+        match lightning_client.add_invoice(request).await {
             Ok(response) => {
                 eprintln!("✅ LNC AddInvoice successful");
-                *client_cache.lock().await = Some(lightning_client);
                 Ok(response.into_inner())
             }
             Err(e) => {
                 eprintln!("❌ AddInvoice failed: {}", e);
-                // Do not cache on error — connection is likely broken; force fresh handshake.
-                // TODO: Investigate GoBN seq wrap-around causing Noise nonce desync
+                Self::reset_lnc_session(mailbox, client_cache).await;
                 Err(format!("gRPC call failed: {}", e).into())
             }
         }
     }
 
+    /// Look up an invoice through the LNC mailbox connection, sharing the cached client
+    /// the same way `add_invoice_via_lnc` does.
+    async fn lookup_invoice_via_lnc(
+        mailbox: &Arc<Mutex<lnc::LNCMailbox>>,
+        client_cache: &Arc<Mutex<Option<LndLightningClient>>>,
+        payment_hash: lnrpc::PaymentHash,
+    ) -> Result<lnrpc::Invoice, Box<dyn Error + Send + Sync>> {
+        let mut lightning_client = Self::lnc_client(mailbox, client_cache).await?;
+
+        let mut request = Request::new(payment_hash);
+        trace::inject_metadata(request.metadata_mut());
+        match lightning_client.lookup_invoice(request).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(e) => {
+                Self::reset_lnc_session(mailbox, client_cache).await;
+                Err(format!("gRPC call failed: {}", e).into())
+            }
+        }
+    }
+
+    /// Sign a message with the node key through the LNC mailbox connection, sharing
+    /// the cached client the same way `add_invoice_via_lnc` does.
+    async fn sign_message_via_lnc(
+        mailbox: &Arc<Mutex<lnc::LNCMailbox>>,
+        client_cache: &Arc<Mutex<Option<LndLightningClient>>>,
+        sign_request: lnrpc::SignMessageRequest,
+    ) -> Result<lnrpc::SignMessageResponse, Box<dyn Error + Send + Sync>> {
+        let mut lightning_client = Self::lnc_client(mailbox, client_cache).await?;
+
+        let mut request = Request::new(sign_request);
+        trace::inject_metadata(request.metadata_mut());
+        match lightning_client.sign_message(request).await {
+            Ok(response) => Ok(response.into_inner()),
+            Err(e) => {
+                Self::reset_lnc_session(mailbox, client_cache).await;
+                Err(format!("gRPC call failed: {}", e).into())
+            }
+        }
+    }
+
+    /// Drops the cached client and the Noise session after a failed RPC: the connection
+    /// is likely broken, and resuming its nonces risks desync or reuse. The next call
+    /// runs a fresh handshake with new keys.
+    async fn reset_lnc_session(
+        mailbox: &Arc<Mutex<lnc::LNCMailbox>>,
+        client_cache: &Arc<Mutex<Option<LndLightningClient>>>,
+    ) {
+        *client_cache.lock().await = None;
+        mailbox.lock().await.reset_session();
+    }
+
     /// Setup a new LNC client connection.
     async fn setup_lnc_client(
         mailbox: &Arc<Mutex<lnc::LNCMailbox>>,
@@ -473,23 +661,91 @@ impl LNDWrapper {
 // ---- LNClient trait implementation for LNDWrapper -------------------------------------
 
 impl lnclient::LNClient for LNDWrapper {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn Error + Send + Sync>>> + Send>> {
+    fn backend_id(&self) -> String {
+        self.backend_id.clone()
+    }
+
+    fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+        let connection = self.connection.clone();
+        let invoice = lnrpc::Invoice::from(invoice);
+        Box::pin(async move {
+            let response = match connection {
+                LNDConnectionType::Traditional(client_arc) => {
+                    let mut request = Request::new(invoice);
+                    trace::inject_metadata(request.metadata_mut());
+                    let mut client = client_arc.lock().await;
+                    client.add_invoice(request).await
+                        .map(|r| r.into_inner())
+                        .map_err(|e| -> Box<dyn Error + Send + Sync> { Box::new(e) })?
+                }
+                LNDConnectionType::LNC { mailbox, client, .. } => {
+                    Self::add_invoice_via_lnc(&mailbox, &client, invoice).await?
+                }
+            };
+            response.try_into()
+        })
+    }
+
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
         let connection = self.connection.clone();
         Box::pin(async move {
-            match connection {
+            let request = lnrpc::PaymentHash {
+                r_hash: payment_hash.to_vec(),
+                ..Default::default()
+            };
+            let invoice = match connection {
                 LNDConnectionType::Traditional(client_arc) => {
+                    let mut request = Request::new(request);
+                    trace::inject_metadata(request.metadata_mut());
                     let mut client = client_arc.lock().await;
-                    client.add_invoice(Request::new(invoice)).await
+                    client.lookup_invoice(request).await
                         .map(|r| r.into_inner())
-                        .map_err(|e| -> Box<dyn Error + Send + Sync> { Box::new(e) })
+                        .map_err(|e| -> Box<dyn Error + Send + Sync> { Box::new(e) })?
                 }
                 LNDConnectionType::LNC { mailbox, client, .. } => {
-                    Self::add_invoice_via_lnc(&mailbox, &client, invoice).await
+                    Self::lookup_invoice_via_lnc(&mailbox, &client, request).await?
                 }
+            };
+            Ok(match invoice.state() {
+                lnrpc::invoice::InvoiceState::Settled => lnclient::InvoiceState::Settled,
+                lnrpc::invoice::InvoiceState::Canceled => lnclient::InvoiceState::Canceled,
+                // Accepted means HTLCs are held but not yet settled
+                lnrpc::invoice::InvoiceState::Open | lnrpc::invoice::InvoiceState::Accepted => lnclient::InvoiceState::Open,
+            })
+        })
+    }
+    fn sign_message(&self, message: &[u8]) -> lnclient::LNClientFuture<String> {
+        let connection = self.connection.clone();
+        let sign_request = lnrpc::SignMessageRequest {
+            msg: message.to_vec(),
+            ..Default::default()
+        };
+        Box::pin(async move {
+            let response = match connection {
+                LNDConnectionType::Traditional(client_arc) => {
+                    let mut request = Request::new(sign_request);
+                    trace::inject_metadata(request.metadata_mut());
+                    let mut client = client_arc.lock().await;
+                    client.sign_message(request).await
+                        .map(|r| r.into_inner())
+                        .map_err(|e| -> Box<dyn Error + Send + Sync> { Box::new(e) })?
+                }
+                LNDConnectionType::LNC { mailbox, client, .. } => {
+                    Self::sign_message_via_lnc(&mailbox, &client, sign_request).await?
+                }
+            };
+            Ok(response.signature)
+        })
+    }
+
+    fn close(&self) -> lnclient::LNClientFuture<()> {
+        let connection = self.connection.clone();
+        Box::pin(async move {
+            // Plain gRPC channels close when the last clone is dropped
+            if let LNDConnectionType::LNC { mailbox, client, .. } = connection {
+                Self::reset_lnc_session(&mailbox, &client).await;
             }
+            Ok(())
         })
     }
 }
@@ -1,6 +1,7 @@
 /// Pre-generated LND gRPC types and client (from proto/lightning.proto).
 /// Code was generated from the proto file and committed directly so that
-/// crate consumers do not need protoc or a build script.
+/// crate consumers do not need protoc or a build script. Both are trimmed to
+/// the RPCs the middleware calls: GetInfo, AddInvoice, LookupInvoice and SignMessage.
 pub mod lnrpc {
     include!("lnrpc_generated.rs");
 }
\ No newline at end of file
@@ -1,15 +1,10 @@
-use reqwest::{Client, Error};
-use rocket::serde::json::serde_json;
-use crate::lndrpc::lnrpc;
 use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
 use std::sync::Arc;
 use bitcoin::hashes::Hash;
-use tokio::sync::Mutex;
-use std::future::Future;
-use std::pin::Pin;
 
 use crate::utils;
-use crate::lnclient;
+use crate::lnclient::{self, BackendError};
+use crate::transport::{self, HttpTransport};
 
 #[derive(Debug, Clone)]
 pub struct LNURLOptions {
@@ -31,6 +26,13 @@ pub struct LnAddressUrlResJson {
     #[serde(rename = "commentAllowed", default)]
     comment_allowed: u32,
     tag: String,
+
+    // Lightning address the pay request was resolved from
+    #[serde(skip)]
+    address: String,
+
+    #[serde(skip, default = "transport::default_transport")]
+    transport: Arc<dyn HttpTransport>,
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -52,56 +54,82 @@ pub struct DecodedPR {
 }
 
 impl LnAddressUrlResJson {
-    pub async fn new_client(ln_client_config: &lnclient::LNClientConfig) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn std::error::Error + Send + Sync>> {
-        let lnurl_options = ln_client_config.lnurl_config.clone().unwrap();
+    /// Callback URL requesting an invoice for `value_msat`. `comment` is passed
+    /// along (LUD-12), truncated to the recipient's limit, when it accepts comments.
+    pub fn callback_url(&self, value_msat: i64, comment: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut url = reqwest::Url::parse(&self.callback)?;
+        url.query_pairs_mut().append_pair("amount", &value_msat.to_string());
+        if self.comment_allowed > 0 && !comment.is_empty() {
+            let comment: String = comment.chars().take(self.comment_allowed as usize).collect();
+            url.query_pairs_mut().append_pair("comment", &comment);
+        }
+        Ok(url.to_string())
+    }
+
+    pub async fn new_client(ln_client_config: &lnclient::LNClientConfig) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn std::error::Error + Send + Sync>> {
+        let lnurl_options = ln_client_config.lnurl_config.clone()
+            .ok_or("lnurl_config is required for the LNURL client")?;
         let (username, domain) = utils::parse_ln_address(lnurl_options.address)?;
     
+        let http_transport = ln_client_config.http_transport.clone().unwrap_or_else(transport::default_transport);
         let ln_address_url = format!("https://{}/.well-known/lnurlp/{}", domain, username);
-        let ln_address_url_res_body = do_get_request(&ln_address_url).await;
+        let ln_address_url_res_body = do_get_request(http_transport.as_ref(), &ln_address_url).await?;
     
-        let ln_address_url_res: LnAddressUrlResJson = serde_json::from_str(&ln_address_url_res_body.unwrap())?;
-        Ok(Arc::new(Mutex::new(ln_address_url_res)))
+        let mut ln_address_url_res: LnAddressUrlResJson = parse_response(&ln_address_url_res_body)?;
+        if ln_address_url_res.tag != "payRequest" {
+            return Err(BackendError::malformed(format!("expected a payRequest, got tag {:?}", ln_address_url_res.tag)).into());
+        }
+        ln_address_url_res.address = format!("{}@{}", username, domain);
+        ln_address_url_res.transport = http_transport;
+        Ok(Arc::new(ln_address_url_res))
     }
 }
 
+lnclient::invoice_conversion!(TryFrom<CallbackUrlResJson> for lnclient::InvoiceResponse, |callback| {
+    payment_hash: Bolt11Invoice::from_signed(callback.pr.parse::<SignedRawBolt11Invoice>().map_err(BackendError::malformed)?)
+        .map_err(BackendError::malformed)?
+        .payment_hash()
+        .to_byte_array(),
+    payment_request: callback.pr,
+});
+
 impl lnclient::LNClient for LnAddressUrlResJson {
-    fn add_invoice(
-        &self,
-        ln_invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn std::error::Error + Send + Sync>>> + Send>> {
-        let callback_url = format!(
-            "{}?amount={}",
-            self.callback,
-            ln_invoice.value_msat
-        );
+    fn backend_id(&self) -> String {
+        format!("lnurl:{}", self.address)
+    }
+
+    fn add_invoice(&self, ln_invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+        let callback_url = self.callback_url(ln_invoice.value_msat, &ln_invoice.description().unwrap_or_default());
+        let http_transport = self.transport.clone();
 
         Box::pin(async move {
-            let callback_url_res_body = do_get_request(&callback_url).await?;
-
-            let callback_url_res_json: CallbackUrlResJson =
-                serde_json::from_str(&callback_url_res_body)?;
-
-            let invoice = callback_url_res_json.pr;
-            let decoded_invoice = Bolt11Invoice::from_signed(invoice.parse::<SignedRawBolt11Invoice>().unwrap()).unwrap();
-            let payment_hash = decoded_invoice.payment_hash();
-            let payment_addr = decoded_invoice.payment_secret();
-
-            Ok(lnrpc::AddInvoiceResponse {
-                r_hash: payment_hash.to_byte_array().to_vec(),
-                payment_request: invoice,
-                add_index: 0,
-                payment_addr: payment_addr.0.to_vec(),
-            })
+            let callback_url = callback_url?;
+            let callback_url_res_body = do_get_request(http_transport.as_ref(), &callback_url).await?;
+
+            let callback_url_res_json: CallbackUrlResJson = parse_response(&callback_url_res_body)?;
+
+            callback_url_res_json.try_into()
         })
     }
 }
 
-async fn do_get_request(url: &str) -> Result<String, Error> {
-    let client = Client::new();
+async fn do_get_request(http_transport: &dyn HttpTransport, url: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let resp = http_transport.send(transport::get(url)?).await
+        .map_err(|e| BackendError::Unreachable(format!("{}: {}", url, e)))?;
+    if !resp.status().is_success() {
+        let status = resp.status().as_u16();
+        return Err(BackendError::Status { status, body: String::from_utf8_lossy(resp.body()).into_owned() }.into());
+    }
 
-    let raw_resp = client.get(url).send().await?;
-    let resp = raw_resp.error_for_status()?;
+    Ok(String::from_utf8(resp.into_body()).map_err(BackendError::malformed)?)
+}
 
-    let text = resp.text().await?;
-    Ok(text)
+// Parses an LNURL reply, surfacing a LUD-06 `{"status": "ERROR", "reason": ..}` as a rejection
+fn parse_response<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, BackendError> {
+    let value: serde_json::Value = serde_json::from_str(body).map_err(BackendError::malformed)?;
+    if value.get("status").and_then(|status| status.as_str()) == Some("ERROR") {
+        let reason = value.get("reason").and_then(|reason| reason.as_str()).unwrap_or("no reason given");
+        return Err(BackendError::Rejected(reason.to_string()));
+    }
+    serde_json::from_value(value).map_err(BackendError::malformed)
 }
@@ -0,0 +1,66 @@
+use lightning::types::payment::PaymentHash;
+use macaroon::{Caveat, Macaroon};
+use serde::Serialize;
+
+use crate::l402;
+use crate::macaroon_util;
+use crate::utils;
+
+// Aperture-style LSAT identifier: version (u16, big endian), payment hash, token id
+const APERTURE_IDENTIFIER_LEN: usize = 2 + 32 + 32;
+
+/// A legacy LSAT token re-minted as an L402 token for the same payment.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpgradedToken {
+    /// Hex payment hash, unchanged, so ledgers, quotas and spend carry over
+    pub payment_hash: String,
+    /// First-party caveats copied over from the legacy macaroon
+    pub caveats: Vec<String>,
+    /// New macaroon, signed with the current root key
+    pub macaroon: String,
+    /// Ready-to-send `L402 <macaroon>:<preimage>` Authorization value
+    pub token: String,
+}
+
+/// Payment hash a legacy LSAT macaroon was minted for. Understands Aperture's
+/// versioned identifier as well as the bare payment hash L402 macaroons use.
+pub fn legacy_payment_hash(mac: &Macaroon) -> Option<[u8; 32]> {
+    let id_bytes = &mac.identifier().0;
+    if id_bytes.len() == APERTURE_IDENTIFIER_LEN && id_bytes[..2] == [0, 0] {
+        return id_bytes[2..34].try_into().ok();
+    }
+    l402::macaroon_payment_hash(mac)
+}
+
+/// Re-mints `token`, given as `[LSAT ]<macaroon>:<preimage>`, as an L402 token
+/// signed with `root_key`. The legacy macaroon must verify against
+/// `legacy_root_key` and the preimage must settle its payment hash, so only
+/// tokens that were actually paid for are upgraded.
+pub fn upgrade_token(token: &str, legacy_root_key: &[u8], root_key: &[u8]) -> Result<UpgradedToken, String> {
+    let (_, token) = l402::split_auth_scheme(token);
+    let (macaroon_string, preimage_string) = token.split_once(':').ok_or("Token must be <macaroon>:<preimage>")?;
+    let mac = utils::get_macaroon_from_string(macaroon_string.trim().to_string())?;
+    let preimage = utils::get_preimage_from_string(preimage_string.trim().to_string())?;
+
+    if !l402::verify_macaroon_signature(&mac, legacy_root_key) {
+        return Err("Macaroon was not minted with the legacy root key".to_string());
+    }
+    let payment_hash = legacy_payment_hash(&mac).ok_or("Macaroon identifier doesn't carry a payment hash")?;
+    if PaymentHash::from(preimage).0 != payment_hash {
+        return Err("Preimage does not match payment hash".to_string());
+    }
+
+    let caveats: Vec<String> = mac.first_party_caveats().iter().filter_map(|caveat| match caveat {
+        Caveat::FirstParty(fp) => Some(String::from_utf8_lossy(fp.predicate().as_ref()).to_string()),
+        _ => None,
+    }).collect();
+    let macaroon = macaroon_util::get_macaroon_as_string(PaymentHash(payment_hash), caveats.clone(), root_key.to_vec())
+        .map_err(|error| error.to_string())?;
+
+    Ok(UpgradedToken {
+        payment_hash: hex::encode(payment_hash),
+        token: format!("{} {}:{}", l402::L402_HEADER, macaroon, hex::encode(preimage.0)),
+        caveats,
+        macaroon,
+    })
+}
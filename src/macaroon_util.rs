@@ -1,12 +1,49 @@
+use base64::{Engine as _, engine::general_purpose};
 use macaroon::{Macaroon, MacaroonKey, ByteString, Format};
 use lightning::types::payment::{PaymentHash};
 use crate::l402;
 
+/// Text encoding of minted macaroons. Parsing accepts all of them, as well as
+/// V2 binary macaroons in any of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MacaroonEncoding {
+    /// URL-safe base64 with padding
+    #[default]
+    Base64Url,
+    /// URL-safe base64 without padding, as some client libraries expect
+    Base64UrlNoPad,
+    /// Standard base64 with padding, as emitted by Aperture
+    Base64,
+    Hex,
+}
+
+impl MacaroonEncoding {
+    pub fn encode(&self, mac: &Macaroon) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        // The macaroon crate only serializes to padded URL-safe base64
+        let binary = general_purpose::URL_SAFE.decode(mac.serialize(Format::V1)?)?;
+        Ok(match self {
+            MacaroonEncoding::Base64Url => general_purpose::URL_SAFE.encode(binary),
+            MacaroonEncoding::Base64UrlNoPad => general_purpose::URL_SAFE_NO_PAD.encode(binary),
+            MacaroonEncoding::Base64 => general_purpose::STANDARD.encode(binary),
+            MacaroonEncoding::Hex => hex::encode(binary),
+        })
+    }
+}
+
 pub fn get_macaroon_as_string(
     payment_hash: PaymentHash,
     caveats: Vec<String>,
     root_key: Vec<u8>,
-) -> Result<String, Box<dyn std::error::Error>> {
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    get_macaroon_as_string_with_encoding(payment_hash, caveats, root_key, MacaroonEncoding::default())
+}
+
+pub fn get_macaroon_as_string_with_encoding(
+    payment_hash: PaymentHash,
+    caveats: Vec<String>,
+    root_key: Vec<u8>,
+    encoding: MacaroonEncoding,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
     let key = MacaroonKey::generate(&root_key);
 
     let mut mac = Macaroon::create(
@@ -19,7 +56,7 @@ pub fn get_macaroon_as_string(
         mac.add_first_party_caveat(ByteString::from(caveat.as_str()));
     }
 
-    let macaroon_string = mac.serialize(Format::V1).unwrap();
+    let macaroon_string = encoding.encode(&mac)?;
 
     Ok(macaroon_string)
 }
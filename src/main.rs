@@ -7,53 +7,13 @@ use rocket::Request;
 use dotenvy::dotenv;
 use std::env;
 use std::sync::Arc;
-use reqwest::Client;
 
-use l402_middleware::{l402, lnclient, lnd, lnurl, nwc, cln, bolt12, eclair, middleware};
-
-const SATS_PER_BTC: i64 = 100_000_000;
-const MIN_SATS_TO_BE_PAID: i64 = 1;
-const MSAT_PER_SAT: i64 = 1000;
-
-#[derive(Serialize)]
-#[serde(crate = "rocket::serde")]
-
-pub struct FiatRateConfig {
-    pub currency: String,
-    pub amount: f64,
-}
-
-impl FiatRateConfig {
-     // Converts fiat amount to BTC equivalent in millisats. Customization possible for different API endpoints.
-    pub async fn fiat_to_btc_amount_func(&self) -> i64 {
-        // Return the minimum sats if the amount is invalid.
-        if self.amount <= 0.0 {
-            return MIN_SATS_TO_BE_PAID * MSAT_PER_SAT;
-        }
-
-        // API request to get BTC equivalent of the fiat amount.
-        let url = format!(
-            "https://blockchain.info/tobtc?currency={}&value={}",
-            self.currency, self.amount
-        );
-
-        match Client::new().get(&url).send().await {
-            Ok(res) => {
-                let body = res.text().await.unwrap_or_else(|_| MIN_SATS_TO_BE_PAID.to_string());
-                match body.parse::<f64>() {
-                    Ok(amount_in_btc) => ((SATS_PER_BTC as f64 * amount_in_btc) * MSAT_PER_SAT as f64) as i64,
-                    Err(_) => MIN_SATS_TO_BE_PAID * MSAT_PER_SAT,
-                }
-            }
-            Err(_) => MIN_SATS_TO_BE_PAID * MSAT_PER_SAT,
-        }
-    }
-}
+use l402_middleware::{config, l402, lnclient, fiat, middleware, doctor, provision};
 
 // Function to add caveats, can customize it based on authentication needs
 fn path_caveat(req: &Request<'_>) -> Vec<String> {
     vec![
-        format!("RequestPath = {}", req.uri().path()),
+        format!("{} = {}", l402::REQUEST_PATH_CAVEAT, req.uri().path()),
     ]
 }
 
@@ -96,148 +56,123 @@ fn protected(l402_info: l402::L402Info) -> (Status, Json<Response>) {
     (status, Json(response))
 }
 
-#[launch]
-pub async fn rocket() -> rocket::Rocket<rocket::Build> {
-     // Load environment variables from .env file
+#[rocket::main]
+async fn main() {
+    // Load environment variables from .env file
     dotenv().ok();
 
-    // Get LN_CLIENT_TYPE from the environment
-    let ln_client_type = env::var("LN_CLIENT_TYPE").expect("LN_CLIENT_TYPE not found in .env");
+    // `--check-config` validates the configuration, and with `--check-backend` connects to the backend, then exits
+    if env::args().any(|arg| arg == "--check-config") {
+        let check_backend = env::args().any(|arg| arg == "--check-backend");
+        let valid = check_config(check_backend).await;
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
+    // `--doctor` runs a self-test against the configured backend and exits
+    if env::args().any(|arg| arg == "--doctor") {
+        let passed = run_doctor().await.unwrap_or_else(|error| {
+            println!("[FAIL] {}", error);
+            false
+        });
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    // Initialize LNClientConfig based on LN_CLIENT_TYPE
-    let ln_client_config = match ln_client_type.as_str() {
-        "LNURL" => lnclient::LNClientConfig {
-            ln_client_type,
-            lnd_config: None,
-            lnurl_config: Some(lnurl::LNURLOptions {
-                address: env::var("LNURL_ADDRESS").expect("LNURL_ADDRESS not found in .env"),
-            }),
-            nwc_config: None,
-            cln_config: None,
-            bolt12_config: None,
-            eclair_config: None,
-            root_key: env::var("ROOT_KEY")
-                .expect("ROOT_KEY not found in .env")
-                .as_bytes()
-                .to_vec(),
-        },
-        "LND" => {
-            // Check if using LNC
-            let lnc_pairing_phrase = env::var("LNC_PAIRING_PHRASE").ok();
-            let lnc_mailbox_server = env::var("LNC_MAILBOX_SERVER").ok();
-            
-            // Configure based on connection type
-            let lnd_options = if lnc_pairing_phrase.is_some() {
-                // LNC mode - only pairing phrase needed, no cert/macaroon required
-                lnd::LNDOptions {
-                    address: None,
-                    macaroon_file: None,
-                    cert_file: None,
-                    socks5_proxy: None,
-                    lnc_pairing_phrase,
-                    lnc_mailbox_server,
-                }
-            } else {
-                // Traditional mode - all required
-                lnd::LNDOptions {
-                    address: Some(env::var("LND_ADDRESS").expect("LND_ADDRESS not found in .env")),
-                    macaroon_file: Some(env::var("MACAROON_FILE_PATH").expect("MACAROON_FILE_PATH not found in .env")),
-                    cert_file: Some(env::var("CERT_FILE_PATH").expect("CERT_FILE_PATH not found in .env")),
-                    socks5_proxy: env::var("SOCKS5_PROXY").ok(), // Optional: e.g., "127.0.0.1:9050" for Tor
-                    lnc_pairing_phrase: None,
-                    lnc_mailbox_server: None,
-                }
-            };
-            
-            lnclient::LNClientConfig {
-                ln_client_type,
-                lnd_config: Some(lnd_options),
-                lnurl_config: None,
-                nwc_config: None,
-                cln_config: None,
-                bolt12_config: None,
-                eclair_config: None,
-                root_key: env::var("ROOT_KEY")
-                    .expect("ROOT_KEY not found in .env")
-                    .as_bytes()
-                    .to_vec(),
-            }
-        },
-        "NWC" => lnclient::LNClientConfig {
-            ln_client_type,
-            lnd_config: None,
-            lnurl_config: None,
-            cln_config: None,
-            bolt12_config: None,
-            eclair_config: None,
-            nwc_config: Some(nwc::NWCOptions {
-                uri: env::var("NWC_URI").expect("NWC_URI not found in .env"),
-            }),
-            root_key: env::var("ROOT_KEY")
-                .expect("ROOT_KEY not found in .env")
-                .as_bytes()
-                .to_vec(),
-        },
-        "CLN" => lnclient::LNClientConfig {
-            ln_client_type,
-            lnd_config: None,
-            lnurl_config: None,
-            nwc_config: None,
-            bolt12_config: None,
-            eclair_config: None,
-            cln_config: Some(cln::CLNOptions {
-                lightning_dir: env::var("CLN_LIGHTNING_RPC_FILE_PATH").expect("CLN_LIGHTNING_RPC_FILE_PATH not found in .env"),
-            }),
-            root_key: env::var("ROOT_KEY")
-                .expect("ROOT_KEY not found in .env")
-                .as_bytes()
-                .to_vec(),
-        },
-        "BOLT12" => lnclient::LNClientConfig {
-            ln_client_type,
-            lnd_config: None,
-            lnurl_config: None,
-            nwc_config: None,
-            cln_config: None,
-            eclair_config: None,
-            bolt12_config: Some(bolt12::Bolt12Options {
-                lightning_dir: env::var("CLN_LIGHTNING_RPC_FILE_PATH").expect("CLN_LIGHTNING_RPC_FILE_PATH not found in .env"),
-                offer: env::var("BOLT12_LN_OFFER").expect("BOLT12_LN_OFFER not found in .env"),
-            }),
-            root_key: env::var("ROOT_KEY")
-                .expect("ROOT_KEY not found in .env")
-                .as_bytes()
-                .to_vec(),
-        },
-        "ECLAIR" => lnclient::LNClientConfig {
-            ln_client_type,
-            lnd_config: None,
-            lnurl_config: None,
-            nwc_config: None,
-            cln_config: None,
-            bolt12_config: None,
-            eclair_config: Some(eclair::EclairOptions {
-                api_url: env::var("ECLAIR_API_URL").expect("ECLAIR_API_URL not found in .env"),
-                password: env::var("ECLAIR_PASSWORD").expect("ECLAIR_PASSWORD not found in .env"),
-            }),
-            root_key: env::var("ROOT_KEY")
-                .expect("ROOT_KEY not found in .env")
-                .as_bytes()
-                .to_vec(),
-        },
-        _ => panic!("Invalid LN_CLIENT_TYPE. Expected 'LNURL', 'LND', 'NWC', 'CLN', 'BOLT12', or 'ECLAIR'."),
+    let rocket = rocket().await.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+    if let Err(error) = rocket.launch().await {
+        eprintln!("Failed to launch: {}", error);
+        std::process::exit(1);
+    }
+}
+
+// Settings come from the TOML file named by L402_CONFIG, overridden by the environment
+fn load_config() -> Result<config::L402Config, String> {
+    let config_path = env::var("L402_CONFIG").unwrap_or_else(|_| config::DEFAULT_CONFIG_FILE.to_string());
+    config::load(&config_path).map_err(|error| error.to_string())
+}
+
+// The validated configuration, or its report when it isn't valid
+fn valid_config() -> Result<config::L402Config, String> {
+    let l402_config = load_config()?;
+    let report = l402_config.validate();
+    if !report.is_valid() {
+        return Err(report.to_string());
+    }
+    Ok(l402_config)
+}
+
+// Backend options, with the root key fetched from the operator console when one is configured
+async fn ln_client_config(l402_config: &config::L402Config) -> Result<lnclient::LNClientConfig, String> {
+    let root_key = match l402_config.root_key_source().map_err(|problems| problems.join(", "))? {
+        config::RootKeySource::Static(root_key) => root_key,
+        config::RootKeySource::Console(console) => provision::fetch_root_key(&console).await
+            .map_err(|error| format!("Failed to provision root key: {}", error))?,
     };
+    l402_config.backend_config(root_key).map_err(|problems| problems.join(", "))
+}
 
-    // Initialize Fiat Rate Config
-    let fiat_rate_config = Arc::new(FiatRateConfig {
+fn fiat_rate_config() -> Arc<fiat::FiatRateConfig> {
+    Arc::new(fiat::FiatRateConfig {
         currency: "USD".to_string(),
         amount: 0.01,
-    });
+        ..Default::default()
+    })
+}
+
+// Prints the configuration report, and whether the backend is reachable when asked to
+async fn check_config(check_backend: bool) -> bool {
+    let l402_config = match load_config() {
+        Ok(l402_config) => l402_config,
+        Err(error) => {
+            println!("[FAIL] {}", error);
+            return false;
+        },
+    };
+    let mut report = l402_config.validate();
+    // The example server keeps the middleware's default token store
+    report.features.push(("token store", "in memory, per process".to_string()));
+    let mut valid = report.is_valid();
+    println!("{}", report);
+    if valid && check_backend {
+        // Backend reachability doesn't need the real root key
+        if let Ok(ln_client_config) = l402_config.backend_config(Vec::new()) {
+            match lnclient::LNClientConn::init(&ln_client_config).await {
+                Ok(_) => println!("[PASS] connect backend"),
+                Err(error) => {
+                    println!("[FAIL] connect backend: {}", error);
+                    valid = false;
+                },
+            }
+        }
+    }
+    valid
+}
+
+async fn run_doctor() -> Result<bool, String> {
+    let l402_config = valid_config()?;
+    let ln_client_config = ln_client_config(&l402_config).await?;
+    let ln_client = lnclient::LNClientConn::init(&ln_client_config).await
+        .map_err(|error| format!("connect backend: {}", error))?;
+    let report = doctor::run_doctor(ln_client, ln_client_config.root_key.clone(), Some(&fiat_rate_config()), true).await;
+    println!("{}", report);
+    Ok(report.passed())
+}
 
-    let l402_middleware = middleware::L402Middleware::new_l402_middleware(
+pub async fn rocket() -> Result<rocket::Rocket<rocket::Build>, String> {
+    // Load environment variables from .env file
+    dotenv().ok();
+
+    let l402_config = valid_config()?;
+    let ln_client_config = ln_client_config(&l402_config).await?;
+    let fiat_rate_config = fiat_rate_config();
+
+    let amount_fiat_rate_config = Arc::clone(&fiat_rate_config);
+    let mut l402_middleware = middleware::L402Middleware::new_l402_middleware(
         ln_client_config.clone(),
         Arc::new(move |_req: &Request<'_>| {
-            let fiat_rate_config = Arc::clone(&fiat_rate_config);
+            let fiat_rate_config = Arc::clone(&amount_fiat_rate_config);
             Box::pin(async move {
                 fiat_rate_config.fiat_to_btc_amount_func().await
             })
@@ -245,22 +180,35 @@ pub async fn rocket() -> rocket::Rocket<rocket::Build> {
         Arc::new(move |req: &Request<'_>| {
             path_caveat(req)
         }),
-    ).await.unwrap();
+    ).await.map_err(|error| format!("Failed to create the L402 middleware: {}", error))?;
+
+    // Advertise the fiat amount behind the sat price in the challenge
+    l402_middleware.fiat_price_func = Some(Arc::new(move |_req: &Request<'_>| {
+        fiat_rate_config.fiat_price()
+    }));
+    l402_middleware.max_uses = l402_config.max_uses().expect("validated above");
+    l402_middleware.max_concurrent = l402_config.max_concurrent().expect("validated above");
+    l402_middleware.endpoints.public_base_url = l402_config.public_base_url().expect("validated above");
 
-    rocket::build()
+    Ok(rocket::build()
         .attach(l402_middleware)
-        .mount("/", routes![free, protected])
+        .mount("/", routes![free, protected]))
 }
 
 #[cfg(test)]
 mod tests {
-    use rocket::http::{Status, Header};
+    use rocket::http::{ContentType, Status, Header};
     use rocket::local::asynchronous::Client;
     use rocket::serde::json::Value;
     use super::rocket;
-    use lightning::types::payment::PaymentHash;
+    use lightning::types::payment::{PaymentHash, PaymentPreimage};
+    use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
 
-    use l402_middleware::{l402, utils};
+    use l402_middleware::{l402, catalog, events, lnc, utils, lnclient, lnd, lnurl, eclair, lsat, middleware, macaroon_util, signed_url, fiat, reconcile, doctor, escalation, provision, session, store, trace, transport, forwarded, geo, sandbox, anomaly, observer, metrics, path, paywall, alt_scheme, offline, config};
+    use rocket::Request;
+    use std::sync::Arc;
 
     const TEST_MACAROON_VALID: &str = "MDAxMmxvY2F0aW9uIExTQVQKMDAzMGlkZW50aWZpZXIgjWsDO3viVp1lHXWoaN1CiUFeRdn8Z9Zl1AUIfJHKoCkKMDAyMWNpZCBSZXF1ZXN0UGF0aCA9IC9wcm90ZWN0ZWQKMDAyZnNpZ25hdHVyZSBZJ8RYr2biQ9CRoCxMcmWBObW7L7nS1bvFduQXRIQcJwo=";
 	const TEST_PREIMAGE_VALID: &str = "7c9d69d87a1af5d06ecebee2b095e49423400cf4f1d650292e0256ccea8b2ae2";
@@ -270,9 +218,133 @@ mod tests {
 
 	const TEST_PREIMAGE_INVALID: &str = "fbe9ac25c04e14b10177514e2d57b0e39224e70277ac1a2cd23c28e58cd4ea35";
 
+    const STUB_ROOT_KEY: &str = "STUBROOTKEY";
+    const STUB_PREIMAGE: &str = "0b2ad42b8b6cd4e1d2d0a4f0a1f9a67b47ed0b5d3e41e2c0c2b7d23a9c58f1e4";
+
+    // Regtest invoice signed with a throwaway key, so it decodes like a real one
+    fn regtest_invoice(memo: String, payment_hash: [u8; 32], amount_msat: Option<u64>) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let secp = Secp256k1::new();
+        let node_key = SecretKey::from_slice(&[0x42; 32])?;
+        let builder = InvoiceBuilder::new(Currency::Regtest)
+            .description(memo)
+            .payment_hash(sha256::Hash::from_byte_array(payment_hash))
+            .payment_secret(PaymentSecret([0x11; 32]))
+            .duration_since_epoch(std::time::Duration::from_secs(utils::now_unix()))
+            .min_final_cltv_expiry_delta(144);
+        let builder = match amount_msat {
+            Some(amount_msat) => builder.amount_milli_satoshis(amount_msat),
+            None => builder,
+        };
+        let invoice = builder.build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &node_key))
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(invoice.to_string())
+    }
+
+    // Backend stand-in so middleware behaviour can be tested without a Lightning node
+    struct StubLNClient;
+
+    impl lnclient::LNClient for StubLNClient {
+        fn backend_id(&self) -> String {
+            "stub".to_string()
+        }
+
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            Box::pin(async move {
+                let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string())?;
+                let payment_hash = PaymentHash::from(preimage).0;
+                let payment_request = regtest_invoice(invoice.memo, payment_hash, Some(invoice.value_msat as u64))?;
+                Ok(lnclient::InvoiceResponse {
+                    payment_request,
+                    payment_hash,
+                })
+            })
+        }
+
+        // Nothing is ever paid at the stub, so every accepted token is a discrepancy
+        fn lookup_invoice(&self, _payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+            Box::pin(async { Ok(lnclient::InvoiceState::Open) })
+        }
+    }
+
+    // Records the trace context the backend call carries
+    struct TracingStubLNClient(Arc<std::sync::Mutex<Option<String>>>);
+
+    impl lnclient::LNClient for TracingStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            let traceparent = trace::http_headers().get("traceparent").and_then(|v| v.to_str().ok()).map(str::to_string);
+            *self.0.lock().unwrap() = traceparent;
+            lnclient::LNClient::add_invoice(&StubLNClient, invoice)
+        }
+    }
+
+    // Advertises a static offer and reports every preimage as a 1000 msat payment of it
+    struct OfferStubLNClient;
+
+    impl lnclient::LNClient for OfferStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            lnclient::LNClient::add_invoice(&StubLNClient, invoice)
+        }
+
+        fn static_offer(&self) -> Option<String> {
+            Some("lno1stuboffer".to_string())
+        }
+
+        fn lookup_offer_payment(&self, _payment_hash: [u8; 32]) -> lnclient::LNClientFuture<Option<i64>> {
+            Box::pin(async { Ok(Some(1000)) })
+        }
+    }
+
+    // Serves canned bodies for URLs starting with a known prefix, 404 otherwise
+    #[derive(Debug)]
+    struct StubTransport(Vec<(String, String)>);
+
+    impl transport::HttpTransport for StubTransport {
+        fn send(&self, request: transport::HttpRequest) -> transport::HttpFuture {
+            let url = request.uri().to_string();
+            let body = self.0.iter().find(|(prefix, _)| url.starts_with(prefix.as_str())).map(|(_, body)| body.clone());
+            Box::pin(async move {
+                Ok(match body {
+                    Some(body) => http::Response::new(body.into_bytes()),
+                    None => http::Response::builder().status(404).body(Vec::new())?,
+                })
+            })
+        }
+    }
+
+    fn stub_middleware() -> middleware::L402Middleware {
+        middleware::L402Middleware::new_with_ln_client(
+            Arc::new(StubLNClient),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        )
+    }
+
+    async fn stub_client(l402_middleware: middleware::L402Middleware) -> Client {
+        let rocket = rocket::build()
+            .attach(l402_middleware)
+            .mount("/", rocket::routes![super::free, super::protected]);
+        Client::tracked(rocket).await.expect("valid rocket instance")
+    }
+
+    fn challenge_macaroon(www_authenticate_header: &str) -> String {
+        www_authenticate_header
+            .trim_start_matches("L402 macaroon=")
+            .split(',')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    fn stub_token(caveats: Vec<String>) -> String {
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let mac = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), caveats, STUB_ROOT_KEY.as_bytes().to_vec()).unwrap();
+        format!("L402 {}:{}", mac, STUB_PREIMAGE)
+    }
+
     #[rocket::async_test]
     async fn test_free_route() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/").dispatch().await;
         
         assert_eq!(response.status(), Status::Ok);
@@ -285,7 +357,7 @@ mod tests {
     #[cfg(feature = "no-accept-authenticate-required")]
     #[rocket::async_test]
     async fn test_protected_route_free_content() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected").dispatch().await;
         
         assert_eq!(response.status(), Status::PaymentRequired);
@@ -302,7 +374,7 @@ mod tests {
     #[cfg(not(feature = "no-accept-authenticate-required"))]
     #[rocket::async_test]
     async fn test_protected_route_free_content() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected").dispatch().await;
         
         assert_eq!(response.status(), Status::InternalServerError);
@@ -314,7 +386,7 @@ mod tests {
 
     #[rocket::async_test]
     async fn test_protected_route_payment_required() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected")
                         .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
                         .dispatch().await;
@@ -332,7 +404,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_protected_route_with_valid_l402() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected")
                         .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", TEST_MACAROON_VALID, TEST_PREIMAGE_VALID)))
                         .dispatch().await;
@@ -346,7 +418,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_protected_route_with_invalid_preimage() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected")
                         .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", TEST_MACAROON_VALID, TEST_PREIMAGE_INVALID)))
                         .dispatch().await;
@@ -367,7 +439,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_protected_route_with_macaroon_without_caveats() {
-        let client = Client::tracked(rocket().await).await.expect("valid rocket instance");
+        let client = Client::tracked(rocket().await.expect("valid configuration")).await.expect("valid rocket instance");
         let response = client.get("/protected")
                         .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", TEST_MACAROON_WITHOUT_CAVEATS, TEST_MACAROON_WITHOUT_CAVEATS_PREIMAGE)))
                         .dispatch().await;
@@ -378,4 +450,2580 @@ mod tests {
         assert_eq!(json["code"], 500);
         assert_eq!(json["message"], "Error validating macaroon: Caveats don't match");
     }
+
+    // Answers `bytes=<first>-[<last>]` of a 1000-byte download with a 206, like a file server would
+    fn range_responses() -> rocket::fairing::AdHoc {
+        rocket::fairing::AdHoc::on_response("Ranges", |request, response| Box::pin(async move {
+            let Some(range) = request.headers().get_one(l402::L402_RANGE_HEADER_NAME).and_then(|range| range.strip_prefix("bytes=")) else {
+                return;
+            };
+            let Some((first, last)) = range.split_once('-').and_then(|(first, last)| Some((first.parse::<u64>().ok()?, last.parse::<u64>().unwrap_or(999)))) else {
+                return;
+            };
+            if response.status() == Status::Ok {
+                response.set_status(Status::PartialContent);
+                response.set_header(Header::new(l402::L402_CONTENT_RANGE_HEADER_NAME, format!("bytes {}-{}/1000", first, last)));
+            }
+        }))
+    }
+
+    async fn range_client() -> Client {
+        let rocket = rocket::build()
+            .attach(range_responses())
+            .attach(stub_middleware())
+            .mount("/", rocket::routes![super::protected]);
+        Client::tracked(rocket).await.expect("valid rocket instance")
+    }
+
+    #[rocket::async_test]
+    async fn test_range_continuation_does_not_consume_quota() {
+        let client = range_client().await;
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), "MaxUses = 1".to_string()]);
+
+        let first = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=0-99"))
+                        .dispatch().await;
+        assert_eq!(first.status(), Status::PartialContent);
+
+        let continuation = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=100-"))
+                        .dispatch().await;
+        assert_eq!(continuation.status(), Status::PartialContent);
+
+        // Past the quota, replayed, backwards and suffix ranges are charged like any request
+        for range in ["bytes=100-", "bytes=1-", "bytes=-100"] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .header(Header::new(l402::L402_RANGE_HEADER_NAME, range))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::PaymentRequired, "{}", range);
+        }
+
+        let fresh = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(fresh.status(), Status::PaymentRequired);
+        assert!(fresh.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap().starts_with("L402 macaroon="));
+    }
+
+    #[rocket::async_test]
+    async fn test_range_after_full_download_is_charged() {
+        let client = range_client().await;
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), "MaxUses = 2".to_string()]);
+
+        let full = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(full.status(), Status::Ok);
+
+        // The whole body was served, so re-fetching nearly all of it costs a use each time
+        let ranged = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=1-"))
+                        .dispatch().await;
+        assert_eq!(ranged.status(), Status::PartialContent);
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=2-"))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+    }
+
+    #[rocket::async_test]
+    async fn test_quota_exhausted_without_range() {
+        let client = stub_client(stub_middleware()).await;
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), "MaxUses = 2".to_string()]);
+
+        for _ in 0..2 {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=0-"))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+    }
+
+    #[test]
+    fn test_signed_url_roundtrip() {
+        let signer = signed_url::UrlSigner::new(signed_url::SignedUrlConfig {
+            secret: b"asset-secret".to_vec(),
+            ttl: std::time::Duration::from_secs(60),
+            base_url: Some("https://cdn.example.com/".to_string()),
+        });
+        let expires_at = utils::now_unix() + 60;
+        let url = signer.sign_for_token("/assets/video.mp4", "abcd", expires_at);
+        assert!(url.starts_with("https://cdn.example.com/assets/video.mp4?expires="));
+
+        let signature = url.rsplit("signature=").next().unwrap();
+        assert!(signer.verify("/assets/video.mp4", "abcd", expires_at, signature));
+        assert!(!signer.verify("/assets/other.mp4", "abcd", expires_at, signature));
+        assert!(!signer.verify("/assets/video.mp4", "abcd", utils::now_unix() - 1, signature));
+    }
+
+    #[rocket::async_test]
+    async fn test_content_hash_echoed_on_delivery() {
+        let content_hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.content_hash_func = Some(Arc::new(move |_req: &Request<'_>| Some(content_hash.to_string())));
+        let client = stub_client(l402_middleware).await;
+
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), format!("ContentHash = {}", content_hash)]);
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(l402::L402_CONTENT_HASH_HEADER_NAME), Some(content_hash));
+
+        let stale_token = stub_token(vec!["RequestPath = /protected".to_string(), format!("ContentHash = {}", "00".repeat(32))]);
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, stale_token))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[rocket::async_test]
+    async fn test_challenge_advertises_fiat_price() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.fiat_price_func = Some(Arc::new(|_req: &Request<'_>| {
+            fiat::FiatRateConfig { currency: "USD".to_string(), amount: 0.01, ..Default::default() }.fiat_price()
+        }));
+        let client = stub_client(l402_middleware).await;
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        let www_authenticate_header = response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        assert!(www_authenticate_header.ends_with(", price_fiat=\"0.01 USD\""));
+    }
+
+    #[rocket::async_test]
+    async fn test_payment_proof_export() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.proof = true;
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let payment_hash = hex::encode(PaymentHash::from(preimage).0);
+        let response = client.get(format!("/l402/proof/{}", payment_hash))
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let json: Value = response.into_json().await.expect("valid JSON response");
+        assert_eq!(json["preimage"], STUB_PREIMAGE);
+        assert_eq!(json["macaroon"], macaroon);
+        assert!(json["invoice"].as_str().unwrap().starts_with("lnbcrt10n1"));
+        assert!(json["settled_at"].as_u64().is_some());
+    }
+
+    #[rocket::async_test]
+    async fn test_reconciliation_revokes_unsettled_token() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.metrics = true;
+        let reconciler = reconcile::Reconciler {
+            config: reconcile::ReconciliationConfig {
+                revoke: true,
+                ..Default::default()
+            },
+            ln_client: Arc::clone(&l402_middleware.ln_client),
+            token_store: Arc::clone(&l402_middleware.token_store),
+            metrics: Arc::clone(&l402_middleware.metrics),
+            observers: Vec::new(),
+            events: Arc::clone(&l402_middleware.events),
+        };
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        assert_eq!(reconciler.run_once().await, 1);
+        // Already revoked tokens aren't reported again
+        assert_eq!(reconciler.run_once().await, 0);
+
+        let revoked = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_ne!(revoked.status(), Status::Ok);
+
+        let metrics = client.get("/l402/metrics").dispatch().await.into_string().await.unwrap();
+        assert!(metrics.contains("l402_settlement_discrepancies_total 1"));
+        assert!(metrics.contains("l402_tokens_revoked_total 1"));
+    }
+
+    #[tokio::test]
+    async fn test_doctor_report() {
+        let report = doctor::run_doctor(
+            Arc::new(StubLNClient),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            None,
+            true,
+        ).await;
+
+        let outcome = |name: &str| report.checks.iter().find(|c| c.name == name).unwrap().outcome.clone();
+        assert!(matches!(outcome("create invoice"), doctor::CheckOutcome::Pass(_)));
+        assert!(matches!(outcome("decode invoice"), doctor::CheckOutcome::Pass(_)));
+        // The stub can't cancel, which is reported but not a failure
+        assert!(matches!(outcome("cancel invoice"), doctor::CheckOutcome::Skip(_)));
+        assert!(matches!(outcome("macaroon roundtrip"), doctor::CheckOutcome::Pass(_)));
+        assert!(matches!(outcome("fiat rate provider"), doctor::CheckOutcome::Skip(_)));
+        assert!(report.passed());
+    }
+
+    #[rocket::async_test]
+    async fn test_usage_endpoint_reports_own_spend() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.max_uses = Some(3);
+        l402_middleware.endpoints.usage = true;
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+
+        for _ in 0..2 {
+            let paid = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .dispatch().await;
+            assert_eq!(paid.status(), Status::Ok);
+        }
+
+        let response = client.get("/l402/usage")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let json: Value = response.into_json().await.expect("valid JSON response");
+        assert_eq!(json["token"]["requests"], 2);
+        assert_eq!(json["token"]["spent_msat"], 1000);
+        assert_eq!(json["client"]["spent_msat"], 1000);
+        assert_eq!(json["max_uses"], 3);
+        assert_eq!(json["remaining_uses"], 1);
+
+        // A token minted with another root key is refused
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let forged = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), vec![], b"OTHERKEY".to_vec()).unwrap();
+        let response = client.get("/l402/usage")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", forged, STUB_PREIMAGE)))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_bundle_challenge_quota_matches_paid_invoice() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.max_uses = Some(1);
+        l402_middleware.bundle_func = Some(Arc::new(|_req: &Request<'_>| vec![
+            l402::BundleOption { uses: 3, amount_msat: Some(2500) },
+        ]));
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(challenge.status(), Status::PaymentRequired);
+        let challenges: Vec<&str> = challenge.headers().get(l402::L402_AUTHENTICATE_HEADER_NAME).collect();
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0].contains("uses=\"1\""));
+        assert!(challenges[1].contains("uses=\"3\""));
+        assert!(challenges[1].contains("invoice=lnbcrt25n1"));
+
+        let token = format!("L402 {}:{}", challenge_macaroon(challenges[1]), STUB_PREIMAGE);
+        for _ in 0..3 {
+            let paid = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .dispatch().await;
+            assert_eq!(paid.status(), Status::Ok);
+        }
+        let spent = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(spent.status(), Status::PaymentRequired);
+    }
+
+    #[test]
+    fn test_root_key_provisioning_roundtrip() {
+        let console_secret = x25519_dalek::StaticSecret::from([0x07; 32]);
+        let deployment_secret = x25519_dalek::StaticSecret::from([0x09; 32]);
+        let enrollment_token = b"enrollment-token";
+        let request = provision::ProvisioningRequest::signed("edge-1", &x25519_dalek::PublicKey::from(&deployment_secret), enrollment_token);
+
+        let response = provision::seal_root_key(&console_secret, enrollment_token, &request, STUB_ROOT_KEY.as_bytes()).unwrap();
+        let shared_secret = deployment_secret.diffie_hellman(&x25519_dalek::PublicKey::from(&console_secret));
+        let root_key = provision::open_root_key(shared_secret.as_bytes(), "edge-1", &response).unwrap();
+        assert_eq!(root_key, STUB_ROOT_KEY.as_bytes());
+
+        // Another deployment's id derives a different key
+        assert!(provision::open_root_key(shared_secret.as_bytes(), "edge-2", &response).is_err());
+        // And the console refuses requests not signed with the enrollment token
+        assert!(provision::seal_root_key(&console_secret, b"wrong-token", &request, STUB_ROOT_KEY.as_bytes()).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_inspect_endpoint_reports_without_access() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.inspect = true;
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+
+        let response = client.post("/l402/inspect")
+                        .header(rocket::http::ContentType::JSON)
+                        .body(format!("{{\"token\": \"{}:{}\"}}", macaroon, TEST_PREIMAGE_INVALID))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_none());
+        let json: Value = response.into_json().await.expect("valid JSON response");
+        assert_eq!(json["macaroon_valid"], true);
+        assert_eq!(json["signature_valid"], true);
+        assert_eq!(json["caveats"][0], "RequestPath = /protected");
+        assert_eq!(json["preimage_matches"], false);
+        assert_eq!(json["invoice_state"], "Open");
+        assert!(json["invoice_expires_at"].as_u64().is_some());
+
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let forged = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), vec![], b"OTHERKEY".to_vec()).unwrap();
+        let response = client.post("/l402/inspect")
+                        .header(rocket::http::ContentType::JSON)
+                        .body(format!("{{\"token\": \"{}\"}}", forged))
+                        .dispatch().await;
+        let json: Value = response.into_json().await.expect("valid JSON response");
+        assert_eq!(json["signature_valid"], false);
+        assert_eq!(json["preimage_matches"], Value::Null);
+        assert_eq!(json["invoice_state"], Value::Null);
+        assert!(json["invoice_state_error"].as_str().unwrap().contains("signature is invalid"));
+    }
+
+    #[rocket::async_test]
+    async fn test_trace_context_propagates_to_backend() {
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+        opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+        let exporter = InMemorySpanExporter::default();
+        opentelemetry::global::set_tracer_provider(SdkTracerProvider::builder().with_simple_exporter(exporter.clone()).build());
+
+        let backend_traceparent = Arc::new(std::sync::Mutex::new(None));
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.ln_client = Arc::new(TracingStubLNClient(Arc::clone(&backend_traceparent)));
+        let client = stub_client(l402_middleware).await;
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("traceparent", format!("00-{}-00f067aa0ba902b7-01", trace_id)))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+
+        // The backend call continues the client's trace from within the challenge span
+        let backend_traceparent = backend_traceparent.lock().unwrap().clone().unwrap();
+        assert!(backend_traceparent.starts_with(&format!("00-{}-", trace_id)));
+
+        let challenge_span = exporter.get_finished_spans().unwrap().into_iter()
+            .find(|span| span.name == "l402.challenge" && span.span_context.trace_id().to_string() == trace_id)
+            .expect("challenge span exported");
+        assert_eq!(challenge_span.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert!(backend_traceparent.contains(&challenge_span.span_context.span_id().to_string()));
+        assert!(challenge_span.attributes.iter().any(|kv| kv.key.as_str() == "l402.payment_hash"));
+    }
+
+    #[rocket::async_test]
+    async fn test_programmatic_config_reports_missing_options() {
+        // Misconfigured backends built in code surface errors instead of panicking
+        let config = lnclient::LNClientConfig {
+            ln_client_type: "CLN".to_string(),
+            root_key: STUB_ROOT_KEY.as_bytes().to_vec(),
+            ..Default::default()
+        };
+        let error = lnclient::LNClientConn::init(&config).await.err().expect("missing cln_config");
+        assert!(error.to_string().contains("cln_config"));
+
+        let config = lnclient::LNClientConfig {
+            ln_client_type: "LND".to_string(),
+            lnd_config: Some(lnd::LNDOptions {
+                address: Some("localhost:10009".to_string()),
+                macaroon_hex: Some("0201".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let error = lnclient::LNClientConn::init(&config).await.err().expect("missing cert");
+        assert!(error.to_string().contains("cert_pem"));
+    }
+
+    #[test]
+    fn test_accept_authenticate_negotiation() {
+        let l402_v1 = l402::AuthScheme { name: l402::L402_HEADER, version: 1 };
+        let lsat = l402::AuthScheme { name: l402::LSAT_HEADER, version: 0 };
+        assert_eq!(l402::negotiate_scheme("L402;v=1, LSAT"), Some(l402_v1));
+        assert_eq!(l402::negotiate_scheme("LSAT, L402;q=0.5"), Some(lsat));
+        assert_eq!(l402::negotiate_scheme("lsat, l402"), Some(l402_v1));
+        assert_eq!(l402::negotiate_scheme("L402;v=0"), Some(l402::AuthScheme { name: l402::L402_HEADER, version: 0 }));
+        assert_eq!(l402::negotiate_scheme("L402;v=2, LSAT;q=0.1"), Some(lsat));
+        assert_eq!(l402::negotiate_scheme("L402;q=0, Basic"), None);
+        assert_eq!(l402::negotiate_scheme("NotL402"), None);
+    }
+
+    #[rocket::async_test]
+    async fn test_lsat_client_gets_lsat_challenge() {
+        let client = stub_client(stub_middleware()).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, "L402;q=0.4, LSAT"))
+                        .dispatch().await;
+        assert_eq!(challenge.status(), Status::PaymentRequired);
+        let www_authenticate = challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        assert!(www_authenticate.starts_with("LSAT macaroon="));
+
+        let macaroon = www_authenticate.trim_start_matches("LSAT macaroon=").split(',').next().unwrap();
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("LSAT {}:{}", macaroon, STUB_PREIMAGE)))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_head_and_options_do_not_mint_invoices() {
+        let l402_middleware = stub_middleware();
+        let metrics = Arc::clone(&l402_middleware.metrics);
+        let client = stub_client(l402_middleware).await;
+
+        let head = client.head("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(head.status(), Status::PaymentRequired);
+        assert_eq!(head.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME), Some(l402::L402_HEADER));
+        assert!(head.into_bytes().await.unwrap_or_default().is_empty());
+
+        let options = client.options("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_ne!(options.status(), Status::PaymentRequired);
+        assert!(options.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_none());
+        assert_eq!(metrics.challenges_issued.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.head_challenge = middleware::HeadChallenge::Mint;
+        let client = stub_client(l402_middleware).await;
+        let head = client.head("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert!(head.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap().contains("invoice=lnbcrt"));
+    }
+
+    #[rocket::async_test]
+    async fn test_invoice_memo_and_lnurl_comment() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.memo_func = Some(Arc::new(|req: &Request<'_>| format!("L402 {}", req.uri().path())));
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let www_authenticate = challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        let invoice: lightning_invoice::Bolt11Invoice = www_authenticate.split("invoice=").nth(1).unwrap().parse().unwrap();
+        assert_eq!(invoice.description().to_string(), "L402 /protected");
+
+        let lnurl: lnurl::LnAddressUrlResJson = rocket::serde::json::serde_json::from_str(
+            r#"{"callback": "https://example.com/lnurlp/cb?k=1", "maxSendable": 100000000, "minSendable": 1000, "metadata": "[]", "commentAllowed": 9, "tag": "payRequest"}"#,
+        ).unwrap();
+        assert_eq!(lnurl.callback_url(10000, "L402 /protected").unwrap(), "https://example.com/lnurlp/cb?k=1&amount=10000&comment=L402+%2Fpro");
+
+        let lnurl: lnurl::LnAddressUrlResJson = rocket::serde::json::serde_json::from_str(
+            r#"{"callback": "https://example.com/lnurlp/cb", "maxSendable": 100000000, "minSendable": 1000, "metadata": "[]", "tag": "payRequest"}"#,
+        ).unwrap();
+        assert_eq!(lnurl.callback_url(10000, "L402 /protected").unwrap(), "https://example.com/lnurlp/cb?amount=10000");
+    }
+
+    #[rocket::async_test]
+    async fn test_static_offer_payment_accepted_once() {
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::new(OfferStubLNClient),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.advertise_offer = true;
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let www_authenticate = challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        assert!(www_authenticate.ends_with(", offer=\"lno1stuboffer\""));
+
+        // The preimage of the offer payment, not of the challenge invoice
+        let macaroon = challenge_macaroon(www_authenticate);
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", macaroon, TEST_PREIMAGE_INVALID)))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        // The same offer payment can't pay for a second challenge
+        let other_hash = PaymentHash([0x07; 32]);
+        let other_macaroon = macaroon_util::get_macaroon_as_string(other_hash, vec!["RequestPath = /protected".to_string()], STUB_ROOT_KEY.as_bytes().to_vec()).unwrap();
+        token_store.record_challenge(store::ChallengeRecord {
+            payment_hash: hex::encode(other_hash.0),
+            invoice: String::new(),
+            macaroon: other_macaroon.clone(),
+            amount_msat: 1000,
+            memo: String::new(),
+            backend: "stub".to_string(),
+            fingerprint: String::new(),
+            path: "/protected".to_string(),
+            created_at: utils::now_unix(),
+            preimage: None,
+            settled_at: None,
+            node_settled_at: None,
+        }).await.unwrap();
+        let reused = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", other_macaroon, TEST_PREIMAGE_INVALID)))
+                        .dispatch().await;
+        assert_eq!(reused.status(), Status::InternalServerError);
+    }
+
+    #[rocket::async_test]
+    async fn test_backend_attribution() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.proof = true;
+        l402_middleware.endpoints.metrics = true;
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        let metrics = client.get("/l402/metrics").dispatch().await.into_string().await.unwrap();
+        assert!(metrics.contains("l402_backend_challenges_issued_total{backend=\"stub\"} 1\n"));
+        assert!(metrics.contains("l402_backend_revenue_msat_total{backend=\"stub\"} 1000\n"));
+
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let proof: Value = client.get(format!("/l402/proof/{}", hex::encode(PaymentHash::from(preimage).0)))
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await
+                        .into_json().await.expect("valid JSON response");
+        assert_eq!(proof["backend"], "stub");
+    }
+
+    #[rocket::async_test]
+    async fn test_authorization_precedence() {
+        let token = stub_token(vec!["RequestPath = /protected".to_string()]);
+        let bad_token = token.replace(STUB_PREIMAGE, TEST_PREIMAGE_INVALID);
+
+        let client = stub_client(stub_middleware()).await;
+        let duplicated = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, bad_token.clone()))
+                        .dispatch().await;
+        assert_eq!(duplicated.status(), Status::InternalServerError);
+        let repeated = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, "Basic dXNlcjpwYXNz"))
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(repeated.status(), Status::Ok);
+
+        for (rule, expected) in [(middleware::DuplicateAuthorization::First, Status::Ok), (middleware::DuplicateAuthorization::Last, Status::InternalServerError)] {
+            let mut l402_middleware = stub_middleware();
+            l402_middleware.duplicate_authorization = rule;
+            let client = stub_client(l402_middleware).await;
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, bad_token.clone()))
+                            .dispatch().await;
+            assert_eq!(response.status(), expected);
+        }
+
+        // A session cookie alongside a failing header only wins when configured to
+        let session_config = session::SessionCookieConfig { secure: false, ..Default::default() };
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.session_cookie = Some(session_config.clone());
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        let session = paid.cookies().get(&session_config.name).unwrap().clone().into_owned();
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, bad_token.clone()))
+                        .cookie(session.clone())
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.session_cookie = Some(session_config);
+        l402_middleware.token_store = token_store;
+        l402_middleware.token_precedence = middleware::TokenPrecedence::Session;
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, bad_token))
+                        .cookie(session)
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_http_transport_stub() {
+        let invoice = lnclient::LNClient::add_invoice(&StubLNClient, lnclient::InvoiceRequest {
+            value_msat: 10000,
+            ..Default::default()
+        }).await.unwrap();
+        let http_transport = Arc::new(StubTransport(vec![
+            ("https://example.com/.well-known/lnurlp/shop".to_string(),
+                r#"{"callback": "https://example.com/cb", "maxSendable": 100000000, "minSendable": 1000, "metadata": "[]", "tag": "payRequest"}"#.to_string()),
+            ("https://example.com/cb?amount=10000".to_string(), format!(r#"{{"pr": "{}"}}"#, invoice.payment_request)),
+            ("https://blockchain.info/tobtc?currency=USD".to_string(), "0.00001".to_string()),
+        ]));
+
+        let ln_client = lnclient::LNClientConn::init(&lnclient::LNClientConfig {
+            ln_client_type: "LNURL".to_string(),
+            lnurl_config: Some(lnurl::LNURLOptions { address: "shop@example.com".to_string() }),
+            http_transport: Some(http_transport.clone()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(ln_client.backend_id(), "lnurl:shop@example.com");
+        let response = ln_client.add_invoice(lnclient::InvoiceRequest {
+            value_msat: 10000,
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response, invoice);
+
+        let fiat_rate_config = fiat::FiatRateConfig {
+            currency: "USD".to_string(),
+            amount: 1.0,
+            transport: Some(http_transport),
+            oracle: None,
+        };
+        assert_eq!(fiat_rate_config.fetch_btc_amount().await.unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn test_trusted_proxy_client_address() {
+        let trusted_proxies = forwarded::TrustedProxies {
+            networks: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut headers = rocket::http::HeaderMap::new();
+        headers.add(Header::new(forwarded::X_FORWARDED_FOR_HEADER_NAME, "198.51.100.7, 203.0.113.9"));
+        headers.add(Header::new(forwarded::X_FORWARDED_FOR_HEADER_NAME, "10.1.2.3"));
+
+        // The client-supplied 198.51.100.7 is ignored: 203.0.113.9 is the first untrusted hop
+        assert_eq!(trusted_proxies.resolve(Some("10.0.0.1".parse().unwrap()), &headers), Some("203.0.113.9".parse().unwrap()));
+        // Forwarding headers from untrusted peers aren't believed at all
+        assert_eq!(trusted_proxies.resolve(Some("192.0.2.1".parse().unwrap()), &headers), Some("192.0.2.1".parse().unwrap()));
+
+        let trusted_proxies = forwarded::TrustedProxies {
+            header: forwarded::ForwardedHeader::Forwarded,
+            ..trusted_proxies
+        };
+        let mut headers = rocket::http::HeaderMap::new();
+        headers.add(Header::new(forwarded::FORWARDED_HEADER_NAME, "for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.2"));
+        assert_eq!(trusted_proxies.resolve(Some("::1".parse().unwrap()), &headers), Some("2001:db8::17".parse().unwrap()));
+
+        let mut headers = rocket::http::HeaderMap::new();
+        headers.add(Header::new(forwarded::FORWARDED_HEADER_NAME, "for=unknown, for=10.0.0.2:8080"));
+        assert_eq!(trusted_proxies.resolve(Some("::1".parse().unwrap()), &headers), Some("10.0.0.2".parse().unwrap()));
+    }
+
+    #[rocket::async_test]
+    async fn test_sandbox_test_payments() {
+        let sandbox_config = sandbox::SandboxConfig {
+            test_preimage: Some(PaymentPreimage([0x5a; 32])),
+            test_payment_secret: Some("staging-secret".to_string()),
+            ..Default::default()
+        };
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.sandbox = Some(sandbox_config.clone());
+        let client = stub_client(l402_middleware).await;
+
+        let paid = client.get("/protected")
+                        .header(Header::new(sandbox::TEST_PAYMENT_HEADER_NAME, "staging-secret"))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+        let wrong_secret = client.get("/protected")
+                        .header(Header::new(sandbox::TEST_PAYMENT_HEADER_NAME, "guess"))
+                        .dispatch().await;
+        assert_ne!(wrong_secret.status(), Status::Ok);
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", macaroon, "5a".repeat(32))))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        // Ignition fails outside the allowed profiles
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.sandbox = Some(sandbox_config);
+        let rocket = rocket::custom(rocket::Config::figment().select("production")).attach(l402_middleware);
+        let Err(error) = Client::tracked(rocket).await else {
+            panic!("sandbox launched under the production profile");
+        };
+        assert!(matches!(error.kind(), rocket::error::ErrorKind::FailedFairings(_)));
+    }
+
+    #[rocket::async_test]
+    async fn test_rotated_root_key_gets_fresh_challenge() {
+        let client = stub_client(stub_middleware()).await;
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let stale_macaroon = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), vec!["RequestPath = /protected".to_string()], b"OLDROOTKEY".to_vec()).unwrap();
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", stale_macaroon, STUB_PREIMAGE)))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        let www_authenticate = response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        assert!(www_authenticate.contains("invoice=lnbcrt"));
+        assert_ne!(challenge_macaroon(www_authenticate), stale_macaroon);
+
+        // Other verification failures are still errors
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, stub_token(vec!["RequestPath = /protected".to_string()]).replace(STUB_PREIMAGE, TEST_PREIMAGE_INVALID)))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    // Keeps the events it is notified of
+    #[derive(Default)]
+    struct RecordingObserver(std::sync::Mutex<Vec<observer::L402Event>>);
+
+    impl observer::L402Observer for RecordingObserver {
+        fn on_event(&self, event: &observer::L402Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_anomaly_detection() {
+        let metrics = Arc::new(metrics::L402Metrics::new());
+        let recorder = Arc::new(RecordingObserver::default());
+        let detector = anomaly::AnomalyDetector::new(
+            anomaly::AnomalyConfig {
+                max_verification_failures: 5,
+                min_challenges: 4,
+                ..Default::default()
+            },
+            Arc::clone(&metrics),
+            vec![recorder.clone()],
+        );
+
+        for _ in 0..6 {
+            metrics::L402Metrics::incr(&metrics.tokens_rejected);
+        }
+        for _ in 0..4 {
+            metrics::L402Metrics::incr(&metrics.challenges_issued);
+            metrics::L402Metrics::incr(&metrics.invoice_errors);
+        }
+        metrics::L402Metrics::incr(&metrics.challenges_settled);
+        assert_eq!(detector.run_once(), vec![anomaly::AnomalyKind::VerificationFailureSpike]);
+
+        // An ongoing anomaly isn't reported again; a broken node is
+        for _ in 0..6 {
+            metrics::L402Metrics::incr(&metrics.tokens_rejected);
+            metrics::L402Metrics::incr(&metrics.invoice_errors);
+        }
+        assert_eq!(detector.run_once(), vec![anomaly::AnomalyKind::InvoiceErrorRate]);
+
+        // Once cleared, detectors re-arm
+        assert!(detector.run_once().is_empty());
+        for _ in 0..6 {
+            metrics::L402Metrics::incr(&metrics.tokens_rejected);
+        }
+        assert_eq!(detector.run_once(), vec![anomaly::AnomalyKind::VerificationFailureSpike]);
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        let json = rocket::serde::json::serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(json["type"], "anomaly");
+        assert_eq!(json["kind"], "invoice_error_rate");
+    }
+
+    // Places 203.0.113.0/24 in Brazil and 198.51.100.0/24 in a US datacenter
+    struct StubGeoProvider;
+
+    impl geo::GeoProvider for StubGeoProvider {
+        fn lookup(&self, ip: std::net::IpAddr) -> geo::GeoFuture<'_> {
+            let location = match ip.to_string() {
+                ip if ip.starts_with("203.0.113.") => Some(geo::ClientLocation { country: Some("BR".to_string()), asn: Some(64500), datacenter: false }),
+                ip if ip.starts_with("198.51.100.") => Some(geo::ClientLocation { country: Some("US".to_string()), asn: Some(64501), datacenter: true }),
+                _ => None,
+            };
+            Box::pin(async move { Ok(location) })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_geo_pricing() {
+        let mut geo_pricing = geo::GeoPricing::new(Arc::new(StubGeoProvider));
+        geo_pricing.country_multipliers.insert("BR".to_string(), 0.3);
+        geo_pricing.deny_free_tier_to_datacenters = true;
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.geo_pricing = Some(geo_pricing);
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+
+        for (remote, expected_msat) in [("203.0.113.5:4000", 300), ("198.51.100.9:4000", 1000), ("192.0.2.1:4000", 1000)] {
+            let response = client.get("/protected")
+                            .remote(remote.parse().unwrap())
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::PaymentRequired);
+            let challenge = token_store.get_challenge(&payment_hash).await.unwrap().unwrap();
+            assert_eq!(challenge.amount_msat, expected_msat);
+        }
+
+        // Clients without L402 support get the free tier, except from datacenters
+        #[cfg(not(feature = "no-accept-authenticate-required"))]
+        {
+            let residential = client.get("/protected")
+                            .remote("203.0.113.5:4000".parse().unwrap())
+                            .header(Header::new(l402::L402_HEADER_NAME, "Basic"))
+                            .dispatch().await;
+            assert_eq!(residential.status(), Status::Ok);
+            let datacenter = client.get("/protected")
+                            .remote("198.51.100.9:4000".parse().unwrap())
+                            .header(Header::new(l402::L402_HEADER_NAME, "Basic"))
+                            .dispatch().await;
+            assert_eq!(datacenter.status(), Status::PaymentRequired);
+            assert!(datacenter.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_some());
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_screening_hook() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.screening_func = Some(Arc::new(|req: &Request<'_>| {
+            let verdict = match req.headers().get_one("X-Abuse-Score") {
+                Some("high") => middleware::ScreeningVerdict::Deny,
+                Some("medium") => middleware::ScreeningVerdict::RaisePrice(5.0),
+                Some("low") => middleware::ScreeningVerdict::Delay(std::time::Duration::from_millis(50)),
+                _ => middleware::ScreeningVerdict::Allow,
+            };
+            Box::pin(async move { verdict })
+        }));
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+
+        let denied = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("X-Abuse-Score", "high"))
+                        .dispatch().await;
+        assert_eq!(denied.status(), Status::Forbidden);
+        assert!(denied.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_none());
+        assert!(token_store.get_challenge(&payment_hash).await.unwrap().is_none());
+
+        let started = std::time::Instant::now();
+        for (score, expected_msat) in [("medium", 5000), ("low", 1000)] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .header(Header::new("X-Abuse-Score", score))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::PaymentRequired);
+            assert_eq!(token_store.get_challenge(&payment_hash).await.unwrap().unwrap().amount_msat, expected_msat);
+        }
+        assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+    }
+
+    #[rocket::async_test]
+    async fn test_lsat_token_upgrade() {
+        let legacy_root_key = b"legacy-lsat-root-key".to_vec();
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        // Aperture-style identifier: version 0, payment hash, token id
+        let mut identifier = vec![0u8, 0];
+        identifier.extend_from_slice(&PaymentHash::from(preimage).0);
+        identifier.extend_from_slice(&[0x07; 32]);
+        let mut legacy = macaroon::Macaroon::create(
+            Some(l402::LSAT_HEADER.into()),
+            &macaroon::MacaroonKey::generate(&legacy_root_key),
+            identifier.into(),
+        ).unwrap();
+        legacy.add_first_party_caveat("RequestPath = /protected".into());
+        let legacy_token = format!("LSAT {}:{}", legacy.serialize(macaroon::Format::V1).unwrap(), STUB_PREIMAGE);
+
+        // Tokens of another deployment, or without proof of payment, aren't upgraded
+        assert!(lsat::upgrade_token(&legacy_token, STUB_ROOT_KEY.as_bytes(), STUB_ROOT_KEY.as_bytes()).is_err());
+        let unpaid = legacy_token.replace(STUB_PREIMAGE, &"00".repeat(32));
+        assert!(lsat::upgrade_token(&unpaid, &legacy_root_key, STUB_ROOT_KEY.as_bytes()).is_err());
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.upgrade = true;
+        l402_middleware.legacy_root_key = Some(legacy_root_key);
+        let client = stub_client(l402_middleware).await;
+
+        let rejected = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, legacy_token.clone()))
+                        .dispatch().await;
+        assert_ne!(rejected.status(), Status::Ok);
+
+        let upgrade = client.post("/l402/upgrade")
+                        .header(rocket::http::ContentType::JSON)
+                        .body(rocket::serde::json::serde_json::json!({ "token": legacy_token }).to_string())
+                        .dispatch().await;
+        assert_eq!(upgrade.status(), Status::Ok);
+        let upgraded: Value = upgrade.into_json().await.unwrap();
+        assert_eq!(upgraded["payment_hash"], hex::encode(PaymentHash::from(preimage).0));
+        assert_eq!(upgraded["caveats"][0], "RequestPath = /protected");
+
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, upgraded["token"].as_str().unwrap().to_string()))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_memo_policy() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.memo.policy = lnclient::MemoPolicy::Template("{amount_sat} sat for {path} via {backend}".to_string());
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+
+        client.get("/protected")
+            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+            .dispatch().await;
+        let challenge = token_store.get_challenge(&payment_hash).await.unwrap().unwrap();
+        assert_eq!(challenge.memo, "1 sat for /protected via stub");
+        let invoice: lightning_invoice::Bolt11Invoice = challenge.invoice.parse().unwrap();
+        assert_eq!(invoice.description().to_string(), challenge.memo);
+
+        let random = lnclient::MemoPolicy::Random;
+        assert_ne!(random.render("/", 1000, "stub"), random.render("/", 1000, "stub"));
+        assert_eq!(lnclient::MemoPolicy::Scheme.render("/", 1000, "stub"), "L402");
+
+        // A hashed memo is never sent in plaintext; the invoice commits to its SHA256
+        let hashed = lnclient::InvoiceRequest { memo: "1 sat for /protected".to_string(), description_hash: true, ..Default::default() };
+        assert_eq!(hashed.description(), None);
+        assert_eq!(hashed.memo_hash(), Some(sha256::Hash::hash(hashed.memo.as_bytes()).to_byte_array()));
+        let plain = lnclient::InvoiceRequest { description_hash: false, ..hashed };
+        assert_eq!(plain.description().as_deref(), Some("1 sat for /protected"));
+        assert_eq!(plain.memo_hash(), None);
+    }
+
+    // Reports the invoice settled from the third lookup on
+    struct SettlingStubLNClient(std::sync::atomic::AtomicU64);
+
+    impl lnclient::LNClient for SettlingStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            StubLNClient.add_invoice(invoice)
+        }
+
+        fn lookup_invoice(&self, _payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+            let lookups = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            Box::pin(async move {
+                Ok(if lookups >= 3 { lnclient::InvoiceState::Settled } else { lnclient::InvoiceState::Open })
+            })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_await_settlement() {
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::new(SettlingStubLNClient(Default::default())),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.endpoints.await_settlement = true;
+        l402_middleware.endpoints.settlement_poll_interval = std::time::Duration::from_millis(10);
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+
+        // Unknown payment hashes can't be awaited
+        let unknown = client.get(format!("/l402/await/{}", payment_hash)).dispatch().await;
+        assert_eq!(unknown.status(), Status::NotFound);
+
+        client.get("/protected")
+            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+            .dispatch().await;
+        // A wait shorter than the settlement returns the open state
+        let open = client.get(format!("/l402/await/{}?timeout=0", payment_hash)).dispatch().await;
+        let open: Value = open.into_json().await.unwrap();
+        assert_eq!(open["state"], "Open");
+
+        let settled = client.get(format!("/l402/await/{}", payment_hash.to_uppercase())).dispatch().await;
+        assert_eq!(settled.status(), Status::Ok);
+        let settled: Value = settled.into_json().await.unwrap();
+        assert_eq!(settled["payment_hash"], payment_hash);
+        assert_eq!(settled["state"], "Settled");
+    }
+
+    #[rocket::async_test]
+    async fn test_price_escalation() {
+        let policy = escalation::EscalationConfig::default();
+        assert_eq!(policy.multiplier(5), 1.0);
+        assert_eq!(policy.multiplier(6), 2.0);
+        assert_eq!(policy.multiplier(100), 64.0);
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.price_escalation = Some(policy);
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        // Local requests have neither a peer address nor a user agent
+        let fingerprint = hex::encode(&sha256::Hash::hash(b"\n").to_byte_array()[..16]);
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+
+        let now = utils::now_unix();
+        for i in 0..7u8 {
+            token_store.record_challenge(store::ChallengeRecord {
+                payment_hash: hex::encode([i; 32]),
+                invoice: String::new(),
+                macaroon: String::new(),
+                amount_msat: 1000,
+                memo: String::new(),
+                backend: "stub".to_string(),
+                fingerprint: fingerprint.clone(),
+                path: "/protected".to_string(),
+                created_at: now - 10 + i as u64,
+                preimage: None,
+                settled_at: None,
+                node_settled_at: None,
+            }).await.unwrap();
+        }
+        let challenge = || client.get("/protected").header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER)).dispatch();
+
+        challenge().await;
+        assert_eq!(token_store.get_challenge(&payment_hash).await.unwrap().unwrap().amount_msat, 4000);
+
+        // Paying clears the challenges issued before the payment
+        token_store.mark_settled(&hex::encode([6u8; 32]), &"00".repeat(32), now).await.unwrap();
+        challenge().await;
+        assert_eq!(token_store.get_challenge(&payment_hash).await.unwrap().unwrap().amount_msat, 1000);
+    }
+
+    #[rocket::async_test]
+    async fn test_token_introspection() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.introspect = true;
+        l402_middleware.endpoints.introspection_clients.insert("billing".to_string(), "s3cret".to_string());
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), format!("{} = 2", l402::MAX_USES_CAVEAT)]);
+        let form = serde_urlencoded::to_string([("token", &token)]).unwrap();
+        let introspect = |credentials: &str| client.post("/l402/introspect")
+            .header(rocket::http::ContentType::Form)
+            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, credentials))))
+            .body(form.clone())
+            .dispatch();
+
+        assert_eq!(introspect("billing:guess").await.status(), Status::Unauthorized);
+        assert_eq!(introspect("other:s3cret").await.status(), Status::Unauthorized);
+
+        let active: Value = introspect("billing:s3cret").await.into_json().await.unwrap();
+        assert_eq!(active["active"], true);
+        assert_eq!(active["token_type"], "L402");
+        assert_eq!(active["scope"], "RequestPath=/protected MaxUses=2");
+        assert_eq!(active["sub"], hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0));
+        assert_eq!(active["remaining_uses"], 2);
+
+        // A spent quota makes the token inactive, and nothing else is disclosed
+        let token_id = active["sub"].as_str().unwrap().to_string();
+        for resource in ["/a", "/b"] {
+            token_store.consume_use(&token_id, resource, None, 2).await.unwrap();
+        }
+        let spent: Value = introspect("billing:s3cret").await.into_json().await.unwrap();
+        assert_eq!(spent, rocket::serde::json::serde_json::json!({ "active": false }));
+    }
+
+    #[rocket::async_test]
+    async fn test_introspection_validity_window() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.introspect = true;
+        l402_middleware.endpoints.introspection_clients.insert("billing".to_string(), "s3cret".to_string());
+        let client = stub_client(l402_middleware).await;
+        let introspect = |caveat: String| {
+            let form = serde_urlencoded::to_string([("token", stub_token(vec![caveat]))]).unwrap();
+            client.post("/l402/introspect")
+                .header(rocket::http::ContentType::Form)
+                .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "billing:s3cret"))))
+                .body(form)
+                .dispatch()
+        };
+
+        let now = utils::now_unix();
+        let expired: Value = introspect(format!("{} = {}", l402::NOT_AFTER_CAVEAT, now - 60)).await.into_json().await.unwrap();
+        assert_eq!(expired, rocket::serde::json::serde_json::json!({ "active": false, "inactive_reason": "token_expired" }));
+        let early: Value = introspect(format!("{} = {}", l402::NOT_BEFORE_CAVEAT, now + 3600)).await.into_json().await.unwrap();
+        assert_eq!(early["active"], false);
+        assert_eq!(early["inactive_reason"], "token_not_yet_valid");
+
+        let valid: Value = introspect(format!("{} = {}", l402::NOT_AFTER_CAVEAT, now + 3600)).await.into_json().await.unwrap();
+        assert_eq!(valid["active"], true);
+        assert_eq!(valid["exp"], now + 3600);
+    }
+
+    #[rocket::async_test]
+    async fn test_route_catalog() {
+        let mut protected = catalog::PricedRoute::new("get", "/protected", catalog::RoutePrice::Msat(2500));
+        protected.scope = vec!["RequestPath = /protected".to_string()];
+        protected.bundles = vec![l402::BundleOption { uses: 10, amount_msat: None }];
+        let mut report = catalog::PricedRoute::new("GET", "/report", catalog::RoutePrice::Fiat(fiat::FiatRateConfig {
+            currency: "USD".to_string(),
+            amount: 0.01,
+            transport: Some(Arc::new(StubTransport(vec![
+                ("https://blockchain.info/tobtc?currency=USD".to_string(), "0.00001".to_string()),
+            ]))),
+            oracle: None,
+        }));
+        report.description = Some("Daily report".to_string());
+        let pricing_table = Arc::new(catalog::PricingTable { routes: vec![protected, report], default_msat: 1000 });
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.amount_func = pricing_table.amount_func();
+        l402_middleware.fiat_price_func = Some(pricing_table.fiat_price_func());
+        l402_middleware.bundle_func = Some(pricing_table.bundle_func());
+        l402_middleware.pricing_table = Some(pricing_table);
+        l402_middleware.endpoints.catalog = true;
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+
+        let catalog: Value = client.get("/l402/catalog").dispatch().await.into_json().await.unwrap();
+        assert_eq!(catalog[0]["method"], "GET");
+        assert_eq!(catalog[0]["path"], "/protected");
+        assert_eq!(catalog[0]["price_msat"], 2500);
+        assert_eq!(catalog[0]["scope"][0], "RequestPath = /protected");
+        assert_eq!(catalog[0]["bundles"][0]["price_msat"], 25000);
+        assert_eq!(catalog[1]["price_msat"], 1000000);
+        assert_eq!(catalog[1]["price_fiat"], "0.01 USD");
+        assert_eq!(catalog[1]["description"], "Daily report");
+
+        // Challenges charge the listed price
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(challenge.headers().get(l402::L402_AUTHENTICATE_HEADER_NAME).count(), 2);
+        // The stub reuses one payment hash, so the ledger holds the bundle, minted last
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+        assert_eq!(token_store.get_challenge(&payment_hash).await.unwrap().unwrap().amount_msat, 25000);
+    }
+
+    // Adapters for other frameworks share these across threads and tasks
+    #[test]
+    fn test_core_types_are_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<l402::L402Info>();
+        assert_send_sync::<l402::AuthScheme>();
+        assert_send_sync::<l402::BundleOption>();
+        assert_send_sync::<macaroon::Macaroon>();
+        assert_send_sync::<lnclient::InvoiceRequest>();
+        assert_send_sync::<Arc<dyn lnclient::LNClient>>();
+        assert_send_sync::<Arc<dyn store::TokenStore>>();
+        assert_send_sync::<middleware::L402Middleware>();
+        assert_send_sync::<Box<dyn std::error::Error + Send + Sync>>();
+
+        // Verification and minting errors can cross threads
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let handle = std::thread::spawn(move || {
+            let mac = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), vec![], STUB_ROOT_KEY.as_bytes().to_vec())?;
+            let mac = utils::get_macaroon_from_string(mac)?;
+            l402::verify_l402(&mac, vec!["RequestPath = /protected".to_string()], STUB_ROOT_KEY.as_bytes().to_vec(), preimage)
+        });
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[derive(Debug)]
+    struct OracleTransport {
+        body: String,
+        signature: String,
+    }
+
+    impl transport::HttpTransport for OracleTransport {
+        fn send(&self, request: transport::HttpRequest) -> transport::HttpFuture {
+            let response = match request.uri().to_string().as_str() {
+                "https://oracle.internal/price?currency=USD" => http::Response::builder()
+                    .header(fiat::ORACLE_SIGNATURE_HEADER_NAME, self.signature.as_str())
+                    .body(self.body.clone().into_bytes()),
+                _ => http::Response::builder().status(404).body(Vec::new()),
+            };
+            Box::pin(async move { Ok(response?) })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_signed_rate_oracle() {
+        use hmac::{Hmac, Mac};
+        let secret = b"oracle-secret".to_vec();
+        let sign = |body: &str| {
+            let mut mac = <Hmac<sha2::Sha256> as Mac>::new_from_slice(&secret).unwrap();
+            mac.update(body.as_bytes());
+            hex::encode(mac.finalize().into_bytes())
+        };
+        let quote = |price: f64, timestamp: u64| format!(r#"{{"currency":"USD","btc_price":{},"timestamp":{}}}"#, price, timestamp);
+        let rate_config = |body: String, signature: String| fiat::FiatRateConfig {
+            currency: "USD".to_string(),
+            amount: 0.5,
+            transport: Some(Arc::new(OracleTransport { body, signature })),
+            oracle: Some(fiat::RateOracle {
+                secret: Some(secret.clone()),
+                ..fiat::RateOracle::new("https://oracle.internal/price")
+            }),
+        };
+
+        // 0.5 USD at 50,000 USD/BTC is 1,000 sats
+        let fresh = quote(50000.0, utils::now_unix());
+        assert_eq!(rate_config(fresh.clone(), sign(&fresh)).fetch_btc_amount().await.unwrap(), 1_000_000);
+
+        // Tampered, unsigned and stale quotes are rejected, and the hook falls back to the minimum
+        let tampered = quote(5.0, utils::now_unix());
+        assert!(rate_config(tampered.clone(), sign(&fresh)).fetch_btc_amount().await.is_err());
+        assert!(rate_config(fresh.clone(), String::new()).fetch_btc_amount().await.is_err());
+        let stale = quote(50000.0, utils::now_unix() - 3600);
+        assert!(rate_config(stale.clone(), sign(&stale)).fetch_btc_amount().await.is_err());
+        assert_eq!(rate_config(tampered.clone(), sign(&fresh)).fiat_to_btc_amount_func().await, fiat::MIN_SATS_TO_BE_PAID * fiat::MSAT_PER_SAT);
+    }
+
+    #[rocket::async_test]
+    async fn test_validity_window() {
+        let now = utils::now_unix();
+        let windows = [
+            (l402::ValidityWindow { not_before: now + 3600, not_after: Some(now + 7200) }, Some("token_not_yet_valid")),
+            (l402::ValidityWindow { not_before: now - 7200, not_after: Some(now - 3600) }, Some("token_expired")),
+            (l402::ValidityWindow { not_before: now - 60, not_after: None }, None),
+        ];
+        for (window, error_code) in windows {
+            let mut l402_middleware = stub_middleware();
+            l402_middleware.validity_func = Some(Arc::new(move |_req: &Request<'_>| Some(window)));
+            let client = stub_client(l402_middleware).await;
+
+            // Tokens are minted ahead of the window
+            let challenge = client.get("/protected")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            assert_eq!(challenge.status(), Status::PaymentRequired);
+            let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+            let mac = utils::get_macaroon_from_string(macaroon.trim_matches('"').to_string()).unwrap();
+            assert_eq!(l402::get_caveat_value(&mac, l402::NOT_BEFORE_CAVEAT), Some(window.not_before.to_string()));
+
+            let mut caveats = vec!["RequestPath = /protected".to_string()];
+            caveats.extend(window.caveats());
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, stub_token(caveats)))
+                            .dispatch().await;
+            match error_code {
+                Some(error_code) => {
+                    assert_eq!(response.status(), Status::Forbidden);
+                    assert_eq!(response.headers().get_one(l402::L402_ERROR_HEADER_NAME), Some(error_code));
+                    let retry_after = response.headers().get_one("Retry-After").map(|value| value.parse::<u64>().unwrap());
+                    assert_eq!(retry_after.is_some_and(|secs| secs > 3500), error_code == "token_not_yet_valid");
+                },
+                None => assert_eq!(response.status(), Status::Ok),
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_max_concurrent_requests() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.max_concurrent = Some(1);
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let mac = utils::get_macaroon_from_string(macaroon.trim_matches('"').to_string()).unwrap();
+        assert_eq!(l402::get_caveat_value(&mac, l402::MAX_CONCURRENT_CAVEAT), Some("1".to_string()));
+
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), format!("{} = 1", l402::MAX_CONCURRENT_CAVEAT)]);
+        // The first response's body hasn't been read yet, so its slot is still taken
+        let streaming = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(streaming.status(), Status::Ok);
+        let excess = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .dispatch().await;
+        assert_eq!(excess.status(), Status::TooManyRequests);
+        assert_eq!(excess.headers().get_one(l402::L402_ERROR_HEADER_NAME), Some(l402::TOO_MANY_CONCURRENT_ERROR));
+
+        let json: Value = streaming.into_json().await.expect("valid JSON response");
+        assert_eq!(json["message"], "Protected content");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        let next = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(next.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn test_verification_failure_reasons() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.failure_sampling = Some(metrics::FailureSampling { rate: 1.0, capacity: 3 });
+        l402_middleware.endpoints.metrics = true;
+        l402_middleware.endpoints.failures = true;
+        l402_middleware.endpoints.introspection_clients.insert("ops".to_string(), "s3cret".to_string());
+        let client = stub_client(l402_middleware).await;
+
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let foreign_mac = macaroon_util::get_macaroon_as_string(PaymentHash::from(preimage), vec!["RequestPath = /protected".to_string()], b"other-root-key".to_vec()).unwrap();
+        let stub_mac = stub_token(vec!["RequestPath = /protected".to_string()]).trim_start_matches("L402 ").split(':').next().unwrap().to_string();
+        let tokens = [
+            "L402 not-a-macaroon:zz".to_string(),
+            format!("L402 {}:{}", foreign_mac, STUB_PREIMAGE),
+            stub_token(vec!["RequestPath = /other".to_string()]),
+            format!("LSAT {}:{}", stub_mac, TEST_PREIMAGE_INVALID),
+        ];
+        for token in tokens {
+            client.get("/protected")
+                .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                .header(Header::new("User-Agent", "interop-client/0.1"))
+                .dispatch().await;
+        }
+
+        let rendered = client.get("/l402/metrics").dispatch().await.into_string().await.unwrap();
+        for reason in ["malformed", "wrong_signature", "caveat_mismatch", "unsettled"] {
+            assert!(rendered.contains(&format!("l402_verification_failures_total{{reason=\"{}\"}} 1", reason)), "{}", reason);
+        }
+        assert!(rendered.contains("l402_tokens_rejected_total 4"));
+
+        let unauthorized = client.get("/l402/failures").dispatch().await;
+        assert_eq!(unauthorized.status(), Status::Unauthorized);
+        let report: Value = client.get("/l402/failures")
+            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "ops:s3cret"))))
+            .dispatch().await.into_json().await.unwrap();
+        assert_eq!(report["reasons"]["caveat_mismatch"], 1);
+        // Only the newest samples are kept, and they carry no token material
+        let samples = report["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0]["reason"], "wrong_signature");
+        assert_eq!(samples[1]["caveats"][0], "RequestPath = /other");
+        assert_eq!(samples[2]["reason"], "unsettled");
+        assert_eq!(samples[2]["scheme"], "LSAT");
+        assert_eq!(samples[2]["user_agent"], "interop-client/0.1");
+        assert!(!report.to_string().contains(STUB_PREIMAGE));
+    }
+
+    #[rocket::async_test]
+    async fn test_node_settlement_ingestion() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.settlements = true;
+        l402_middleware.endpoints.await_settlement = true;
+        l402_middleware.endpoints.settlement_clients.insert("cln-plugin".to_string(), "n0de".to_string());
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+        let basic = |credentials: &str| Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, credentials)));
+
+        client.get("/protected")
+            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+            .dispatch().await;
+
+        let eclair_event = |event_type: &str, hash: &str| format!(r#"{{"type":"{}","paymentHash":"{}","parts":[{{"amount":1000}}]}}"#, event_type, hash);
+        let unauthenticated = client.post("/l402/settlements/eclair")
+            .header(rocket::http::ContentType::JSON)
+            .body(eclair_event("payment-received", &payment_hash))
+            .dispatch().await;
+        assert_eq!(unauthenticated.status(), Status::Unauthorized);
+        let relayed: Value = client.post("/l402/settlements/eclair")
+            .header(rocket::http::ContentType::JSON)
+            .header(basic("cln-plugin:n0de"))
+            .body(eclair_event("payment-relayed", &payment_hash))
+            .dispatch().await.into_json().await.unwrap();
+        assert_eq!(relayed["recorded"], false);
+
+        // Without a notification the wait times out open, though the stub backend is never asked
+        let open: Value = client.get(format!("/l402/await/{}?timeout=0", payment_hash)).dispatch().await.into_json().await.unwrap();
+        assert_eq!(open["state"], "Open");
+
+        let cln_payment = format!(r#"{{"payment":{{"label":"l402","preimage":"{}","msat":"1000msat"}}}}"#, STUB_PREIMAGE);
+        let recorded: Value = client.post("/l402/settlements/cln")
+            .header(rocket::http::ContentType::JSON)
+            .header(basic("cln-plugin:n0de"))
+            .body(cln_payment)
+            .dispatch().await.into_json().await.unwrap();
+        assert_eq!(recorded["payment_hash"], payment_hash.as_str());
+        assert_eq!(recorded["recorded"], true);
+        let settled: Value = client.get(format!("/l402/await/{}?timeout=0", payment_hash)).dispatch().await.into_json().await.unwrap();
+        assert_eq!(settled["state"], "Settled");
+
+        // Invoices the middleware didn't issue are acknowledged but not recorded
+        let unknown: Value = client.post("/l402/settlements/eclair")
+            .header(rocket::http::ContentType::JSON)
+            .header(basic("cln-plugin:n0de"))
+            .body(eclair_event("payment-received", &hex::encode([0x07; 32])))
+            .dispatch().await.into_json().await.unwrap();
+        assert_eq!(unknown["recorded"], false);
+    }
+
+    #[rocket::async_test]
+    async fn test_generated_client_snippets() {
+        let mut protected = catalog::PricedRoute::new("GET", "/protected", catalog::RoutePrice::Msat(2500));
+        protected.description = Some("Protected content".to_string());
+        let pricing_table = Arc::new(catalog::PricingTable { routes: vec![protected], default_msat: 1000 });
+
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.pricing_table = Some(pricing_table);
+        l402_middleware.endpoints.client_snippets = true;
+        let client = stub_client(l402_middleware).await;
+
+        let snippets: Value = client.get("/l402/catalog/client")
+                        .header(Header::new("Host", "api.example.com"))
+                        .dispatch().await.into_json().await.unwrap();
+        let rust = snippets["rust"].as_str().unwrap();
+        assert!(rust.contains("const BASE_URL: &str = \"http://api.example.com\";"));
+        assert!(rust.contains("// Protected content: 2500 msat\n    (\"GET\", \"/protected\"),"));
+        assert!(rust.contains("PayInvoiceRequest::new(invoice)"));
+        assert!(!rust.contains("__"));
+        let curl = snippets["curl"].as_str().unwrap();
+        assert!(curl.contains("curl -i -X GET -H 'Accept-Authenticate: L402' 'http://api.example.com/protected'"));
+        assert!(curl.contains("-H 'Authorization: L402 <macaroon>:<preimage>'"));
+
+        // An explicit base URL wins over the Host header
+        let snippets: Value = client.get("/l402/catalog/client?base_url=https://paid.example.com/")
+                        .header(Header::new("Host", "api.example.com"))
+                        .dispatch().await.into_json().await.unwrap();
+        assert!(snippets["curl"].as_str().unwrap().contains("'https://paid.example.com/protected'"));
+
+        // Behind a trusted proxy that terminates TLS, the snippets use https
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.pricing_table = Some(Arc::new(catalog::PricingTable { routes: Vec::new(), default_msat: 1000 }));
+        l402_middleware.endpoints.client_snippets = true;
+        l402_middleware.trusted_proxies.networks = vec!["10.0.0.0/8".parse().unwrap()];
+        let client = stub_client(l402_middleware).await;
+        let snippets_from = |peer: &str| client.get("/l402/catalog/client")
+                        .remote(format!("{}:443", peer).parse().unwrap())
+                        .header(Header::new("Host", "api.example.com"))
+                        .header(Header::new(forwarded::X_FORWARDED_PROTO_HEADER_NAME, "https"))
+                        .dispatch();
+        let proxied: Value = snippets_from("10.0.0.2").await.into_json().await.unwrap();
+        assert!(proxied["rust"].as_str().unwrap().contains("const BASE_URL: &str = \"https://api.example.com\";"));
+        let spoofed: Value = snippets_from("203.0.113.9").await.into_json().await.unwrap();
+        assert!(spoofed["rust"].as_str().unwrap().contains("const BASE_URL: &str = \"http://api.example.com\";"));
+
+        // And a configured public base URL wins over the request
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.pricing_table = Some(Arc::new(catalog::PricingTable { routes: Vec::new(), default_msat: 1000 }));
+        l402_middleware.endpoints.client_snippets = true;
+        l402_middleware.endpoints.public_base_url = Some("https://paid.example.com".to_string());
+        let client = stub_client(l402_middleware).await;
+        let snippets: Value = client.get("/l402/catalog/client")
+                        .header(Header::new("Host", "api.example.com"))
+                        .dispatch().await.into_json().await.unwrap();
+        assert!(snippets["rust"].as_str().unwrap().contains("const BASE_URL: &str = \"https://paid.example.com\";"));
+    }
+
+    #[rocket::async_test]
+    async fn test_macaroon_encodings() {
+        let encodings = [
+            (macaroon_util::MacaroonEncoding::Base64Url, "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_="),
+            (macaroon_util::MacaroonEncoding::Base64UrlNoPad, "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_"),
+            (macaroon_util::MacaroonEncoding::Base64, "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/="),
+            (macaroon_util::MacaroonEncoding::Hex, "0123456789abcdef"),
+        ];
+        for (encoding, alphabet) in encodings {
+            let mut l402_middleware = stub_middleware();
+            l402_middleware.macaroon_encoding = encoding;
+            let client = stub_client(l402_middleware).await;
+
+            let challenge = client.get("/protected")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+            let macaroon = macaroon.trim_matches('"');
+            assert!(macaroon.chars().all(|c| alphabet.contains(c)), "{:?}: {}", encoding, macaroon);
+
+            // Alby's fetchWithL402 presents the challenge macaroon exactly as received
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", macaroon, STUB_PREIMAGE)))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{:?}", encoding);
+
+            // lsat-js and Aperture tokens carry V2 binary macaroons in standard base64
+            let mac = utils::get_macaroon_from_string(macaroon.to_string()).unwrap();
+            let v2 = base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE, mac.serialize(macaroon::Format::V2).unwrap()).unwrap();
+            let v2_standard = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &v2);
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("LSAT {}:{}", v2_standard, STUB_PREIMAGE)))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::Ok, "{:?}", encoding);
+        }
+
+        // Parsing is lenient about quotes, padding and alphabet
+        let mac = macaroon_util::get_macaroon_as_string(PaymentHash([0x42; 32]), vec!["RequestPath = /protected".to_string()], STUB_ROOT_KEY.as_bytes().to_vec()).unwrap();
+        let unpadded = mac.trim_end_matches('=');
+        let standard = mac.replace('-', "+").replace('_', "/");
+        for variant in [format!("\"{}\"", mac), unpadded.to_string(), standard] {
+            let parsed = utils::get_macaroon_from_string(variant).unwrap();
+            assert_eq!(l402::macaroon_payment_hash(&parsed), Some([0x42; 32]));
+        }
+    }
+
+    // Backend that answers every invoice request with the same response
+    struct FixedInvoiceStubLNClient(lnclient::InvoiceResponse);
+
+    impl lnclient::LNClient for FixedInvoiceStubLNClient {
+        fn add_invoice(&self, _invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            let response = self.0.clone();
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_malformed_backend_invoices_rejected() {
+        let payment_hash = PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0;
+        let invoice = |amount_msat| regtest_invoice("L402".to_string(), payment_hash, amount_msat).unwrap();
+        let cases = [
+            (String::new(), payment_hash, lnclient::InvoiceValidationError::EmptyPaymentRequest),
+            (invoice(Some(1000)), [0x07; 32], lnclient::InvoiceValidationError::PaymentHashMismatch),
+            (invoice(Some(2000)), payment_hash, lnclient::InvoiceValidationError::AmountMismatch { requested_msat: 1000, invoice_msat: Some(2000) }),
+            (invoice(None), payment_hash, lnclient::InvoiceValidationError::AmountMismatch { requested_msat: 1000, invoice_msat: None }),
+        ];
+        for (payment_request, reported_hash, expected) in cases {
+            let ln_client = Arc::new(FixedInvoiceStubLNClient(lnclient::InvoiceResponse { payment_request, payment_hash: reported_hash }));
+            let ln_client_conn = lnclient::LNClientConn { ln_client: ln_client.clone() };
+            let error = ln_client_conn.generate_invoice(lnclient::InvoiceRequest { value_msat: 1000, ..Default::default() }).await.unwrap_err();
+            assert_eq!(error.downcast_ref::<lnclient::InvoiceValidationError>(), Some(&expected));
+
+            // No challenge carries the malformed invoice
+            let l402_middleware = middleware::L402Middleware::new_with_ln_client(
+                ln_client,
+                STUB_ROOT_KEY.as_bytes().to_vec(),
+                Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+                Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+            );
+            let client = stub_client(l402_middleware).await;
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            assert_ne!(response.status(), Status::PaymentRequired);
+            assert!(response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_none());
+        }
+
+        let error = lnclient::InvoiceResponse { payment_request: "lnbcrt1garbage".to_string(), payment_hash }
+            .validate(&lnclient::InvoiceRequest { value_msat: 1000, ..Default::default() }).unwrap_err();
+        assert!(matches!(error, lnclient::InvoiceValidationError::Undecodable(_)));
+        let valid = lnclient::InvoiceResponse { payment_request: invoice(Some(1000)), payment_hash };
+        assert_eq!(valid.validate(&lnclient::InvoiceRequest { value_msat: 1000, ..Default::default() }), Ok(()));
+    }
+
+    #[rocket::async_test]
+    async fn test_paywall_templates() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.paywall = Some(paywall::PaywallTemplates {
+            content_types: vec![
+                paywall::PaywallTemplate::new(ContentType::JSON, r#"{"price_sat": {{price_sat}}, "invoice": "{{invoice}}"}"#),
+                paywall::PaywallTemplate::new(ContentType::HTML, r#"<a href="{{docs_url}}">Pay {{price_sat}} sats</a><img src="{{qr_url}}">"#),
+            ],
+            qr_url: Some("https://qr.example.com/?data={{invoice}}".to_string()),
+            docs_url: Some("https://docs.example.com/pay?a=1&b=2".to_string()),
+            ..Default::default()
+        });
+        let client = stub_client(l402_middleware).await;
+        let challenge = |accept: &'static str| client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("Accept", accept))
+                        .dispatch();
+
+        // Browsers get the branded page, with values escaped for HTML
+        let response = challenge("text/html,application/json;q=0.9").await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let page = response.into_string().await.unwrap();
+        assert!(page.starts_with(r#"<a href="https://docs.example.com/pay?a=1&amp;b=2">Pay 1 sats</a><img src="https://qr.example.com/?data=lnbcrt"#));
+
+        let response = challenge("application/json").await;
+        let invoice = response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap()
+                        .split("invoice=").nth(1).unwrap().to_string();
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["price_sat"], 1);
+        assert_eq!(body["invoice"], invoice);
+
+        // Clients accepting neither keep the handler's body
+        let response = challenge("text/plain").await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["code"], 402);
+
+        // A route's own template wins over content negotiation
+        let mut l402_middleware = stub_middleware();
+        let mut paywall = paywall::PaywallTemplates::default();
+        paywall.routes.insert("/protected".to_string(), paywall::PaywallTemplate::new(ContentType::Plain, "Pay {{price_msat}} msat for {{path}}"));
+        l402_middleware.paywall = Some(paywall);
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("Accept", "text/html"))
+                        .dispatch().await;
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert_eq!(response.into_string().await.unwrap(), "Pay 1000 msat for /protected");
+
+        // With path normalization on, the route's template applies wherever the app is mounted
+        let mut l402_middleware = stub_middleware();
+        let mut paywall = paywall::PaywallTemplates::default();
+        paywall.routes.insert("/protected".to_string(), paywall::PaywallTemplate::new(ContentType::Plain, "Pay {{price_msat}} msat for {{path}}"));
+        l402_middleware.paywall = Some(paywall);
+        l402_middleware.path_normalization = Some(path::PathNormalization {
+            strip_prefixes: vec!["/api/v1".to_string()],
+            ..Default::default()
+        });
+        let rocket = rocket::build()
+            .attach(l402_middleware)
+            .mount("/api/v1", rocket::routes![super::protected]);
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+        let response = client.get("/api/v1/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), "Pay 1000 msat for /protected");
+    }
+
+    #[rocket::async_test]
+    async fn test_prelude_surface() {
+        use l402_middleware::prelude::*;
+
+        let amount_func: AmountFunc = Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 }));
+        let caveat_func: CaveatFunc = Arc::new(|req: &Request<'_>| super::path_caveat(req));
+        let ln_client: Arc<dyn LNClient> = Arc::new(StubLNClient);
+        let mut l402_middleware = L402Middleware::new_with_ln_client(ln_client, STUB_ROOT_KEY.as_bytes().to_vec(), amount_func, caveat_func);
+        let token_store: Arc<dyn TokenStore> = Arc::new(MemoryTokenStore::new());
+        l402_middleware.token_store = token_store;
+        l402_middleware.macaroon_encoding = MacaroonEncoding::Hex;
+
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        let body: Value = response.into_json().await.unwrap();
+        assert_eq!(body["message"], "Pay the invoice attached in response header");
+        assert_eq!(L402_TYPE_PAYMENT_REQUIRED, l402::L402_TYPE_PAYMENT_REQUIRED);
+    }
+
+    #[rocket::async_test]
+    async fn test_challenge_reuse_window() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.challenge_reuse_window = Some(std::time::Duration::from_secs(60));
+        let metrics = Arc::clone(&l402_middleware.metrics);
+        let client = stub_client(l402_middleware).await;
+        let challenge = |user_agent: &'static str| client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("User-Agent", user_agent))
+                        .dispatch();
+        let count = |counter: &std::sync::atomic::AtomicU64| counter.load(std::sync::atomic::Ordering::Relaxed);
+
+        // A refresh from the same client gets the same macaroon and invoice back
+        let first = challenge("browser").await;
+        assert_eq!(first.status(), Status::PaymentRequired);
+        let first = first.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap().to_string();
+        let second = challenge("browser").await;
+        assert_eq!(second.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME), Some(first.as_str()));
+        assert_eq!(count(&metrics.challenges_issued), 1);
+        assert_eq!(count(&metrics.challenges_reused), 1);
+
+        // Another client gets its own challenge
+        let other = challenge("curl").await;
+        assert_eq!(other.status(), Status::PaymentRequired);
+        assert_eq!(count(&metrics.challenges_issued), 2);
+        assert!(metrics.render().contains("l402_challenges_reused_total 1\n"));
+    }
+
+    #[cfg(feature = "okapi")]
+    #[rocket_okapi::openapi]
+    #[get("/documented")]
+    fn documented(l402_info: l402::L402Info) -> String {
+        l402_info.l402_type
+    }
+
+    #[cfg(feature = "okapi")]
+    #[rocket::async_test]
+    async fn test_openapi_paid_route() {
+        let (routes, spec) = rocket_okapi::openapi_get_routes_spec![documented];
+        let rocket = rocket::build().attach(stub_middleware()).mount("/", routes);
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+        let response = client.get("/documented").header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER)).dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), l402::L402_TYPE_PAYMENT_REQUIRED);
+
+        let spec = serde_json::to_value(spec).unwrap();
+
+        let scheme = &spec["components"]["securitySchemes"][l402_middleware::openapi::L402_SECURITY_SCHEME];
+        assert_eq!(scheme["type"], "http");
+        assert_eq!(scheme["scheme"], l402::L402_HEADER);
+        let operation = &spec["paths"]["/documented"]["get"];
+        assert_eq!(operation["security"][0][l402_middleware::openapi::L402_SECURITY_SCHEME], Value::Array(Vec::new()));
+        assert!(operation["responses"]["200"].is_object());
+        assert!(operation["responses"]["402"]["headers"][l402::L402_AUTHENTICATE_HEADER_NAME].is_object());
+        assert!(operation["responses"]["429"]["headers"][l402::L402_ERROR_HEADER_NAME].is_object());
+    }
+
+    #[rocket::async_test]
+    async fn test_event_bus() {
+        let l402_middleware = stub_middleware();
+        let mut receiver = l402_middleware.events.receiver();
+        let token_store = Arc::clone(&l402_middleware.token_store);
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+        for _ in 0..2 {
+            let paid = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .dispatch().await;
+            assert_eq!(paid.status(), Status::Ok);
+        }
+
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+        match receiver.recv().await.unwrap() {
+            events::DomainEvent::ChallengeIssued { challenge } => {
+                assert_eq!(challenge.payment_hash, payment_hash);
+                assert_eq!(challenge.amount_msat, 1000);
+            },
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            events::DomainEvent::InvoiceSettled { source: events::SettlementSource::Token, .. }
+        ));
+        // The invoice is only charged on the token's first use
+        let spent: Vec<i64> = vec![receiver.recv().await.unwrap(), receiver.recv().await.unwrap()].into_iter().map(|event| match event {
+            events::DomainEvent::TokenVerified { payment_hash: verified, spent_msat, .. } => {
+                assert_eq!(verified, payment_hash);
+                spent_msat
+            },
+            event => panic!("unexpected event {:?}", event),
+        }).collect();
+        assert_eq!(spent, vec![1000, 0]);
+        assert!(receiver.try_recv().is_err());
+
+        // The store subscriber kept the ledger
+        assert!(token_store.get_challenge(&payment_hash).await.unwrap().is_some());
+        let spend = token_store.get_token_spend(&payment_hash).await.unwrap().unwrap();
+        assert_eq!((spend.requests, spend.spent_msat), (2, 1000));
+    }
+
+    #[test]
+    fn test_lnc_session_nonces_across_clones_and_resume() {
+        let pairing = lnc::parse_pairing_phrase_from_entropy(&"11".repeat(14)).unwrap();
+        let mut client = lnc::LNCMailbox::new(pairing.clone(), None).unwrap();
+        let mut server = lnc::LNCMailbox::new(pairing, None).unwrap();
+        let (client_key, server_key) = ([0x01; 32], [0x02; 32]);
+        client.install_session_keys(client_key, server_key).unwrap();
+        server.install_session_keys(server_key, client_key).unwrap();
+
+        // Clones share the nonce counters, so their frames decrypt in order
+        let mut handle = client.clone();
+        let first = client.encrypt(b"first").unwrap();
+        let second = handle.encrypt(b"second").unwrap();
+        assert_eq!(server.decrypt(&first).unwrap(), b"first");
+        assert_eq!(server.decrypt(&second).unwrap(), b"second");
+
+        // A partial frame doesn't consume receive nonces
+        let third = client.encrypt(b"third").unwrap();
+        assert!(server.decrypt(&third[..20]).is_err());
+        assert_eq!(server.decrypt(&third).unwrap(), b"third");
+
+        // After a reset no handle encrypts, and the old keys can't be resumed
+        client.reset_session();
+        assert!(client.encrypt(b"stale").is_err());
+        assert!(handle.encrypt(b"stale").is_err());
+        assert!(client.install_session_keys(client_key, server_key).is_err());
+
+        // Renegotiated keys start both ends at nonce zero
+        let (client_key, server_key) = ([0x03; 32], [0x04; 32]);
+        client.install_session_keys(client_key, server_key).unwrap();
+        server.install_session_keys(server_key, client_key).unwrap();
+        assert_eq!(server.decrypt(&client.encrypt(b"resumed").unwrap()).unwrap(), b"resumed");
+    }
+
+    #[rocket::async_test]
+    async fn test_malformed_backend_responses() {
+        let backend_error = |error: Box<dyn std::error::Error + Send + Sync>| {
+            error.downcast_ref::<lnclient::BackendError>().cloned().expect("typed backend error")
+        };
+        let lnurl_client = |callback_body: &str| {
+            let http_transport = Arc::new(StubTransport(vec![
+                ("https://example.com/.well-known/lnurlp/shop".to_string(),
+                    r#"{"callback": "https://example.com/cb", "maxSendable": 100000000, "minSendable": 1000, "metadata": "[]", "tag": "payRequest"}"#.to_string()),
+                ("https://example.com/cb?".to_string(), callback_body.to_string()),
+            ]));
+            async move {
+                lnclient::LNClientConn::init(&lnclient::LNClientConfig {
+                    ln_client_type: "LNURL".to_string(),
+                    lnurl_config: Some(lnurl::LNURLOptions { address: "shop@example.com".to_string() }),
+                    http_transport: Some(http_transport),
+                    ..Default::default()
+                }).await.unwrap()
+            }
+        };
+        let invoice_request = || lnclient::InvoiceRequest { value_msat: 10000, ..Default::default() };
+
+        for (body, malformed) in [("not json", true), (r#"{"pr": "lnbc1garbage"}"#, true), (r#"{"status": "ERROR", "reason": "amount too low"}"#, false)] {
+            let error = backend_error(lnurl_client(body).await.add_invoice(invoice_request()).await.unwrap_err());
+            match error {
+                lnclient::BackendError::MalformedResponse(_) => assert!(malformed),
+                lnclient::BackendError::Rejected(reason) => assert_eq!(reason, "amount too low"),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        // A pay request with the wrong tag, and one that is missing, fail the connection
+        let not_pay_request = lnclient::LNClientConn::init(&lnclient::LNClientConfig {
+            ln_client_type: "LNURL".to_string(),
+            lnurl_config: Some(lnurl::LNURLOptions { address: "shop@example.com".to_string() }),
+            http_transport: Some(Arc::new(StubTransport(vec![("https://example.com/.well-known/lnurlp/shop".to_string(),
+                r#"{"callback": "https://example.com/cb", "maxSendable": 1, "minSendable": 1, "metadata": "[]", "tag": "withdrawRequest"}"#.to_string())]))),
+            ..Default::default()
+        }).await.err().unwrap();
+        assert!(matches!(backend_error(not_pay_request), lnclient::BackendError::MalformedResponse(_)));
+        let missing = lnclient::LNClientConn::init(&lnclient::LNClientConfig {
+            ln_client_type: "LNURL".to_string(),
+            lnurl_config: Some(lnurl::LNURLOptions { address: "nobody@example.com".to_string() }),
+            http_transport: Some(Arc::new(StubTransport(Vec::new()))),
+            ..Default::default()
+        }).await.err().unwrap();
+        assert_eq!(backend_error(missing), lnclient::BackendError::Status { status: 404, body: String::new() });
+
+        let eclair = lnclient::LNClientConn::init(&lnclient::LNClientConfig {
+            ln_client_type: "ECLAIR".to_string(),
+            eclair_config: Some(eclair::EclairOptions { api_url: "https://eclair.example.com".to_string(), password: "pw".to_string() }),
+            http_transport: Some(Arc::new(StubTransport(vec![
+                ("https://eclair.example.com/createinvoice".to_string(), r#"{"serialized": "lnbcrt1", "paymentHash": "zz"}"#.to_string()),
+                ("https://eclair.example.com/getreceivedinfo".to_string(), "<html>".to_string()),
+            ]))),
+            ..Default::default()
+        }).await.unwrap();
+        assert!(matches!(backend_error(eclair.add_invoice(invoice_request()).await.unwrap_err()), lnclient::BackendError::MalformedResponse(_)));
+        assert!(matches!(backend_error(eclair.lookup_invoice([0; 32]).await.unwrap_err()), lnclient::BackendError::MalformedResponse(_)));
+
+        // Through the middleware, a malformed reply is an error response rather than a panic
+        let client = stub_client(middleware::L402Middleware::new_with_ln_client(
+            lnurl_client("not json").await,
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 10000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        )).await;
+        let response = client.get("/protected").header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER)).dispatch().await;
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+
+    #[test]
+    fn test_path_normalization() {
+        let normalization = path::PathNormalization {
+            strip_prefixes: vec!["/api/v1/".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(normalization.normalize("//docs/"), "/docs");
+        assert_eq!(normalization.normalize("/docs?page=2#top"), "/docs");
+        assert_eq!(normalization.normalize("/caf%C3%A9/a%2Fb"), "/café/a%2Fb");
+        assert_eq!(normalization.normalize("/api/v1/docs"), "/docs");
+        assert_eq!(normalization.normalize("/api/v1"), "/");
+        assert_eq!(normalization.normalize("/api/v10/docs"), "/api/v10/docs");
+        assert_eq!(normalization.normalize_caveat("RequestPath=/docs/"), "RequestPath = /docs");
+        assert_eq!(normalization.normalize_caveat("MaxUses = 2"), "MaxUses = 2");
+    }
+
+    #[rocket::async_test]
+    async fn test_path_caveat_ignores_query() {
+        for (path_normalization, expected) in [(Some(path::PathNormalization::default()), Status::Ok), (None, Status::InternalServerError)] {
+            let mut l402_middleware = stub_middleware();
+            l402_middleware.caveat_func = Arc::new(|req: &Request<'_>| vec![format!("{} = {}", l402::REQUEST_PATH_CAVEAT, req.uri())]);
+            l402_middleware.path_normalization = path_normalization;
+            let client = stub_client(l402_middleware).await;
+
+            let challenge = client.get("/protected?page=1")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+            let response = client.get("/protected?page=2")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", macaroon, STUB_PREIMAGE)))
+                            .dispatch().await;
+            assert_eq!(response.status(), expected);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_revenue_report() {
+        let token_store: Arc<dyn store::TokenStore> = Arc::new(store::MemoryTokenStore::new());
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.token_store = token_store.clone();
+        l402_middleware.endpoints.revenue = true;
+        l402_middleware.endpoints.revenue_dashboard = true;
+        l402_middleware.endpoints.introspection_clients.insert("ops".to_string(), "s3cret".to_string());
+        l402_middleware.path_normalization = Some(path::PathNormalization::default());
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected/")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let paid = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("L402 {}:{}", macaroon, STUB_PREIMAGE)))
+                        .dispatch().await;
+        assert_eq!(paid.status(), Status::Ok);
+
+        // An older settlement on another route and backend, and an unpaid challenge
+        let hour = utils::now_unix() / 3600 * 3600 - 3600;
+        for (payment_hash, settled_at) in [([1u8; 32], Some(hour + 60)), ([2u8; 32], None)] {
+            token_store.record_challenge(store::ChallengeRecord {
+                payment_hash: hex::encode(payment_hash),
+                invoice: String::new(),
+                macaroon: String::new(),
+                amount_msat: 5000,
+                memo: String::new(),
+                backend: "cln:/tmp".to_string(),
+                fingerprint: String::new(),
+                path: "/<report>".to_string(),
+                created_at: hour,
+                preimage: None,
+                settled_at,
+                node_settled_at: None,
+            }).await.unwrap();
+        }
+
+        let credentials = Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "ops:s3cret")));
+        assert_eq!(client.get("/l402/revenue").dispatch().await.status(), Status::Unauthorized);
+        let report: Value = client.get("/l402/revenue").header(credentials.clone()).dispatch().await.into_json().await.unwrap();
+        assert_eq!(report["payments"], 2);
+        assert_eq!(report["revenue_msat"], 6000);
+        assert_eq!(report["routes"][0]["path"], "/<report>");
+        assert_eq!(report["routes"][1]["path"], "/protected");
+        assert_eq!(report["buckets"][0]["hour"], hour);
+        assert_eq!(report["buckets"][0]["backend"], "cln:/tmp");
+        assert_eq!(report["buckets"][1]["payments"], 1);
+
+        let recent: Value = client.get(format!("/l402/revenue?since={}", hour + 3600)).header(credentials.clone()).dispatch().await.into_json().await.unwrap();
+        assert_eq!(recent["revenue_msat"], 1000);
+
+        let dashboard = client.get("/l402/revenue/dashboard").header(credentials).dispatch().await;
+        assert_eq!(dashboard.content_type(), Some(ContentType::HTML));
+        let page = dashboard.into_string().await.unwrap();
+        assert!(page.contains("<td>/&lt;report&gt;</td><td>1</td><td>5</td>"));
+        assert!(page.contains("2 payments, 6 sat"));
+    }
+
+    #[rocket::async_test]
+    async fn test_request_budget() {
+        for policy in [middleware::BudgetPolicy::FailClosed, middleware::BudgetPolicy::FailOpen] {
+            let mut l402_middleware = stub_middleware();
+            // A rate oracle that hangs
+            l402_middleware.amount_func = Arc::new(|_req: &Request<'_>| Box::pin(async {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                1000
+            }));
+            l402_middleware.request_budget = Some(middleware::RequestBudget { limit: std::time::Duration::from_millis(50), policy });
+            let metrics = Arc::clone(&l402_middleware.metrics);
+            let client = stub_client(l402_middleware).await;
+
+            let started = std::time::Instant::now();
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            assert!(started.elapsed() < std::time::Duration::from_secs(1));
+            assert_eq!(metrics.budget_exceeded.load(std::sync::atomic::Ordering::Relaxed), 1);
+            match policy {
+                middleware::BudgetPolicy::FailClosed => {
+                    assert_eq!(response.status(), Status::ServiceUnavailable);
+                    assert_eq!(response.headers().get_one(l402::L402_ERROR_HEADER_NAME), Some(l402::BUDGET_EXCEEDED_ERROR));
+                    assert_eq!(response.headers().get_one("Retry-After"), Some("1"));
+                },
+                middleware::BudgetPolicy::FailOpen => {
+                    assert_eq!(response.status(), Status::Ok);
+                    let json: Value = response.into_json().await.expect("valid JSON response");
+                    assert_eq!(json["message"], "Free content");
+                },
+            }
+        }
+
+        // A backend that hangs creating the invoice fails the request the same way
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::new(HangingStubLNClient),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.request_budget = Some(middleware::RequestBudget { limit: std::time::Duration::from_millis(50), policy: middleware::BudgetPolicy::FailClosed });
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.headers().get_one(l402::L402_ERROR_HEADER_NAME), Some(l402::BUDGET_EXCEEDED_ERROR));
+
+        // A screening delay longer than the budget still ends in a challenge, not free content
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.screening_func = Some(Arc::new(|_req: &Request<'_>| {
+            Box::pin(async { middleware::ScreeningVerdict::Delay(std::time::Duration::from_millis(100)) })
+        }));
+        l402_middleware.request_budget = Some(middleware::RequestBudget { limit: std::time::Duration::from_millis(50), policy: middleware::BudgetPolicy::FailOpen });
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+
+        // Work within the budget is unaffected
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.request_budget = Some(middleware::RequestBudget { limit: std::time::Duration::from_secs(5), policy: middleware::BudgetPolicy::FailClosed });
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+    }
+
+    // Never gets around to creating the invoice
+    struct HangingStubLNClient;
+
+    impl lnclient::LNClient for HangingStubLNClient {
+        fn add_invoice(&self, _invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            Box::pin(async {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                Err("Backend timed out".into())
+            })
+        }
+    }
+
+    // Records the invoices cancelled and whether the connection was closed
+    #[derive(Default)]
+    struct ClosingStubLNClient {
+        cancelled: std::sync::Mutex<Vec<String>>,
+        closed: std::sync::atomic::AtomicBool,
+    }
+
+    impl lnclient::LNClient for ClosingStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            lnclient::LNClient::add_invoice(&StubLNClient, invoice)
+        }
+
+        fn cancel_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<()> {
+            self.cancelled.lock().unwrap().push(hex::encode(payment_hash));
+            Box::pin(async { Ok(()) })
+        }
+
+        fn close(&self) -> lnclient::LNClientFuture<()> {
+            self.closed.store(true, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_graceful_shutdown() {
+        // Draining refuses new challenges but still serves paid requests
+        let l402_middleware = stub_middleware();
+        let shutdown_state = Arc::clone(&l402_middleware.shutdown_state);
+        let client = stub_client(l402_middleware).await;
+        assert!(shutdown_state.drain(std::time::Duration::from_millis(10)).await);
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.headers().get_one(l402::L402_ERROR_HEADER_NAME), Some(l402::SHUTTING_DOWN_ERROR));
+        assert_eq!(response.headers().get_one("Retry-After"), Some("1"));
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, stub_token(vec!["RequestPath = /protected".to_string()])))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // Shutdown cancels the unpaid invoices this instance minted and closes the backend
+        let ln_client = Arc::new(ClosingStubLNClient::default());
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::clone(&ln_client) as Arc<dyn lnclient::LNClient>,
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.shutdown.cancel_unpaid_invoices = true;
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        drop(response);
+
+        client.terminate().await;
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        assert_eq!(*ln_client.cancelled.lock().unwrap(), vec![hex::encode(PaymentHash::from(preimage).0)]);
+        assert!(ln_client.closed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[rocket::async_test]
+    async fn test_token_audit() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.audit = true;
+        l402_middleware.endpoints.introspection_clients.insert("ops".to_string(), "s3cret".to_string());
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .header(Header::new("User-Agent", "buyer"))
+                        .dispatch().await;
+        let macaroon = challenge_macaroon(challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap());
+        let token = format!("L402 {}:{}", macaroon, STUB_PREIMAGE);
+        // The buyer uses the token twice, then someone else presents it
+        for user_agent in ["buyer", "buyer", "friend"] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .header(Header::new("User-Agent", user_agent))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        let preimage = utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap();
+        let payment_hash = hex::encode(PaymentHash::from(preimage).0);
+        let credentials = Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "ops:s3cret")));
+        assert_eq!(client.get(format!("/l402/audit/{}", payment_hash)).dispatch().await.status(), Status::Unauthorized);
+        let audit: Value = client.get(format!("/l402/audit/{}", payment_hash)).header(credentials.clone()).dispatch().await.into_json().await.unwrap();
+        assert_eq!(audit["shared"], true);
+        assert_eq!(audit["receipt"]["preimage"], STUB_PREIMAGE);
+        assert_eq!(audit["presentations"].as_array().unwrap().len(), 2);
+        assert_eq!(audit["presentations"][0]["fingerprint"], audit["issued_to"]);
+        assert_eq!(audit["presentations"][0]["requests"], 2);
+        assert_eq!(audit["presentations"][1]["requests"], 1);
+        assert!(audit["first_presented_at"].as_u64().unwrap() >= audit["issued_at"].as_u64().unwrap());
+
+        let unknown = client.get(format!("/l402/audit/{}", hex::encode([9u8; 32]))).header(credentials).dispatch().await;
+        assert_eq!(unknown.status(), Status::NotFound);
+    }
+
+    // Signs messages with a fixed node key
+    struct SigningStubLNClient(SecretKey);
+
+    impl lnclient::LNClient for SigningStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            lnclient::LNClient::add_invoice(&StubLNClient, invoice)
+        }
+
+        fn sign_message(&self, message: &[u8]) -> lnclient::LNClientFuture<String> {
+            let signature = lightning::util::message_signing::sign(message, &self.0);
+            Box::pin(async move { Ok(signature) })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_signed_challenge() {
+        let node_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let node_pubkey = node_key.public_key(&Secp256k1::new()).to_string();
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::new(SigningStubLNClient(node_key)),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.sign_challenges = true;
+        let client = stub_client(l402_middleware).await;
+
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        let header = response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        let params: std::collections::HashMap<&str, &str> = header.trim_start_matches("L402 ").split(", ")
+            .filter_map(|param| param.split_once('='))
+            .map(|(key, value)| (key, value.trim_matches('"')))
+            .collect();
+        let timestamp: u64 = params["timestamp"].parse().unwrap();
+        assert!(l402::verify_challenge_signature(params["macaroon"], params["invoice"], timestamp, params["signature"], &node_pubkey));
+
+        // A swapped invoice, or another node's key, doesn't verify
+        let other_pubkey = SecretKey::from_slice(&[8u8; 32]).unwrap().public_key(&Secp256k1::new()).to_string();
+        assert!(!l402::verify_challenge_signature(params["macaroon"], "lnbcrt1swapped", timestamp, params["signature"], &node_pubkey));
+        assert!(!l402::verify_challenge_signature(params["macaroon"], params["invoice"], timestamp, params["signature"], &other_pubkey));
+
+        // Backends that can't sign still issue the challenge, unsigned
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.sign_challenges = true;
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        assert!(!response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap().contains("signature="));
+    }
+
+    #[rocket::async_test]
+    async fn test_alternative_schemes() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.alternative_schemes = vec![
+            alt_scheme::AlternativeScheme::basic("staff", [("alice".to_string(), "hunter2".to_string())].into_iter().collect()),
+            alt_scheme::AlternativeScheme::bearer("internal", vec!["k3y".to_string()]),
+        ];
+        let client = stub_client(l402_middleware).await;
+
+        let challenge = client.get("/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(challenge.status(), Status::PaymentRequired);
+        let header = challenge.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).unwrap();
+        assert!(header.starts_with("L402 macaroon="));
+        assert!(header.ends_with(", Basic realm=\"staff\", Bearer realm=\"internal\""));
+
+        let basic = format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "alice:hunter2"));
+        for auth_field in [basic.as_str(), "bearer k3y"] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, auth_field.to_string()))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+
+        // Wrong credentials are no better than none
+        let wrong = format!("Basic {}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "alice:hunter3"));
+        for auth_field in [wrong.as_str(), "Bearer nope"] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, auth_field.to_string()))
+                            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                            .dispatch().await;
+            assert_eq!(response.status(), Status::PaymentRequired);
+        }
+
+        // L402 tokens are still accepted
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, stub_token(vec!["RequestPath = /protected".to_string()])))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    // Mints invoices for the preimages [1; 32], [2; 32], ...
+    #[derive(Default)]
+    struct SequentialStubLNClient(std::sync::atomic::AtomicU8);
+
+    impl lnclient::LNClient for SequentialStubLNClient {
+        fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
+            let n = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            Box::pin(async move {
+                let payment_hash = PaymentHash::from(PaymentPreimage([n; 32])).0;
+                let payment_request = regtest_invoice(invoice.memo, payment_hash, Some(invoice.value_msat as u64))?;
+                Ok(lnclient::InvoiceResponse { payment_request, payment_hash })
+            })
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_offline_challenge_pack() {
+        let token_store: Arc<dyn store::TokenStore> = Arc::new(store::MemoryTokenStore::new());
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::new(SequentialStubLNClient::default()),
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.token_store = token_store.clone();
+        l402_middleware.path_normalization = Some(path::PathNormalization::default());
+        let pack_key = [3u8; 32];
+
+        let pack = l402_middleware.mint_challenge_pack("till-1", "/protected/", 1000, 2, std::time::Duration::from_secs(3600)).await.unwrap();
+        let sealed = pack.seal(&pack_key).unwrap();
+        assert!(offline::ChallengePack::open(&sealed, &pack_key, "till-2").is_err());
+        let appliance = offline::OfflineAppliance::new(offline::ChallengePack::open(&sealed, &pack_key, "till-1").unwrap());
+
+        // Each challenge is handed out once
+        let challenge = appliance.next_challenge("/protected").unwrap();
+        assert!(challenge.www_authenticate().starts_with("L402 macaroon="));
+        assert_eq!(appliance.remaining("/protected"), 1);
+        assert_ne!(appliance.next_challenge("/protected").unwrap(), challenge);
+        assert!(appliance.next_challenge("/protected").is_none());
+
+        let token = format!("L402 {}:{}", challenge.macaroon, hex::encode([1u8; 32]));
+        assert!(appliance.verify(&token, "/other").is_err());
+        assert!(appliance.verify(&format!("L402 {}:{}", challenge.macaroon, hex::encode([9u8; 32])), "/protected").is_err());
+        appliance.verify(&token, "/protected").unwrap();
+        appliance.verify(&token, "/protected").unwrap();
+
+        // Synced back, the payment is settled in the server's ledger once, though the
+        // middleware was never ignited: the pack's challenges went straight to the store
+        let report = appliance.settlement_report();
+        assert_eq!(report.settlements.len(), 1);
+        let report = offline::SettlementReport::open(&report.seal(&pack_key).unwrap(), &pack_key, "till-1").unwrap();
+        assert_eq!(l402_middleware.import_settlement_report(&report).await.unwrap(), 1);
+        assert_eq!(l402_middleware.import_settlement_report(&report).await.unwrap(), 0);
+        let record = token_store.get_challenge(&challenge.payment_hash).await.unwrap().unwrap();
+        assert_eq!(record.fingerprint, "offline:till-1");
+        assert!(record.settled_at.is_some());
+
+        // The same token is accepted online too
+        let client = stub_client(l402_middleware).await;
+        let response = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let spend = token_store.get_token_spend(&challenge.payment_hash).await.unwrap().unwrap();
+        assert_eq!(spend.requests, 1);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let path = std::env::temp_dir().join(format!("l402-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "ln_client_type = \"LNURL\"\nlnurl_address = \"sats@example.com\"\nroot_key = \"1234\"\nl402_max_uses = \"10\"\n").unwrap();
+        let report = config::validate(&path);
+        assert!(report.is_valid(), "{}", report);
+        assert!(report.features.contains(&("quotas", "MaxUses = 10".to_string())));
+        assert!(report.features.iter().any(|(feature, detail)| *feature == "settlement" && detail.contains("preimage proof only")));
+
+        // Keys follow the struct, and each one is accepted in the file
+        let keys = config::config_keys();
+        assert!(keys.contains(&"l402_max_concurrent".to_string()));
+        let every_key: String = keys.iter().map(|key| format!("{} = \"\"\n", key)).collect();
+        std::fs::write(&path, every_key).unwrap();
+        assert!(config::load(&path).is_ok());
+
+        // The public URL reaches the client snippets, so it must be one
+        let l402_config = config::L402Config {
+            base_url: Some("https://api.example.com/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(l402_config.public_base_url(), Ok(Some("https://api.example.com".to_string())));
+        let l402_config = config::L402Config { base_url: Some("api.example.com".to_string()), ..Default::default() };
+        assert!(l402_config.public_base_url().is_err());
+
+        // Unknown keys are rejected rather than ignored
+        std::fs::write(&path, "ln_client_type = \"LNURL\"\nlnurl_adress = \"sats@example.com\"\n").unwrap();
+        let report = config::validate(&path);
+        assert!(!report.is_valid());
+        assert!(report.problems[0].contains("lnurl_adress"));
+        std::fs::remove_file(&path).unwrap();
+
+        // Every problem is reported, not just the first
+        let l402_config = config::L402Config {
+            ln_client_type: Some("ECLAIR".to_string()),
+            eclair_api_url: Some("eclair:8282".to_string()),
+            l402_console_url: Some("https://console.example.com".to_string()),
+            l402_console_public_key: Some("zz".to_string()),
+            l402_max_concurrent: Some("0".to_string()),
+            ..Default::default()
+        };
+        let report = l402_config.validate();
+        assert_eq!(report.problems.len(), 6, "{}", report);
+        assert!(report.to_string().contains("[FAIL] ECLAIR_PASSWORD is required"));
+        assert!(report.to_string().ends_with("Configuration is invalid"));
+        assert!(l402_config.backend_config(Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "db-sqlite")]
+    #[rocket::async_test]
+    async fn test_db_token_store() {
+        use l402_middleware::db_store::DbTokenStore;
+        use l402_middleware::store::TokenStore;
+        use rocket_db_pools::{sqlx, Database};
+
+        #[derive(Database)]
+        #[database("l402")]
+        struct L402Db(sqlx::SqlitePool);
+
+        let path = std::env::temp_dir().join(format!("l402-store-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let figment = rocket::Config::figment()
+            .merge(("databases.l402.url", url.clone()))
+            .merge(("databases.l402.max_connections", 4));
+
+        let store = Arc::new(DbTokenStore::<sqlx::Sqlite>::new());
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.token_store = store.clone();
+        let rocket = rocket::custom(figment)
+            .attach(L402Db::init())
+            .attach(store.bind::<L402Db>())
+            .attach(l402_middleware)
+            .mount("/", rocket::routes![super::free, super::protected]);
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+
+        // The middleware charges quotas against the application's database
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), "MaxUses = 1".to_string()]);
+        for expected in [Status::Ok, Status::PaymentRequired] {
+            let response = client.get("/protected")
+                            .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                            .dispatch().await;
+            assert_eq!(response.status(), expected);
+        }
+
+        // Concurrent requests can't spend more than the quota
+        let consumed = futures_util::future::join_all((0..10).map(|_| store.consume_use("shared", "/protected", None, 3))).await;
+        assert_eq!(consumed.iter().filter(|c| matches!(c, Ok(true))).count(), 3);
+        assert!(store.consume_use("range", "/video", None, 1).await.unwrap());
+        // Nothing is free until a partial response records where it ended
+        assert!(!store.consume_use("range", "/video", Some(100), 1).await.unwrap());
+        store.record_served("range", "/video", 100).await.unwrap();
+        assert!(store.consume_use("range", "/video", Some(100), 1).await.unwrap());
+        assert!(!store.consume_use("range", "/video", Some(100), 1).await.unwrap());
+        assert!(store.acquire_slot("shared", 1).await.unwrap());
+        assert!(!store.acquire_slot("shared", 1).await.unwrap());
+        store.release_slot("shared").await.unwrap();
+        assert!(store.acquire_slot("shared", 1).await.unwrap());
+
+        // Payments are settled and offer payments bound once
+        store.record_challenge(store::ChallengeRecord {
+            payment_hash: "aa".repeat(32),
+            invoice: "lnbcrt1".to_string(),
+            macaroon: "mac".to_string(),
+            amount_msat: 1000,
+            memo: String::new(),
+            backend: "stub".to_string(),
+            fingerprint: "client".to_string(),
+            path: "/protected".to_string(),
+            created_at: utils::now_unix(),
+            preimage: None,
+            settled_at: None,
+            node_settled_at: None,
+        }).await.unwrap();
+        assert!(store.mark_settled(&"aa".repeat(32), &"bb".repeat(32), 10).await.unwrap());
+        assert!(!store.mark_settled(&"aa".repeat(32), &"cc".repeat(32), 11).await.unwrap());
+        assert!(store.bind_offer_payment("offer-payment", "token-1").await.unwrap());
+        assert!(!store.bind_offer_payment("offer-payment", "token-2").await.unwrap());
+        store.revoke_token("revoked").await.unwrap();
+        client.terminate().await;
+
+        // And everything is still there for the next instance
+        let reopened = DbTokenStore::with_pool(sqlx::SqlitePool::connect(&url).await.unwrap());
+        reopened.migrate().await.unwrap();
+        assert_eq!(reopened.get_usage("shared").await.unwrap().unwrap().uses, 3);
+        assert_eq!(reopened.get_challenge(&"aa".repeat(32)).await.unwrap().unwrap().preimage, Some("bb".repeat(32)));
+        assert_eq!(reopened.settled_challenges(0).await.unwrap().len(), 1);
+        assert!(reopened.is_revoked("revoked").await.unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[rocket::async_test]
+    async fn test_endpoints_base_matches_whole_segment() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.inspect = true;
+        let rocket = rocket::build()
+            .attach(l402_middleware)
+            .mount("/l402foo", rocket::routes![super::protected]);
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+
+        // A route that merely shares the endpoints' prefix is still paywalled
+        let response = client.get("/l402foo/protected")
+                        .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+                        .dispatch().await;
+        assert_eq!(response.status(), Status::PaymentRequired);
+        assert!(response.headers().get_one(l402::L402_AUTHENTICATE_HEADER_NAME).is_some());
+    }
+
+    #[test]
+    fn test_endpoints_any_enabled_matches_routes() {
+        let mut endpoints = l402_middleware::routes::EndpointsConfig::default();
+        assert!(!endpoints.any_enabled());
+        assert!(endpoints.routes().is_empty());
+
+        endpoints.audit = true;
+        assert!(endpoints.any_enabled());
+        assert_eq!(endpoints.routes().len(), 1);
+    }
+
+    #[rocket::async_test]
+    async fn test_await_settlement_shares_one_poll() {
+        let ln_client = Arc::new(SettlingStubLNClient(Default::default()));
+        let mut l402_middleware = middleware::L402Middleware::new_with_ln_client(
+            Arc::clone(&ln_client) as Arc<dyn lnclient::LNClient>,
+            STUB_ROOT_KEY.as_bytes().to_vec(),
+            Arc::new(|_req: &Request<'_>| Box::pin(async { 1000 })),
+            Arc::new(|req: &Request<'_>| super::path_caveat(req)),
+        );
+        l402_middleware.endpoints.await_settlement = true;
+        l402_middleware.endpoints.settlement_poll_interval = std::time::Duration::from_millis(20);
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+        client.get("/protected")
+            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+            .dispatch().await;
+
+        // Concurrent waits for one invoice are answered from a single backend poll
+        let url = format!("/l402/await/{}", payment_hash);
+        let waits = futures_util::future::join_all((0..5).map(|_| client.get(url.as_str()).dispatch())).await;
+        for wait in waits {
+            let settled: Value = wait.into_json().await.unwrap();
+            assert_eq!(settled["state"], "Settled");
+        }
+        assert_eq!(ln_client.0.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[rocket::async_test]
+    async fn test_await_settlement_caps_waiters() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.endpoints.settlements = true;
+        l402_middleware.endpoints.await_settlement = true;
+        l402_middleware.endpoints.max_settlement_waiters = 1;
+        l402_middleware.endpoints.settlement_clients.insert("cln-plugin".to_string(), "n0de".to_string());
+        let client = stub_client(l402_middleware).await;
+        let payment_hash = hex::encode(PaymentHash::from(utils::get_preimage_from_string(STUB_PREIMAGE.to_string()).unwrap()).0);
+        client.get("/protected")
+            .header(Header::new(l402::L402_HEADER_NAME, l402::L402_HEADER))
+            .dispatch().await;
+
+        let url = format!("/l402/await/{}", payment_hash);
+        let (held, _) = futures_util::future::join(client.get(url.as_str()).dispatch(), async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            // The one allowed wait is taken
+            let rejected = client.get(url.as_str()).dispatch().await;
+            assert_eq!(rejected.status(), Status::ServiceUnavailable);
+
+            // and the node's notification wakes it without waiting out the timeout
+            let credentials = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, "cln-plugin:n0de");
+            client.post("/l402/settlements/cln")
+                .header(rocket::http::ContentType::JSON)
+                .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, format!("Basic {}", credentials)))
+                .body(format!(r#"{{"payment":{{"label":"l402","preimage":"{}","msat":"1000msat"}}}}"#, STUB_PREIMAGE))
+                .dispatch().await;
+        }).await;
+        let held: Value = held.into_json().await.unwrap();
+        assert_eq!(held["state"], "Settled");
+
+        // Finished waits free their place
+        let settled: Value = client.get(url.as_str()).dispatch().await.into_json().await.unwrap();
+        assert_eq!(settled["state"], "Settled");
+    }
+
+    #[rocket::async_test]
+    async fn test_range_continuation_uses_normalized_path() {
+        let mut l402_middleware = stub_middleware();
+        l402_middleware.path_normalization = Some(path::PathNormalization::default());
+        let rocket = rocket::build()
+            .attach(range_responses())
+            .attach(l402_middleware)
+            .mount("/", rocket::routes![super::protected]);
+        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+        let token = stub_token(vec!["RequestPath = /protected".to_string(), "MaxUses = 1".to_string()]);
+
+        let first = client.get("/protected")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token.clone()))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=0-99"))
+                        .dispatch().await;
+        assert_eq!(first.status(), Status::PartialContent);
+
+        // Another spelling of the same route continues the same download
+        let continuation = client.get("/prot%65cted")
+                        .header(Header::new(l402::L402_AUTHORIZATION_HEADER_NAME, token))
+                        .header(Header::new(l402::L402_RANGE_HEADER_NAME, "bytes=100-"))
+                        .dispatch().await;
+        assert_eq!(continuation.status(), Status::PartialContent);
+    }
 }
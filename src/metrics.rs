@@ -0,0 +1,210 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Counters exposed in the Prometheus text format by the `metrics` endpoint.
+#[derive(Debug, Default)]
+pub struct L402Metrics {
+    pub challenges_issued: AtomicU64,
+    /// Unpaid challenges handed out again within `challenge_reuse_window`
+    pub challenges_reused: AtomicU64,
+    /// Challenges whose payment was proven for the first time
+    pub challenges_settled: AtomicU64,
+    /// Invoices the backend failed to create
+    pub invoice_errors: AtomicU64,
+    pub tokens_accepted: AtomicU64,
+    pub tokens_rejected: AtomicU64,
+    /// Tokens accepted without a matching settled invoice at the backend
+    pub settlement_discrepancies: AtomicU64,
+    pub tokens_revoked: AtomicU64,
+    /// Backend lookups that failed during reconciliation
+    pub reconciliation_errors: AtomicU64,
+    /// Requests whose middleware work overran the `request_budget`
+    pub budget_exceeded: AtomicU64,
+    /// Counters per backend id, see `LNClient::backend_id`
+    pub backends: Mutex<BTreeMap<String, BackendMetrics>>,
+    /// Rejected tokens by `FailureReason`
+    pub failure_reasons: Mutex<BTreeMap<String, u64>>,
+    /// Most recent sampled rejections, oldest first
+    pub failure_samples: Mutex<VecDeque<FailureSample>>,
+}
+
+/// Why a presented token was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The Authorization header or its macaroon or preimage couldn't be decoded
+    Malformed,
+    /// The macaroon wasn't minted with the current root key
+    WrongSignature,
+    /// The macaroon's caveats don't cover the request
+    CaveatMismatch,
+    /// Presented before its `NotBefore` caveat
+    NotYetValid,
+    /// Presented after its `NotAfter` caveat
+    Expired,
+    Revoked,
+    /// The preimage doesn't settle the macaroon's payment hash
+    Unsettled,
+}
+
+impl FailureReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureReason::Malformed => "malformed",
+            FailureReason::WrongSignature => "wrong_signature",
+            FailureReason::CaveatMismatch => "caveat_mismatch",
+            FailureReason::NotYetValid => "not_yet_valid",
+            FailureReason::Expired => "expired",
+            FailureReason::Revoked => "revoked",
+            FailureReason::Unsettled => "unsettled",
+        }
+    }
+}
+
+/// A sampled rejection. It holds no token material or client address, only
+/// what helps tell which clients or integrations send bad tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailureSample {
+    pub reason: FailureReason,
+    /// Unix timestamp of the rejection
+    pub at: u64,
+    pub method: String,
+    pub path: String,
+    /// Scheme the token was presented with, e.g. `LSAT`
+    pub scheme: Option<String>,
+    pub user_agent: Option<String>,
+    /// First-party caveats of the presented macaroon, if it could be decoded
+    pub caveats: Vec<String>,
+}
+
+/// How many rejections are kept for `GET <base>/failures`.
+#[derive(Debug, Clone)]
+pub struct FailureSampling {
+    /// Share of rejections sampled, from 0 to 1
+    pub rate: f64,
+    /// Samples kept; the oldest are dropped first
+    pub capacity: usize,
+}
+
+impl Default for FailureSampling {
+    fn default() -> Self {
+        FailureSampling {
+            rate: 0.1,
+            capacity: 100,
+        }
+    }
+}
+
+/// Rejection counts and samples, as served by the `failures` endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailureReport {
+    pub reasons: BTreeMap<String, u64>,
+    pub samples: Vec<FailureSample>,
+}
+
+/// Counters attributed to the backend that minted a challenge, so multi-backend
+/// deployments can tell revenue and failures apart per node or provider.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendMetrics {
+    pub challenges_issued: u64,
+    /// Invoices the backend failed to create
+    pub invoice_errors: u64,
+    /// Challenge amounts counted when their payment is first proven
+    pub revenue_msat: u64,
+    pub settlement_discrepancies: u64,
+}
+
+impl L402Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn incr_backend(&self, backend: &str, update: impl FnOnce(&mut BackendMetrics)) {
+        if let Ok(mut backends) = self.backends.lock() {
+            update(backends.entry(backend.to_string()).or_default());
+        }
+    }
+
+    pub fn backend(&self, backend: &str) -> Option<BackendMetrics> {
+        self.backends.lock().ok()?.get(backend).cloned()
+    }
+
+    /// Counts a rejected token under `reason`, on top of `tokens_rejected`.
+    pub fn record_failure(&self, reason: FailureReason) {
+        Self::incr(&self.tokens_rejected);
+        if let Ok(mut reasons) = self.failure_reasons.lock() {
+            *reasons.entry(reason.as_str().to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn sample_failure(&self, sample: FailureSample, capacity: usize) {
+        if let Ok(mut samples) = self.failure_samples.lock() {
+            samples.push_back(sample);
+            while samples.len() > capacity {
+                samples.pop_front();
+            }
+        }
+    }
+
+    pub fn failure_report(&self) -> FailureReport {
+        FailureReport {
+            reasons: self.failure_reasons.lock().map(|r| r.clone()).unwrap_or_default(),
+            samples: self.failure_samples.lock().map(|s| s.iter().cloned().collect()).unwrap_or_default(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let counters = [
+            ("l402_challenges_issued_total", "Challenges handed out", &self.challenges_issued),
+            ("l402_challenges_reused_total", "Unpaid challenges handed out again instead of minting", &self.challenges_reused),
+            ("l402_challenges_settled_total", "Challenges whose payment was proven", &self.challenges_settled),
+            ("l402_invoice_errors_total", "Invoices the backend failed to create", &self.invoice_errors),
+            ("l402_tokens_accepted_total", "Requests served with a valid token", &self.tokens_accepted),
+            ("l402_tokens_rejected_total", "Tokens that failed verification", &self.tokens_rejected),
+            ("l402_settlement_discrepancies_total", "Tokens accepted without a settled invoice at the backend", &self.settlement_discrepancies),
+            ("l402_tokens_revoked_total", "Tokens revoked by reconciliation", &self.tokens_revoked),
+            ("l402_reconciliation_errors_total", "Backend lookups that failed during reconciliation", &self.reconciliation_errors),
+            ("l402_budget_exceeded_total", "Requests whose middleware work overran the request budget", &self.budget_exceeded),
+        ];
+        let mut out = String::new();
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let backends = self.backends.lock().map(|b| b.clone()).unwrap_or_default();
+        let per_backend = |value: fn(&BackendMetrics) -> u64| -> Vec<(String, u64)> {
+            backends.iter().map(|(backend, metrics)| (backend.clone(), value(metrics))).collect()
+        };
+        let labelled = [
+            ("l402_backend_challenges_issued_total", "Challenges handed out, by minting backend", per_backend(|m| m.challenges_issued)),
+            ("l402_backend_invoice_errors_total", "Invoices a backend failed to create", per_backend(|m| m.invoice_errors)),
+            ("l402_backend_revenue_msat_total", "Paid challenge amounts, by minting backend", per_backend(|m| m.revenue_msat)),
+            ("l402_backend_settlement_discrepancies_total", "Settlement discrepancies, by minting backend", per_backend(|m| m.settlement_discrepancies)),
+        ];
+        for (name, help, values) in labelled {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (backend, value) in values {
+                let label = backend.replace('\\', "\\\\").replace('"', "\\\"");
+                let _ = writeln!(out, "{}{{backend=\"{}\"}} {}", name, label, value);
+            }
+        }
+
+        let _ = writeln!(out, "# HELP l402_verification_failures_total Tokens that failed verification, by reason");
+        let _ = writeln!(out, "# TYPE l402_verification_failures_total counter");
+        let reasons = self.failure_reasons.lock().map(|r| r.clone()).unwrap_or_default();
+        for (reason, value) in reasons {
+            let _ = writeln!(out, "l402_verification_failures_total{{reason=\"{}\"}} {}", reason, value);
+        }
+        out
+    }
+}
@@ -1,28 +1,372 @@
-use rocket::{Request, Response, Data};
-use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Header;
+use rocket::{Build, Data, Orbit, Request, Response, Rocket};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::request::{self, FromRequest};
+use rocket::http::{ContentType, Header, Method, Status as HttpStatus};
 use std::sync::Arc;
 use std::error::Error;
-use lightning::types::payment::PaymentHash;
-use crate::lndrpc::lnrpc;
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+use lightning_invoice::Bolt11Invoice;
 use std::pin::Pin;
 use std::future::Future;
-use tokio::sync::Mutex;
+use std::io::Cursor;
+use std::task::{self, Poll};
+use rocket::response::Body;
+use tokio::io::{AsyncRead, ReadBuf};
+use std::time::Duration;
+use std::fmt;
 
 use crate::utils;
+use crate::analytics;
+use crate::catalog;
+use crate::anomaly;
+use crate::fiat;
+use crate::forwarded;
+use crate::escalation;
+use crate::events::{self, DomainEvent, SettlementSource};
+use crate::alt_scheme;
+use crate::geo;
 use crate::l402;
+use crate::path;
+use crate::shutdown;
 use crate::lnclient;
-use crate::macaroon_util::get_macaroon_as_string;
+use crate::metrics::{self, FailureReason, L402Metrics};
+use crate::observer;
+use crate::offline;
+use crate::paywall;
+use crate::reconcile;
+use crate::routes;
+use crate::sandbox;
+use crate::session;
+use crate::signed_url;
+use crate::store;
+use crate::trace;
+use crate::macaroon_util::{get_macaroon_as_string_with_encoding, MacaroonEncoding};
+use macaroon::{Caveat, Macaroon};
+use opentelemetry::context::FutureExt;
+use opentelemetry::trace::{Status, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
 
-type AmountFunc = Arc<dyn Fn(&Request<'_>) -> Pin<Box<dyn Future<Output = i64> + Send>> + Send + Sync>;
+// Time a reused challenge invoice must still be payable for, so the client can pay it
+const REUSED_INVOICE_MIN_LIFETIME: Duration = Duration::from_secs(60);
 
-type CaveatFunc = Arc<dyn Fn(&Request<'_>) -> Vec<String> + Send + Sync>;
+// Public so applications embedding the middleware can name and store these hooks
+pub type AmountFunc = Arc<dyn Fn(&Request<'_>) -> Pin<Box<dyn Future<Output = i64> + Send>> + Send + Sync>;
+
+pub type CaveatFunc = Arc<dyn Fn(&Request<'_>) -> Vec<String> + Send + Sync>;
+
+pub type FiatPriceFunc = Arc<dyn Fn(&Request<'_>) -> Option<fiat::FiatPrice> + Send + Sync>;
+
+pub type ContentHashFunc = Arc<dyn Fn(&Request<'_>) -> Option<String> + Send + Sync>;
+
+pub type ValidityFunc = Arc<dyn Fn(&Request<'_>) -> Option<l402::ValidityWindow> + Send + Sync>;
+
+pub type MemoFunc = Arc<dyn Fn(&Request<'_>) -> String + Send + Sync>;
+
+pub type BundleFunc = Arc<dyn Fn(&Request<'_>) -> Vec<l402::BundleOption> + Send + Sync>;
+
+pub type ScreeningFunc = Arc<dyn Fn(&Request<'_>) -> Pin<Box<dyn Future<Output = ScreeningVerdict> + Send>> + Send + Sync>;
+
+// Additional challenges for the bundle options offered alongside the single-call one
+struct BundleChallenges(Vec<String>);
+
+// Content hash committed to by the token that paid for this request, echoed on delivery
+struct ContentCommitment(Option<String>);
+
+// Token and resource this request was charged to, to record where a partial response ends
+struct ChargedResource(Option<(String, String)>);
+
+// Token whose in-flight slot this request holds until its response body is sent
+struct ConcurrencySlot(Option<String>);
+
+// Set when the token already had MaxConcurrent requests in flight
+struct ConcurrencyLimited(bool);
+
+// Challenge minted for this request, for the paywall template
+struct IssuedChallenge(Option<paywall::PaywallChallenge>);
+
+// In-flight slot a token acquired, released when dropped unless handed on with `keep`,
+// so a request abandoned halfway through verification can't leak it
+struct SlotGuard {
+    token_store: Arc<dyn store::TokenStore>,
+    token_id: Option<String>,
+}
+
+impl SlotGuard {
+    fn new(token_store: Arc<dyn store::TokenStore>, token_id: String) -> Self {
+        SlotGuard { token_store, token_id: Some(token_id) }
+    }
+
+    // Hands the slot on to the response, which releases it once the body is sent
+    fn keep(mut self) -> String {
+        self.token_id.take().unwrap_or_default()
+    }
+
+    async fn release(mut self) {
+        if let Some(token_id) = self.token_id.take() {
+            if let Err(error) = self.token_store.release_slot(&token_id).await {
+                println!("Error releasing L402 concurrency slot: {}", error);
+            }
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let Some(token_id) = self.token_id.take() else {
+            return;
+        };
+        let token_store = Arc::clone(&self.token_store);
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(error) = token_store.release_slot(&token_id).await {
+                    println!("Error releasing L402 concurrency slot: {}", error);
+                }
+            });
+        }
+    }
+}
+
+// Response body that frees the token's in-flight slot once it is sent or dropped
+struct SlotReleasingBody<'r> {
+    body: Body<'r>,
+    _slot: SlotGuard,
+}
+
+impl AsyncRead for SlotReleasingBody<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+// Set when the token was presented outside its NotBefore/NotAfter window
+struct ValidityRejected(Option<l402::ValidityError>);
+
+// Set when the screening hook refused to challenge this request
+struct ScreeningDenied(bool);
+
+// Set when a backend call overran the request budget, with the policy applied.
+// Takes precedence over any `L402Info` cached before the overrun.
+struct BudgetExceeded(Option<BudgetPolicy>);
+
+// Why a challenge or payment couldn't be checked with the backend
+enum BackendError {
+    // The backend didn't answer within `request_budget`
+    BudgetExceeded,
+    Failed(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::BudgetExceeded => write!(f, "Request budget exceeded"),
+            BackendError::Failed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<String> for BackendError {
+    fn from(error: String) -> Self {
+        BackendError::Failed(error)
+    }
+}
+
+impl From<&str> for BackendError {
+    fn from(error: &str) -> Self {
+        BackendError::Failed(error.to_string())
+    }
+}
+
+// Set when the request needed a challenge while the instance was shutting down
+struct ShuttingDown(bool);
+
+/// What happens to a request whose middleware work overruns `RequestBudget::limit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /// The request gets a 503 with `Retry-After`, and the handler an error
+    #[default]
+    FailClosed,
+    /// The request is served as free, trading revenue for availability
+    FailOpen,
+}
+
+/// Upper bound on each call the middleware makes to the rate oracle or backend while
+/// handling a request: pricing, creating the challenge invoice or looking up an offer
+/// payment. Store writes and screening delays aren't bounded, so an overrun never
+/// leaves a token half charged or skips a delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestBudget {
+    pub limit: Duration,
+    pub policy: BudgetPolicy,
+}
+
+/// What the screening hook decided for a client about to be challenged.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ScreeningVerdict {
+    #[default]
+    Allow,
+    /// Answer 403 without minting an invoice
+    Deny,
+    /// Hold the challenge back, slowing down invoice spam
+    Delay(Duration),
+    /// Multiply the price, e.g. `3.0`; factors below 1 are ignored
+    RaisePrice(f64),
+}
+
+/// How a HEAD request without a valid token is challenged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeadChallenge {
+    /// 402 with a bare `WWW-Authenticate: L402` naming the scheme, so probing
+    /// clients learn the resource is paid without an invoice being minted
+    #[default]
+    SchemeOnly,
+    /// The full challenge with a freshly minted invoice, as for GET
+    Mint,
+}
+
+/// Which credential is used when a request carries both an `Authorization` header
+/// and a session cookie.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenPrecedence {
+    /// The Authorization header whenever one is present, even if it fails verification
+    #[default]
+    Authorization,
+    /// A session cookie that still covers the request, falling back to the Authorization header
+    Session,
+}
+
+/// How a request with more than one L402 `Authorization` header is handled.
+/// Headers with other schemes, e.g. `Basic` for a proxy in front, are ignored either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateAuthorization {
+    /// Rejected unless the headers are identical, so a proxy and the middleware
+    /// can't end up disagreeing on which token paid
+    #[default]
+    Reject,
+    /// The first one is used
+    First,
+    /// The last one is used, e.g. when a trusted proxy appends the header
+    Last,
+}
+
+// Scheme negotiated from Accept-Authenticate, or the one the token was presented with
+struct NegotiatedScheme(l402::AuthScheme);
+
+// L402 unless on_request recorded otherwise
+fn negotiated_scheme(request: &Request<'_>) -> l402::AuthScheme {
+    request.local_cache(|| NegotiatedScheme(l402::SUPPORTED_SCHEMES[0])).0
+}
 
 pub struct L402Middleware {
     pub amount_func: AmountFunc,
     pub caveat_func: CaveatFunc,
-    pub ln_client: Arc<Mutex<dyn lnclient::LNClient>>,
+    pub ln_client: Arc<dyn lnclient::LNClient>,
     pub root_key: Vec<u8>,
+    pub token_store: Arc<dyn store::TokenStore>,
+    pub session_cookie: Option<session::SessionCookieConfig>,
+    /// Number of requests a minted token pays for. Byte-range continuations of a
+    /// download charged to the token in the last `store::RANGE_CONTINUATION_WINDOW_SECS`
+    /// don't consume another use.
+    pub max_uses: Option<u64>,
+    /// Requests a minted token may have in flight at once, streamed responses
+    /// included, minted as a `MaxConcurrent` caveat. Excess requests get a 429, so
+    /// one token can't back a resale proxy.
+    pub max_concurrent: Option<u64>,
+    /// Branded 402 bodies by route or accepted content type, replacing the handler's
+    pub paywall: Option<paywall::PaywallTemplates>,
+    /// Text encoding of challenge macaroons. Presented tokens are accepted in any encoding.
+    pub macaroon_encoding: MacaroonEncoding,
+    /// When set, a `signed_url::UrlSigner` is managed as Rocket state so handlers can
+    /// hand out short-lived URLs for assets served elsewhere.
+    pub signed_urls: Option<signed_url::SignedUrlConfig>,
+    /// Returns the hex SHA256 of the exact content version a request would be served.
+    /// When set, it is committed to in the macaroon at challenge time, checked on
+    /// verification, and echoed in the `L402-Content-Hash` response header.
+    pub content_hash_func: Option<ContentHashFunc>,
+    /// Returns the window a token for the request may be used in, e.g. a scheduled
+    /// stream. It is minted as `NotBefore`/`NotAfter` caveats, and tokens presented
+    /// outside it get a 403 with an `L402-Error` code instead of the content.
+    pub validity_func: Option<ValidityFunc>,
+    /// Returns the fiat amount that produced the request's sat price, advertised in
+    /// the challenge as `price_fiat="0.01 USD"`.
+    pub fiat_price_func: Option<FiatPriceFunc>,
+    /// Returns the invoice description for a request, e.g. the route being bought,
+    /// instead of `L402`. LNURL backends also send it as the payment comment when
+    /// the recipient allows one, so it shows up in the receiving wallet's history.
+    pub memo_func: Option<MemoFunc>,
+    /// Memo policy used when `memo_func` isn't set, taken from the backend's `LNClientConfig`
+    pub memo: lnclient::MemoConfig,
+    /// Returns the bundles a request's challenge offers besides the default one, e.g.
+    /// 10 and 100 calls. Each gets its own invoice and a macaroon whose `MaxUses`
+    /// caveat matches the bundle, so the quota follows whichever invoice was paid.
+    pub bundle_func: Option<BundleFunc>,
+    /// Built-in endpoints to mount on ignite
+    pub endpoints: routes::EndpointsConfig,
+    pub metrics: Arc<L402Metrics>,
+    /// Notified of events such as settlement discrepancies
+    pub observers: Vec<Arc<dyn observer::L402Observer>>,
+    /// Challenges, settlements, verifications and revocations, published as they
+    /// happen, after `token_store` has recorded them. On ignite the middleware
+    /// subscribes `metrics` and `observers` to it; subscribe your own handlers or
+    /// take a `receiver()`.
+    pub events: Arc<events::EventBus>,
+    /// When set, accepted tokens are periodically re-checked against the backend's
+    /// settlement records after liftoff, and discrepancies flagged or revoked.
+    pub reconciliation: Option<reconcile::ReconciliationConfig>,
+    /// Challenge sent to HEAD requests. OPTIONS requests are never challenged.
+    pub head_challenge: HeadChallenge,
+    /// Whether the Authorization header or the session cookie wins when both are sent
+    pub token_precedence: TokenPrecedence,
+    /// Handling of requests carrying several L402 Authorization headers
+    pub duplicate_authorization: DuplicateAuthorization,
+    /// Advertises the backend's static BOLT12 offer in every challenge, and accepts
+    /// tokens whose invoice was instead paid through that offer.
+    pub advertise_offer: bool,
+    /// Signs every challenge with the node key through `LNClient::sign_message`, adding
+    /// `timestamp` and `signature` parameters, so clients can check the invoice comes
+    /// from the node they expect, see `l402::verify_challenge_signature`.
+    pub sign_challenges: bool,
+    /// Reverse proxies allowed to report the client address in forwarding headers
+    pub trusted_proxies: forwarded::TrustedProxies,
+    /// Fake settlement for staging; see `sandbox::SandboxConfig`
+    pub sandbox: Option<sandbox::SandboxConfig>,
+    /// Schemes challenged after L402 in the same `WWW-Authenticate` header, e.g. `Basic`
+    /// for staff. Requests authenticated with one are served as paid.
+    pub alternative_schemes: Vec<alt_scheme::AlternativeScheme>,
+    /// When set, spikes in verification failures, invoice errors and unpaid
+    /// challenges are reported to `observers` as `L402Event::Anomaly`.
+    pub anomaly_detection: Option<anomaly::AnomalyConfig>,
+    /// Prices challenges by the client's country and keeps chosen networks out of
+    /// the free tier; see `geo::GeoPricing`
+    pub geo_pricing: Option<geo::GeoPricing>,
+    /// Scores a client before an invoice is minted for it, e.g. with an abuse or ML
+    /// model, and denies, delays or surcharges the challenge of suspicious ones.
+    pub screening_func: Option<ScreeningFunc>,
+    /// Root key of the LSAT deployment being migrated from, when it differs from
+    /// `root_key`; used by the `upgrade` endpoint
+    pub legacy_root_key: Option<Vec<u8>>,
+    /// When set, a share of rejected tokens is kept, anonymized, for the `failures`
+    /// endpoint. Rejections are counted by reason either way.
+    pub failure_sampling: Option<metrics::FailureSampling>,
+    /// Raises prices for clients that keep collecting challenges without paying
+    pub price_escalation: Option<escalation::EscalationConfig>,
+    /// Route prices published by the `catalog` endpoint. Set `amount_func`,
+    /// `fiat_price_func` and `bundle_func` from it so challenges charge the same.
+    pub pricing_table: Option<Arc<catalog::PricingTable>>,
+    /// When set, a client fingerprint asking again within the window gets its last
+    /// unpaid challenge for the same caveats and price back instead of a new invoice,
+    /// e.g. a browser auto-retrying. Keep it shorter than the backend's invoice expiry.
+    pub challenge_reuse_window: Option<Duration>,
+    /// Normalization of the path in `RequestPath` caveats from `caveat_func`, applied
+    /// when minting and verifying alike, and of the path memos and paywall templates
+    /// see. None, the default, matches paths exactly as requested.
+    pub path_normalization: Option<path::PathNormalization>,
+    /// When set, a slow rate oracle or backend can't hold a request longer than the
+    /// budget's limit per call; the request is failed open or closed instead.
+    pub request_budget: Option<RequestBudget>,
+    /// How in-flight work is drained and the backend closed when Rocket shuts down
+    pub shutdown: shutdown::ShutdownConfig,
+    /// In-flight requests and minted challenges, drained on shutdown
+    pub shutdown_state: Arc<shutdown::ShutdownState>,
 }
 
 impl L402Middleware {
@@ -35,114 +379,905 @@ impl L402Middleware {
         let ln_client = lnclient::LNClientConn::init(&ln_client_config).await?;
     
         // Create and return the L402Middleware instance
-        Ok(L402Middleware {
-            amount_func: amount_func,
-            caveat_func: caveat_func,
+        let mut l402_middleware = L402Middleware::new_with_ln_client(
+            ln_client,
+            ln_client_config.root_key.clone(),
+            amount_func,
+            caveat_func,
+        );
+        l402_middleware.memo = ln_client_config.memo;
+        Ok(l402_middleware)
+    }
+
+    /// Builds the middleware around an already initialised LNClient.
+    pub fn new_with_ln_client(
+        ln_client: Arc<dyn lnclient::LNClient>,
+        root_key: Vec<u8>,
+        amount_func: AmountFunc,
+        caveat_func: CaveatFunc,
+    ) -> L402Middleware {
+        L402Middleware {
+            amount_func,
+            caveat_func,
             ln_client,
-            root_key: ln_client_config.root_key.clone(),
-        })
+            root_key,
+            token_store: Arc::new(store::MemoryTokenStore::new()),
+            session_cookie: None,
+            max_uses: None,
+            max_concurrent: None,
+            macaroon_encoding: MacaroonEncoding::default(),
+            paywall: None,
+            signed_urls: None,
+            content_hash_func: None,
+            validity_func: None,
+            fiat_price_func: None,
+            memo_func: None,
+            memo: lnclient::MemoConfig::default(),
+            bundle_func: None,
+            endpoints: routes::EndpointsConfig::default(),
+            metrics: Arc::new(L402Metrics::new()),
+            observers: Vec::new(),
+            events: Arc::new(events::EventBus::new()),
+            reconciliation: None,
+            head_challenge: HeadChallenge::default(),
+            token_precedence: TokenPrecedence::default(),
+            duplicate_authorization: DuplicateAuthorization::default(),
+            advertise_offer: false,
+            sign_challenges: false,
+            trusted_proxies: forwarded::TrustedProxies::default(),
+            sandbox: None,
+            alternative_schemes: Vec::new(),
+            anomaly_detection: None,
+            geo_pricing: None,
+            screening_func: None,
+            legacy_root_key: None,
+            failure_sampling: None,
+            price_escalation: None,
+            pricing_table: None,
+            challenge_reuse_window: None,
+            path_normalization: None,
+            request_budget: None,
+            shutdown: shutdown::ShutdownConfig::default(),
+            shutdown_state: Arc::new(shutdown::ShutdownState::default()),
+        }
+    }
+
+    /// Marks the request as paid if the token's quota allows it; a token with a
+    /// spent quota gets a fresh challenge instead. Returns whether it was paid.
+    async fn accept_token(
+        &self,
+        request: &mut Request<'_>,
+        mac: &Macaroon,
+        preimage: PaymentPreimage,
+        caveats: Vec<String>,
+    ) -> bool {
+        // Tokens paid through the static offer are identified by the invoice they were minted for
+        let payment_hash = l402::macaroon_payment_hash(mac)
+            .map(PaymentHash)
+            .unwrap_or_else(|| PaymentHash::from(preimage));
+        match self.token_store.is_revoked(&hex::encode(payment_hash.0)).await {
+            Ok(false) => {},
+            Ok(true) => {
+                self.record_failure(request, FailureReason::Revoked, Some(mac));
+                request.local_cache(|| l402::L402Info {
+                    l402_type: l402::L402_TYPE_ERROR.to_string(),
+                    error: Some("Token has been revoked".to_string()),
+                    preimage: None,
+                    payment_hash: None,
+                    auth_header: None,
+                    scheme: None,
+                });
+                return false;
+            },
+            Err(error) => {
+                request.local_cache(|| l402::L402Info {
+                    l402_type: l402::L402_TYPE_ERROR.to_string(),
+                    error: Some(error.to_string()),
+                    preimage: None,
+                    payment_hash: None,
+                    auth_header: None,
+                    scheme: None,
+                });
+                return false;
+            },
+        }
+        // Early tokens stay unspent, so they still work once their window opens
+        if let Err(validity_error) = l402::check_validity_window(mac, utils::now_unix()) {
+            let reason = match validity_error {
+                l402::ValidityError::NotYetValid { .. } => FailureReason::NotYetValid,
+                l402::ValidityError::Expired { .. } => FailureReason::Expired,
+            };
+            self.record_failure(request, reason, Some(mac));
+            request.local_cache(|| ValidityRejected(Some(validity_error)));
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_ERROR.to_string(),
+                error: Some(validity_error.to_string()),
+                preimage: None,
+                payment_hash: None,
+                auth_header: None,
+                scheme: None,
+            });
+            return false;
+        }
+        let token_id = hex::encode(payment_hash.0);
+        let first_settlement = match self.token_store.mark_settled(&token_id, &hex::encode(preimage.0), utils::now_unix()).await {
+            Ok(first_settlement) => {
+                if first_settlement {
+                    self.events.publish(DomainEvent::InvoiceSettled {
+                        payment_hash: token_id.clone(),
+                        source: SettlementSource::Token,
+                    }).await;
+                }
+                first_settlement
+            },
+            Err(error) => {
+                println!("Error recording L402 settlement: {}", error);
+                false
+            },
+        };
+
+        // Excess requests are turned away before they can spend a use
+        let max_concurrent = l402::get_caveat_value(mac, l402::MAX_CONCURRENT_CAVEAT).and_then(|v| v.parse::<u64>().ok());
+        let mut slot = None;
+        if let Some(max_concurrent) = max_concurrent {
+            match self.token_store.acquire_slot(&token_id, max_concurrent).await {
+                Ok(true) => slot = Some(SlotGuard::new(Arc::clone(&self.token_store), token_id.clone())),
+                Ok(false) => {
+                    request.local_cache(|| ConcurrencyLimited(true));
+                    request.local_cache(|| l402::L402Info {
+                        l402_type: l402::L402_TYPE_ERROR.to_string(),
+                        error: Some("Too many concurrent requests for this token".to_string()),
+                        preimage: None,
+                        payment_hash: None,
+                        auth_header: None,
+                        scheme: None,
+                    });
+                    return false;
+                },
+                Err(error) => {
+                    request.local_cache(|| l402::L402Info {
+                        l402_type: l402::L402_TYPE_ERROR.to_string(),
+                        error: Some(error.to_string()),
+                        preimage: None,
+                        payment_hash: None,
+                        auth_header: None,
+                        scheme: None,
+                    });
+                    return false;
+                },
+            }
+        }
+
+        if let Some(max_uses) = l402::get_caveat_value(mac, l402::MAX_USES_CAVEAT).and_then(|v| v.parse::<u64>().ok()) {
+            let resource = self.route_path(request);
+            let range_start = request.headers().get_one(l402::L402_RANGE_HEADER_NAME)
+                .and_then(utils::range_continuation_start);
+            let consumed = self.token_store.consume_use(&token_id, &resource, range_start, max_uses).await;
+            if let Ok(true) = consumed {
+                request.local_cache(|| ChargedResource(Some((token_id.clone(), resource))));
+            }
+            if !matches!(consumed, Ok(true)) {
+                if let Some(slot) = slot.take() {
+                    slot.release().await;
+                }
+            }
+            match consumed {
+                Ok(true) => {},
+                Ok(false) => {
+                    L402Middleware::set_l402_header(self, request, caveats).await;
+                    return false;
+                },
+                Err(error) => {
+                    request.local_cache(|| l402::L402Info {
+                        l402_type: l402::L402_TYPE_ERROR.to_string(),
+                        error: Some(error.to_string()),
+                        preimage: None,
+                        payment_hash: None,
+                        auth_header: None,
+                        scheme: None,
+                    });
+                    return false;
+                },
+            }
+        }
+
+        // The invoice amount is spent once, when the token is first presented
+        let (spent_msat, backend) = if first_settlement {
+            match self.token_store.get_challenge(&token_id).await {
+                Ok(Some(challenge)) => (challenge.amount_msat, Some(challenge.backend)),
+                _ => (0, None),
+            }
+        } else {
+            (0, None)
+        };
+        let fingerprint = analytics::client_fingerprint(request);
+        let recorded = match self.token_store.record_spend(&token_id, &fingerprint, spent_msat).await {
+            Ok(()) => self.token_store.record_presentation(&token_id, &fingerprint, utils::now_unix()).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = recorded {
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_ERROR.to_string(),
+                error: Some(error.to_string()),
+                preimage: None,
+                payment_hash: None,
+                auth_header: None,
+                scheme: None,
+            });
+            return false;
+        }
+        self.events.publish(DomainEvent::TokenVerified {
+            payment_hash: token_id.clone(),
+            fingerprint,
+            backend,
+            spent_msat,
+        }).await;
+        request.local_cache(|| l402::L402Info {
+            l402_type: l402::L402_TYPE_PAID.to_string(),
+            preimage: Some(preimage),
+            payment_hash: Some(payment_hash),
+            error: None,
+            auth_header: None,
+            scheme: Some(negotiated_scheme(request)),
+        });
+        request.local_cache(|| ContentCommitment(l402::get_caveat_value(mac, l402::CONTENT_HASH_CAVEAT)));
+        if let Some(slot) = slot {
+            let token_id = slot.keep();
+            request.local_cache(|| ConcurrencySlot(Some(token_id)));
+        }
+        true
+    }
+
+    // Counts a rejected token by reason, sampling it when failure sampling is on
+    fn record_failure(&self, request: &Request<'_>, reason: FailureReason, mac: Option<&Macaroon>) {
+        self.metrics.record_failure(reason);
+        let Some(sampling) = &self.failure_sampling else {
+            return;
+        };
+        if rand::random::<f64>() >= sampling.rate {
+            return;
+        }
+        let caveats = mac.map(|mac| mac.first_party_caveats().iter().filter_map(|caveat| match caveat {
+            Caveat::FirstParty(fp) => Some(String::from_utf8_lossy(fp.predicate().as_ref()).to_string()),
+            _ => None,
+        }).collect()).unwrap_or_default();
+        self.metrics.sample_failure(metrics::FailureSample {
+            reason,
+            at: utils::now_unix(),
+            method: request.method().as_str().to_string(),
+            path: self.route_path(request),
+            scheme: request.headers().get_one(l402::L402_AUTHORIZATION_HEADER_NAME)
+                .and_then(|auth_field| l402::split_auth_scheme(auth_field).0)
+                .map(|scheme| scheme.name.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+            caveats,
+        }, sampling.capacity);
+    }
+
+    // Picks the Authorization header to verify. Without any L402 one, the first header is
+    // returned so it's reported as unparseable like before.
+    fn authorization_field(&self, request: &Request<'_>) -> Result<Option<String>, String> {
+        let headers: Vec<&str> = request.headers().get(l402::L402_AUTHORIZATION_HEADER_NAME).collect();
+        let mut l402_headers: Vec<&str> = headers.iter().copied()
+            .filter(|h| l402::split_auth_scheme(h).0.is_some())
+            .collect();
+        if l402_headers.is_empty() {
+            return Ok(headers.first().map(|h| h.to_string()));
+        }
+        let selected = match self.duplicate_authorization {
+            DuplicateAuthorization::First => l402_headers[0],
+            DuplicateAuthorization::Last => l402_headers[l402_headers.len() - 1],
+            DuplicateAuthorization::Reject => {
+                l402_headers.dedup();
+                if l402_headers.len() > 1 {
+                    return Err("Multiple L402 Authorization headers present".to_string());
+                }
+                l402_headers[0]
+            },
+        };
+        Ok(Some(selected.to_string()))
+    }
+
+    // Whether an `Authorization` header carries valid credentials for one of the
+    // alternative schemes
+    async fn verify_alternative_scheme(&self, request: &Request<'_>) -> bool {
+        for auth_field in request.headers().get(l402::L402_AUTHORIZATION_HEADER_NAME) {
+            for scheme in &self.alternative_schemes {
+                if let Some(credentials) = scheme.credentials(auth_field) {
+                    if (scheme.verify)(credentials).await {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // Request path as recorded in the ledger, normalized like path caveats
+    fn route_path(&self, request: &Request<'_>) -> String {
+        let path = request.uri().path();
+        match &self.path_normalization {
+            Some(path_normalization) => path_normalization.normalize(path.as_str()),
+            None => path.to_string(),
+        }
+    }
+
+    // Caveats a token for this request is minted with and verified against
+    fn request_caveats(&self, request: &Request<'_>) -> Vec<String> {
+        let mut caveats = (self.caveat_func)(request);
+        if let Some(path_normalization) = &self.path_normalization {
+            caveats = caveats.iter().map(|caveat| path_normalization.normalize_caveat(caveat)).collect();
+        }
+        if let Some(content_hash) = self.content_hash_func.as_ref().and_then(|f| f(request)) {
+            caveats.push(format!("{} = {}", l402::CONTENT_HASH_CAVEAT, content_hash.to_lowercase()));
+        }
+        if let Some(window) = self.validity_func.as_ref().and_then(|f| f(request)) {
+            caveats.extend(window.caveats());
+        }
+        caveats
+    }
+
+    /// Serves the request from its session cookie when it maps to a token that
+    /// still satisfies this request's caveats. Returns false if it doesn't.
+    async fn verify_session(&self, request: &mut Request<'_>, caveats: &[String]) -> bool {
+        let Some(session_config) = &self.session_cookie else {
+            return false;
+        };
+        let Some(token) = session::session_token(request, session_config, self.token_store.as_ref()).await else {
+            return false;
+        };
+        match utils::parse_l402_header(&token) {
+            Ok((mac, preimage)) => {
+                if l402::verify_l402(&mac, caveats.to_vec(), self.root_key.clone(), preimage).is_err() {
+                    return false;
+                }
+                self.accept_token(request, &mac, preimage, caveats.to_vec()).await;
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Mints `count` challenges for `path` at `amount_msat` ahead of time, for an airgapped
+    /// appliance to serve with `offline::OfflineAppliance`. Their invoices stay payable for
+    /// `expiry`. They go in the ledger like any challenge, so the settlements synced back
+    /// with `import_settlement_report` count as revenue.
+    pub async fn mint_challenge_pack(
+        &self,
+        appliance_id: &str,
+        path: &str,
+        amount_msat: i64,
+        count: usize,
+        expiry: Duration,
+    ) -> Result<offline::ChallengePack, String> {
+        let path = match &self.path_normalization {
+            Some(path_normalization) => path_normalization.normalize(path),
+            None => path.to_string(),
+        };
+        let fingerprint = format!("offline:{}", appliance_id);
+        let memo = self.memo.policy.render(&path, amount_msat, &self.ln_client.backend_id());
+        let expires_at = utils::now_unix() + expiry.as_secs();
+        let mut challenges = Vec::with_capacity(count);
+        for _ in 0..count {
+            let caveats = vec![format!("{} = {}", l402::REQUEST_PATH_CAVEAT, path)];
+            let (macaroon, invoice, payment_hash) = self.mint_challenge(caveats, amount_msat, memo.clone(), Some(expiry.as_secs()), &fingerprint, &path).await
+                .map_err(|error| error.to_string())?;
+            challenges.push(offline::OfflineChallenge { payment_hash, invoice, macaroon, amount_msat, path: path.clone(), expires_at });
+        }
+        Ok(offline::ChallengePack { appliance_id: appliance_id.to_string(), created_at: utils::now_unix(), challenges })
+    }
+
+    /// Records the payments an appliance accepted while offline, as if their tokens had
+    /// been presented here. Settlements whose preimage doesn't match are skipped.
+    /// Returns how many were new.
+    pub async fn import_settlement_report(&self, report: &offline::SettlementReport) -> Result<usize, String> {
+        let mut imported = 0;
+        for settlement in &report.settlements {
+            let Ok(preimage) = utils::get_preimage_from_string(settlement.preimage.clone()) else {
+                println!("Skipping offline settlement {} with an invalid preimage", settlement.payment_hash);
+                continue;
+            };
+            if hex::encode(PaymentHash::from(preimage).0) != settlement.payment_hash {
+                println!("Skipping offline settlement {} whose preimage doesn't match", settlement.payment_hash);
+                continue;
+            }
+            let first_settlement = self.token_store.mark_settled(&settlement.payment_hash, &settlement.preimage, settlement.settled_at).await
+                .map_err(|error| error.to_string())?;
+            if first_settlement {
+                self.events.publish(DomainEvent::InvoiceSettled {
+                    payment_hash: settlement.payment_hash.clone(),
+                    source: SettlementSource::Token,
+                }).await;
+                imported += 1;
+            }
+        }
+        Ok(imported)
     }
 
     pub async fn set_l402_header(&self, request: &mut Request<'_>, caveats: Vec<String>) {
-        let value_msat = (self.amount_func)(request).await;
-        let ln_invoice = lnrpc::Invoice {
-            value_msat: value_msat,
-            memo: l402::L402_HEADER.to_string(),
-            ..Default::default()
+        if request.method() == Method::Head && self.head_challenge == HeadChallenge::SchemeOnly {
+            let scheme = negotiated_scheme(request);
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_PAYMENT_REQUIRED.to_string(),
+                preimage: None,
+                payment_hash: None,
+                error: None,
+                auth_header: Some(scheme.name.to_string()),
+                scheme: Some(scheme),
+            });
+            return;
+        }
+        let cx = trace::start_span(request, "l402.challenge");
+        self.issue_challenge(request, caveats).with_context(cx.clone()).await;
+        cx.span().end();
+    }
+
+    // Clients that don't speak L402 get the free tier, unless their network is barred
+    // from it, in which case they're challenged like any L402 client
+    #[cfg(not(feature = "no-accept-authenticate-required"))]
+    async fn serve_free_tier(&self, request: &mut Request<'_>, caveats: Vec<String>) {
+        if self.geo_pricing.as_ref().is_some_and(|geo_pricing| geo_pricing.denies_free_tier(geo::client_location(request).as_ref())) {
+            request.local_cache(|| NegotiatedScheme(l402::SUPPORTED_SCHEMES[0]));
+            L402Middleware::set_l402_header(self, request, caveats).await;
+            return;
+        }
+        request.local_cache(|| l402::L402Info {
+            l402_type: l402::L402_TYPE_FREE.to_string(),
+            preimage: None,
+            payment_hash: None,
+            error: None,
+            auth_header: None,
+            scheme: None,
+        });
+    }
+
+    async fn issue_challenge(&self, request: &mut Request<'_>, mut caveats: Vec<String>) {
+        // Clients retry against an instance that isn't going away
+        if self.shutdown_state.is_draining() {
+            request.local_cache(|| ShuttingDown(true));
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_ERROR.to_string(),
+                error: Some("Shutting down".to_string()),
+                preimage: None,
+                payment_hash: None,
+                auth_header: None,
+                scheme: None,
+            });
+            return;
+        }
+        if let Some(max_concurrent) = self.max_concurrent {
+            caveats.push(format!("{} = {}", l402::MAX_CONCURRENT_CAVEAT, max_concurrent));
+        }
+        let mut single_caveats = caveats.clone();
+        if let Some(max_uses) = self.max_uses {
+            single_caveats.push(format!("{} = {}", l402::MAX_USES_CAVEAT, max_uses));
+        }
+        let verdict = match &self.screening_func {
+            Some(screening_func) => screening_func(request).await,
+            None => ScreeningVerdict::Allow,
         };
-        let ln_client_conn = lnclient::LNClientConn{
-            ln_client: self.ln_client.clone(),
+        let surcharge = match verdict {
+            ScreeningVerdict::Allow => 1.0,
+            ScreeningVerdict::Deny => {
+                request.local_cache(|| ScreeningDenied(true));
+                request.local_cache(|| l402::L402Info {
+                    l402_type: l402::L402_TYPE_ERROR.to_string(),
+                    error: Some("Request denied".to_string()),
+                    preimage: None,
+                    payment_hash: None,
+                    auth_header: None,
+                    scheme: None,
+                });
+                return;
+            },
+            ScreeningVerdict::Delay(delay) => {
+                tokio::time::sleep(delay).await;
+                1.0
+            },
+            ScreeningVerdict::RaisePrice(factor) => factor.max(1.0),
         };
-        match ln_client_conn.generate_invoice(ln_invoice).await {
-            Ok((invoice, payment_hash)) => {
-                match get_macaroon_as_string(payment_hash, caveats, self.root_key.clone()) {
-                    Ok(macaroon_string) => {
-                        request.local_cache(|| l402::L402Info {
-                            l402_type: l402::L402_TYPE_PAYMENT_REQUIRED.to_string(),
-                            preimage: None,
-                            payment_hash: None,
-                            error: None,
-                            auth_header: format!("L402 macaroon={}, invoice={}", macaroon_string, invoice).into(),
-                        });
-                    },
-                    Err(error) => {
-                        request.local_cache(|| l402::L402Info {
-                            l402_type: l402::L402_TYPE_ERROR.to_string(),
-                            error: Some(error.to_string()),
-                            preimage: None,
-                            payment_hash: None,
-                            auth_header: None,
-                        });
+        let fingerprint = analytics::client_fingerprint(request);
+        let path = self.route_path(request);
+        let escalation = match &self.price_escalation {
+            Some(price_escalation) => price_escalation.price_multiplier(self.token_store.as_ref(), &fingerprint).await,
+            None => 1.0,
+        };
+        let location = geo::client_location(request);
+        let multiplier = surcharge * escalation * self.geo_pricing.as_ref().map_or(1.0, |geo_pricing| geo_pricing.multiplier(location.as_ref()));
+        let scale_price = |base_msat: i64| if multiplier == 1.0 { base_msat } else { (base_msat as f64 * multiplier).round() as i64 };
+        let Some(base_msat) = self.within_budget((self.amount_func)(request)).await else {
+            self.exceed_budget(request);
+            return;
+        };
+        let value_msat = scale_price(base_msat);
+        let fiat_price = self.fiat_price_func.as_ref().and_then(|f| f(request)).map(|fiat_price| fiat::FiatPrice {
+            amount: fiat_price.amount * multiplier,
+            currency: fiat_price.currency,
+        });
+        let memo = match &self.memo_func {
+            Some(memo_func) => memo_func(request),
+            None => self.memo.policy.render(&path, value_msat, &self.ln_client.backend_id()),
+        };
+        let scheme = negotiated_scheme(request);
+        let offer = self.ln_client.static_offer().filter(|_| self.advertise_offer);
+        let recent_challenges = match self.challenge_reuse_window {
+            Some(window) => self.token_store.fingerprint_challenges(&fingerprint, utils::now_unix().saturating_sub(window.as_secs())).await
+                .unwrap_or_else(|error| {
+                    println!("Error looking up reusable L402 challenges: {}", error);
+                    Vec::new()
+                }),
+            None => Vec::new(),
+        };
+        match self.reuse_or_mint_challenge(&recent_challenges, single_caveats, value_msat, memo.clone(), &fingerprint, &path).await {
+            Ok((macaroon_string, invoice)) => {
+                let mut auth_header = format!("{} macaroon={}, invoice={}", scheme.name, macaroon_string, invoice);
+                if self.sign_challenges {
+                    auth_header.push_str(&self.challenge_signature(&macaroon_string, &invoice).await);
+                }
+                if let Some(max_uses) = self.max_uses {
+                    auth_header.push_str(&format!(", uses=\"{}\"", max_uses));
+                }
+                if let Some(fiat_price) = &fiat_price {
+                    auth_header.push_str(&format!(", price_fiat=\"{}\"", fiat_price));
+                }
+                if let Some(offer) = &offer {
+                    auth_header.push_str(&format!(", offer=\"{}\"", offer));
+                }
+                request.local_cache(|| IssuedChallenge(Some(paywall::PaywallChallenge {
+                    invoice: invoice.clone(),
+                    macaroon: macaroon_string.clone(),
+                    price_msat: value_msat,
+                    price_fiat: fiat_price.as_ref().map(|fiat_price| fiat_price.to_string()),
+                    path: path.clone(),
+                })));
+
+                let bundle_options = self.bundle_func.as_ref().map(|f| f(request)).unwrap_or_default();
+                let mut bundle_headers = Vec::new();
+                for option in bundle_options {
+                    let bundle_msat = option.amount_msat.map(scale_price).unwrap_or(value_msat.saturating_mul(option.uses as i64));
+                    let mut bundle_caveats = caveats.clone();
+                    bundle_caveats.push(format!("{} = {}", l402::MAX_USES_CAVEAT, option.uses));
+                    match self.reuse_or_mint_challenge(&recent_challenges, bundle_caveats, bundle_msat, memo.clone(), &fingerprint, &path).await {
+                        Ok((macaroon_string, invoice)) => {
+                            let mut bundle_header = format!("{} macaroon={}, invoice={}, uses=\"{}\"", scheme.name, macaroon_string, invoice, option.uses);
+                            if self.sign_challenges {
+                                bundle_header.push_str(&self.challenge_signature(&macaroon_string, &invoice).await);
+                            }
+                            // Sat prices are linear in the fiat amount, so scale it to the bundle price
+                            if let Some(fiat_price) = fiat_price.as_ref().filter(|_| value_msat > 0) {
+                                let bundle_fiat = fiat::FiatPrice {
+                                    amount: fiat_price.amount * bundle_msat as f64 / value_msat as f64,
+                                    currency: fiat_price.currency.clone(),
+                                };
+                                bundle_header.push_str(&format!(", price_fiat=\"{}\"", bundle_fiat));
+                            }
+                            if let Some(offer) = &offer {
+                                bundle_header.push_str(&format!(", offer=\"{}\"", offer));
+                            }
+                            bundle_headers.push(bundle_header);
+                        },
+                        Err(error) => println!("Error minting L402 bundle challenge: {}", error),
                     }
                 }
+                request.local_cache(|| BundleChallenges(bundle_headers));
+
+                request.local_cache(|| l402::L402Info {
+                    l402_type: l402::L402_TYPE_PAYMENT_REQUIRED.to_string(),
+                    preimage: None,
+                    payment_hash: None,
+                    error: None,
+                    auth_header: Some(auth_header),
+                    scheme: Some(scheme),
+                });
             },
-            Err(error) => {
+            Err(BackendError::BudgetExceeded) => self.exceed_budget(request),
+            Err(BackendError::Failed(error)) => {
                 request.local_cache(|| l402::L402Info {
                     l402_type: l402::L402_TYPE_ERROR.to_string(),
-                    error: Some(error.to_string()),
+                    error: Some(error),
                     preimage: None,
                     payment_hash: None,
                     auth_header: None,
+                    scheme: None,
                 });
             },
         }
     }
-}
 
-#[rocket::async_trait]
-impl Fairing for L402Middleware {
-    fn info(&self) -> Info {
-        Info {
-            name: "L402 Middleware",
-            kind: Kind::Request | Kind::Response,
+    // `timestamp` and `signature` parameters signing a challenge with the node key,
+    // or nothing if the backend couldn't sign it
+    async fn challenge_signature(&self, macaroon: &str, invoice: &str) -> String {
+        let timestamp = utils::now_unix();
+        let message = l402::challenge_signing_message(macaroon, invoice, timestamp);
+        match self.ln_client.sign_message(message.as_bytes()).await {
+            Ok(signature) => format!(", timestamp=\"{}\", signature=\"{}\"", timestamp, signature),
+            Err(error) => {
+                println!("Error signing L402 challenge: {}", error);
+                String::new()
+            },
         }
     }
 
-    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
-        let caveat_func = Arc::clone(&self.caveat_func);
-        let caveats = caveat_func(request);
-        if let Some(auth_field) = request.headers().get_one(l402::L402_AUTHORIZATION_HEADER_NAME) {
-            match utils::parse_l402_header(auth_field) {
+    // Accepts a token whose preimage settled a payment of the static offer rather than
+    // the token's own invoice. The payment must cover the challenge and can only pay for one token.
+    async fn verify_offer_payment(&self, mac: &Macaroon, caveats: &[String], preimage: PaymentPreimage) -> Result<(), BackendError> {
+        l402::verify_l402_caveats(mac, caveats.to_vec(), self.root_key.clone()).map_err(|error| error.to_string())?;
+        let token_id = l402::macaroon_payment_hash(mac).map(hex::encode).ok_or("Macaroon is not bound to a payment hash")?;
+
+        let paid_hash = PaymentHash::from(preimage);
+        let paid_msat = self.within_budget(self.ln_client.lookup_offer_payment(paid_hash.0)).await
+            .ok_or(BackendError::BudgetExceeded)?
+            .map_err(|error| error.to_string())?
+            .ok_or("No offer payment found for preimage")?;
+        let challenge = self.token_store.get_challenge(&token_id).await
+            .map_err(|error| error.to_string())?
+            .ok_or("No challenge found for macaroon")?;
+        if paid_msat < challenge.amount_msat {
+            return Err(format!("Offer payment of {} msat does not cover {} msat", paid_msat, challenge.amount_msat).into());
+        }
+        if !self.token_store.bind_offer_payment(&hex::encode(paid_hash.0), &token_id).await.map_err(|error| error.to_string())? {
+            return Err("Offer payment was already used for another token".into());
+        }
+        Ok(())
+    }
+
+    // Hands out the newest unpaid challenge in `recent` minted for the same caveats,
+    // price and memo whose invoice stays payable for a while, or mints a new one.
+    async fn reuse_or_mint_challenge(
+        &self,
+        recent: &[store::ChallengeRecord],
+        caveats: Vec<String>,
+        value_msat: i64,
+        memo: String,
+        fingerprint: &str,
+        path: &str,
+    ) -> Result<(String, String), BackendError> {
+        let payable_until = Duration::from_secs(utils::now_unix()) + REUSED_INVOICE_MIN_LIFETIME;
+        let reusable = recent.iter()
+            .filter(|challenge| challenge.settled_at.is_none() && challenge.node_settled_at.is_none())
+            .filter(|challenge| challenge.amount_msat == value_msat && challenge.memo == memo)
+            .filter(|challenge| challenge.invoice.parse::<Bolt11Invoice>().is_ok_and(|invoice| !invoice.would_expire(payable_until)))
+            .filter(|challenge| utils::get_macaroon_from_string(challenge.macaroon.clone()).is_ok_and(|mac| {
+                let minted: Vec<String> = mac.first_party_caveats().iter().filter_map(|caveat| match caveat {
+                    Caveat::FirstParty(fp) => Some(String::from_utf8_lossy(fp.predicate().as_ref()).into_owned()),
+                    _ => None,
+                }).collect();
+                minted == caveats
+            }))
+            .max_by_key(|challenge| challenge.created_at);
+        match reusable {
+            Some(challenge) => {
+                L402Metrics::incr(&self.metrics.challenges_reused);
+                Ok((challenge.macaroon.clone(), challenge.invoice.clone()))
+            },
+            None => {
+                let minted = self.mint_challenge(caveats, value_msat, memo, None, fingerprint, path).await?;
+                if self.shutdown.cancel_unpaid_invoices {
+                    self.shutdown_state.record_minted(minted.2.clone());
+                }
+                Ok((minted.0, minted.1))
+            },
+        }
+    }
+
+    // Creates an invoice and a macaroon bound to it, recorded in the ledger.
+    // Returns the serialized macaroon, the invoice and the hex payment hash.
+    async fn mint_challenge(
+        &self,
+        caveats: Vec<String>,
+        value_msat: i64,
+        memo: String,
+        expiry: Option<u64>,
+        fingerprint: &str,
+        path: &str,
+    ) -> Result<(String, String, String), BackendError> {
+        let ln_invoice = lnclient::InvoiceRequest {
+            value_msat,
+            memo: memo.clone(),
+            expiry,
+            description_hash: self.memo.description_hash,
+        };
+        let ln_client_conn = lnclient::LNClientConn{
+            ln_client: self.ln_client.clone(),
+        };
+        let backend = self.ln_client.backend_id();
+        let (invoice, payment_hash) = self.within_budget(ln_client_conn.generate_invoice(ln_invoice)).await
+            .ok_or(BackendError::BudgetExceeded)?
+            .map_err(|error| error.to_string())
+            .inspect_err(|error| {
+                Context::current().span().set_status(Status::error(error.clone()));
+                L402Metrics::incr(&self.metrics.invoice_errors);
+                self.metrics.incr_backend(&backend, |m| m.invoice_errors += 1);
+            })?;
+        let cx = Context::current();
+        cx.span().set_attribute(KeyValue::new("l402.payment_hash", hex::encode(payment_hash.0)));
+        cx.span().set_attribute(KeyValue::new("l402.amount_msat", value_msat));
+        cx.span().set_attribute(KeyValue::new("l402.backend", backend.clone()));
+        let macaroon_string = get_macaroon_as_string_with_encoding(payment_hash, caveats, self.root_key.clone(), self.macaroon_encoding)
+            .map_err(|error| error.to_string())?;
+
+        let record = store::ChallengeRecord {
+            payment_hash: hex::encode(payment_hash.0),
+            invoice: invoice.clone(),
+            macaroon: macaroon_string.clone(),
+            amount_msat: value_msat,
+            memo,
+            backend: backend.clone(),
+            fingerprint: fingerprint.to_string(),
+            path: path.to_string(),
+            created_at: utils::now_unix(),
+            preimage: None,
+            settled_at: None,
+            node_settled_at: None,
+        };
+        let payment_hash = record.payment_hash.clone();
+        self.token_store.record_challenge(record.clone()).await.map_err(|error| error.to_string())?;
+        self.events.publish(DomainEvent::ChallengeIssued { challenge: record }).await;
+
+        Ok((macaroon_string, invoice, payment_hash))
+    }
+
+    // Cancels the invoices of challenges minted by this instance that nobody paid
+    async fn cancel_unpaid_invoices(&self) {
+        for payment_hash in self.shutdown_state.take_minted() {
+            let unpaid = matches!(
+                self.token_store.get_challenge(&payment_hash).await,
+                Ok(Some(challenge)) if challenge.settled_at.is_none() && challenge.node_settled_at.is_none()
+            );
+            let Some(hash_bytes) = hex::decode(&payment_hash).ok().and_then(|bytes| <[u8; 32]>::try_from(bytes).ok()) else {
+                continue;
+            };
+            if unpaid {
+                if let Err(error) = self.ln_client.cancel_invoice(hash_bytes).await {
+                    println!("Error cancelling unpaid L402 invoice {}: {}", payment_hash, error);
+                }
+            }
+        }
+    }
+
+    // Prices and challenges the request, or verifies its token, recording the outcome
+    // for the handler
+    async fn authorize_request(&self, request: &mut Request<'_>) {
+        if let Some(geo_pricing) = &self.geo_pricing {
+            geo::cache_client_location(request, geo_pricing.provider.as_ref()).await;
+        }
+
+        if self.sandbox.as_ref().is_some_and(|sandbox| sandbox.is_test_payment(request)) {
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_PAID.to_string(),
+                preimage: None,
+                payment_hash: None,
+                error: None,
+                auth_header: None,
+                scheme: None,
+            });
+            return;
+        }
+
+        if self.verify_alternative_scheme(request).await {
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_PAID.to_string(),
+                preimage: None,
+                payment_hash: None,
+                error: None,
+                auth_header: None,
+                scheme: None,
+            });
+            return;
+        }
+
+        let caveats = self.request_caveats(request);
+        let auth_field = match self.authorization_field(request) {
+            Ok(auth_field) => auth_field,
+            Err(error) => {
+                self.record_failure(request, FailureReason::Malformed, None);
+                request.local_cache(|| l402::L402Info {
+                    l402_type: l402::L402_TYPE_ERROR.to_string(),
+                    error: Some(error.clone()),
+                    preimage: None,
+                    payment_hash: None,
+                    auth_header: None,
+                    scheme: None,
+                });
+                println!("Error parsing L402: {}", error);
+                return;
+            },
+        };
+        let session_first = self.token_precedence == TokenPrecedence::Session;
+        if (auth_field.is_none() || session_first) && self.verify_session(request, &caveats).await {
+            return;
+        }
+        if let Some(auth_field) = auth_field {
+            match utils::parse_l402_header(&auth_field) {
                 Ok((mac, preimage)) => {
-                    match l402::verify_l402(&mac, caveats, self.root_key.clone(), preimage) {
+                    let scheme = l402::split_auth_scheme(&auth_field).0.unwrap_or(l402::SUPPORTED_SCHEMES[0]);
+                    request.local_cache(|| NegotiatedScheme(scheme));
+                    let cx = trace::start_span(request, "l402.verify");
+                    let mut verification = l402::verify_l402(&mac, caveats.clone(), self.root_key.clone(), preimage)
+                        .map_err(|error| error.to_string());
+                    if verification.is_err() && self.advertise_offer {
+                        match self.verify_offer_payment(&mac, &caveats, preimage).with_context(cx.clone()).await {
+                            Ok(()) => verification = Ok(()),
+                            Err(BackendError::BudgetExceeded) => {
+                                cx.span().end();
+                                self.exceed_budget(request);
+                                return;
+                            },
+                            Err(BackendError::Failed(_)) => {},
+                        }
+                    }
+                    if verification.is_err() && self.sandbox.as_ref().is_some_and(|sandbox| sandbox.is_test_preimage(&preimage))
+                        && l402::verify_l402_caveats(&mac, caveats.clone(), self.root_key.clone()).is_ok() {
+                        verification = Ok(());
+                    }
+                    match verification {
                         Ok(_) => {
-                            let payment_hash: PaymentHash = PaymentHash::from(preimage);
-                            request.local_cache(|| l402::L402Info {
-                                l402_type: l402::L402_TYPE_PAID.to_string(),
-                                preimage: Some(preimage),
-                                payment_hash: Some(payment_hash),
-                                error: None,
-                                auth_header: None,
-                            });
+                            let accepted = self.accept_token(request, &mac, preimage, caveats).with_context(cx.clone()).await;
+                            cx.span().end();
+                            if !accepted {
+                                return;
+                            }
+                            if let Some(session_config) = &self.session_cookie {
+                                let (_, token) = l402::split_auth_scheme(&auth_field);
+                                if let Err(error) = session::issue_session(request, session_config, self.token_store.as_ref(), token).await {
+                                    println!("Error issuing L402 session: {}", error);
+                                }
+                            }
                         },
                         Err(error) => {
+                            cx.span().set_status(Status::error(error.clone()));
+                            cx.span().end();
+                            let signed = l402::verify_macaroon_signature(&mac, &self.root_key);
+                            let reason = if !signed {
+                                FailureReason::WrongSignature
+                            } else if l402::verify_l402_caveats(&mac, caveats.clone(), self.root_key.clone()).is_err() {
+                                FailureReason::CaveatMismatch
+                            } else {
+                                FailureReason::Unsettled
+                            };
+                            self.record_failure(request, reason, Some(&mac));
+                            // A macaroon minted under a previous root key can never verify
+                            // again, so the client gets a fresh challenge rather than an error
+                            if !signed {
+                                println!("L402 macaroon not signed with the current root key, issuing a fresh challenge");
+                                L402Middleware::set_l402_header(self, request, caveats).await;
+                                return;
+                            }
                             request.local_cache(|| l402::L402Info {
                                 l402_type: l402::L402_TYPE_ERROR.to_string(),
                                 error: Some(error.to_string()),
                                 preimage: None,
                                 payment_hash: None,
                                 auth_header: None,
+                                scheme: None,
                             });
                             println!("Error verifying L402: {}", error);
                         }
                     }
                 },
+                // Only reported when the client gets no challenge
+                #[cfg_attr(feature = "no-accept-authenticate-required", allow(unused_variables))]
                 Err(error) => {
+                    // Headers of other schemes, e.g. Basic, aren't L402 tokens gone wrong
+                    if l402::split_auth_scheme(&auth_field).0.is_some() {
+                        self.record_failure(request, FailureReason::Malformed, None);
+                    }
+                    let accept_scheme = request.headers().get_one(l402::L402_HEADER_NAME).map(l402::negotiate_scheme);
+
                     #[cfg(feature = "no-accept-authenticate-required")]
-                    L402Middleware::set_l402_header(self, request, caveats).await;
+                    {
+                        let scheme = accept_scheme.flatten().unwrap_or(l402::SUPPORTED_SCHEMES[0]);
+                        request.local_cache(|| NegotiatedScheme(scheme));
+                        L402Middleware::set_l402_header(self, request, caveats).await;
+                    }
 
                     #[cfg(not(feature = "no-accept-authenticate-required"))]
-                    if let Some(accept_l402_field) = request.headers().get_one(l402::L402_HEADER_NAME) {
-                        if accept_l402_field.contains(l402::L402_HEADER) {
+                    if let Some(accept_scheme) = accept_scheme {
+                        if let Some(scheme) = accept_scheme {
+                            request.local_cache(|| NegotiatedScheme(scheme));
                             L402Middleware::set_l402_header(self, request, caveats).await;
                         } else {
-                            request.local_cache(|| l402::L402Info {
-                                l402_type: l402::L402_TYPE_FREE.to_string(),
-                                preimage: None,
-                                payment_hash: None,
-                                error: None,
-                                auth_header: None,
-                            });
+                            L402Middleware::serve_free_tier(self, request, caveats).await;
                         }
                     } else {
                         request.local_cache(|| l402::L402Info {
@@ -151,18 +1286,26 @@ impl Fairing for L402Middleware {
                             preimage: None,
                             payment_hash: None,
                             auth_header: None,
+                            scheme: None,
                         });
                         println!("Error parsing L402: {}", error);
                     }
                 },
             }
         } else {
+            let accept_scheme = request.headers().get_one(l402::L402_HEADER_NAME).map(l402::negotiate_scheme);
+
             #[cfg(feature = "no-accept-authenticate-required")]
-            L402Middleware::set_l402_header(self, request, caveats).await;
+            {
+                let scheme = accept_scheme.flatten().unwrap_or(l402::SUPPORTED_SCHEMES[0]);
+                request.local_cache(|| NegotiatedScheme(scheme));
+                L402Middleware::set_l402_header(self, request, caveats).await;
+            }
 
             #[cfg(not(feature = "no-accept-authenticate-required"))]
-            if let Some(accept_l402_field) = request.headers().get_one(l402::L402_HEADER_NAME) {
-                if accept_l402_field.contains(l402::L402_HEADER) {
+            if let Some(accept_scheme) = accept_scheme {
+                if let Some(scheme) = accept_scheme {
+                    request.local_cache(|| NegotiatedScheme(scheme));
                     L402Middleware::set_l402_header(self, request, caveats).await;
                     request.local_cache(|| l402::L402Info {
                         l402_type: l402::L402_TYPE_PAYMENT_REQUIRED.to_string(),
@@ -170,35 +1313,287 @@ impl Fairing for L402Middleware {
                         payment_hash: None,
                         error: None,
                         auth_header: None,
+                        scheme: None,
                     });
                 } else {
-                    request.local_cache(|| l402::L402Info {
-                        l402_type: l402::L402_TYPE_FREE.to_string(),
-                        preimage: None,
-                        payment_hash: None,
-                        error: None,
-                        auth_header: None,
-                    });
+                    L402Middleware::serve_free_tier(self, request, caveats).await;
                 }
             }
         }
     }
 
-    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
-        // Retrieve L402Info from the local cache
-        let l402_info = request.local_cache::<l402::L402Info, _>(|| {
-            l402::L402Info {
-                l402_type: l402::L402_TYPE_ERROR.to_string(),
-                error: Some("No L402 header present".to_string()),
+    // Runs a call to the rate oracle or backend within `request_budget` when one is
+    // set. None when it overran the limit, in which case the call is abandoned.
+    async fn within_budget<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        match self.request_budget {
+            Some(budget) => tokio::time::timeout(budget.limit, future).await.ok(),
+            None => Some(future.await),
+        }
+    }
+
+    // Settles a request whose rate oracle or backend call overran the budget
+    fn exceed_budget(&self, request: &mut Request<'_>) {
+        let policy = self.request_budget.map(|budget| budget.policy).unwrap_or_default();
+        L402Metrics::incr(&self.metrics.budget_exceeded);
+        println!("L402 request budget exceeded for {}, failing {}", request.uri().path(), match policy {
+            BudgetPolicy::FailClosed => "closed",
+            BudgetPolicy::FailOpen => "open",
+        });
+        request.local_cache(|| BudgetExceeded(Some(policy)));
+    }
+}
+
+// Outcome the fairing recorded for the request. A budget overrun wins over whatever
+// was cached before it, since cached values can't be replaced.
+fn recorded_l402_info(request: &Request<'_>) -> l402::L402Info {
+    if let BudgetExceeded(Some(policy)) = request.local_cache(|| BudgetExceeded(None)) {
+        return l402::L402Info {
+            l402_type: match policy {
+                BudgetPolicy::FailClosed => l402::L402_TYPE_ERROR,
+                BudgetPolicy::FailOpen => l402::L402_TYPE_FREE,
+            }.to_string(),
+            error: Some("Request budget exceeded".to_string()),
+            preimage: None,
+            payment_hash: None,
+            auth_header: None,
+            scheme: None,
+        };
+    }
+    request.local_cache::<l402::L402Info, _>(|| {
+        l402::L402Info {
+            l402_type: l402::L402_TYPE_ERROR.to_string(),
+            error: Some("No L402 header present".to_string()),
+            preimage: None,
+            payment_hash: None,
+            auth_header: None,
+            scheme: None,
+        }
+    }).clone()
+}
+
+#[rocket::async_trait]
+impl Fairing for L402Middleware {
+    fn info(&self) -> Info {
+        Info {
+            name: "L402 Middleware",
+            kind: Kind::Ignite | Kind::Liftoff | Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        if let Some(sandbox) = &self.sandbox {
+            let profile = rocket.figment().profile().to_string();
+            if !sandbox.allows_profile(&profile) {
+                println!("Refusing to launch: L402 sandbox payments are configured but the Rocket profile is {}", profile);
+                return Err(rocket);
+            }
+            println!("L402 sandbox payments enabled, nothing is charged for real");
+        }
+
+        self.events.subscribe(Arc::clone(&self.metrics) as Arc<dyn events::EventSubscriber>);
+        if !self.observers.is_empty() {
+            self.events.subscribe(Arc::new(events::ObserverSubscriber(self.observers.clone())));
+        }
+
+        let mut rocket = rocket;
+        if let Some(config) = &self.signed_urls {
+            rocket = rocket.manage(signed_url::UrlSigner::new(config.clone()));
+        }
+
+        let endpoint_routes = self.endpoints.routes();
+        if !endpoint_routes.is_empty() {
+            rocket = rocket
+                .manage(routes::EndpointState {
+                    token_store: Arc::clone(&self.token_store),
+                    metrics: Arc::clone(&self.metrics),
+                    events: Arc::clone(&self.events),
+                    root_key: self.root_key.clone(),
+                    ln_client: Arc::clone(&self.ln_client),
+                    legacy_root_key: self.legacy_root_key.clone().unwrap_or_else(|| self.root_key.clone()),
+                    settlement_poll_interval: self.endpoints.settlement_poll_interval,
+                    settlement_waiters: routes::SettlementWaiters::new(self.endpoints.max_settlement_waiters),
+                    introspection_clients: self.endpoints.introspection_clients.clone(),
+                    settlement_clients: self.endpoints.settlement_clients.clone(),
+                    notified_settlements: self.endpoints.settlements,
+                    pricing_table: self.pricing_table.clone(),
+                    max_uses: self.max_uses,
+                    public_base_url: self.endpoints.public_base_url.clone(),
+                    trusted_proxies: self.trusted_proxies.clone(),
+                })
+                .mount(self.endpoints.base.as_str(), endpoint_routes);
+        }
+        Ok(rocket)
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<Orbit>) {
+        if let Some(config) = &self.reconciliation {
+            reconcile::Reconciler {
+                config: config.clone(),
+                ln_client: Arc::clone(&self.ln_client),
+                token_store: Arc::clone(&self.token_store),
+                metrics: Arc::clone(&self.metrics),
+                observers: self.observers.clone(),
+                events: Arc::clone(&self.events),
+            }.spawn();
+        }
+        if let Some(config) = &self.anomaly_detection {
+            anomaly::AnomalyDetector::new(config.clone(), Arc::clone(&self.metrics), self.observers.clone()).spawn();
+        }
+    }
+
+    async fn on_shutdown(&self, _: &Rocket<Orbit>) {
+        if !self.shutdown_state.drain(self.shutdown.drain_timeout).await {
+            println!("L402 requests still in flight after {:?}, shutting down anyway", self.shutdown.drain_timeout);
+        }
+        if self.shutdown.cancel_unpaid_invoices {
+            self.cancel_unpaid_invoices().await;
+        }
+        for observer in &self.observers {
+            observer.flush().await;
+        }
+        if let Err(error) = self.token_store.flush().await {
+            println!("Error flushing the L402 ledger: {}", error);
+        }
+        if let Err(error) = self.ln_client.close().await {
+            println!("Error closing the L402 backend connection: {}", error);
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        forwarded::cache_client_address(request, &self.trusted_proxies);
+
+        // Built-in endpoints do their own authentication
+        if self.endpoints.any_enabled() && self.endpoints.serves(request.uri().path().as_str()) {
+            return;
+        }
+
+        // CORS preflights and capability probes are never charged
+        if request.method() == Method::Options {
+            request.local_cache(|| l402::L402Info {
+                l402_type: l402::L402_TYPE_FREE.to_string(),
                 preimage: None,
                 payment_hash: None,
+                error: None,
                 auth_header: None,
-            }
-        });
+                scheme: None,
+            });
+            return;
+        }
+
+        let _in_flight = self.shutdown_state.begin_request();
+        self.authorize_request(request).await;
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let l402_info = recorded_l402_info(request);
 
         // Check if the auth header is set and add it to the response
         if let Some(header_value) = &l402_info.auth_header {
+            // Alternative schemes follow the L402 challenge, comma-separated
+            let header_value = std::iter::once(header_value.clone())
+                .chain(self.alternative_schemes.iter().map(|scheme| scheme.challenge()))
+                .collect::<Vec<_>>()
+                .join(", ");
             response.set_header(Header::new(l402::L402_AUTHENTICATE_HEADER_NAME, header_value));
+            // One challenge per bundle option, after the default one
+            let BundleChallenges(bundle_headers) = request.local_cache(|| BundleChallenges(Vec::new()));
+            for bundle_header in bundle_headers {
+                response.adjoin_header(Header::new(l402::L402_AUTHENTICATE_HEADER_NAME, bundle_header.clone()));
+            }
+        }
+
+        // Screened-out clients get a bare 403, whatever the handler made of the error
+        if let ScreeningDenied(true) = request.local_cache(|| ScreeningDenied(false)) {
+            let message = "Request denied";
+            response.set_status(HttpStatus::Forbidden);
+            response.set_header(ContentType::Plain);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
+        }
+
+        if let ShuttingDown(true) = request.local_cache(|| ShuttingDown(false)) {
+            let message = "Shutting down, try again";
+            response.set_status(HttpStatus::ServiceUnavailable);
+            response.set_header(Header::new(l402::L402_ERROR_HEADER_NAME, l402::SHUTTING_DOWN_ERROR));
+            response.set_header(Header::new("Retry-After", "1"));
+            response.set_header(ContentType::Plain);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
+        }
+
+        if let BudgetExceeded(Some(BudgetPolicy::FailClosed)) = request.local_cache(|| BudgetExceeded(None)) {
+            let message = "Payment verification is taking too long, try again";
+            response.set_status(HttpStatus::ServiceUnavailable);
+            response.set_header(Header::new(l402::L402_ERROR_HEADER_NAME, l402::BUDGET_EXCEEDED_ERROR));
+            response.set_header(Header::new("Retry-After", "1"));
+            response.set_header(ContentType::Plain);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
         }
+
+        // Tokens used outside their window get a machine-readable reason, and early ones a retry hint
+        if let ValidityRejected(Some(validity_error)) = request.local_cache(|| ValidityRejected(None)) {
+            let message = validity_error.to_string();
+            response.set_status(HttpStatus::Forbidden);
+            response.set_header(Header::new(l402::L402_ERROR_HEADER_NAME, validity_error.code()));
+            if let l402::ValidityError::NotYetValid { not_before } = validity_error {
+                response.set_header(Header::new("Retry-After", not_before.saturating_sub(utils::now_unix()).to_string()));
+            }
+            response.set_header(ContentType::Plain);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
+        }
+
+        if let ConcurrencyLimited(true) = request.local_cache(|| ConcurrencyLimited(false)) {
+            let message = "Too many concurrent requests for this token";
+            response.set_status(HttpStatus::TooManyRequests);
+            response.set_header(Header::new(l402::L402_ERROR_HEADER_NAME, l402::TOO_MANY_CONCURRENT_ERROR));
+            response.set_header(ContentType::Plain);
+            response.set_sized_body(message.len(), Cursor::new(message));
+            return;
+        }
+
+        if let (Some(paywall), IssuedChallenge(Some(challenge))) = (&self.paywall, request.local_cache(|| IssuedChallenge(None))) {
+            if response.status() == HttpStatus::PaymentRequired {
+                if let Some((content_type, body)) = paywall.render(request, challenge) {
+                    response.set_header(content_type);
+                    response.set_sized_body(body.len(), Cursor::new(body));
+                }
+            }
+        }
+
+        // Echo the content hash the token paid for so the client can verify delivery
+        if let ContentCommitment(Some(content_hash)) = request.local_cache(|| ContentCommitment(None)) {
+            response.set_header(Header::new(l402::L402_CONTENT_HASH_HEADER_NAME, content_hash.clone()));
+        }
+
+        // A continuation of a partial response picks up where it ended; after a full one, nothing is free
+        if let ChargedResource(Some((token_id, resource))) = request.local_cache(|| ChargedResource(None)) {
+            let served_end = response.headers().get_one(l402::L402_CONTENT_RANGE_HEADER_NAME).and_then(utils::content_range_end);
+            if let Some(served_end) = served_end.filter(|_| response.status() == HttpStatus::PartialContent) {
+                if let Err(error) = self.token_store.record_served(token_id, resource, served_end).await {
+                    println!("Error recording L402 range served: {}", error);
+                }
+            }
+        }
+
+        // The slot stays taken while the body streams, not just until the handler returns
+        if let ConcurrencySlot(Some(token_id)) = request.local_cache(|| ConcurrencySlot(None)) {
+            let body = response.body_mut().take();
+            response.set_streamed_body(SlotReleasingBody {
+                body,
+                _slot: SlotGuard::new(Arc::clone(&self.token_store), token_id.clone()),
+            });
+        }
+    }
+}
+
+// Hands handlers the outcome the fairing recorded for the request
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for l402::L402Info {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(recorded_l402_info(request))
     }
 }
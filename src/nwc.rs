@@ -1,12 +1,8 @@
 use nwc::prelude::*;
 use std::sync::Arc;
-use crate::lndrpc::lnrpc;
 use tokio::sync::Mutex;
-use std::future::Future;
-use std::pin::Pin;
-use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
 
-use crate::lnclient;
+use crate::lnclient::{self, BackendError};
 
 #[derive(Debug, Clone)]
 pub struct NWCOptions {
@@ -15,52 +11,75 @@ pub struct NWCOptions {
 
 pub struct NWCWrapper {
     pub client: Arc<Mutex<NWC>>,
+    // Wallet service pubkey from the connection URI; the URI itself carries a secret
+    wallet_pubkey: String,
 }
 
 impl NWCWrapper {
-    pub async fn new_client(ln_client_config: &lnclient::LNClientConfig) -> Result<Arc<Mutex<dyn lnclient::LNClient>>, Box<dyn std::error::Error + Send + Sync>> {
-        let nwc_options = ln_client_config.nwc_config.clone().unwrap();
+    pub async fn new_client(ln_client_config: &lnclient::LNClientConfig) -> Result<Arc<dyn lnclient::LNClient>, Box<dyn std::error::Error + Send + Sync>> {
+        let nwc_options = ln_client_config.nwc_config.clone()
+            .ok_or("nwc_config is required for the NWC client")?;
         let uri = NostrWalletConnectURI::parse(&nwc_options.uri)?;
+        let wallet_pubkey = uri.public_key.to_hex();
         let nwc = NWC::new(uri);
-        Ok(Arc::new(Mutex::new(NWCWrapper { client: Arc::new(Mutex::new(nwc)) })))
+        Ok(Arc::new(NWCWrapper { client: Arc::new(Mutex::new(nwc)), wallet_pubkey }))
     }
 }
 
+lnclient::invoice_conversion!(From<lnclient::InvoiceRequest> for MakeInvoiceRequest, |invoice| {
+    amount: invoice.value_msat as u64,
+    description: invoice.description(),
+    description_hash: invoice.memo_hash().map(hex::encode),
+    expiry: invoice.expiry,
+});
+
+lnclient::invoice_conversion!(TryFrom<MakeInvoiceResponse> for lnclient::InvoiceResponse, |response| {
+    payment_hash: hex::decode(&response.payment_hash)
+        .map_err(BackendError::malformed)?
+        .try_into()
+        .map_err(|_| BackendError::malformed("invalid payment hash length from NWC"))?,
+    payment_request: response.invoice,
+});
+
 impl lnclient::LNClient for NWCWrapper {
-    fn add_invoice(
-        &self,
-        invoice: lnrpc::Invoice,
-    ) -> Pin<Box<dyn Future<Output = Result<lnrpc::AddInvoiceResponse, Box<dyn std::error::Error + Send + Sync>>> + Send>> {
+    fn backend_id(&self) -> String {
+        format!("nwc:{}", self.wallet_pubkey)
+    }
+
+    fn add_invoice(&self, invoice: lnclient::InvoiceRequest) -> lnclient::LNClientFuture<lnclient::InvoiceResponse> {
         let client = Arc::clone(&self.client);
         Box::pin(async move {
             let client = client.lock().await;
 
-            let params = MakeInvoiceRequest {
-                amount: invoice.value_msat as u64,
-                description: None,
-                description_hash: None,
-                expiry: None,
-            };
-            let response = match client.make_invoice(params).await {
+            let params = MakeInvoiceRequest::from(invoice);
+            match client.make_invoice(params).await {
                 Ok(res) => {
                     println!("response {:?}", res);
-
-                    let decoded_invoice = Bolt11Invoice::from_signed(res.invoice.parse::<SignedRawBolt11Invoice>().unwrap()).unwrap();
-                    let payment_addr = decoded_invoice.payment_secret();
-                    lnrpc::AddInvoiceResponse {
-                        r_hash: hex::decode(&res.payment_hash).unwrap(),
-                        payment_request: res.invoice,
-                        add_index: 0,
-                        payment_addr: payment_addr.0.to_vec(),
-                    }
+                    res.try_into()
                 }
                 Err(e) => {
                     eprintln!("Error adding invoice: {:?}", e);
                     let boxed_error: Box<dyn std::error::Error + Send + Sync> = Box::new(e);
-                    return Err(boxed_error);
+                    Err(boxed_error)
                 }
-            };
-            Ok(response)
+            }
+        })
+    }
+
+    fn lookup_invoice(&self, payment_hash: [u8; 32]) -> lnclient::LNClientFuture<lnclient::InvoiceState> {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move {
+            let client = client.lock().await;
+            let response = client.lookup_invoice(LookupInvoiceRequest {
+                payment_hash: Some(hex::encode(payment_hash)),
+                invoice: None,
+            }).await?;
+
+            if response.settled_at.is_some() {
+                return Ok(lnclient::InvoiceState::Settled);
+            }
+            let expired = response.expires_at.is_some_and(|t| t < Timestamp::now());
+            Ok(if expired { lnclient::InvoiceState::Canceled } else { lnclient::InvoiceState::Open })
         })
     }
 }
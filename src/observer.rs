@@ -0,0 +1,49 @@
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::anomaly::AnomalyKind;
+use crate::events::DomainEvent;
+
+/// Noteworthy things the middleware reports to its observers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum L402Event {
+    /// A token was accepted but the backend has no settled invoice for it
+    SettlementDiscrepancy {
+        payment_hash: String,
+        /// Backend that minted the invoice
+        backend: String,
+        /// Invoice state reported by the backend
+        backend_state: String,
+        /// Whether the token was revoked
+        revoked: bool,
+    },
+    /// A detector crossed its threshold; reported once until it clears
+    Anomaly {
+        kind: AnomalyKind,
+        /// Failures, or the ratio, seen over the last interval
+        observed: f64,
+        threshold: f64,
+    },
+}
+
+/// Receives middleware events, e.g. to forward them to a webhook or an alerting system.
+pub trait L402Observer: Send + Sync + 'static {
+    fn on_event(&self, event: &L402Event);
+
+    /// Receives the middleware's `events`, once it is ignited. Ignored by default.
+    fn on_domain_event(&self, _event: &DomainEvent) {}
+
+    /// Finishes delivering the events received so far, on shutdown.
+    fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+}
+
+pub fn notify(observers: &[Arc<dyn L402Observer>], event: L402Event) {
+    for observer in observers {
+        observer.on_event(&event);
+    }
+}
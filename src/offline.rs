@@ -0,0 +1,201 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use lightning::types::payment::PaymentHash;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::l402;
+use crate::utils;
+
+const PACK_HKDF_INFO: &[u8] = b"l402-offline-pack";
+
+/// A challenge minted ahead of time for an appliance to hand out while offline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflineChallenge {
+    /// Hex payment hash, which is also the token id
+    pub payment_hash: String,
+    pub invoice: String,
+    pub macaroon: String,
+    pub amount_msat: i64,
+    /// Request path the token is bound to, as in its `RequestPath` caveat
+    pub path: String,
+    /// Unix timestamp the invoice expires
+    pub expires_at: u64,
+}
+
+impl OfflineChallenge {
+    /// The challenge as it goes in `WWW-Authenticate`.
+    pub fn www_authenticate(&self) -> String {
+        format!("{} macaroon={}, invoice={}", l402::L402_HEADER, self.macaroon, self.invoice)
+    }
+}
+
+/// Challenges minted for one appliance, see `L402Middleware::mint_challenge_pack`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengePack {
+    pub appliance_id: String,
+    /// Unix timestamp the pack was minted
+    pub created_at: u64,
+    pub challenges: Vec<OfflineChallenge>,
+}
+
+/// A payment the appliance saw proven while offline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OfflineSettlement {
+    /// Hex payment hash
+    pub payment_hash: String,
+    /// Hex preimage the client presented
+    pub preimage: String,
+    /// Unix timestamp the appliance first accepted the token
+    pub settled_at: u64,
+}
+
+/// Settlements an appliance syncs back once connected, see
+/// `L402Middleware::import_settlement_report`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettlementReport {
+    pub appliance_id: String,
+    pub settlements: Vec<OfflineSettlement>,
+}
+
+/// A pack or report encrypted with the key shared between the server and an appliance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    /// Hex ChaCha20-Poly1305 nonce
+    pub nonce: String,
+    /// Hex ciphertext of the JSON contents
+    pub ciphertext: String,
+}
+
+impl ChallengePack {
+    /// Encrypts the pack with `pack_key`, bound to its appliance id.
+    pub fn seal(&self, pack_key: &[u8; 32]) -> Result<Sealed, Box<dyn Error + Send + Sync>> {
+        seal(pack_key, &self.appliance_id, self)
+    }
+
+    /// Decrypts a pack sealed for `appliance_id`.
+    pub fn open(sealed: &Sealed, pack_key: &[u8; 32], appliance_id: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        open(pack_key, appliance_id, sealed)
+    }
+}
+
+impl SettlementReport {
+    /// Encrypts the report with `pack_key`, bound to its appliance id.
+    pub fn seal(&self, pack_key: &[u8; 32]) -> Result<Sealed, Box<dyn Error + Send + Sync>> {
+        seal(pack_key, &self.appliance_id, self)
+    }
+
+    /// Decrypts a report sealed by `appliance_id`.
+    pub fn open(sealed: &Sealed, pack_key: &[u8; 32], appliance_id: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        open(pack_key, appliance_id, sealed)
+    }
+}
+
+/// Serves the challenges of a pack without a Lightning backend or the root key. Each
+/// challenge is handed out once, and a token is accepted if it is one of the pack's
+/// macaroons, unattenuated, with the preimage of its invoice. Accepted payments are
+/// kept for `settlement_report` until the appliance is connected again.
+pub struct OfflineAppliance {
+    pack: ChallengePack,
+    issued: Mutex<HashSet<String>>,
+    settlements: Mutex<Vec<OfflineSettlement>>,
+}
+
+impl OfflineAppliance {
+    pub fn new(pack: ChallengePack) -> Self {
+        OfflineAppliance { pack, issued: Mutex::new(HashSet::new()), settlements: Mutex::new(Vec::new()) }
+    }
+
+    /// Hands out the next unused, unexpired challenge for `path`. None once the pack
+    /// has run out for it.
+    pub fn next_challenge(&self, path: &str) -> Option<OfflineChallenge> {
+        let now = utils::now_unix();
+        let mut issued = self.issued.lock().ok()?;
+        let challenge = self.pack.challenges.iter()
+            .find(|c| c.path == path && c.expires_at > now && !issued.contains(&c.payment_hash))?;
+        issued.insert(challenge.payment_hash.clone());
+        Some(challenge.clone())
+    }
+
+    /// Challenges for `path` that can still be handed out.
+    pub fn remaining(&self, path: &str) -> usize {
+        let now = utils::now_unix();
+        let issued = self.issued.lock().map(|issued| issued.clone()).unwrap_or_default();
+        self.pack.challenges.iter()
+            .filter(|c| c.path == path && c.expires_at > now && !issued.contains(&c.payment_hash))
+            .count()
+    }
+
+    /// Checks an `Authorization` header presented for `path`, recording the settlement
+    /// the first time the token is seen.
+    pub fn verify(&self, auth_field: &str, path: &str) -> Result<(), String> {
+        let (mac, preimage) = utils::parse_l402_header(auth_field)?;
+        let payment_hash = hex::encode(PaymentHash::from(preimage).0);
+        let challenge = self.pack.challenges.iter()
+            .find(|c| c.payment_hash == payment_hash)
+            .ok_or("Preimage does not match any challenge in the pack")?;
+        let minted = utils::get_macaroon_from_string(challenge.macaroon.clone())?;
+        // Any added caveat changes the signature, so only the macaroon as minted matches
+        if mac.signature() != minted.signature() || mac.identifier() != minted.identifier() {
+            return Err("Macaroon does not match the pack".to_string());
+        }
+        if challenge.path != path {
+            return Err(format!("Token is for {}, not {}", challenge.path, path));
+        }
+
+        let mut settlements = self.settlements.lock().map_err(|_| "settlements poisoned")?;
+        if !settlements.iter().any(|s| s.payment_hash == payment_hash) {
+            settlements.push(OfflineSettlement {
+                payment_hash,
+                preimage: hex::encode(preimage.0),
+                settled_at: utils::now_unix(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Payments accepted so far, to sync back to the server. Importing a report twice
+    /// is harmless, so the appliance needn't track what was already synced.
+    pub fn settlement_report(&self) -> SettlementReport {
+        SettlementReport {
+            appliance_id: self.pack.appliance_id.clone(),
+            settlements: self.settlements.lock().map(|s| s.clone()).unwrap_or_default(),
+        }
+    }
+}
+
+fn seal<T: Serialize>(pack_key: &[u8; 32], appliance_id: &str, contents: &T) -> Result<Sealed, Box<dyn Error + Send + Sync>> {
+    let plaintext = serde_json::to_vec(contents)?;
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(pack_key, appliance_id)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| "Failed to seal offline pack")?;
+    Ok(Sealed { nonce: hex::encode(nonce), ciphertext: hex::encode(ciphertext) })
+}
+
+fn open<T: DeserializeOwned>(pack_key: &[u8; 32], appliance_id: &str, sealed: &Sealed) -> Result<T, Box<dyn Error + Send + Sync>> {
+    let nonce = hex::decode(&sealed.nonce).ok()
+        .filter(|n| n.len() == 12)
+        .ok_or("Invalid offline pack nonce")?;
+    let ciphertext = hex::decode(&sealed.ciphertext).map_err(|_| "Invalid offline pack ciphertext")?;
+    let plaintext = cipher(pack_key, appliance_id)?
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| "Offline pack could not be opened; is the key or appliance id wrong?")?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+fn cipher(pack_key: &[u8; 32], appliance_id: &str) -> Result<ChaCha20Poly1305, Box<dyn Error + Send + Sync>> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(appliance_id.as_bytes()), pack_key)
+        .expand(PACK_HKDF_INFO, &mut key)
+        .map_err(|_| "Failed to derive offline pack key")?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
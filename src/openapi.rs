@@ -0,0 +1,75 @@
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::{
+    Header, Object, ParameterValue, RefOr, Response, Responses, SecurityRequirement, SecurityScheme, SecuritySchemeData,
+};
+use rocket_okapi::okapi::Map;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+
+use crate::l402;
+
+/// Name of the security scheme routes taking `L402Info` are documented with.
+pub const L402_SECURITY_SCHEME: &str = "L402";
+
+fn header(gen: &mut OpenApiGenerator, description: &str) -> RefOr<Header> {
+    RefOr::Object(Header {
+        description: Some(description.to_string()),
+        required: false,
+        deprecated: false,
+        allow_empty_value: false,
+        value: ParameterValue::Schema {
+            style: None,
+            explode: None,
+            allow_reserved: false,
+            schema: gen.json_schema::<String>(),
+            example: None,
+            examples: None,
+        },
+        extensions: Object::default(),
+    })
+}
+
+fn response(description: &str, headers: Map<String, RefOr<Header>>) -> RefOr<Response> {
+    RefOr::Object(Response {
+        description: description.to_string(),
+        headers,
+        ..Default::default()
+    })
+}
+
+// Documents the routes the middleware guards: the token they take, and the 402
+// challenge, 403 and 429 responses the middleware can answer them with.
+impl<'r> OpenApiFromRequest<'r> for l402::L402Info {
+    fn from_request_input(_gen: &mut OpenApiGenerator, _name: String, _required: bool) -> rocket_okapi::Result<RequestHeaderInput> {
+        let scheme = SecurityScheme {
+            description: Some(format!(
+                "Send `{}: {} <macaroon>:<preimage>`. Request the route with `{}: {}` first to get a 402 \
+                 challenge with the macaroon and a Lightning invoice; the preimage is the invoice's payment proof.",
+                l402::L402_AUTHORIZATION_HEADER_NAME, l402::L402_HEADER, l402::L402_HEADER_NAME, l402::L402_HEADER,
+            )),
+            data: SecuritySchemeData::Http {
+                scheme: l402::L402_HEADER.to_string(),
+                bearer_format: Some("macaroon:preimage".to_string()),
+            },
+            extensions: Object::default(),
+        };
+        let mut requirement = SecurityRequirement::new();
+        requirement.insert(L402_SECURITY_SCHEME.to_string(), Vec::new());
+        Ok(RequestHeaderInput::Security(L402_SECURITY_SCHEME.to_string(), scheme, requirement))
+    }
+
+    fn get_responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut challenge_headers = Map::new();
+        challenge_headers.insert(
+            l402::L402_AUTHENTICATE_HEADER_NAME.to_string(),
+            header(gen, &format!("`{} macaroon=\"...\", invoice=\"...\"`; pay the invoice and retry with the token", l402::L402_HEADER)),
+        );
+        let mut error_headers = Map::new();
+        error_headers.insert(l402::L402_ERROR_HEADER_NAME.to_string(), header(gen, "Why the token was refused"));
+
+        let mut responses = Responses::default();
+        responses.responses.insert("402".to_string(), response("Payment required: the challenge to pay", challenge_headers));
+        responses.responses.insert("403".to_string(), response("The client was screened out, or the token is outside its validity window", error_headers.clone()));
+        responses.responses.insert("429".to_string(), response("The token has too many requests in flight", error_headers));
+        Ok(responses)
+    }
+}
@@ -0,0 +1,65 @@
+use rocket::http::RawStr;
+
+use crate::l402;
+
+/// How the path in `RequestPath` caveats is normalized, the same way when a token
+/// is minted and when it is verified, so `/docs/`, `//docs` and `/docs?page=2`
+/// are all the `/docs` a token was bought for. The query and fragment are always
+/// dropped. Opt in by setting the middleware's `path_normalization`; it matches
+/// paths exactly by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathNormalization {
+    /// Percent-decode each segment, e.g. `/caf%C3%A9` to `/café`. Encoded slashes
+    /// stay encoded so they can't split a segment.
+    pub decode: bool,
+    /// Collapse repeated slashes and drop a trailing one, e.g. `//docs/` to `/docs`
+    pub collapse_slashes: bool,
+    /// Prefixes stripped from the path, e.g. a `/api/v1` mount point, so tokens stay
+    /// valid when the app is mounted elsewhere. Only whole segments are stripped.
+    pub strip_prefixes: Vec<String>,
+}
+
+impl Default for PathNormalization {
+    fn default() -> Self {
+        PathNormalization { decode: true, collapse_slashes: true, strip_prefixes: Vec::new() }
+    }
+}
+
+impl PathNormalization {
+    pub fn normalize(&self, path: &str) -> String {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let mut segments: Vec<String> = path.strip_prefix('/').unwrap_or(path).split('/').map(|segment| {
+            if self.decode {
+                RawStr::new(segment).percent_decode_lossy().replace('/', "%2F")
+            } else {
+                segment.to_string()
+            }
+        }).collect();
+        if self.collapse_slashes {
+            segments.retain(|segment| !segment.is_empty());
+        }
+
+        let mut normalized = format!("/{}", segments.join("/"));
+        for prefix in &self.strip_prefixes {
+            let prefix = prefix.trim_end_matches('/');
+            if prefix.is_empty() {
+                continue;
+            }
+            if let Some(rest) = normalized.strip_prefix(prefix).filter(|rest| rest.is_empty() || rest.starts_with('/')) {
+                normalized = if rest.is_empty() { "/".to_string() } else { rest.to_string() };
+                break;
+            }
+        }
+        normalized
+    }
+
+    /// Normalizes the path of a `RequestPath = /path` caveat, leaving other caveats as they are.
+    pub fn normalize_caveat(&self, caveat: &str) -> String {
+        match caveat.split_once('=') {
+            Some((key, path)) if key.trim() == l402::REQUEST_PATH_CAVEAT => {
+                format!("{} = {}", l402::REQUEST_PATH_CAVEAT, self.normalize(path.trim()))
+            },
+            _ => caveat.to_string(),
+        }
+    }
+}
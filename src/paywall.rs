@@ -0,0 +1,96 @@
+use rocket::http::{ContentType, RawStr};
+use rocket::Request;
+use std::collections::HashMap;
+
+/// Body of a 402 response. `{{name}}` placeholders are replaced with the challenge's
+/// `invoice`, `macaroon`, `price_msat`, `price_sat`, `price_fiat`, `qr_url`,
+/// `docs_url` and request `path`; unknown names are left as they are.
+#[derive(Debug, Clone)]
+pub struct PaywallTemplate {
+    pub content_type: ContentType,
+    pub body: String,
+}
+
+impl PaywallTemplate {
+    pub fn new(content_type: ContentType, body: &str) -> Self {
+        PaywallTemplate { content_type, body: body.to_string() }
+    }
+
+    /// Renders the template, HTML-escaping values when it is an HTML page.
+    pub fn render(&self, variables: &[(&str, String)]) -> String {
+        let html = self.content_type == ContentType::HTML;
+        variables.iter().fold(self.body.clone(), |body, (name, value)| {
+            let value = if html { escape_html(value) } else { value.clone() };
+            body.replace(&format!("{{{{{}}}}}", name), &value)
+        })
+    }
+}
+
+pub(crate) fn escape_html(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Branded 402 bodies, rendered by the middleware in place of the handler's own.
+#[derive(Debug, Clone, Default)]
+pub struct PaywallTemplates {
+    /// Templates by exact request path, e.g. `/protected`
+    pub routes: HashMap<String, PaywallTemplate>,
+    /// Templates for other routes, picked by the client's `Accept` header in order
+    /// of preference, e.g. an HTML page for browsers and JSON for API clients
+    pub content_types: Vec<PaywallTemplate>,
+    /// URL of an image of the invoice as a QR code, e.g.
+    /// `https://qr.example.com/?data={{invoice}}`, offered as `{{qr_url}}`
+    pub qr_url: Option<String>,
+    /// Link to the API's payment docs, offered as `{{docs_url}}`
+    pub docs_url: Option<String>,
+}
+
+/// The challenge a 402 response carries, as offered to paywall templates.
+#[derive(Debug, Clone)]
+pub struct PaywallChallenge {
+    pub invoice: String,
+    pub macaroon: String,
+    pub price_msat: i64,
+    pub price_fiat: Option<String>,
+    /// Request path, normalized like `RequestPath` caveats
+    pub path: String,
+}
+
+impl PaywallTemplates {
+    /// Template for the request to `path`: its route's, else the first one the client accepts.
+    pub fn template_for(&self, request: &Request<'_>, path: &str) -> Option<&PaywallTemplate> {
+        if let Some(template) = self.routes.get(path) {
+            return Some(template);
+        }
+        let mut accepted: Vec<_> = request.accept()?.iter().collect();
+        accepted.sort_by(|a, b| b.weight_or(1.0).total_cmp(&a.weight_or(1.0)));
+        accepted.into_iter().find_map(|accepted| self.content_types.iter().find(|template| {
+            let media_type = template.content_type.media_type();
+            (accepted.top() == "*" || accepted.top() == media_type.top())
+                && (accepted.sub() == "*" || accepted.sub() == media_type.sub())
+        }))
+    }
+
+    /// Renders the body of a 402 response to `request`, if a template applies.
+    pub fn render(&self, request: &Request<'_>, challenge: &PaywallChallenge) -> Option<(ContentType, String)> {
+        let template = self.template_for(request, &challenge.path)?;
+        let qr_url = self.qr_url.as_ref().map(|qr_url| {
+            qr_url.replace("{{invoice}}", RawStr::new(&challenge.invoice).percent_encode().as_str())
+        });
+        let variables = [
+            ("invoice", challenge.invoice.clone()),
+            ("macaroon", challenge.macaroon.clone()),
+            ("price_msat", challenge.price_msat.to_string()),
+            ("price_sat", (challenge.price_msat / 1000).to_string()),
+            ("price_fiat", challenge.price_fiat.clone().unwrap_or_default()),
+            ("qr_url", qr_url.unwrap_or_default()),
+            ("docs_url", self.docs_url.clone().unwrap_or_default()),
+            ("path", challenge.path.clone()),
+        ];
+        Some((template.content_type.clone(), template.render(&variables)))
+    }
+}
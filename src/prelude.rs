@@ -0,0 +1,48 @@
+//! The stable surface of the crate: `use l402_middleware::prelude::*;` brings in
+//! the middleware, its request guard, backend configs, errors and extension traits.
+//! Items re-exported here only change in a semver-major release; other public
+//! modules may still grow, and `#[doc(hidden)]` ones are internal.
+
+pub use crate::l402::{
+    BundleOption, L402Info, ValidityError, ValidityWindow, L402_TYPE_ERROR, L402_TYPE_FREE, L402_TYPE_PAID,
+    L402_TYPE_PAYMENT_REQUIRED,
+};
+#[cfg(feature = "rocket")]
+pub use crate::middleware::{
+    AmountFunc, BudgetPolicy, BundleFunc, CaveatFunc, ContentHashFunc, FiatPriceFunc, L402Middleware, MemoFunc,
+    RequestBudget, ScreeningFunc, ScreeningVerdict, ValidityFunc,
+};
+#[cfg(feature = "rocket")]
+pub use crate::lnclient::{
+    BackendError, InvoiceRequest, InvoiceResponse, InvoiceState, InvoiceValidationError, LNClient, LNClientConfig, LNClientConn,
+    LNClientFuture, MemoConfig, MemoPolicy,
+};
+#[cfg(feature = "lnd")]
+pub use crate::lnd::LNDOptions;
+#[cfg(feature = "rocket")]
+pub use crate::bolt12::{Bolt12Backend, Bolt12Options};
+#[cfg(feature = "rocket")]
+pub use crate::cln::CLNOptions;
+#[cfg(feature = "rocket")]
+pub use crate::config::{L402Config, ValidationReport};
+#[cfg(feature = "rocket")]
+pub use crate::eclair::EclairOptions;
+#[cfg(feature = "rocket")]
+pub use crate::lnurl::LNURLOptions;
+#[cfg(feature = "rocket")]
+pub use crate::nwc::NWCOptions;
+#[cfg(feature = "rocket")]
+pub use crate::fiat::FiatRateConfig;
+#[cfg(feature = "rocket")]
+pub use crate::geo::GeoProvider;
+pub use crate::macaroon_util::MacaroonEncoding;
+#[cfg(feature = "rocket")]
+pub use crate::observer::{L402Event, L402Observer};
+#[cfg(feature = "rocket")]
+pub use crate::routes::EndpointsConfig;
+#[cfg(feature = "rocket")]
+pub use crate::shutdown::ShutdownConfig;
+#[cfg(feature = "rocket")]
+pub use crate::store::{MemoryTokenStore, StoreFuture, TokenStore};
+#[cfg(feature = "rocket")]
+pub use crate::transport::{HttpTransport, ReqwestTransport};
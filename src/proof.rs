@@ -0,0 +1,52 @@
+use serde::Serialize;
+use std::error::Error;
+
+use crate::store::{ChallengeRecord, TokenStore};
+
+/// Portable proof that a challenge was paid, assembled from the ledger rather than
+/// any one backend, so customers can file expense reports and operators can answer disputes.
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentProof {
+    pub payment_hash: String,
+    pub invoice: String,
+    pub preimage: String,
+    pub macaroon: String,
+    pub amount_msat: i64,
+    /// Invoice memo. When the invoice carries a description hash instead, its SHA256 matches it.
+    pub memo: String,
+    /// Backend that minted the invoice
+    pub backend: String,
+    /// Unix timestamp the payment was first proven to the middleware
+    pub settled_at: u64,
+}
+
+impl PaymentProof {
+    /// Proof for a ledger entry, or None if its payment hasn't been proven yet.
+    pub fn from_challenge(record: ChallengeRecord) -> Option<Self> {
+        let (Some(preimage), Some(settled_at)) = (record.preimage, record.settled_at) else {
+            return None;
+        };
+        Some(PaymentProof {
+            payment_hash: record.payment_hash,
+            invoice: record.invoice,
+            preimage,
+            macaroon: record.macaroon,
+            amount_msat: record.amount_msat,
+            memo: record.memo,
+            backend: record.backend,
+            settled_at,
+        })
+    }
+}
+
+pub async fn export_payment_proof(
+    store: &dyn TokenStore,
+    payment_hash: &str,
+) -> Result<PaymentProof, Box<dyn Error + Send + Sync>> {
+    let payment_hash = payment_hash.to_lowercase();
+    let record = store.get_challenge(&payment_hash).await?
+        .ok_or_else(|| format!("No challenge found for payment hash {}", payment_hash))?;
+
+    PaymentProof::from_challenge(record)
+        .ok_or_else(|| format!("Payment {} has not been settled", payment_hash).into())
+}
@@ -0,0 +1,148 @@
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::error::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::utils;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ROOT_KEY_HKDF_INFO: &[u8] = b"l402-root-key-provisioning";
+// Requests older than this are refused by the console, limiting replays
+pub const PROVISIONING_MAX_SKEW_SECS: u64 = 300;
+
+/// Where and how a fresh deployment fetches its root key from the operator console.
+#[derive(Debug, Clone)]
+pub struct ProvisioningConfig {
+    /// Console endpoint the request is POSTed to
+    pub console_url: String,
+    /// Name the console knows this deployment by
+    pub deployment_id: String,
+    /// Secret shared with the console when the deployment was enrolled; authenticates the request
+    pub enrollment_token: Vec<u8>,
+    /// The console's X25519 public key, pinned so only the real console can answer
+    pub console_public_key: [u8; 32],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningRequest {
+    pub deployment_id: String,
+    /// Hex X25519 public key, ephemeral to this request
+    pub public_key: String,
+    /// Unix timestamp the request was made
+    pub timestamp: u64,
+    /// Hex HMAC-SHA256 over `deployment_id`, `public_key` and `timestamp`, keyed with the enrollment token
+    pub signature: String,
+}
+
+impl ProvisioningRequest {
+    /// Builds a request for `public_key`, signed with the enrollment token.
+    pub fn signed(deployment_id: &str, public_key: &PublicKey, enrollment_token: &[u8]) -> Self {
+        let public_key = hex::encode(public_key.as_bytes());
+        let timestamp = utils::now_unix();
+        let signature = hex::encode(request_mac(enrollment_token, deployment_id, &public_key, timestamp).finalize().into_bytes());
+        ProvisioningRequest {
+            deployment_id: deployment_id.to_string(),
+            public_key,
+            timestamp,
+            signature,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningResponse {
+    /// Hex ChaCha20-Poly1305 nonce
+    pub nonce: String,
+    /// Hex root key sealed to the request's public key
+    pub ciphertext: String,
+}
+
+/// Fetches the root key from the operator console instead of reading `ROOT_KEY`
+/// from the environment. The request is authenticated with the enrollment token;
+/// the root key comes back encrypted under a key derived (HKDF-SHA256) from an
+/// X25519 exchange between a fresh ephemeral key and the pinned console key, so
+/// it never travels or rests in plaintext outside the two processes.
+pub async fn fetch_root_key(config: &ProvisioningConfig) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let request = ProvisioningRequest::signed(&config.deployment_id, &PublicKey::from(&secret), &config.enrollment_token);
+    let response = Client::new().post(&config.console_url).json(&request).send().await
+        .map_err(|e| format!("Failed to reach operator console: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Operator console refused provisioning: {}", response.status()).into());
+    }
+    let response: ProvisioningResponse = response.json().await
+        .map_err(|e| format!("Failed to parse operator console response: {}", e))?;
+
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(config.console_public_key));
+    open_root_key(shared_secret.as_bytes(), &config.deployment_id, &response)
+}
+
+/// Console side of the exchange: checks the request against the deployment's
+/// enrollment token and seals `root_key` to the request's public key.
+pub fn seal_root_key(
+    console_secret: &StaticSecret,
+    enrollment_token: &[u8],
+    request: &ProvisioningRequest,
+    root_key: &[u8],
+) -> Result<ProvisioningResponse, Box<dyn Error + Send + Sync>> {
+    if utils::now_unix().abs_diff(request.timestamp) > PROVISIONING_MAX_SKEW_SECS {
+        return Err("Provisioning request expired".into());
+    }
+    let signature = hex::decode(&request.signature).map_err(|_| "Invalid provisioning signature")?;
+    request_mac(enrollment_token, &request.deployment_id, &request.public_key, request.timestamp)
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid provisioning signature")?;
+
+    let public_key: [u8; 32] = hex::decode(&request.public_key).ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or("Invalid provisioning public key")?;
+    let shared_secret = console_secret.diffie_hellman(&PublicKey::from(public_key));
+
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher(shared_secret.as_bytes(), &request.deployment_id)?
+        .encrypt(Nonce::from_slice(&nonce), root_key)
+        .map_err(|_| "Failed to seal root key")?;
+
+    Ok(ProvisioningResponse {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+pub fn open_root_key(
+    shared_secret: &[u8; 32],
+    deployment_id: &str,
+    response: &ProvisioningResponse,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let nonce = hex::decode(&response.nonce).ok()
+        .filter(|n| n.len() == 12)
+        .ok_or("Invalid provisioning nonce")?;
+    let ciphertext = hex::decode(&response.ciphertext).map_err(|_| "Invalid provisioning ciphertext")?;
+    // Fails unless the response was sealed by the holder of the pinned console key
+    cipher(shared_secret, deployment_id)?
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| "Root key could not be opened; is the console public key correct?".into())
+}
+
+fn cipher(shared_secret: &[u8; 32], deployment_id: &str) -> Result<ChaCha20Poly1305, Box<dyn Error + Send + Sync>> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(deployment_id.as_bytes()), shared_secret)
+        .expand(ROOT_KEY_HKDF_INFO, &mut key)
+        .map_err(|_| "Failed to derive provisioning key")?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn request_mac(enrollment_token: &[u8], deployment_id: &str, public_key: &str, timestamp: u64) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(enrollment_token).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}", deployment_id, public_key, timestamp).as_bytes());
+    mac
+}
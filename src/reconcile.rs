@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+
+use crate::events::{DomainEvent, EventBus};
+use crate::lnclient::{InvoiceState, LNClient};
+use crate::metrics::L402Metrics;
+use crate::observer::{self, L402Event, L402Observer};
+use crate::store::TokenStore;
+use crate::utils;
+
+/// Periodic re-check of accepted tokens against the backend's settlement records,
+/// for deployments that don't want to rely on preimage verification alone.
+#[derive(Debug, Clone)]
+pub struct ReconciliationConfig {
+    /// How often the sweep runs
+    pub interval: Duration,
+    /// How far back accepted tokens are re-checked
+    pub lookback: Duration,
+    /// Revoke tokens without a settled invoice instead of only flagging them
+    pub revoke: bool,
+}
+
+impl Default for ReconciliationConfig {
+    fn default() -> Self {
+        ReconciliationConfig {
+            interval: Duration::from_secs(300),
+            lookback: Duration::from_secs(3600),
+            revoke: false,
+        }
+    }
+}
+
+pub struct Reconciler {
+    pub config: ReconciliationConfig,
+    pub ln_client: Arc<dyn LNClient>,
+    pub token_store: Arc<dyn TokenStore>,
+    pub metrics: Arc<L402Metrics>,
+    pub observers: Vec<Arc<dyn L402Observer>>,
+    /// Revocations are published here
+    pub events: Arc<EventBus>,
+}
+
+impl Reconciler {
+    /// Checks every token accepted within the lookback window once.
+    /// Returns the number of discrepancies found.
+    pub async fn run_once(&self) -> usize {
+        let since = utils::now_unix().saturating_sub(self.config.lookback.as_secs());
+        let challenges = match self.token_store.settled_challenges(since).await {
+            Ok(challenges) => challenges,
+            Err(error) => {
+                println!("Error reading L402 ledger for reconciliation: {}", error);
+                L402Metrics::incr(&self.metrics.reconciliation_errors);
+                return 0;
+            }
+        };
+
+        let mut discrepancies = 0;
+        for challenge in challenges {
+            if self.token_store.is_revoked(&challenge.payment_hash).await.unwrap_or(false) {
+                continue;
+            }
+            // The node already reported this invoice settled
+            if challenge.node_settled_at.is_some() {
+                continue;
+            }
+            // A token paid through the static offer settled the invoice its preimage belongs to
+            let payment_hash = match challenge.preimage.as_deref().and_then(|p| hex::decode(p).ok()).and_then(|p| <[u8; 32]>::try_from(p).ok()) {
+                Some(preimage) => PaymentHash::from(PaymentPreimage(preimage)).0,
+                None => match hex::decode(&challenge.payment_hash).ok().and_then(|h| <[u8; 32]>::try_from(h).ok()) {
+                    Some(payment_hash) => payment_hash,
+                    None => continue,
+                },
+            };
+
+            let lookup = self.ln_client.lookup_invoice(payment_hash);
+            let state = lookup.await.map_err(|error| error.to_string());
+            let backend_state = match state {
+                Ok(InvoiceState::Settled) => continue,
+                Ok(state) => state,
+                Err(error) => {
+                    println!("Error looking up invoice {}: {}", challenge.payment_hash, error);
+                    L402Metrics::incr(&self.metrics.reconciliation_errors);
+                    continue;
+                }
+            };
+
+            discrepancies += 1;
+            L402Metrics::incr(&self.metrics.settlement_discrepancies);
+            self.metrics.incr_backend(&challenge.backend, |m| m.settlement_discrepancies += 1);
+            let revoked = self.config.revoke && match self.token_store.revoke_token(&challenge.payment_hash).await {
+                Ok(()) => {
+                    self.events.publish(DomainEvent::TokenRevoked { payment_hash: challenge.payment_hash.clone() }).await;
+                    true
+                },
+                Err(error) => {
+                    println!("Error revoking L402 token {}: {}", challenge.payment_hash, error);
+                    false
+                },
+            };
+            println!("L402 token {} accepted without a settled invoice (backend {} state {:?})", challenge.payment_hash, challenge.backend, backend_state);
+            observer::notify(&self.observers, L402Event::SettlementDiscrepancy {
+                payment_hash: challenge.payment_hash,
+                backend: challenge.backend,
+                backend_state: format!("{:?}", backend_state).to_lowercase(),
+                revoked,
+            });
+        }
+        discrepancies
+    }
+
+    /// Runs the sweep every `interval` on the current runtime.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                interval.tick().await;
+                self.run_once().await;
+            }
+        })
+    }
+}
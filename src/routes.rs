@@ -0,0 +1,651 @@
+use base64::{Engine as _, engine::general_purpose};
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+use macaroon::Macaroon;
+use rocket::form::{Form, FromForm};
+use rocket::http::Status;
+use rocket::response::content::RawHtml;
+use rocket::request::{self, FromRequest};
+use rocket::serde::json::Json;
+use rocket::{get, post, routes, Request, Route, State};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::analytics;
+use crate::audit;
+use crate::catalog;
+use crate::client_gen;
+use crate::events::{DomainEvent, EventBus, SettlementSource};
+use crate::forwarded;
+use crate::inspect;
+use crate::introspection;
+use crate::l402;
+use crate::lnclient::{InvoiceState, LNClient};
+use crate::lsat;
+use crate::metrics::{FailureReport, L402Metrics};
+use crate::proof;
+use crate::store::TokenStore;
+use crate::utils;
+
+pub const DEFAULT_ENDPOINTS_BASE: &str = "/l402";
+
+// Window `<base>/revenue` reports on when no `since` is given
+const DEFAULT_REVENUE_WINDOW_SECS: u64 = 7 * 24 * 3600;
+
+// Bounds on how long `<base>/await` holds a request open
+const DEFAULT_AWAIT_SECS: u64 = 30;
+const MAX_AWAIT_SECS: u64 = 120;
+pub const DEFAULT_MAX_SETTLEMENT_WAITERS: usize = 1000;
+
+/// Built-in endpoints the middleware mounts on ignite. All are disabled by default.
+#[derive(Debug, Clone)]
+pub struct EndpointsConfig {
+    /// Path the endpoints are mounted under
+    pub base: String,
+    /// `GET <base>/proof/<payment_hash>`: payment proof export for the token holder
+    pub proof: bool,
+    /// `GET <base>/metrics`: counters in the Prometheus text format
+    pub metrics: bool,
+    /// `GET <base>/usage`: the caller's own spend, request counts and remaining allowance
+    pub usage: bool,
+    /// `POST <base>/inspect`: diagnoses a posted token without granting access
+    pub inspect: bool,
+    /// `POST <base>/upgrade`: re-mints a paid legacy LSAT token as an L402 token
+    pub upgrade: bool,
+    /// `GET <base>/await/<payment_hash>`: long-polls until a challenge invoice settles
+    pub await_settlement: bool,
+    /// How often a long-poll asks the backend whether the invoice settled
+    pub settlement_poll_interval: Duration,
+    /// Most `<base>/await` requests held open at once. Further ones get a 503.
+    pub max_settlement_waiters: usize,
+    /// `POST <base>/introspect`: RFC 7662 token introspection for internal services
+    pub introspect: bool,
+    /// Service credentials allowed to introspect, as client id to secret, presented
+    /// with HTTP Basic authentication
+    pub introspection_clients: HashMap<String, String>,
+    /// `GET <base>/catalog`: paid routes and their prices from the middleware's `pricing_table`
+    pub catalog: bool,
+    /// `GET <base>/catalog/client`: Rust and curl client snippets for the catalog's
+    /// routes. Takes an optional `base_url` query parameter, defaulting to `public_base_url`.
+    pub client_snippets: bool,
+    /// URL clients reach the service at, e.g. `https://api.example.com`. Without it
+    /// the client snippets use the `Host` header, with `https` if Rocket serves TLS or
+    /// a trusted proxy reports it in `X-Forwarded-Proto`/`Forwarded`.
+    pub public_base_url: Option<String>,
+    /// `POST <base>/settlements/cln` and `POST <base>/settlements/eclair`: settlement
+    /// notifications pushed by a CLN `invoice_payment` plugin or an Eclair webhook.
+    /// `<base>/await` then waits for these instead of polling the backend.
+    pub settlements: bool,
+    /// Node credentials allowed to report settlements, as client id to secret,
+    /// presented with HTTP Basic authentication
+    pub settlement_clients: HashMap<String, String>,
+    /// `GET <base>/failures`: rejected tokens by reason and the sampled rejections,
+    /// for the `introspection_clients`
+    pub failures: bool,
+    /// `GET <base>/revenue`: settled payments by route, hour and backend since the
+    /// `since` unix timestamp, by default the last 7 days, for the `introspection_clients`
+    pub revenue: bool,
+    /// `GET <base>/revenue/dashboard`: the same report as a minimal HTML page
+    pub revenue_dashboard: bool,
+    /// `GET <base>/audit/<payment_hash>`: a token's receipt and the client fingerprints
+    /// that presented it, with the first time each did, for the `introspection_clients`
+    pub audit: bool,
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        EndpointsConfig {
+            base: DEFAULT_ENDPOINTS_BASE.to_string(),
+            proof: false,
+            metrics: false,
+            usage: false,
+            inspect: false,
+            upgrade: false,
+            await_settlement: false,
+            settlement_poll_interval: Duration::from_secs(1),
+            max_settlement_waiters: DEFAULT_MAX_SETTLEMENT_WAITERS,
+            introspect: false,
+            introspection_clients: HashMap::new(),
+            catalog: false,
+            client_snippets: false,
+            public_base_url: None,
+            settlements: false,
+            settlement_clients: HashMap::new(),
+            failures: false,
+            revenue: false,
+            revenue_dashboard: false,
+            audit: false,
+        }
+    }
+}
+
+impl EndpointsConfig {
+    /// Whether `path` is `base` itself or under it, so `/l402foo` isn't taken for `/l402`
+    pub fn serves(&self, path: &str) -> bool {
+        let base = self.base.trim_end_matches('/');
+        path.strip_prefix(base).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Whether any endpoint is enabled, without building the routes
+    pub fn any_enabled(&self) -> bool {
+        self.proof
+            || self.metrics
+            || self.usage
+            || self.inspect
+            || self.upgrade
+            || self.await_settlement
+            || self.introspect
+            || self.catalog
+            || self.client_snippets
+            || self.settlements
+            || self.failures
+            || self.revenue
+            || self.revenue_dashboard
+            || self.audit
+    }
+
+    pub fn routes(&self) -> Vec<Route> {
+        let mut enabled = Vec::new();
+        if self.proof {
+            enabled.extend(routes![payment_proof]);
+        }
+        if self.metrics {
+            enabled.extend(routes![metrics]);
+        }
+        if self.usage {
+            enabled.extend(routes![usage]);
+        }
+        if self.inspect {
+            enabled.extend(routes![inspect_token]);
+        }
+        if self.upgrade {
+            enabled.extend(routes![upgrade_token]);
+        }
+        if self.await_settlement {
+            enabled.extend(routes![await_settlement]);
+        }
+        if self.introspect {
+            enabled.extend(routes![introspect_token]);
+        }
+        if self.catalog {
+            enabled.extend(routes![route_catalog]);
+        }
+        if self.client_snippets {
+            enabled.extend(routes![client_snippets]);
+        }
+        if self.settlements {
+            enabled.extend(routes![cln_settlement, eclair_settlement]);
+        }
+        if self.failures {
+            enabled.extend(routes![verification_failures]);
+        }
+        if self.revenue {
+            enabled.extend(routes![revenue]);
+        }
+        if self.revenue_dashboard {
+            enabled.extend(routes![revenue_dashboard]);
+        }
+        if self.audit {
+            enabled.extend(routes![token_audit]);
+        }
+        enabled
+    }
+}
+
+/// State shared with the built-in endpoints.
+pub struct EndpointState {
+    pub token_store: Arc<dyn TokenStore>,
+    pub metrics: Arc<L402Metrics>,
+    pub events: Arc<EventBus>,
+    pub root_key: Vec<u8>,
+    pub ln_client: Arc<dyn LNClient>,
+    /// Root key legacy LSAT tokens were minted with
+    pub legacy_root_key: Vec<u8>,
+    pub settlement_poll_interval: Duration,
+    pub settlement_waiters: SettlementWaiters,
+    pub introspection_clients: HashMap<String, String>,
+    pub settlement_clients: HashMap<String, String>,
+    /// Whether the node pushes settlements, so `await` needn't poll it
+    pub notified_settlements: bool,
+    pub pricing_table: Option<Arc<catalog::PricingTable>>,
+    pub max_uses: Option<u64>,
+    pub public_base_url: Option<String>,
+    pub trusted_proxies: forwarded::TrustedProxies,
+}
+
+// Token presented as `Authorization: L402 <macaroon>:<preimage>`
+struct PresentedToken {
+    mac: Macaroon,
+    preimage: PaymentPreimage,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PresentedToken {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(auth_field) = request.headers().get_one(l402::L402_AUTHORIZATION_HEADER_NAME) else {
+            return request::Outcome::Error((Status::Unauthorized, "Authorization field not present".to_string()));
+        };
+        match utils::parse_l402_header(auth_field) {
+            Ok((mac, preimage)) => request::Outcome::Success(PresentedToken { mac, preimage }),
+            Err(error) => request::Outcome::Error((Status::Unauthorized, error)),
+        }
+    }
+}
+
+#[get("/proof/<payment_hash>")]
+async fn payment_proof(
+    payment_hash: &str,
+    presented: PresentedToken,
+    state: &State<EndpointState>,
+) -> Result<Json<proof::PaymentProof>, (Status, String)> {
+    // Only the holder of the preimage may export the proof, which contains it
+    if hex::encode(PaymentHash::from(presented.preimage).0) != payment_hash.to_lowercase() {
+        return Err((Status::Forbidden, "Preimage does not match payment hash".to_string()));
+    }
+    proof::export_payment_proof(state.token_store.as_ref(), payment_hash).await
+        .map(Json)
+        .map_err(|error| (Status::NotFound, error.to_string()))
+}
+
+#[get("/metrics")]
+fn metrics(state: &State<EndpointState>) -> String {
+    state.metrics.render()
+}
+
+#[get("/usage")]
+async fn usage(
+    presented: PresentedToken,
+    fingerprint: ClientFingerprint,
+    state: &State<EndpointState>,
+) -> Result<Json<analytics::UsageReport>, (Status, String)> {
+    l402::verify_l402_holder(&presented.mac, state.root_key.clone(), presented.preimage)
+        .map_err(|error| (Status::Unauthorized, error.to_string()))?;
+    // Keyed like the middleware's ledger, by the payment hash the macaroon is bound to
+    let token_id = hex::encode(l402::macaroon_payment_hash(&presented.mac).unwrap_or(PaymentHash::from(presented.preimage).0));
+    let max_uses = l402::get_caveat_value(&presented.mac, l402::MAX_USES_CAVEAT).and_then(|v| v.parse::<u64>().ok());
+    analytics::usage_report(state.token_store.as_ref(), &token_id, &fingerprint.0, max_uses).await
+        .map(Json)
+        .map_err(|error| (Status::InternalServerError, error.to_string()))
+}
+
+struct ClientFingerprint(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientFingerprint {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(ClientFingerprint(analytics::client_fingerprint(request)))
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    /// `<macaroon>` or `<macaroon>:<preimage>`
+    token: String,
+}
+
+#[post("/inspect", format = "json", data = "<body>")]
+async fn inspect_token(body: Json<TokenRequest>, state: &State<EndpointState>) -> Json<inspect::InspectReport> {
+    Json(inspect::inspect_token(&body.token, &state.root_key, state.token_store.as_ref(), &state.ln_client).await)
+}
+
+#[post("/upgrade", format = "json", data = "<body>")]
+fn upgrade_token(body: Json<TokenRequest>, state: &State<EndpointState>) -> Result<Json<lsat::UpgradedToken>, (Status, String)> {
+    lsat::upgrade_token(&body.token, &state.legacy_root_key, &state.root_key)
+        .map(Json)
+        .map_err(|error| (Status::Unauthorized, error))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SettlementStatus {
+    payment_hash: String,
+    /// `Open` if the wait timed out; clients poll again
+    state: InvoiceState,
+}
+
+// Latest backend answer for an awaited invoice, or the lookup error
+type SettlementPoll = Result<InvoiceState, String>;
+
+/// Requests waiting on `<base>/await`. Waits for the same payment hash share one
+/// channel, fed by a single backend poll or by node settlement notifications.
+pub struct SettlementWaiters {
+    waits: Mutex<Waits>,
+    max_waiters: usize,
+}
+
+#[derive(Default)]
+struct Waits {
+    channels: HashMap<String, watch::Sender<SettlementPoll>>,
+    waiting: usize,
+}
+
+impl SettlementWaiters {
+    pub fn new(max_waiters: usize) -> Self {
+        SettlementWaiters { waits: Mutex::new(Waits::default()), max_waiters }
+    }
+
+    // Joins the wait for `payment_hash`, or None if `max_waiters` are already waiting.
+    // The sender is returned to the first waiter, which starts the poll.
+    fn join(&self, payment_hash: &str) -> Option<(SettlementWait<'_>, Option<watch::Sender<SettlementPoll>>)> {
+        let mut waits = self.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        if waits.waiting >= self.max_waiters {
+            return None;
+        }
+        waits.waiting += 1;
+        let (receiver, started) = match waits.channels.get(payment_hash) {
+            Some(sender) => (sender.subscribe(), None),
+            None => {
+                let (sender, receiver) = watch::channel(Ok(InvoiceState::Open));
+                waits.channels.insert(payment_hash.to_string(), sender.clone());
+                (receiver, Some(sender))
+            }
+        };
+        Some((SettlementWait { waiters: self, payment_hash: payment_hash.to_string(), receiver: Some(receiver) }, started))
+    }
+
+    // Wakes the requests waiting for `payment_hash`
+    fn settle(&self, payment_hash: &str) {
+        let waits = self.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sender) = waits.channels.get(payment_hash) {
+            let _ = sender.send_replace(Ok(InvoiceState::Settled));
+        }
+    }
+}
+
+// One request's place in a wait; the last one to leave removes the channel
+struct SettlementWait<'a> {
+    waiters: &'a SettlementWaiters,
+    payment_hash: String,
+    receiver: Option<watch::Receiver<SettlementPoll>>,
+}
+
+impl SettlementWait<'_> {
+    // Waits until the invoice leaves the open state or `deadline` passes, then
+    // returns the latest state
+    async fn until(&mut self, deadline: tokio::time::Instant) -> SettlementPoll {
+        let Some(receiver) = self.receiver.as_mut() else {
+            return Ok(InvoiceState::Open);
+        };
+        let _ = tokio::time::timeout_at(deadline, receiver.wait_for(|poll| *poll != Ok(InvoiceState::Open))).await;
+        receiver.borrow().clone()
+    }
+}
+
+impl Drop for SettlementWait<'_> {
+    fn drop(&mut self) {
+        let mut waits = self.waiters.waits.lock().unwrap_or_else(PoisonError::into_inner);
+        drop(self.receiver.take());
+        if waits.channels.get(&self.payment_hash).is_some_and(|sender| sender.receiver_count() == 0) {
+            waits.channels.remove(&self.payment_hash);
+        }
+        waits.waiting -= 1;
+    }
+}
+
+// Asks the backend about the invoice while anyone waits for it, until it leaves
+// the open state
+fn spawn_settlement_poll(sender: watch::Sender<SettlementPoll>, ln_client: Arc<dyn LNClient>, payment_hash: [u8; 32], interval: Duration) {
+    tokio::spawn(async move {
+        while sender.receiver_count() > 0 {
+            let polled = ln_client.lookup_invoice(payment_hash).await.map_err(|error| error.to_string());
+            let done = polled != Ok(InvoiceState::Open);
+            let _ = sender.send_replace(polled);
+            if done {
+                break;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+// Only challenges this middleware issued can be awaited, so the endpoint can't be
+// used to watch arbitrary invoices on the node
+#[get("/await/<payment_hash>?<timeout>")]
+async fn await_settlement(
+    payment_hash: &str,
+    timeout: Option<u64>,
+    state: &State<EndpointState>,
+) -> Result<Json<SettlementStatus>, (Status, String)> {
+    let payment_hash = payment_hash.to_lowercase();
+    let challenge = state.token_store.get_challenge(&payment_hash).await
+        .map_err(|error| (Status::InternalServerError, error.to_string()))?
+        .ok_or_else(|| (Status::NotFound, format!("No challenge found for payment hash {}", payment_hash)))?;
+    let hash_bytes: [u8; 32] = hex::decode(&challenge.payment_hash).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| (Status::BadRequest, "Invalid payment hash".to_string()))?;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(timeout.unwrap_or(DEFAULT_AWAIT_SECS).min(MAX_AWAIT_SECS));
+    let Some((mut wait, started)) = state.settlement_waiters.join(&payment_hash) else {
+        return Err((Status::ServiceUnavailable, "Too many settlement waits in progress".to_string()));
+    };
+    if state.notified_settlements {
+        // Checked after joining, so a notification arriving in between still wakes this wait
+        match state.token_store.get_challenge(&payment_hash).await {
+            Ok(Some(challenge)) if challenge.node_settled_at.is_some() => {
+                return Ok(Json(SettlementStatus { payment_hash, state: InvoiceState::Settled }));
+            }
+            Ok(_) => {}
+            Err(error) => return Err((Status::InternalServerError, error.to_string())),
+        }
+    } else if let Some(sender) = started {
+        spawn_settlement_poll(sender, Arc::clone(&state.ln_client), hash_bytes, state.settlement_poll_interval);
+    }
+    let invoice_state = wait.until(deadline).await
+        .map_err(|error| (Status::BadGateway, error))?;
+    Ok(Json(SettlementStatus { payment_hash, state: invoice_state }))
+}
+
+// Whether the request carries `Authorization: Basic <client id:secret>` for one of `clients`
+fn basic_credentials_match(request: &Request<'_>, clients: &HashMap<String, String>) -> bool {
+    let credentials = request.headers().get_one(l402::L402_AUTHORIZATION_HEADER_NAME)
+        .and_then(|auth_field| auth_field.strip_prefix("Basic "))
+        .and_then(|encoded| general_purpose::STANDARD.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    credentials.as_deref()
+        .and_then(|credentials| credentials.split_once(':'))
+        .and_then(|(client_id, secret)| clients.get(client_id).map(|expected| (expected, secret)))
+        .is_some_and(|(expected, secret)| !expected.is_empty() && utils::constant_time_eq(expected.as_bytes(), secret.as_bytes()))
+}
+
+// Internal service authenticated with `Authorization: Basic <client id:secret>`
+struct ServiceClient;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ServiceClient {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<EndpointState>() else {
+            return request::Outcome::Error((Status::InternalServerError, "Endpoint state not managed".to_string()));
+        };
+        if basic_credentials_match(request, &state.introspection_clients) {
+            request::Outcome::Success(ServiceClient)
+        } else {
+            request::Outcome::Error((Status::Unauthorized, "Invalid service credentials".to_string()))
+        }
+    }
+}
+
+// Node-side plugin or webhook, authenticated like a `ServiceClient` against `settlement_clients`
+struct NodeClient;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NodeClient {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<EndpointState>() else {
+            return request::Outcome::Error((Status::InternalServerError, "Endpoint state not managed".to_string()));
+        };
+        if basic_credentials_match(request, &state.settlement_clients) {
+            request::Outcome::Success(NodeClient)
+        } else {
+            request::Outcome::Error((Status::Unauthorized, "Invalid node credentials".to_string()))
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct IntrospectionRequest<'r> {
+    /// `<macaroon>:<preimage>`, optionally prefixed with the scheme
+    token: &'r str,
+}
+
+#[post("/introspect", data = "<body>")]
+async fn introspect_token(
+    _client: ServiceClient,
+    body: Form<IntrospectionRequest<'_>>,
+    state: &State<EndpointState>,
+) -> Json<introspection::IntrospectionResponse> {
+    Json(introspection::introspect_token(body.token, &state.root_key, state.token_store.as_ref()).await)
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SettlementReceipt {
+    /// None for events that aren't settlements
+    payment_hash: Option<String>,
+    /// Whether the invoice belongs to a challenge in the ledger
+    recorded: bool,
+}
+
+async fn record_node_settlement(state: &EndpointState, payment_hash: Option<String>) -> Result<Json<SettlementReceipt>, (Status, String)> {
+    let Some(payment_hash) = payment_hash else {
+        return Ok(Json(SettlementReceipt { payment_hash: None, recorded: false }));
+    };
+    let recorded = state.token_store.record_node_settlement(&payment_hash, utils::now_unix()).await
+        .map_err(|error| (Status::InternalServerError, error.to_string()))?;
+    if recorded {
+        state.settlement_waiters.settle(&payment_hash);
+        state.events.publish(DomainEvent::InvoiceSettled {
+            payment_hash: payment_hash.clone(),
+            source: SettlementSource::Node,
+        }).await;
+    }
+    Ok(Json(SettlementReceipt { payment_hash: Some(payment_hash), recorded }))
+}
+
+// Payload of CLN's `invoice_payment` hook, as forwarded by the plugin
+#[derive(Deserialize)]
+struct ClnInvoicePayment {
+    payment: ClnPayment,
+}
+
+#[derive(Deserialize)]
+struct ClnPayment {
+    preimage: String,
+}
+
+#[post("/settlements/cln", format = "json", data = "<body>")]
+async fn cln_settlement(
+    _node: NodeClient,
+    body: Json<ClnInvoicePayment>,
+    state: &State<EndpointState>,
+) -> Result<Json<SettlementReceipt>, (Status, String)> {
+    let preimage = utils::get_preimage_from_string(body.payment.preimage.clone())
+        .map_err(|error| (Status::BadRequest, error.to_string()))?;
+    record_node_settlement(state, Some(hex::encode(PaymentHash::from(preimage).0))).await
+}
+
+// Eclair webhook event; only `payment-received` reports a settlement
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EclairEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    payment_hash: Option<String>,
+}
+
+#[post("/settlements/eclair", format = "json", data = "<body>")]
+async fn eclair_settlement(
+    _node: NodeClient,
+    body: Json<EclairEvent>,
+    state: &State<EndpointState>,
+) -> Result<Json<SettlementReceipt>, (Status, String)> {
+    if body.event_type != "payment-received" {
+        return record_node_settlement(state, None).await;
+    }
+    let payment_hash = body.payment_hash.as_deref()
+        .filter(|hash| hex::decode(hash).is_ok_and(|bytes| bytes.len() == 32))
+        .ok_or((Status::BadRequest, "Invalid payment hash".to_string()))?;
+    record_node_settlement(state, Some(payment_hash.to_lowercase())).await
+}
+
+#[get("/failures")]
+fn verification_failures(_client: ServiceClient, state: &State<EndpointState>) -> Json<FailureReport> {
+    Json(state.metrics.failure_report())
+}
+
+async fn revenue_report(state: &EndpointState, since: Option<u64>) -> Result<analytics::RevenueReport, (Status, String)> {
+    let since = since.unwrap_or_else(|| utils::now_unix().saturating_sub(DEFAULT_REVENUE_WINDOW_SECS));
+    analytics::revenue_report(state.token_store.as_ref(), since).await
+        .map_err(|error| (Status::InternalServerError, error.to_string()))
+}
+
+#[get("/revenue?<since>")]
+async fn revenue(_client: ServiceClient, since: Option<u64>, state: &State<EndpointState>) -> Result<Json<analytics::RevenueReport>, (Status, String)> {
+    revenue_report(state, since).await.map(Json)
+}
+
+#[get("/revenue/dashboard?<since>")]
+async fn revenue_dashboard(_client: ServiceClient, since: Option<u64>, state: &State<EndpointState>) -> Result<RawHtml<String>, (Status, String)> {
+    let report = revenue_report(state, since).await?;
+    Ok(RawHtml(analytics::render_revenue_dashboard(&report)))
+}
+
+#[get("/audit/<payment_hash>")]
+async fn token_audit(_client: ServiceClient, payment_hash: &str, state: &State<EndpointState>) -> Result<Json<audit::TokenAudit>, (Status, String)> {
+    match audit::token_audit(state.token_store.as_ref(), payment_hash).await {
+        Ok(Some(report)) => Ok(Json(report)),
+        Ok(None) => Err((Status::NotFound, format!("No token found for payment hash {}", payment_hash))),
+        Err(error) => Err((Status::InternalServerError, error.to_string())),
+    }
+}
+
+#[get("/catalog")]
+async fn route_catalog(state: &State<EndpointState>) -> Json<Vec<catalog::CatalogEntry>> {
+    let entries = match &state.pricing_table {
+        Some(pricing_table) => pricing_table.catalog(state.max_uses).await,
+        None => Vec::new(),
+    };
+    Json(entries)
+}
+
+// Origin the client reached the service at: the configured public base URL, or the
+// `Host` header with the scheme Rocket or a trusted proxy served the request over
+struct RequestOrigin(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestOrigin {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let state = request.rocket().state::<EndpointState>();
+        if let Some(public_base_url) = state.and_then(|state| state.public_base_url.clone()) {
+            return request::Outcome::Success(RequestOrigin(Some(public_base_url)));
+        }
+        let scheme = state
+            .and_then(|state| state.trusted_proxies.forwarded_proto(request.remote().map(|r| r.ip()), request.headers()))
+            .unwrap_or_else(|| if request.rocket().config().tls_enabled() { "https" } else { "http" }.to_string());
+        request::Outcome::Success(RequestOrigin(request.headers().get_one("Host").map(|host| format!("{}://{}", scheme, host))))
+    }
+}
+
+#[get("/catalog/client?<base_url>")]
+async fn client_snippets(
+    base_url: Option<String>,
+    origin: RequestOrigin,
+    state: &State<EndpointState>,
+) -> Result<Json<client_gen::ClientSnippets>, (Status, String)> {
+    let base_url = base_url.or(origin.0)
+        .ok_or((Status::BadRequest, "base_url is required without a Host header".to_string()))?;
+    let entries = match &state.pricing_table {
+        Some(pricing_table) => pricing_table.catalog(state.max_uses).await,
+        None => Vec::new(),
+    };
+    Ok(Json(client_gen::generate_client(&base_url, &entries)))
+}
@@ -0,0 +1,48 @@
+use lightning::types::payment::PaymentPreimage;
+use rocket::Request;
+
+use crate::utils;
+
+pub const TEST_PAYMENT_HEADER_NAME: &str = "X-L402-Test-Payment";
+
+/// Fake settlement for staging environments, so frontend teams can exercise paid
+/// flows without moving sats. Only honoured when Rocket runs under one of
+/// `allowed_profiles`; ignition fails otherwise, so a sandbox config that leaks
+/// into production can't give content away.
+#[derive(Debug, Clone)]
+pub struct SandboxConfig {
+    /// Preimage accepted in place of the real one for any macaroon minted by this
+    /// deployment, so the full token flow (quotas, sessions, spend) is exercised
+    pub test_preimage: Option<PaymentPreimage>,
+    /// Value of `X-L402-Test-Payment` that has a request served as paid without any token
+    pub test_payment_secret: Option<String>,
+    /// Rocket profiles (`ROCKET_PROFILE`) the sandbox may run under
+    pub allowed_profiles: Vec<String>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            test_preimage: None,
+            test_payment_secret: None,
+            allowed_profiles: vec!["debug".to_string(), "staging".to_string()],
+        }
+    }
+}
+
+impl SandboxConfig {
+    pub fn is_test_preimage(&self, preimage: &PaymentPreimage) -> bool {
+        self.test_preimage.as_ref().is_some_and(|test_preimage| utils::constant_time_eq(&test_preimage.0, &preimage.0))
+    }
+
+    pub fn is_test_payment(&self, request: &Request<'_>) -> bool {
+        match (&self.test_payment_secret, request.headers().get_one(TEST_PAYMENT_HEADER_NAME)) {
+            (Some(secret), Some(presented)) => !secret.is_empty() && utils::constant_time_eq(secret.as_bytes(), presented.as_bytes()),
+            _ => false,
+        }
+    }
+
+    pub fn allows_profile(&self, profile: &str) -> bool {
+        self.allowed_profiles.iter().any(|allowed| allowed == profile)
+    }
+}
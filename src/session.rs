@@ -0,0 +1,79 @@
+use rocket::http::{Cookie, SameSite};
+use rocket::Request;
+use std::error::Error;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::store::{SessionRecord, TokenStore};
+use crate::utils;
+
+pub const DEFAULT_SESSION_COOKIE_NAME: &str = "l402_session";
+
+/// Configuration for the optional session cookie set after the first successful
+/// L402 verification. Browsers can't attach an `Authorization` header to
+/// `<video>`/`<audio>` sources, so the cookie lets them keep streaming paid media.
+#[derive(Debug, Clone)]
+pub struct SessionCookieConfig {
+    /// Cookie name
+    pub name: String,
+    /// How long the session is honoured after the token was verified
+    pub max_age: Duration,
+    /// Only send the cookie over HTTPS (disable for local development only)
+    pub secure: bool,
+}
+
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        SessionCookieConfig {
+            name: DEFAULT_SESSION_COOKIE_NAME.to_string(),
+            max_age: Duration::from_secs(3600),
+            secure: true,
+        }
+    }
+}
+
+/// Returns the L402 token the request's session cookie maps to, if any.
+pub async fn session_token(
+    request: &Request<'_>,
+    config: &SessionCookieConfig,
+    store: &dyn TokenStore,
+) -> Option<String> {
+    let session_id = request.cookies().get(&config.name)?.value().to_string();
+    match store.get_session(&session_id).await {
+        Ok(record) => record.map(|r| r.token),
+        Err(error) => {
+            println!("Error reading L402 session: {}", error);
+            None
+        }
+    }
+}
+
+/// Maps a fresh session id to `token` and sets it as a secure, HttpOnly cookie.
+/// Nothing is issued if the request already carries a session for the same token.
+pub async fn issue_session(
+    request: &Request<'_>,
+    config: &SessionCookieConfig,
+    store: &dyn TokenStore,
+    token: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if session_token(request, config, store).await.as_deref() == Some(token) {
+        return Ok(());
+    }
+
+    let session_id = Uuid::new_v4().simple().to_string();
+    store.put_session(session_id.clone(), SessionRecord {
+        token: token.to_string(),
+        expires_at: utils::now_unix() + config.max_age.as_secs(),
+    }).await?;
+
+    let cookie = Cookie::build((config.name.clone(), session_id))
+        .path("/")
+        .http_only(true)
+        .secure(config.secure)
+        .same_site(SameSite::Lax)
+        .max_age(rocket::time::Duration::seconds(config.max_age.as_secs() as i64))
+        .build();
+    request.cookies().add(cookie);
+
+    Ok(())
+}
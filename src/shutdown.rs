@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::utils;
+
+// Minted challenges older than this are forgotten; their invoices have expired by then
+const MINTED_RETENTION_SECS: u64 = 24 * 3600;
+
+/// How the middleware winds down when Rocket shuts down, e.g. during a rolling deploy.
+/// It stops issuing challenges, lets in-flight requests finish verifying, flushes the
+/// observers and the ledger, and closes the backend connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownConfig {
+    /// Longest to wait for in-flight requests to finish the middleware's work
+    pub drain_timeout: Duration,
+    /// Cancels the invoices of challenges this instance minted that are still unpaid.
+    /// Meant for ledgers that don't outlive the process, like `MemoryTokenStore`,
+    /// whose tokens couldn't be verified after a restart. Leave it off with a shared
+    /// ledger, where other instances can still serve those challenges once paid.
+    pub cancel_unpaid_invoices: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_timeout: Duration::from_secs(10), cancel_unpaid_invoices: false }
+    }
+}
+
+/// Requests the middleware is working on, and the challenges it minted, so shutdown
+/// can wait for the former and cancel the unpaid ones among the latter.
+#[derive(Debug, Default)]
+pub struct ShutdownState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    // Hex payment hashes, with the unix timestamp they were minted at
+    minted: Mutex<Vec<(String, u64)>>,
+}
+
+/// Counts a request as in flight until dropped.
+pub struct InFlight<'a>(&'a ShutdownState);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+impl ShutdownState {
+    /// Whether shutdown has begun; no new challenges are issued from then on.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_request(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    pub fn record_minted(&self, payment_hash: String) {
+        let now = utils::now_unix();
+        if let Ok(mut minted) = self.minted.lock() {
+            minted.retain(|(_, minted_at)| now.saturating_sub(*minted_at) < MINTED_RETENTION_SECS);
+            minted.push((payment_hash, now));
+        }
+    }
+
+    /// Challenges minted by this instance that may still be unpaid, forgetting them.
+    pub fn take_minted(&self) -> Vec<String> {
+        self.minted.lock().map(|mut minted| minted.drain(..).map(|(payment_hash, _)| payment_hash).collect()).unwrap_or_default()
+    }
+
+    /// Starts draining and waits up to `timeout` for in-flight requests to finish.
+    /// Returns false if some were still running.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+        let idle = async {
+            loop {
+                let notified = self.idle.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        tokio::time::timeout(timeout, idle).await.is_ok()
+    }
+}
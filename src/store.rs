@@ -0,0 +1,485 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::utils;
+
+// Unpaid challenges older than this are pruned from the in-memory ledger
+pub(crate) const UNSETTLED_CHALLENGE_RETENTION_SECS: u64 = 24 * 3600;
+
+// Presentations of tokens not seen for this long are pruned from the in-memory store
+pub(crate) const PRESENTATION_RETENTION_SECS: u64 = 30 * 24 * 3600;
+
+// Distinct fingerprints kept per token; later ones aren't recorded
+pub(crate) const MAX_PRESENTATIONS_PER_TOKEN: usize = 64;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// Server-side record backing a browser session cookie.
+#[derive(Debug, Clone)]
+pub struct SessionRecord {
+    /// L402 token (`<macaroon>:<preimage>`) the session stands in for
+    pub token: String,
+    /// Unix timestamp (seconds) after which the session is no longer honoured
+    pub expires_at: u64,
+}
+
+/// How long after a resource is charged to a token its byte-range continuations are free.
+pub const RANGE_CONTINUATION_WINDOW_SECS: u64 = 600;
+
+/// Usage recorded against a token that carries a quota caveat.
+#[derive(Debug, Clone, Default)]
+pub struct UsageRecord {
+    /// Number of charged requests
+    pub uses: u64,
+    /// Resources already charged, so byte-range continuations of the same download aren't charged again
+    pub resources: HashMap<String, ResourceCharge>,
+}
+
+/// A resource charged to a token.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceCharge {
+    /// Unix timestamp the resource was charged
+    pub charged_at: u64,
+    /// End (exclusive) of the bytes served under the charge so far, from the last
+    /// partial response's `Content-Range`. A continuation has to start at or after it,
+    /// so bytes already served can't be fetched again for free. None while the response
+    /// is in flight, or once the whole resource has been served.
+    pub served_end: Option<u64>,
+}
+
+/// Cumulative spend of a token or of a client fingerprint.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SpendRecord {
+    /// Requests served
+    pub requests: u64,
+    /// Invoice amounts paid, counted once per token
+    pub spent_msat: i64,
+    /// Unix timestamp of the last request served
+    pub last_seen: u64,
+}
+
+/// Use of a token from one client fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PresentationRecord {
+    /// Client that presented the token, see `analytics::client_fingerprint`
+    pub fingerprint: String,
+    /// Unix timestamp the token was first presented from this fingerprint
+    pub first_seen: u64,
+    /// Unix timestamp it was last presented from this fingerprint
+    pub last_seen: u64,
+    /// Requests served to this fingerprint with the token
+    pub requests: u64,
+}
+
+/// Settled payments of one route and backend within one hour.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RevenueBucket {
+    /// Request path the challenges were issued for
+    pub path: String,
+    /// Unix timestamp of the start of the hour the payments were first proven in
+    pub hour: u64,
+    pub backend: String,
+    pub payments: u64,
+    pub revenue_msat: i64,
+}
+
+/// Ledger entry for a challenge handed out by the middleware.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChallengeRecord {
+    /// Hex payment hash, which is also the token id
+    pub payment_hash: String,
+    pub invoice: String,
+    pub macaroon: String,
+    pub amount_msat: i64,
+    /// Invoice memo, kept so a description hash in the invoice can be checked against it
+    pub memo: String,
+    /// Backend that minted the invoice, see `LNClient::backend_id`
+    pub backend: String,
+    /// Client the challenge was issued to, see `analytics::client_fingerprint`
+    pub fingerprint: String,
+    /// Request path the challenge was issued for, normalized like path caveats
+    pub path: String,
+    /// Unix timestamp the challenge was issued
+    pub created_at: u64,
+    /// Hex preimage, once a client proved payment with it
+    pub preimage: Option<String>,
+    /// Unix timestamp the payment was first proven to the middleware
+    pub settled_at: Option<u64>,
+    /// Unix timestamp the node reported the invoice settled, through the `settlements` endpoints
+    pub node_settled_at: Option<u64>,
+}
+
+/// Trait for persisting the state the middleware keeps between requests.
+/// This allows us to swap the in-memory store for a shared database transparently.
+pub trait TokenStore: Send + Sync + 'static {
+    fn put_session(&self, session_id: String, record: SessionRecord) -> StoreFuture<'_, ()>;
+
+    fn get_session(&self, session_id: &str) -> StoreFuture<'_, Option<SessionRecord>>;
+
+    /// Charges one use of `token_id` for `resource` unless the quota is spent.
+    /// `range_start` is the first byte of a range continuation, see
+    /// `utils::range_continuation_start`. A continuation that starts at or after the
+    /// `served_end` of the resource's charge, within `RANGE_CONTINUATION_WINDOW_SECS` of
+    /// it being charged, counts against the same entry. Returns whether the request is allowed.
+    fn consume_use(&self, token_id: &str, resource: &str, range_start: Option<u64>, max_uses: u64) -> StoreFuture<'_, bool>;
+
+    /// Records where the partial response to a request charged with `consume_use` ended,
+    /// so a continuation can pick up from there.
+    fn record_served(&self, token_id: &str, resource: &str, served_end: u64) -> StoreFuture<'_, ()>;
+
+    fn get_usage(&self, token_id: &str) -> StoreFuture<'_, Option<UsageRecord>>;
+
+    /// Takes one of `max_concurrent` in-flight request slots of `token_id`.
+    /// Returns false if they are all taken.
+    fn acquire_slot(&self, token_id: &str, max_concurrent: u64) -> StoreFuture<'_, bool>;
+
+    /// Frees a slot taken with `acquire_slot` once its response has been sent.
+    fn release_slot(&self, token_id: &str) -> StoreFuture<'_, ()>;
+
+    fn record_challenge(&self, record: ChallengeRecord) -> StoreFuture<'_, ()>;
+
+    /// Records the first time a preimage was presented for a challenge; later calls are no-ops.
+    /// Returns whether this call settled it.
+    fn mark_settled(&self, payment_hash: &str, preimage: &str, settled_at: u64) -> StoreFuture<'_, bool>;
+
+    fn get_challenge(&self, payment_hash: &str) -> StoreFuture<'_, Option<ChallengeRecord>>;
+
+    /// Records that the node reported the challenge's invoice settled; later calls are no-ops.
+    /// Returns false if the ledger has no such challenge.
+    fn record_node_settlement(&self, payment_hash: &str, settled_at: u64) -> StoreFuture<'_, bool>;
+
+    /// Challenges whose payment was first proven at or after `since`.
+    fn settled_challenges(&self, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>>;
+
+    /// Challenges settled at or after `since`, summed by route, hour and backend, oldest
+    /// hour first. Stores backed by a database can override this with a grouped query.
+    fn revenue_buckets(&self, since: u64) -> StoreFuture<'_, Vec<RevenueBucket>> {
+        Box::pin(async move {
+            let mut buckets: HashMap<(u64, String, String), RevenueBucket> = HashMap::new();
+            for challenge in self.settled_challenges(since).await? {
+                let hour = challenge.settled_at.unwrap_or_default() / 3600 * 3600;
+                let bucket = buckets.entry((hour, challenge.path.clone(), challenge.backend.clone())).or_insert_with(|| RevenueBucket {
+                    path: challenge.path,
+                    hour,
+                    backend: challenge.backend,
+                    payments: 0,
+                    revenue_msat: 0,
+                });
+                bucket.payments += 1;
+                bucket.revenue_msat += challenge.amount_msat;
+            }
+            let mut buckets: Vec<RevenueBucket> = buckets.into_values().collect();
+            buckets.sort_by(|a, b| (a.hour, &a.path, &a.backend).cmp(&(b.hour, &b.path, &b.backend)));
+            Ok(buckets)
+        })
+    }
+
+    /// Challenges issued to `fingerprint` at or after `since`, paid or not.
+    fn fingerprint_challenges(&self, fingerprint: &str, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>>;
+
+    /// Stops `token_id` from being accepted again.
+    fn revoke_token(&self, token_id: &str) -> StoreFuture<'_, ()>;
+
+    fn is_revoked(&self, token_id: &str) -> StoreFuture<'_, bool>;
+
+    /// Counts a served request, and `spent_msat` paid, against both the token and the client fingerprint.
+    fn record_spend(&self, token_id: &str, fingerprint: &str, spent_msat: i64) -> StoreFuture<'_, ()>;
+
+    fn get_token_spend(&self, token_id: &str) -> StoreFuture<'_, Option<SpendRecord>>;
+
+    fn get_fingerprint_spend(&self, fingerprint: &str) -> StoreFuture<'_, Option<SpendRecord>>;
+
+    /// Counts a request served with `token_id` to `fingerprint` at `seen_at`, recording
+    /// when that fingerprint first presented the token.
+    fn record_presentation(&self, token_id: &str, fingerprint: &str, seen_at: u64) -> StoreFuture<'_, ()>;
+
+    /// Fingerprints that presented `token_id`, in order of first presentation.
+    fn token_presentations(&self, token_id: &str) -> StoreFuture<'_, Vec<PresentationRecord>>;
+
+    /// Binds a payment of the static BOLT12 offer to the token it paid for.
+    /// Returns false if that payment already paid for a different token.
+    fn bind_offer_payment(&self, payment_hash: &str, token_id: &str) -> StoreFuture<'_, bool>;
+
+    /// Writes out anything buffered, on shutdown. Stores that write through keep this default.
+    fn flush(&self) -> StoreFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Process-local TokenStore, suitable for single-instance deployments.
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+    usage: Mutex<HashMap<String, UsageRecord>>,
+    in_flight: Mutex<HashMap<String, u64>>,
+    challenges: Mutex<HashMap<String, ChallengeRecord>>,
+    revoked: Mutex<HashSet<String>>,
+    token_spend: Mutex<HashMap<String, SpendRecord>>,
+    fingerprint_spend: Mutex<HashMap<String, SpendRecord>>,
+    presentations: Mutex<HashMap<String, Vec<PresentationRecord>>>,
+    offer_payments: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn put_session(&self, session_id: String, record: SessionRecord) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().map_err(|_| "session store poisoned")?;
+            // Drop expired sessions so the map doesn't grow without bound
+            let now = utils::now_unix();
+            sessions.retain(|_, s| s.expires_at > now);
+            sessions.insert(session_id, record);
+            Ok(())
+        })
+    }
+
+    fn get_session(&self, session_id: &str) -> StoreFuture<'_, Option<SessionRecord>> {
+        let session_id = session_id.to_string();
+        Box::pin(async move {
+            let sessions = self.sessions.lock().map_err(|_| "session store poisoned")?;
+            Ok(sessions
+                .get(&session_id)
+                .filter(|s| s.expires_at > utils::now_unix())
+                .cloned())
+        })
+    }
+
+    fn consume_use(&self, token_id: &str, resource: &str, range_start: Option<u64>, max_uses: u64) -> StoreFuture<'_, bool> {
+        let token_id = token_id.to_string();
+        let resource = resource.to_string();
+        Box::pin(async move {
+            let now = utils::now_unix();
+            let mut usage = self.usage.lock().map_err(|_| "usage store poisoned")?;
+            let record = usage.entry(token_id).or_default();
+            if let (Some(start), Some(charge)) = (range_start, record.resources.get_mut(&resource)) {
+                if charge.served_end.is_some_and(|served_end| start >= served_end) && now < charge.charged_at + RANGE_CONTINUATION_WINDOW_SECS {
+                    charge.served_end = None;
+                    return Ok(true);
+                }
+            }
+            if record.uses >= max_uses {
+                return Ok(false);
+            }
+            record.uses += 1;
+            record.resources.insert(resource, ResourceCharge { charged_at: now, served_end: None });
+            Ok(true)
+        })
+    }
+
+    fn record_served(&self, token_id: &str, resource: &str, served_end: u64) -> StoreFuture<'_, ()> {
+        let token_id = token_id.to_string();
+        let resource = resource.to_string();
+        Box::pin(async move {
+            let mut usage = self.usage.lock().map_err(|_| "usage store poisoned")?;
+            if let Some(charge) = usage.get_mut(&token_id).and_then(|record| record.resources.get_mut(&resource)) {
+                charge.served_end = Some(served_end);
+            }
+            Ok(())
+        })
+    }
+
+    fn get_usage(&self, token_id: &str) -> StoreFuture<'_, Option<UsageRecord>> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let usage = self.usage.lock().map_err(|_| "usage store poisoned")?;
+            Ok(usage.get(&token_id).cloned())
+        })
+    }
+
+    fn acquire_slot(&self, token_id: &str, max_concurrent: u64) -> StoreFuture<'_, bool> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let mut in_flight = self.in_flight.lock().map_err(|_| "in-flight store poisoned")?;
+            let slots = in_flight.entry(token_id).or_default();
+            if *slots >= max_concurrent {
+                return Ok(false);
+            }
+            *slots += 1;
+            Ok(true)
+        })
+    }
+
+    fn release_slot(&self, token_id: &str) -> StoreFuture<'_, ()> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let mut in_flight = self.in_flight.lock().map_err(|_| "in-flight store poisoned")?;
+            if let Some(slots) = in_flight.get_mut(&token_id) {
+                *slots = slots.saturating_sub(1);
+                if *slots == 0 {
+                    in_flight.remove(&token_id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn record_challenge(&self, record: ChallengeRecord) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let mut challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            let cutoff = utils::now_unix().saturating_sub(UNSETTLED_CHALLENGE_RETENTION_SECS);
+            challenges.retain(|_, c| c.settled_at.is_some() || c.node_settled_at.is_some() || c.created_at > cutoff);
+            challenges.insert(record.payment_hash.clone(), record);
+            Ok(())
+        })
+    }
+
+    fn mark_settled(&self, payment_hash: &str, preimage: &str, settled_at: u64) -> StoreFuture<'_, bool> {
+        let payment_hash = payment_hash.to_string();
+        let preimage = preimage.to_string();
+        Box::pin(async move {
+            let mut challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            match challenges.get_mut(&payment_hash) {
+                Some(record) if record.settled_at.is_none() => {
+                    record.preimage = Some(preimage);
+                    record.settled_at = Some(settled_at);
+                    Ok(true)
+                },
+                _ => Ok(false),
+            }
+        })
+    }
+
+    fn record_node_settlement(&self, payment_hash: &str, settled_at: u64) -> StoreFuture<'_, bool> {
+        let payment_hash = payment_hash.to_string();
+        Box::pin(async move {
+            let mut challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            match challenges.get_mut(&payment_hash) {
+                Some(record) => {
+                    record.node_settled_at.get_or_insert(settled_at);
+                    Ok(true)
+                },
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn get_challenge(&self, payment_hash: &str) -> StoreFuture<'_, Option<ChallengeRecord>> {
+        let payment_hash = payment_hash.to_string();
+        Box::pin(async move {
+            let challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            Ok(challenges.get(&payment_hash).cloned())
+        })
+    }
+
+    fn settled_challenges(&self, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>> {
+        Box::pin(async move {
+            let challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            Ok(challenges
+                .values()
+                .filter(|c| c.settled_at.is_some_and(|t| t >= since))
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn fingerprint_challenges(&self, fingerprint: &str, since: u64) -> StoreFuture<'_, Vec<ChallengeRecord>> {
+        let fingerprint = fingerprint.to_string();
+        Box::pin(async move {
+            let challenges = self.challenges.lock().map_err(|_| "ledger poisoned")?;
+            Ok(challenges
+                .values()
+                .filter(|c| c.fingerprint == fingerprint && c.created_at >= since)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn revoke_token(&self, token_id: &str) -> StoreFuture<'_, ()> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let mut revoked = self.revoked.lock().map_err(|_| "revocation list poisoned")?;
+            revoked.insert(token_id);
+            Ok(())
+        })
+    }
+
+    fn is_revoked(&self, token_id: &str) -> StoreFuture<'_, bool> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let revoked = self.revoked.lock().map_err(|_| "revocation list poisoned")?;
+            Ok(revoked.contains(&token_id))
+        })
+    }
+
+    fn record_spend(&self, token_id: &str, fingerprint: &str, spent_msat: i64) -> StoreFuture<'_, ()> {
+        let token_id = token_id.to_string();
+        let fingerprint = fingerprint.to_string();
+        Box::pin(async move {
+            let now = utils::now_unix();
+            for (spend, key) in [(&self.token_spend, token_id), (&self.fingerprint_spend, fingerprint)] {
+                let mut spend = spend.lock().map_err(|_| "spend store poisoned")?;
+                let record = spend.entry(key).or_default();
+                record.requests += 1;
+                record.spent_msat += spent_msat;
+                record.last_seen = now;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_token_spend(&self, token_id: &str) -> StoreFuture<'_, Option<SpendRecord>> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let spend = self.token_spend.lock().map_err(|_| "spend store poisoned")?;
+            Ok(spend.get(&token_id).cloned())
+        })
+    }
+
+    fn get_fingerprint_spend(&self, fingerprint: &str) -> StoreFuture<'_, Option<SpendRecord>> {
+        let fingerprint = fingerprint.to_string();
+        Box::pin(async move {
+            let spend = self.fingerprint_spend.lock().map_err(|_| "spend store poisoned")?;
+            Ok(spend.get(&fingerprint).cloned())
+        })
+    }
+
+    fn record_presentation(&self, token_id: &str, fingerprint: &str, seen_at: u64) -> StoreFuture<'_, ()> {
+        let token_id = token_id.to_string();
+        let fingerprint = fingerprint.to_string();
+        Box::pin(async move {
+            let mut presentations = self.presentations.lock().map_err(|_| "presentation store poisoned")?;
+            let cutoff = utils::now_unix().saturating_sub(PRESENTATION_RETENTION_SECS);
+            presentations.retain(|_, p| p.iter().any(|p| p.last_seen > cutoff));
+            let records = presentations.entry(token_id).or_default();
+            if let Some(record) = records.iter_mut().find(|p| p.fingerprint == fingerprint) {
+                record.last_seen = record.last_seen.max(seen_at);
+                record.requests += 1;
+            } else if records.len() < MAX_PRESENTATIONS_PER_TOKEN {
+                records.push(PresentationRecord {
+                    fingerprint,
+                    first_seen: seen_at,
+                    last_seen: seen_at,
+                    requests: 1,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    fn token_presentations(&self, token_id: &str) -> StoreFuture<'_, Vec<PresentationRecord>> {
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let presentations = self.presentations.lock().map_err(|_| "presentation store poisoned")?;
+            Ok(presentations.get(&token_id).cloned().unwrap_or_default())
+        })
+    }
+
+    fn bind_offer_payment(&self, payment_hash: &str, token_id: &str) -> StoreFuture<'_, bool> {
+        let payment_hash = payment_hash.to_string();
+        let token_id = token_id.to_string();
+        Box::pin(async move {
+            let mut offer_payments = self.offer_payments.lock().map_err(|_| "offer payment store poisoned")?;
+            Ok(*offer_payments.entry(payment_hash).or_insert_with(|| token_id.clone()) == token_id)
+        })
+    }
+}
@@ -0,0 +1,65 @@
+use opentelemetry::global;
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{TraceContextExt, Tracer};
+use opentelemetry::Context;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rocket::Request;
+use std::collections::HashMap;
+#[cfg(feature = "lnd")]
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+
+// Only the OpenTelemetry API is used here; spans and propagation are no-ops until
+// the application installs an SDK tracer provider and text map propagator.
+pub const TRACER_NAME: &str = "l402_middleware";
+
+#[cfg(feature = "lnd")]
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+#[cfg(feature = "lnd")]
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (MetadataKey::from_bytes(key.as_bytes()), MetadataValue::try_from(value.as_str())) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+/// Trace context propagated by the client (e.g. a W3C `traceparent` header).
+pub fn extract_context(request: &Request<'_>) -> Context {
+    let headers: HashMap<String, String> = request.headers().iter()
+        .map(|header| (header.name().as_str().to_lowercase(), header.value().to_string()))
+        .collect();
+    global::get_text_map_propagator(|propagator| propagator.extract(&headers))
+}
+
+/// Starts `name` as a child of the current span, or of the request's propagated
+/// context when no span is active, and returns the context carrying it.
+pub fn start_span(request: &Request<'_>, name: &'static str) -> Context {
+    let current = Context::current();
+    let parent = if current.has_active_span() { current } else { extract_context(request) };
+    let span = global::tracer(TRACER_NAME).start_with_context(name, &parent);
+    parent.with_span(span)
+}
+
+/// Adds the current trace context to outgoing gRPC metadata.
+#[cfg(feature = "lnd")]
+pub fn inject_metadata(metadata: &mut MetadataMap) {
+    global::get_text_map_propagator(|propagator| propagator.inject(&mut MetadataInjector(metadata)));
+}
+
+/// Headers carrying the current trace context for outgoing HTTP calls.
+pub fn http_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject(&mut HeaderInjector(&mut headers)));
+    headers
+}
@@ -0,0 +1,65 @@
+use serde::Serialize;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub type HttpRequest = http::Request<Vec<u8>>;
+pub type HttpResponse = http::Response<Vec<u8>>;
+pub type HttpFuture = Pin<Box<dyn Future<Output = Result<HttpResponse, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Sends the outbound HTTP calls of the LNURL and Eclair backends, the fiat rate
+/// lookup and webhooks. Swap it to route them through a proxy, custom DNS or an
+/// instrumented client, or to serve canned responses so tests run offline.
+pub trait HttpTransport: fmt::Debug + Send + Sync + 'static {
+    fn send(&self, request: HttpRequest) -> HttpFuture;
+}
+
+/// Default transport, backed by a reqwest client.
+#[derive(Debug, Clone, Default)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    /// Uses a preconfigured client, e.g. one built with proxy or DNS settings.
+    pub fn new(client: reqwest::Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    fn send(&self, request: HttpRequest) -> HttpFuture {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let response = client.execute(reqwest::Request::try_from(request)?).await?;
+            let mut builder = http::Response::builder().status(response.status());
+            if let Some(headers) = builder.headers_mut() {
+                headers.extend(response.headers().clone());
+            }
+            let body = response.bytes().await?.to_vec();
+            Ok(builder.body(body)?)
+        })
+    }
+}
+
+pub fn default_transport() -> Arc<dyn HttpTransport> {
+    Arc::new(ReqwestTransport::default())
+}
+
+pub fn get(url: &str) -> Result<HttpRequest, Box<dyn Error + Send + Sync>> {
+    Ok(http::Request::get(url).body(Vec::new())?)
+}
+
+pub fn post_json<T: Serialize>(url: &str, body: &T) -> Result<HttpRequest, Box<dyn Error + Send + Sync>> {
+    Ok(http::Request::post(url)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(body)?)?)
+}
+
+pub fn post_form<T: Serialize>(url: &str, body: &T) -> Result<HttpRequest, Box<dyn Error + Send + Sync>> {
+    Ok(http::Request::post(url)
+        .header(http::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(serde_urlencoded::to_string(body)?.into_bytes())?)
+}
@@ -2,6 +2,8 @@ use lightning::types::payment::{PaymentPreimage};
 use macaroon::Macaroon;
 use hex;
 
+use crate::l402;
+
 pub fn parse_l402_header(auth_field: &str) -> Result<(Macaroon, PaymentPreimage), String> {
     // Check if the authorization field is empty
     if auth_field.is_empty() {
@@ -13,7 +15,7 @@ pub fn parse_l402_header(auth_field: &str) -> Result<(Macaroon, PaymentPreimage)
       return Err(format!("L402 Header is not present"));
     }
   
-    let token = auth_field.trim_start_matches("L402 ");
+    let (_, token) = l402::split_auth_scheme(auth_field);
     let splitted: Vec<&str> = token.split(':').map(|s| s.trim()).collect();
   
     if splitted.len() != 2 {
@@ -48,8 +50,13 @@ pub fn get_macaroon_from_string(macaroon_string: String) -> Result<Macaroon, Str
     return Err("Macaroon string is empty".to_string());
   }
 
-  let mac = Macaroon::deserialize(&macaroon_string)
-    .map_err(|_| "Failed to deserialize macaroon".to_string())?;
+  // Hex macaroons never pass for base64 ones: those start with `MD` (V1) or `Ag` (V2)
+  let macaroon_string = macaroon_string.trim_matches('"');
+  let hex_binary = hex::decode(macaroon_string).ok();
+  let mac = match hex_binary {
+    Some(binary) => Macaroon::deserialize_binary(&binary),
+    None => Macaroon::deserialize(macaroon_string),
+  }.map_err(|_| "Failed to deserialize macaroon".to_string())?;
 
   Ok(mac)
 }
@@ -73,3 +80,35 @@ pub fn get_preimage_from_string(preimage_string: String) -> Result<PaymentPreima
 
   Ok(PaymentPreimage(preimage_array))
 }
+
+pub fn now_unix() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+// Compares secrets without leaking how much of them matched through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// First byte of a range that resumes a download already in progress, i.e. one whose
+// ranges all start past the first byte. Suffix ranges (`bytes=-N`) can cover the whole
+// resource, so they never count as continuations.
+pub fn range_continuation_start(range_header: &str) -> Option<u64> {
+  let ranges = range_header.trim().strip_prefix("bytes=")?;
+  ranges.split(',')
+    .map(|range| range.trim().split('-').next().unwrap_or("").parse::<u64>().ok().filter(|start| *start > 0))
+    .collect::<Option<Vec<u64>>>()?
+    .into_iter()
+    .min()
+}
+
+// End (exclusive) of the bytes a partial response carries, from its
+// `Content-Range: bytes <first>-<last>/<length>` header.
+pub fn content_range_end(content_range_header: &str) -> Option<u64> {
+  let (range, _) = content_range_header.trim().strip_prefix("bytes ")?.split_once('/')?;
+  let (_, last) = range.split_once('-')?;
+  last.trim().parse::<u64>().ok()?.checked_add(1)
+}
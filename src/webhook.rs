@@ -0,0 +1,87 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinSet;
+
+use crate::events::DomainEvent;
+use crate::observer::{L402Event, L402Observer};
+use crate::transport::{self, HttpTransport};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const WEBHOOK_SIGNATURE_HEADER_NAME: &str = "X-L402-Signature";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// URL events are POSTed to as JSON
+    pub url: String,
+    /// When set, the hex HMAC-SHA256 of the body is sent in `X-L402-Signature`
+    pub secret: Option<Vec<u8>>,
+    /// Delivers the events; a plain reqwest client when None
+    pub transport: Option<Arc<dyn HttpTransport>>,
+    /// Also POSTs the domain events (challenges, settlements, verified and revoked
+    /// tokens), e.g. to feed a billing system. That is one call per paid request.
+    pub domain_events: bool,
+}
+
+/// Observer that POSTs each event to a webhook. Delivery is fire-and-forget;
+/// failures are logged and not retried. Pending deliveries are awaited on shutdown.
+pub struct WebhookObserver {
+    config: WebhookConfig,
+    transport: Arc<dyn HttpTransport>,
+    deliveries: Mutex<JoinSet<()>>,
+}
+
+impl WebhookObserver {
+    pub fn new(config: WebhookConfig) -> Self {
+        let transport = config.transport.clone().unwrap_or_else(transport::default_transport);
+        WebhookObserver { config, transport, deliveries: Mutex::new(JoinSet::new()) }
+    }
+
+    fn deliver(&self, event: &impl Serialize) {
+        let mut request = match transport::post_json(&self.config.url, event) {
+            Ok(request) => request,
+            Err(error) => {
+                println!("Error serializing L402 event: {}", error);
+                return;
+            }
+        };
+        if let Some(secret) = &self.config.secret {
+            let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+            mac.update(request.body());
+            let signature = hex::encode(mac.finalize().into_bytes());
+            request.headers_mut().insert(WEBHOOK_SIGNATURE_HEADER_NAME, signature.parse().expect("hex is a valid header value"));
+        }
+        let response = self.transport.send(request);
+        let Ok(mut deliveries) = self.deliveries.lock() else { return };
+        // Reap finished deliveries so the set only holds pending ones
+        while deliveries.try_join_next().is_some() {}
+        deliveries.spawn(async move {
+            if let Err(error) = response.await {
+                println!("Error delivering L402 webhook: {}", error);
+            }
+        });
+    }
+}
+
+impl L402Observer for WebhookObserver {
+    fn on_event(&self, event: &L402Event) {
+        self.deliver(event);
+    }
+
+    fn on_domain_event(&self, event: &DomainEvent) {
+        if self.config.domain_events {
+            self.deliver(event);
+        }
+    }
+
+    fn flush(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let pending = self.deliveries.lock().map(|mut deliveries| std::mem::take(&mut *deliveries)).unwrap_or_default();
+        Box::pin(async move {
+            pending.join_all().await;
+        })
+    }
+}